@@ -0,0 +1,227 @@
+// -------------------------------------
+// -- `cargo bench`: throughput of the chunking and retrieval code on generated inputs
+//
+// Numbers to compare before and after an optimization, without ollama or a
+// store: a 1 MB text split (tables first, as `split_page` does) with the
+// token and the character sizer, and the retrieval post-processing
+// (deduplication, context headers, a compressor stub keeping the first
+// sentence) over chunks found in the memory store by hashed word embeddings.
+// The modules are shared with the binary the way `src/bin/query.rs` shares
+// them; the window prompts and the chunk metadata are built in main.rs and
+// aren't reachable from here. There is no MMR (maximal marginal relevance)
+// in the retrieval yet, so there's nothing of it to measure.
+
+// -- the tests of the shared modules run with the binary, without a harness here
+#![cfg_attr(test, allow(unused_imports))]
+
+// -- only the chat prompts are used here
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+// -- no --query-expansion here
+#[allow(dead_code)]
+#[path = "../src/expansion.rs"]
+mod expansion;
+// -- no --injection-denylist here
+#[allow(dead_code)]
+#[path = "../src/injection.rs"]
+mod injection;
+// -- no --rerank here
+#[allow(dead_code)]
+#[path = "../src/rerank.rs"]
+mod rerank;
+#[allow(dead_code)]
+#[path = "../src/retrieval.rs"]
+mod retrieval;
+// -- only the memory store is used here
+#[allow(dead_code)]
+#[path = "../src/store.rs"]
+mod store;
+#[allow(dead_code)]
+#[path = "../src/tables.rs"]
+mod tables;
+
+use std::{collections::HashMap, hint::black_box, sync::Arc};
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use langchain_rust::{
+    embedding::{Embedder, EmbedderError},
+    schemas::{Document, Retriever},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde_json::json;
+use text_splitter::{Characters, ChunkConfig, ChunkSizer, TextSplitter};
+use tiktoken_rs::cl100k_base;
+
+// --chunk-size of the split text
+const CHUNK_SIZE: usize = 512;
+const TEXT_BYTES: usize = 1024 * 1024;
+const STORED_CHUNKS: usize = 5_000;
+const RETRIEVED: usize = 50;
+// cosine similarity above which retrieved chunks are duplicates
+const DEDUP_THRESHOLD: f64 = 0.98;
+// dimensions of the hashed embeddings, as the --offline selftest's
+const DIMENSIONS: usize = 16;
+
+const WORDS: &[&str] = &[
+    "zaměstnanec",
+    "dovolená",
+    "mzda",
+    "smlouva",
+    "vedoucí",
+    "žádost",
+    "schválení",
+    "pracoviště",
+    "směna",
+    "příplatek",
+    "čl.",
+    "3.2",
+    "odst.",
+    "je",
+    "se",
+    "na",
+    "do",
+    "podle",
+];
+
+// -- sentences of the words, paragraphs every few sentences
+fn generated_text(rng: &mut StdRng, bytes: usize) -> String {
+    let mut text = String::with_capacity(bytes + 200);
+    while text.len() < bytes {
+        for _ in 0..rng.gen_range(5..20) {
+            text.push_str(WORDS.choose(rng).unwrap());
+            text.push(' ');
+        }
+        text.pop();
+        text.push_str(match rng.gen_range(0..6) {
+            0 => ".\n\n",
+            _ => ". ",
+        });
+    }
+    text
+}
+
+// -- counts of the words hashed into a few dimensions, the embeddings of the --offline selftest
+struct HashedEmbedder;
+
+#[async_trait]
+impl Embedder for HashedEmbedder {
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        let mut vectors = vec![];
+        for text in texts {
+            vectors.push(self.embed_query(text).await?);
+        }
+        Ok(vectors)
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+        let mut vector = vec![0.0; DIMENSIONS];
+        vector[0] = 1.0;
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 2)
+        {
+            let hash = word
+                .to_lowercase()
+                .bytes()
+                .fold(2_166_136_261_u32, |hash, byte| {
+                    (hash ^ byte as u32).wrapping_mul(16_777_619)
+                });
+            vector[hash as usize % DIMENSIONS] += 1.0;
+        }
+        Ok(vector)
+    }
+}
+
+// -- the first sentence of every chunk, the work around a compression model without it
+struct FirstSentence;
+
+#[async_trait]
+impl retrieval::Compressor for FirstSentence {
+    async fn compress(&self, _question: &str, docs: Vec<Document>) -> Vec<Document> {
+        docs.into_iter()
+            .map(|mut doc| {
+                if let Some((sentence, _)) = doc.page_content.split_once(". ") {
+                    doc.page_content = format!("{}.", sentence);
+                }
+                doc
+            })
+            .collect()
+    }
+}
+
+// -- the page without its tables split into chunks, and the rows of its tables
+fn split_page(text: &str, sizer: &(impl ChunkSizer + Copy)) -> usize {
+    let (page_text, tables) = tables::extract_tables(text);
+    let rows: usize = tables
+        .iter()
+        .map(|table| table.split(|text| sizer.size(text), CHUNK_SIZE).len())
+        .sum();
+    let splitter = TextSplitter::new(ChunkConfig::new(CHUNK_SIZE).with_sizer(*sizer));
+    splitter.chunks(&page_text).count() + rows
+}
+
+fn splitting(c: &mut Criterion) {
+    let text = generated_text(&mut StdRng::seed_from_u64(42), TEXT_BYTES);
+    let tokenizer = cl100k_base().unwrap();
+    let mut group = c.benchmark_group("split");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.sample_size(10);
+    group.bench_with_input(BenchmarkId::new("sizer", "cl100k"), &text, |b, text| {
+        b.iter(|| black_box(split_page(text, &&tokenizer)))
+    });
+    group.bench_with_input(BenchmarkId::new("sizer", "chars"), &text, |b, text| {
+        b.iter(|| black_box(split_page(text, &Characters)))
+    });
+    group.finish();
+}
+
+fn retrieval_stack(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let store = Arc::new(store::MemoryStore::new(Arc::new(HashedEmbedder)));
+    let chunks: Vec<Document> = (0..STORED_CHUNKS)
+        .map(|i| {
+            // -- every tenth chunk repeats the one before, for the deduplication
+            let text = match i % 10 {
+                9 => format!(
+                    "Duplicita. {}",
+                    generated_text(&mut StdRng::seed_from_u64(i as u64 - 1), 600)
+                ),
+                _ => generated_text(&mut StdRng::seed_from_u64(i as u64), 600),
+            };
+            Document::new(text).with_metadata(HashMap::from([
+                (
+                    "path".to_string(),
+                    json!(format!("documents/{}.pdf", i / 20)),
+                ),
+                ("page".to_string(), json!(i % 20)),
+            ]))
+        })
+        .collect();
+    runtime
+        .block_on(store::ChunkStore::add_documents(store.as_ref(), &chunks))
+        .unwrap();
+    let retriever = retrieval::StoreRetriever::new(store, RETRIEVED, 0.0)
+        .dedup_threshold(Some(DEDUP_THRESHOLD))
+        .compressor(Some(Arc::new(FirstSentence)))
+        .context_header(Some("Zdroj: {path}, strana {page}\n".to_string()));
+    let mut rng = StdRng::seed_from_u64(7);
+    let questions: Vec<String> = (0..10).map(|_| generated_text(&mut rng, 80)).collect();
+
+    let mut group = c.benchmark_group("retrieval");
+    group.throughput(Throughput::Elements(questions.len() as u64));
+    group.bench_function("post-processing", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for question in &questions {
+                    black_box(retriever.get_relevant_documents(question).await.unwrap());
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, splitting, retrieval_stack);
+criterion_main!(benches);
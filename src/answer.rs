@@ -0,0 +1,56 @@
+// -------------------------------------
+// -- answer text of a chain output
+//
+// The chain's `output` is a json string already decoded by serde, newlines,
+// backslashes and unicode escapes are the characters they stand for. Running
+// it through `unescape` once more turned `C:\new` into a line break and
+// failed on `\x`-like sequences. Only an answer the model itself wrapped as
+// a json string literal is decoded again, anything else is kept as it is.
+
+use serde_json::Value;
+
+pub fn answer_text(output: &Value) -> String {
+    let Some(output) = output.as_str() else {
+        return match output {
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+    };
+    let trimmed = output.trim();
+    if trimmed.len() >= 2 && trimmed.starts_with('"') && trimmed.ends_with('"') {
+        if let Ok(decoded) = serde_json::from_str::<String>(trimmed) {
+            return decoded;
+        }
+    }
+    output.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn backslashes_of_answers_are_kept() {
+        let kept = [
+            "Soubor je v C:\\new\\test\\x64\\report.pdf.",
+            "Znak \\n odděluje řádky, \\t tabulátory.",
+            "Vzorec \\frac{a}{b} a \\u00e9 zůstanou.",
+            "Řádek 1\nŘádek 2",
+            "\"Citace\" na začátku a \\ na konci \"",
+        ];
+        for answer in kept {
+            assert_eq!(answer_text(&json!(answer)), answer);
+        }
+    }
+
+    #[test]
+    fn answers_wrapped_as_json_strings_are_decoded() {
+        assert_eq!(
+            answer_text(&json!("\"Řádek 1\\nŘádek 2 \\u00e9 C:\\\\new\"")),
+            "Řádek 1\nŘádek 2 é C:\\new"
+        );
+        assert_eq!(answer_text(&Value::Null), "");
+        assert_eq!(answer_text(&json!(42)), "42");
+    }
+}
@@ -1,34 +1,31 @@
 use reqwest::Url;
+use std::io::Write;
 use std::sync::Arc;
-use std::{env, io::Write};
 
 use futures_util::StreamExt;
 use langchain_rust::{
     chain::{Chain, ConversationalRetrieverChainBuilder},
-    document_loaders::{pdf_extract_loader::PdfExtractLoader, Loader},
-    embedding::{Embedder, OllamaEmbedder},
+    embedding::OllamaEmbedder,
     fmt_message, fmt_template,
     llm::client::{GenerationOptions, Ollama, OllamaClient},
     memory::SimpleMemory,
     message_formatter,
     prompt::HumanMessagePromptTemplate,
     prompt_args,
-    schemas::{Document, Message},
+    schemas::Message,
     template_jinja2,
     vectorstore::{
         qdrant::{Qdrant, StoreBuilder},
-        Retriever, VecStoreOptions, VectorStore,
+        Retriever,
     },
 };
-use text_splitter::{ChunkConfig, TextSplitter};
-use tiktoken_rs::cl100k_base;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
     // -- llm
-    let ollama = Ollama::default().with_model("aya");
+    // let ollama = Ollama::default().with_model("aya");
     let ollama_client = Arc::new(OllamaClient::from_url(
         Url::parse("http://192.168.1.159:11434").unwrap(),
     ));
@@ -74,7 +71,7 @@ async fn main() {
 
     loop {
         // Ask for user input
-        println!("");
+        println!();
         print!("Query> ");
         std::io::stdout().flush().unwrap();
         let mut query = String::new();
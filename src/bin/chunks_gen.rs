@@ -118,7 +118,7 @@ async fn main() {
             doc_text += &doc_entry.page_content;
             let chunks = splitter
                 .chunks(&doc_entry.page_content)
-                .map(|d| Document::new(d))
+                .map(Document::new)
                 .collect::<Vec<_>>();
             chunks_vec.extend(chunks);
         }
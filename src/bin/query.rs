@@ -0,0 +1,257 @@
+// -------------------------------------
+// -- one-shot question for scripts: `echo "What is policy 42?" | query`
+//
+// The answer is streamed to stdout and the sources go to stderr, so stdout
+// can be captured as it is. Exit codes: 0 answered, 1 ollama or qdrant
+// failed, 2 no chunk scored above the threshold (nothing is answered then
+// unless --answer-without-sources).
+
+#[path = "../answer.rs"]
+mod answer;
+// -- only the chat prompts are used here
+#[allow(dead_code)]
+#[path = "../config.rs"]
+mod config;
+// -- no --query-expansion here
+#[allow(dead_code)]
+#[path = "../expansion.rs"]
+mod expansion;
+// -- no --injection-denylist here, chunks are only quoted
+#[allow(dead_code)]
+#[path = "../injection.rs"]
+mod injection;
+// -- no --rerank here
+#[allow(dead_code)]
+#[path = "../rerank.rs"]
+mod rerank;
+// -- only the recording of the retrieval is used here
+#[allow(dead_code)]
+#[path = "../retrieval.rs"]
+mod retrieval;
+// -- only the qdrant store and the embedding truncation are used here
+#[allow(dead_code)]
+#[path = "../store.rs"]
+mod store;
+
+use std::{
+    io::{Read, Write},
+    process::exit,
+    sync::Arc,
+};
+
+use clap::Parser;
+use futures::StreamExt;
+use langchain_rust::{
+    chain::{Chain, ConversationalRetrieverChainBuilder},
+    embedding::OllamaEmbedder,
+    fmt_message, fmt_template,
+    llm::client::{GenerationOptions, Ollama, OllamaClient},
+    memory::SimpleMemory,
+    message_formatter,
+    prompt::HumanMessagePromptTemplate,
+    prompt_args,
+    schemas::Message,
+    template_jinja2,
+    vectorstore::qdrant::Qdrant,
+};
+use reqwest::Url;
+use serde_json::json;
+use store::{QdrantStore, SimilarityMetric, TruncatedEmbedder};
+
+// same retrieval as the chat mode of chunk_contextor
+const RETRIEVED_DOCUMENTS: usize = 5;
+const SCORE_THRESHOLD: f32 = 0.55;
+
+// exit code of questions no chunk matched
+const EXIT_NO_SOURCES: i32 = 2;
+
+#[derive(Parser)]
+#[command(version, about = "Answer a single question from the document store", long_about = None)]
+struct Cli {
+    // question, read from stdin when not given
+    #[arg(short, long)]
+    question: Option<String>,
+    // print {"answer": ..., "sources": [...]} at the end instead of streaming the answer
+    #[arg(long)]
+    json_output: bool,
+    // answer from the model's own knowledge when no chunk matches, still exiting with 2
+    #[arg(long)]
+    answer_without_sources: bool,
+    // chatting model
+    #[arg(short, long, default_value = "gemma3:12b")]
+    model: String,
+    // embedding model
+    #[arg(short, long, default_value = "paraphrase-multilingual")]
+    embed: String,
+    // --embed-dimensions the documents were ingested with
+    #[arg(long)]
+    embed_dimensions: Option<usize>,
+    // qdrant gRPC url
+    #[arg(long, default_value = "http://localhost:6334")]
+    db: String,
+    // --similarity-metric the collection was created with
+    #[arg(long, value_enum, default_value_t = SimilarityMetric::Cosine)]
+    similarity_metric: SimilarityMetric,
+    #[arg(short, long, default_value = "http://localhost:11434")]
+    ollama: String,
+    // show `[src:<hash>]` instead of document paths
+    #[arg(long)]
+    anonymize_sources: bool,
+    // text added to the end of the system prompt, can be repeated
+    #[arg(long)]
+    system_prompt_append: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let question = match cli.question.clone() {
+        Some(question) => question,
+        None => {
+            let mut question = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut question) {
+                eprintln!("Error reading question from stdin: {}", e);
+                exit(1);
+            }
+            question
+        }
+    };
+    let question = question.trim();
+    if question.is_empty() {
+        eprintln!("Empty question. Pass --question or pipe it into stdin.");
+        exit(1);
+    }
+
+    // -- llm
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama).expect("Invalid --ollama url"),
+    ));
+    let ollama = Ollama::new(
+        ollama_client.clone(),
+        cli.model.clone(),
+        Some(GenerationOptions::default()),
+    );
+
+    let ollama_embed = TruncatedEmbedder::new(
+        OllamaEmbedder::new(
+            ollama_client,
+            cli.embed.clone(),
+            Some(GenerationOptions::default()),
+        ),
+        cli.embed_dimensions,
+    );
+    let db_client = Qdrant::from_url(&cli.db).build().expect("Invalid --db url");
+    match store::collection_metric(&db_client).await {
+        Ok(Some(created)) if created != cli.similarity_metric => {
+            eprintln!(
+                "The collection compares chunks by {}, not by --similarity-metric {}.",
+                created.name(),
+                cli.similarity_metric.name()
+            );
+            exit(1);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Error connecting to qdrant at {}: {}", cli.db, e);
+            exit(1);
+        }
+    }
+    // -- query only reads, the collection parameters are generate's
+    let vector_store = QdrantStore::open(
+        db_client,
+        ollama_embed,
+        cli.similarity_metric,
+        &store::CollectionParams::default(),
+    )
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Error connecting to qdrant at {}: {}", cli.db, e);
+        exit(1);
+    });
+
+    let msg_template = template_jinja2!(config::CHAT_PROMPT_STR, "context", "question");
+    let system_prompt = std::iter::once(config::SYSTEM_PROMPT_STR)
+        .chain(cli.system_prompt_append.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let prompt = message_formatter![
+        fmt_message!(Message::new_system_message(system_prompt)),
+        fmt_template!(HumanMessagePromptTemplate::new(msg_template))
+    ];
+    // -- there is no history to rephrase the question with
+    let chain = ConversationalRetrieverChainBuilder::new()
+        .llm(ollama)
+        .rephrase_question(false)
+        .memory(SimpleMemory::new().into())
+        .retriever(
+            retrieval::StoreRetriever::new(
+                Arc::new(vector_store),
+                RETRIEVED_DOCUMENTS,
+                SCORE_THRESHOLD,
+            )
+            .anonymize_sources(cli.anonymize_sources),
+        )
+        .return_source_documents(true)
+        .prompt(prompt)
+        .build()
+        .expect("Error building ConversationalChain");
+
+    let input_variables = prompt_args! {
+        "question" => question,
+    };
+    let (stream, retrieval) = retrieval::recording(chain.stream(input_variables)).await;
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Error answering the question: {}", e);
+            exit(1);
+        }
+    };
+    let mut sources: Vec<String> = retrieval
+        .map(|retrieval| retrieval.documents)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|d| d.metadata.get("path")?.as_str().map(str::to_string))
+        .collect();
+    sources.sort();
+    sources.dedup();
+    let code = match sources.is_empty() {
+        true => EXIT_NO_SOURCES,
+        false => 0,
+    };
+    if sources.is_empty() && !cli.answer_without_sources {
+        eprintln!("No document matches the question.");
+        exit(code);
+    }
+
+    // -- streamed as it is generated, buffered for the json
+    let mut answer = String::new();
+    let mut stdout = std::io::stdout();
+    while let Some(data) = stream.next().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("\nError answering the question: {}", e);
+                exit(1);
+            }
+        };
+        answer.push_str(&data.content);
+        if !cli.json_output {
+            print!("{}", data.content);
+            stdout.flush().ok();
+        }
+    }
+
+    if cli.json_output {
+        let answer = answer::answer_text(&json!(answer));
+        println!("{}", json!({ "answer": answer, "sources": sources }));
+    } else {
+        println!();
+        if !sources.is_empty() {
+            eprintln!("-------\ndocuments:[{}]", sources.join(", "));
+        }
+    }
+    exit(code);
+}
@@ -0,0 +1,289 @@
+// -------------------------------------
+// -- interactive retrieval for tuning the chunking and prompts: `test_retrieval`
+//
+// Every line typed is a query, and the chunks qdrant returns for it are
+// printed raw with their scores, without any model answering. Commands
+// change the search and run the last query again:
+//   /threshold 0.6   minimal score of a returned chunk
+//   /limit 10        chunks returned
+//   /explain         similarity details of the last query's chunks
+//   /help, /quit
+
+// -- only the qdrant store and the embedding truncation are used here
+#[allow(dead_code)]
+#[path = "../store.rs"]
+mod store;
+
+use std::{
+    io::{BufRead, Write},
+    process::exit,
+    sync::Arc,
+};
+
+use clap::Parser;
+use langchain_rust::{
+    embedding::{Embedder, OllamaEmbedder},
+    llm::client::{GenerationOptions, OllamaClient},
+    schemas::Document,
+    vectorstore::qdrant::Qdrant,
+};
+use reqwest::Url;
+use serde_json::Value;
+use store::{ChunkStore, MetadataFilter, QdrantStore, SimilarityMetric, TruncatedEmbedder};
+
+// characters of a chunk's text shown
+const PREVIEW_CHARS: usize = 300;
+
+#[derive(Parser)]
+#[command(version, about = "Show the chunks retrieved for queries, without a model", long_about = None)]
+struct Cli {
+    // chunks returned for a query, `/limit` changes it
+    #[arg(short, long, default_value_t = 5)]
+    limit: usize,
+    // minimal score of a returned chunk, `/threshold` changes it
+    #[arg(short, long, default_value_t = 0.55)]
+    threshold: f32,
+    // embedding model
+    #[arg(short, long, default_value = "paraphrase-multilingual")]
+    embed: String,
+    // --embed-dimensions the documents were ingested with
+    #[arg(long)]
+    embed_dimensions: Option<usize>,
+    // qdrant gRPC url
+    #[arg(long, default_value = "http://localhost:6334")]
+    db: String,
+    // --similarity-metric the collection was created with
+    #[arg(long, value_enum, default_value_t = SimilarityMetric::Cosine)]
+    similarity_metric: SimilarityMetric,
+    #[arg(short, long, default_value = "http://localhost:11434")]
+    ollama: String,
+}
+
+enum Command {
+    Query(String),
+    Threshold(f32),
+    Limit(usize),
+    Explain,
+    Help,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let Some(command) = line.strip_prefix('/') else {
+        return Ok(Command::Query(line.to_string()));
+    };
+    let (name, value) = command.split_once(' ').unwrap_or((command, ""));
+    let value = value.trim();
+    match name {
+        "threshold" => value
+            .parse()
+            .map(Command::Threshold)
+            .map_err(|_| "usage: /threshold 0.6".to_string()),
+        "limit" => match value.parse() {
+            Ok(limit) if limit > 0 => Ok(Command::Limit(limit)),
+            _ => Err("usage: /limit 10".to_string()),
+        },
+        "explain" => Ok(Command::Explain),
+        "help" => Ok(Command::Help),
+        "quit" | "exit" => Ok(Command::Quit),
+        _ => Err(format!("unknown command /{}, see /help", name)),
+    }
+}
+
+fn help() {
+    println!("a query         the chunks retrieved for it");
+    println!("/threshold 0.6  minimal score, the last query runs again");
+    println!("/limit 10       chunks returned, the last query runs again");
+    println!("/explain        similarity details of the last query's chunks");
+    println!("/quit");
+}
+
+fn preview(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+fn metadata_text(doc: &Document, key: &str) -> String {
+    match doc.metadata.get(key) {
+        Some(Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn print_chunks(found: &[(Document, Vec<f64>)], threshold: f32) {
+    if found.is_empty() {
+        println!("no chunk scored above {}", threshold);
+        return;
+    }
+    for (rank, (doc, _)) in found.iter().enumerate() {
+        println!(
+            "#{} score {:.4}  {} chunk {}",
+            rank + 1,
+            doc.score,
+            metadata_text(doc, "path"),
+            metadata_text(doc, "chunk_index")
+        );
+        println!("   {}", preview(&store::chunk_text(&doc.page_content)));
+    }
+}
+
+// -- the query's embedding against every chunk's stored one, by every metric
+fn explain(query: &str, query_vector: &[f64], found: &[(Document, Vec<f64>)], threshold: f32) {
+    println!(
+        "query {:?}: {} dimensions, norm {:.4}",
+        query,
+        query_vector.len(),
+        store::l2_norm(query_vector)
+    );
+    for (rank, (doc, vector)) in found.iter().enumerate() {
+        let dot: f64 = query_vector.iter().zip(vector).map(|(a, b)| a * b).sum();
+        let distance = query_vector
+            .iter()
+            .zip(vector)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt();
+        println!(
+            "#{} score {:.4} ({:+.4} over the threshold)  cosine {:.4}  dot {:.4}  euclidean {:.4}  norm {:.4}",
+            rank + 1,
+            doc.score,
+            doc.score - threshold as f64,
+            store::cosine_similarity(query_vector, vector),
+            dot,
+            distance,
+            store::l2_norm(vector)
+        );
+    }
+    if let [.., (second_last, _), (last, _)] = found {
+        println!(
+            "score gap between the last two chunks {:.4}",
+            second_last.score - last.score
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama).expect("Invalid --ollama url"),
+    ));
+    let embedder = || {
+        TruncatedEmbedder::new(
+            OllamaEmbedder::new(
+                ollama_client.clone(),
+                cli.embed.clone(),
+                Some(GenerationOptions::default()),
+            ),
+            cli.embed_dimensions,
+        )
+    };
+    let db_client = Qdrant::from_url(&cli.db).build().expect("Invalid --db url");
+    match store::collection_metric(&db_client).await {
+        Ok(Some(created)) if created != cli.similarity_metric => {
+            eprintln!(
+                "The collection compares chunks by {}, not by --similarity-metric {}.",
+                created.name(),
+                cli.similarity_metric.name()
+            );
+            exit(1);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Error connecting to qdrant at {}: {}", cli.db, e);
+            exit(1);
+        }
+    }
+    let vector_store = QdrantStore::open(
+        db_client,
+        embedder(),
+        cli.similarity_metric,
+        &store::CollectionParams::default(),
+    )
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Error connecting to qdrant at {}: {}", cli.db, e);
+        exit(1);
+    });
+    let query_embedder = embedder();
+
+    let mut limit = cli.limit;
+    let mut threshold = cli.threshold;
+    let mut last_query: Option<String> = None;
+    let mut found: Vec<(Document, Vec<f64>)> = vec![];
+    println!(
+        "limit {}, threshold {}, /help for the commands",
+        limit, threshold
+    );
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().unwrap();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let query = match parse_command(&line) {
+            Ok(Command::Query(query)) => query,
+            Ok(Command::Threshold(value)) => {
+                threshold = value;
+                println!("threshold {}", threshold);
+                match &last_query {
+                    Some(query) => query.clone(),
+                    None => continue,
+                }
+            }
+            Ok(Command::Limit(value)) => {
+                limit = value;
+                println!("limit {}", limit);
+                match &last_query {
+                    Some(query) => query.clone(),
+                    None => continue,
+                }
+            }
+            Ok(Command::Explain) => {
+                let Some(query) = &last_query else {
+                    println!("no query yet");
+                    continue;
+                };
+                match query_embedder.embed_query(query).await {
+                    Ok(query_vector) => explain(query, &query_vector, &found, threshold),
+                    Err(e) => eprintln!("embedding the query failed: {}", e),
+                }
+                continue;
+            }
+            Ok(Command::Help) => {
+                help();
+                continue;
+            }
+            Ok(Command::Quit) => break,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        match vector_store
+            .similarity_search_with_vectors(&query, limit, threshold, &MetadataFilter::default())
+            .await
+        {
+            Ok(results) => {
+                found = results;
+                print_chunks(&found, threshold);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                found = vec![];
+            }
+        }
+        last_query = Some(query);
+    }
+}
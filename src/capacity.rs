@@ -0,0 +1,73 @@
+// -------------------------------------
+// -- `--max-collection-points`: warnings before the collection outgrows its limit
+//
+// Qdrant keeps accepting points until its storage runs out, and ingestion
+// then fails half way through a document. `generate` warns when the chunks it
+// is about to store would go over the limit, `web` checks the collection every
+// `--collection-check-interval-mins` and warns once it is 80% full.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::store::ChunkStore;
+
+// share of the limit `web` warns at
+const WARNING_SHARE: f64 = 0.8;
+
+// -- warning when storing `adding` more chunks goes over `max`
+pub fn ingestion_warning(count: u64, adding: u64, max: u64) -> Option<String> {
+    (count + adding > max).then(|| {
+        format!(
+            "the collection holds {} chunks, storing {} more exceeds --max-collection-points {}",
+            count, adding, max
+        )
+    })
+}
+
+// -- warning when the collection is over `WARNING_SHARE` of `max`
+pub fn fill_warning(count: u64, max: u64) -> Option<String> {
+    (count as f64 > max as f64 * WARNING_SHARE).then(|| {
+        format!(
+            "the collection holds {} chunks, {:.0}% of --max-collection-points {}",
+            count,
+            count as f64 * 100.0 / max as f64,
+            max
+        )
+    })
+}
+
+// -- logs `fill_warning` every `interval`, a failed count is logged and tried next time
+pub fn spawn_monitor(store: Arc<dyn ChunkStore>, max: u64, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match store.count().await {
+                Ok(count) => match fill_warning(count, max) {
+                    Some(warning) => log::warn!("{}", warning),
+                    None => log::debug!("the collection holds {} chunks", count),
+                },
+                Err(e) => log::warn!("checking the collection size failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_start_at_the_limit_and_at_its_share() {
+        assert_eq!(ingestion_warning(900, 100, 1000), None);
+        assert_eq!(
+            ingestion_warning(900, 101, 1000).as_deref(),
+            Some("the collection holds 900 chunks, storing 101 more exceeds --max-collection-points 1000")
+        );
+
+        assert_eq!(fill_warning(800, 1000), None);
+        assert_eq!(
+            fill_warning(850, 1000).as_deref(),
+            Some("the collection holds 850 chunks, 85% of --max-collection-points 1000")
+        );
+    }
+}
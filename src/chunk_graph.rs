@@ -0,0 +1,235 @@
+// -------------------------------------
+// -- `--chunk-graph-export`: the chunks and their context windows as a graph
+//
+// Every chunk is a node with its text, document path, index and metadata,
+// and an undirected edge joins it to each chunk of its window prompt, the
+// `neighbours` on either side in the same document, weighted by their
+// distance. Written after splitting, before any enrichment, as GraphML
+// (yEd, Gephi, networkx.read_graphml) or as networkx's node-link json; the
+// graph is a petgraph `UnGraph` and both formats are written from it.
+
+use std::{collections::HashMap, fs};
+
+use clap::ValueEnum;
+use petgraph::{
+    graph::{NodeIndex, UnGraph},
+    visit::EdgeRef,
+};
+use serde_json::{json, Value};
+
+use crate::PreparedDocument;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    #[default]
+    Graphml,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GraphFormat::Graphml => "graphml",
+            GraphFormat::Json => "json",
+        }
+    }
+}
+
+struct Node {
+    path: String,
+    chunk_index: usize,
+    text: String,
+    metadata: HashMap<String, Value>,
+}
+
+// -- edges are weighted by the distance of their chunks
+struct Graph(UnGraph<Node, usize>);
+
+impl Graph {
+    fn new(documents: &[&PreparedDocument]) -> Self {
+        let mut graph = UnGraph::new_undirected();
+        for document in documents {
+            let mut window: Vec<NodeIndex> = vec![];
+            for (index, chunk) in document.chunks.iter().enumerate() {
+                let mut metadata = chunk.metadata.clone();
+                metadata.extend(document.metadata.clone());
+                if let Some(collection) = &document.collection {
+                    metadata.insert("collection".to_string(), collection.clone());
+                }
+                let node = graph.add_node(Node {
+                    path: document.doc_path.clone(),
+                    chunk_index: index,
+                    text: chunk.page_content.clone(),
+                    metadata,
+                });
+                // -- the edges to the previous chunks of the window, the next ones add theirs
+                for distance in 1..=document.neighbours.min(index) {
+                    graph.add_edge(window[index - distance], node, distance);
+                }
+                window.push(node);
+            }
+        }
+        Graph(graph)
+    }
+
+    fn graphml(&self) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"path\" for=\"node\" attr.name=\"path\" attr.type=\"string\"/>\n",
+            "  <key id=\"chunk_index\" for=\"node\" attr.name=\"chunk_index\" attr.type=\"int\"/>\n",
+            "  <key id=\"text\" for=\"node\" attr.name=\"text\" attr.type=\"string\"/>\n",
+            "  <key id=\"metadata\" for=\"node\" attr.name=\"metadata\" attr.type=\"string\"/>\n",
+            "  <key id=\"distance\" for=\"edge\" attr.name=\"distance\" attr.type=\"int\"/>\n",
+            "  <graph id=\"chunks\" edgedefault=\"undirected\">\n",
+        ));
+        for id in self.0.node_indices() {
+            let node = &self.0[id];
+            xml.push_str(&format!(
+                concat!(
+                    "    <node id=\"n{}\">\n",
+                    "      <data key=\"path\">{}</data>\n",
+                    "      <data key=\"chunk_index\">{}</data>\n",
+                    "      <data key=\"text\">{}</data>\n",
+                    "      <data key=\"metadata\">{}</data>\n",
+                    "    </node>\n",
+                ),
+                id.index(),
+                xml_text(&node.path),
+                node.chunk_index,
+                xml_text(&node.text),
+                xml_text(&serde_json::to_string(&node.metadata).unwrap()),
+            ));
+        }
+        for edge in self.0.edge_references() {
+            xml.push_str(&format!(
+                "    <edge source=\"n{}\" target=\"n{}\"><data key=\"distance\">{}</data></edge>\n",
+                edge.source().index(),
+                edge.target().index(),
+                edge.weight()
+            ));
+        }
+        xml.push_str("  </graph>\n</graphml>\n");
+        xml
+    }
+
+    // -- node-link json as networkx.node_link_graph reads it
+    fn json(&self) -> Value {
+        json!({
+            "directed": false,
+            "multigraph": false,
+            "graph": {},
+            "nodes": self.0.node_indices().map(|id| {
+                let node = &self.0[id];
+                json!({
+                    "id": format!("n{}", id.index()),
+                    "path": node.path,
+                    "chunk_index": node.chunk_index,
+                    "text": node.text,
+                    "metadata": node.metadata,
+                })
+            }).collect::<Vec<_>>(),
+            "edges": self.0.edge_references().map(|edge| json!({
+                "source": format!("n{}", edge.source().index()),
+                "target": format!("n{}", edge.target().index()),
+                "distance": edge.weight(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+// -- escaped for xml, without the control characters xml 1.0 can't hold (form feeds of pdfs)
+fn xml_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// -- the nodes and edges written
+pub fn write(
+    path: &str,
+    format: GraphFormat,
+    documents: &[&PreparedDocument],
+) -> Result<(usize, usize), String> {
+    let graph = Graph::new(documents);
+    let content = match format {
+        GraphFormat::Graphml => graph.graphml(),
+        GraphFormat::Json => serde_json::to_string_pretty(&graph.json()).unwrap(),
+    };
+    fs::write(path, content)
+        .map_err(|e| format!("writing the chunk graph to {} failed: {}", path, e))?;
+    Ok((graph.0.node_count(), graph.0.edge_count()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DocumentSizer, Sizer};
+    use langchain_rust::schemas::Document;
+
+    fn document(path: &str, chunks: &[&str], neighbours: usize) -> PreparedDocument {
+        PreparedDocument {
+            doc_path: path.to_string(),
+            collection: Some(json!({ "name": "hr" })),
+            metadata: HashMap::from([("department".to_string(), json!("HR"))]),
+            language: None,
+            sizer: DocumentSizer::new(Some(Sizer::Chars), None),
+            doc_text: chunks.join(" "),
+            chunks: chunks.iter().map(|chunk| Document::new(*chunk)).collect(),
+            enrich: true,
+            neighbours,
+            last_modified: None,
+        }
+    }
+
+    #[test]
+    fn window_neighbours_are_joined_within_a_document() {
+        let a = document("a.pdf", &["A0", "A1", "A2", "A3"], 2);
+        let b = document("b.pdf", &["B0 <čl. 3> & \"x\"\u{c}", "B1"], 1);
+        let graph = Graph::new(&[&a, &b]);
+        assert_eq!(graph.0.node_count(), 6);
+        assert_eq!(
+            graph
+                .0
+                .edge_references()
+                .map(|edge| (edge.source().index(), edge.target().index(), *edge.weight()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 1, 1),
+                (1, 2, 1),
+                (0, 2, 2),
+                (2, 3, 1),
+                (1, 3, 2),
+                (4, 5, 1)
+            ]
+        );
+        assert_eq!(
+            graph.0[NodeIndex::new(4)].metadata["collection"],
+            json!({ "name": "hr" })
+        );
+
+        let xml = graph.graphml();
+        assert!(xml.contains("<data key=\"text\">B0 &lt;čl. 3&gt; &amp; &quot;x&quot;</data>"));
+        assert!(xml
+            .contains("<edge source=\"n4\" target=\"n5\"><data key=\"distance\">1</data></edge>"));
+        assert!(xml.contains("&quot;department&quot;:&quot;HR&quot;"));
+
+        let json = graph.json();
+        assert_eq!(json["nodes"][5]["path"], json!("b.pdf"));
+        assert_eq!(json["nodes"][5]["chunk_index"], json!(1));
+        assert_eq!(
+            json["edges"][4],
+            json!({ "source": "n1", "target": "n3", "distance": 2 })
+        );
+    }
+}
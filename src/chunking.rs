@@ -0,0 +1,439 @@
+// -------------------------------------
+// -- sentence based chunking
+//
+// semantic: every sentence is embedded and a chunk ends where the mean
+// embedding of the sentences before a boundary and of the sentences after it
+// stop being similar, or where the next sentence would overflow the chunk size.
+//
+// sentence-window: every sentence is its own chunk, the sentences around it
+// are stored next to it and used in the prompt instead of it.
+
+use langchain_rust::embedding::Embedder;
+use text_splitter::{ChunkConfig, ChunkSizer, TextSplitter};
+
+use crate::{cosine_similarity, DocumentSizer};
+
+// sentences on each side of a boundary that are compared
+const SENTENCE_WINDOW: usize = 2;
+// shorter texts have too few boundaries to compare, the token splitter is used
+const MIN_SENTENCES: usize = 4;
+// sentences embedded in one request
+const EMBED_BATCH_SIZE: usize = 64;
+
+// -- sentences end with `.`, `!` or `?` followed by whitespace, or at an empty line
+pub fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = vec![];
+    for paragraph in text.split("\n\n") {
+        let mut start = 0;
+        let mut chars = paragraph.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let at_end = matches!(c, '.' | '!' | '?')
+                && chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+            if at_end {
+                let end = i + c.len_utf8();
+                sentences.push(paragraph[start..end].to_string());
+                start = end;
+            }
+        }
+        sentences.push(paragraph[start..].to_string());
+    }
+    sentences
+        .into_iter()
+        .map(|sentence| sentence.trim().to_string())
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+// -- every sentence with `size` sentences before and after it, cut at the document ends
+pub fn sentence_windows(sentences: &[String], size: usize) -> Vec<String> {
+    (0..sentences.len())
+        .map(|i| {
+            let start = i.saturating_sub(size);
+            let end = (i + size + 1).min(sentences.len());
+            sentences[start..end].join(" ")
+        })
+        .collect()
+}
+
+fn mean(vectors: &[Vec<f64>]) -> Vec<f64> {
+    let mut mean = vec![0.0; vectors[0].len()];
+    for vector in vectors {
+        for (sum, value) in mean.iter_mut().zip(vector) {
+            *sum += value;
+        }
+    }
+    mean.iter().map(|sum| sum / vectors.len() as f64).collect()
+}
+
+pub async fn semantic_chunks(
+    text: &str,
+    embedder: &dyn Embedder,
+    sizer: &DocumentSizer,
+    max_size: usize,
+    threshold: f64,
+) -> Result<Vec<String>, String> {
+    let sentences = split_sentences(text);
+    if sentences.len() < MIN_SENTENCES {
+        return Err(format!("only {} sentences", sentences.len()));
+    }
+
+    let mut embeddings: Vec<Vec<f64>> = Vec::with_capacity(sentences.len());
+    for batch in sentences.chunks(EMBED_BATCH_SIZE) {
+        let vectors = embedder
+            .embed_documents(batch)
+            .await
+            .map_err(|e| format!("embedding sentences failed: {}", e))?;
+        if vectors.len() != batch.len() {
+            return Err("embedder returned a wrong number of vectors".to_string());
+        }
+        embeddings.extend(vectors);
+    }
+
+    // -- similarity across the boundary before every sentence
+    let similarities: Vec<f64> = (0..sentences.len())
+        .map(|i| {
+            if i == 0 {
+                return 1.0;
+            }
+            let before = mean(&embeddings[i.saturating_sub(SENTENCE_WINDOW)..i]);
+            let after = mean(&embeddings[i..(i + SENTENCE_WINDOW).min(sentences.len())]);
+            cosine_similarity(&before, &after)
+        })
+        .collect();
+
+    let mut chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    for (sentence, similarity) in sentences.iter().zip(similarities) {
+        let candidate = if current.is_empty() {
+            sentence.clone()
+        } else {
+            format!("{} {}", current, sentence)
+        };
+        let topic_changed = similarity < threshold;
+        if !current.is_empty() && (topic_changed || sizer.size(&candidate) > max_size) {
+            chunks.push(std::mem::take(&mut current));
+            current = sentence.clone();
+        } else {
+            current = candidate;
+        }
+
+        // -- a single sentence over the ceiling is cut by the token splitter
+        if sizer.size(&current) > max_size {
+            let splitter = TextSplitter::new(ChunkConfig::new(max_size).with_sizer(sizer));
+            chunks.extend(splitter.chunks(&current).map(str::to_string));
+            current.clear();
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sentences(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("S{}.", i)).collect()
+    }
+
+    #[test]
+    fn sentence_windows_are_cut_at_document_start_and_end() {
+        let windows = sentence_windows(&sentences(5), 2);
+        assert_eq!(
+            windows,
+            vec![
+                "S1. S2. S3.",
+                "S1. S2. S3. S4.",
+                "S1. S2. S3. S4. S5.",
+                "S2. S3. S4. S5.",
+                "S3. S4. S5.",
+            ]
+        );
+    }
+
+    #[test]
+    fn sentence_window_larger_than_document_covers_it_whole() {
+        let windows = sentence_windows(&sentences(3), 10);
+        assert!(windows.iter().all(|w| w == "S1. S2. S3."));
+    }
+
+    #[test]
+    fn sentence_window_of_zero_is_the_sentence_itself() {
+        assert_eq!(
+            sentence_windows(&sentences(3), 0),
+            vec!["S1.", "S2.", "S3."]
+        );
+    }
+
+    #[test]
+    fn sentence_windows_of_empty_document() {
+        assert!(sentence_windows(&[], 2).is_empty());
+    }
+
+    #[test]
+    fn split_sentences_keeps_decimal_numbers_and_paragraphs() {
+        assert_eq!(
+            split_sentences("Rate is 2.5 percent. Really?\n\nNew paragraph"),
+            vec!["Rate is 2.5 percent.", "Really?", "New paragraph"]
+        );
+    }
+}
+
+// -------------------------------------
+// -- the chunking invariants on generated documents, with the splitting of main.rs
+#[cfg(test)]
+mod properties {
+    use std::collections::HashMap;
+
+    use clap::Parser;
+    use langchain_rust::{prompt::PromptArgs, schemas::Document};
+    use proptest::{
+        prelude::*,
+        test_runner::{Config, TestRunner},
+    };
+    use text_splitter::{ChunkConfig, TextSplitter};
+
+    use super::*;
+    use crate::{
+        apply_overlap, selftest, split_page, trimmed_neighbours, window_input, Cli, Ingest,
+        OverlapStrategy, PreparedDocument, Sizer,
+    };
+
+    const CASES: u32 = 32;
+    // words of the pages: accents, combining marks, other scripts, emoji, numbers and
+    // sentence ends, besides any printable text
+    const WORDS: &[&str] = &[
+        "dovolená",
+        "Mzda",
+        "čl.",
+        "3.2",
+        "Žádost",
+        "e\u{301}",
+        "東京都",
+        "данные",
+        "🚀",
+        "x",
+        "Konec.",
+        "Proč?",
+        "ano!",
+        "...",
+        "1 000 Kč",
+    ];
+    // whitespace between the words, pathological runs included
+    const SPACES: &[&str] = &[
+        " ", "  ", "\n", "\n\n", "\n\n\n", "\t", "\u{a0}", " \n \n", "\r\n", "   \t  ",
+    ];
+
+    fn word() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => prop::sample::select(WORDS).prop_map(str::to_string),
+            1 => "\\PC{1,8}",
+        ]
+    }
+
+    fn whitespace() -> impl Strategy<Value = String> {
+        prop_oneof![
+            3 => prop::sample::select(SPACES).prop_map(str::to_string),
+            1 => "[ \t\n\r\u{a0}\u{2003}\u{3000}]{1,6}",
+        ]
+    }
+
+    fn page() -> impl Strategy<Value = String> {
+        (
+            prop::option::weighted(0.2, whitespace()),
+            prop::collection::vec((word(), whitespace()), 0..120),
+        )
+            .prop_map(|(leading, words)| {
+                let mut page = leading.unwrap_or_default();
+                for (word, space) in words {
+                    page.push_str(&word);
+                    page.push_str(&space);
+                }
+                page
+            })
+    }
+
+    fn document() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec(page(), 0..5)
+    }
+
+    fn overlap() -> impl Strategy<Value = OverlapStrategy> {
+        prop_oneof![
+            Just(OverlapStrategy::None),
+            (1..20usize).prop_map(OverlapStrategy::Token),
+            Just(OverlapStrategy::Sentence),
+        ]
+    }
+
+    #[test]
+    fn pages_split_into_non_empty_chunks_of_the_page() {
+        // -- tokenizers take long to load in debug builds, once for all the cases
+        let sizers = [Some(Sizer::Chars), Some(Sizer::Cl100k), None]
+            .map(|sizer| DocumentSizer::new(sizer, None));
+        let strategy = (
+            0..sizers.len(),
+            8..64usize,
+            document(),
+            0..4usize,
+            overlap(),
+        );
+        let mut runner = TestRunner::new(Config::with_cases(CASES));
+        let result = runner.run(&strategy, |(sizer, chunk_size, pages, window, overlap)| {
+            let sizer = &sizers[sizer];
+            let splitter = TextSplitter::new(ChunkConfig::new(chunk_size).with_sizer(sizer));
+            let mut chunks = vec![];
+            for page in pages {
+                let (page_text, page_chunks, table_chunks) =
+                    split_page(&page, &splitter, sizer, chunk_size);
+                for chunk in page_chunks.iter().chain(&table_chunks) {
+                    prop_assert!(!chunk.page_content.trim().is_empty());
+                }
+                // -- the chunks are text of the page, at an offset within it
+                for chunk in &page_chunks {
+                    prop_assert!(page_text.contains(&chunk.page_content));
+                }
+
+                let sentences = split_sentences(&page_text);
+                for sentence in &sentences {
+                    prop_assert!(!sentence.is_empty());
+                    prop_assert_eq!(sentence, sentence.trim());
+                }
+                let windows = sentence_windows(&sentences, window);
+                prop_assert_eq!(windows.len(), sentences.len());
+                for (sentence, window) in sentences.iter().zip(&windows) {
+                    prop_assert!(window.contains(sentence.as_str()));
+                }
+                chunks.extend(page_chunks);
+            }
+
+            let overlapped = apply_overlap(chunks.clone(), overlap);
+            prop_assert_eq!(overlapped.len(), chunks.len());
+            for (chunk, original) in overlapped.iter().zip(&chunks) {
+                prop_assert!(chunk.page_content.ends_with(&original.page_content));
+            }
+            Ok(())
+        });
+        result.unwrap();
+    }
+
+    proptest! {
+        #![proptest_config(Config::with_cases(CASES))]
+
+        #[test]
+        fn windows_hold_only_the_neighbours_of_the_chunk(
+            pages in prop::collection::vec(page(), 1..12),
+            neighbours in 0..5usize,
+            (previous, next) in (page(), page()),
+            available in 0..400usize,
+        ) {
+            // -- indexes of the `<i>` markers of the chunks in a part of the window
+            let markers = |args: &PromptArgs, key: &str| -> Vec<usize> {
+                args[key]
+                    .as_str()
+                    .unwrap()
+                    .split('<')
+                    .skip(1)
+                    .map(|part| part[..part.find('>').unwrap()].parse().unwrap())
+                    .collect()
+            };
+            let chars = |text: &str| text.chars().count();
+            // -- markers of other chunks can't come from the page text
+            let chunks: Vec<Document> = pages
+                .iter()
+                .enumerate()
+                .map(|(i, page)| Document::new(format!("<{}>{}", i, page.replace(['<', '>'], ""))))
+                .collect();
+            for index in 0..chunks.len() {
+                let args = window_input(&chunks, index, neighbours);
+                prop_assert_eq!(
+                    markers(&args, "previous_chunks"),
+                    (index.saturating_sub(neighbours)..index).collect::<Vec<_>>()
+                );
+                prop_assert_eq!(
+                    markers(&args, "next_chunks"),
+                    (index + 1..(index + neighbours + 1).min(chunks.len())).collect::<Vec<_>>()
+                );
+                prop_assert_eq!(markers(&args, "input"), vec![index]);
+            }
+
+            let (kept_previous, kept_next) = trimmed_neighbours(&previous, &next, available, chars);
+            // -- the sentences nearest the chunk, within the budget
+            let previous = split_sentences(&previous);
+            let next = split_sentences(&next);
+            let kept_before = (0..=previous.len())
+                .find(|&count| previous[previous.len() - count..].join(" ") == kept_previous);
+            let kept_after = (0..=next.len()).find(|&count| next[..count].join(" ") == kept_next);
+            prop_assert!(kept_before.is_some() && kept_after.is_some());
+            let used: usize = previous[previous.len() - kept_before.unwrap()..]
+                .iter()
+                .chain(&next[..kept_after.unwrap()])
+                .map(|sentence| chars(sentence))
+                .sum();
+            prop_assert!(used <= available);
+        }
+    }
+
+    #[test]
+    fn documents_are_stored_with_contiguous_chunk_indexes() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let url = runtime.block_on(selftest::mock_ollama()).unwrap();
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--ollama",
+            &url,
+            "--model",
+            "selftest",
+            "--embed",
+            "selftest",
+            "--sizer",
+            "chars",
+            // -- the memory store is shared by the tests of the process
+            "--dry-embed",
+            "generate",
+        ]);
+        let sizer = DocumentSizer::new(cli.sizer, None);
+        let strategy = (16..128usize, document(), 0..4usize);
+        let mut runner = TestRunner::new(Config::with_cases(CASES / 4));
+        let result = runner.run(&strategy, |(chunk_size, pages, neighbours)| {
+            let splitter = TextSplitter::new(ChunkConfig::new(chunk_size).with_sizer(&sizer));
+            let mut chunks = vec![];
+            for page in pages {
+                let (_, page_chunks, table_chunks) =
+                    split_page(&page, &splitter, &sizer, chunk_size);
+                chunks.extend(page_chunks);
+                chunks.extend(table_chunks);
+            }
+            let count = chunks.len();
+            let ingest = Ingest::new(&cli);
+            let prepared = PreparedDocument {
+                doc_path: "random.pdf".to_string(),
+                collection: None,
+                metadata: HashMap::new(),
+                language: None,
+                sizer: DocumentSizer::new(cli.sizer, None),
+                doc_text: String::new(),
+                chunks,
+                enrich: true,
+                neighbours,
+                last_modified: None,
+            };
+            let stats = runtime
+                .block_on(ingest.contextualize(prepared, &HashMap::new(), &|_, _| {}))
+                .map_err(TestCaseError::fail)?;
+            prop_assert_eq!(stats.chunks, count);
+            let stored = ingest.dry_embedded.lock().unwrap();
+            let indexes: Vec<u64> = stored
+                .iter()
+                .map(|chunk| chunk["metadata"]["chunk_index"].as_u64().unwrap())
+                .collect();
+            prop_assert_eq!(indexes, (0..count as u64).collect::<Vec<_>>());
+            for chunk in stored.iter() {
+                prop_assert!(!chunk["text"].as_str().unwrap().trim().is_empty());
+            }
+            Ok(())
+        });
+        result.unwrap();
+    }
+}
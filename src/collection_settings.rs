@@ -0,0 +1,335 @@
+// -------------------------------------
+// -- per-collection defaults: the system prompt, top-k and score threshold
+//
+// `config-set --collection hr system_prompt "..."` (or generate with
+// `--collection` and the flags) stores the settings of a collection in the
+// chunk store. Chat, slack and mcp read the ones of `--collection` at start,
+// web also the ones of a request's `collection`; `--system-prompt`, `--top-k`
+// and `--score-threshold` still win over them. Settings that are missing or
+// can't be read leave the global defaults, with a warning.
+
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use futures::Stream;
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, MessageType, StreamData},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::store::ChunkStore;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// keys `config-set` takes
+pub const KEYS: &[&str] = &["system_prompt", "top_k", "score_threshold"];
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    // chunks retrieved for a question
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    // minimal score of a retrieved chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_threshold: Option<f32>,
+}
+
+impl CollectionSettings {
+    // -- these settings, the missing ones taken from `defaults`
+    pub fn or(self, defaults: CollectionSettings) -> CollectionSettings {
+        CollectionSettings {
+            system_prompt: self.system_prompt.or(defaults.system_prompt),
+            top_k: self.top_k.or(defaults.top_k),
+            score_threshold: self.score_threshold.or(defaults.score_threshold),
+        }
+    }
+
+    // -- one of the `KEYS` from its text, an empty value removes it
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key {
+            "system_prompt" => {
+                self.system_prompt = (!value.is_empty()).then(|| value.to_string());
+            }
+            "top_k" => {
+                self.top_k = match value {
+                    "" => None,
+                    _ => match value.parse() {
+                        Ok(top_k) if top_k > 0 => Some(top_k),
+                        _ => return Err(format!("top_k is a positive number, got {}", value)),
+                    },
+                };
+            }
+            "score_threshold" => {
+                self.score_threshold = match value {
+                    "" => None,
+                    _ => match value.parse::<f32>() {
+                        Ok(threshold) if threshold.is_finite() => Some(threshold),
+                        _ => {
+                            return Err(format!("score_threshold is a number, got {}", value));
+                        }
+                    },
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "unknown setting {}, one of {}",
+                    key,
+                    KEYS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+// -- the stored settings of the collection, None when it has none
+pub async fn load(
+    store: &dyn ChunkStore,
+    collection: &str,
+) -> Result<Option<CollectionSettings>, String> {
+    store
+        .collection_settings(collection)
+        .await?
+        .map(|settings| parse(collection, settings))
+        .transpose()
+}
+
+// -- the stored settings, none with the warning when they are missing or can't be read
+pub async fn load_or_warn(
+    store: &dyn ChunkStore,
+    collection: &str,
+    warn: impl Fn(&str),
+) -> CollectionSettings {
+    match load(store, collection).await {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            warn(&format!(
+                "collection {} has no stored settings, using the global defaults",
+                collection
+            ));
+            CollectionSettings::default()
+        }
+        Err(e) => {
+            warn(&format!("{}, using the global defaults", e));
+            CollectionSettings::default()
+        }
+    }
+}
+
+fn parse(collection: &str, settings: Value) -> Result<CollectionSettings, String> {
+    let settings: CollectionSettings = serde_json::from_value(settings).map_err(|e| {
+        format!(
+            "invalid stored settings of collection {}: {}",
+            collection, e
+        )
+    })?;
+    if settings.top_k == Some(0) {
+        return Err(format!(
+            "invalid stored settings of collection {}: top_k 0",
+            collection
+        ));
+    }
+    Ok(settings)
+}
+
+pub async fn save(
+    store: &dyn ChunkStore,
+    collection: &str,
+    settings: &CollectionSettings,
+) -> Result<(), String> {
+    let settings = serde_json::to_value(settings).map_err(|e| e.to_string())?;
+    store.save_collection_settings(collection, &settings).await
+}
+
+tokio::task_local! {
+    static REQUEST_SYSTEM_PROMPT: String;
+}
+
+// -- runs the future with the system prompt in place of the configured one
+pub async fn prompted<F: Future>(system_prompt: String, future: F) -> F::Output {
+    REQUEST_SYSTEM_PROMPT.scope(system_prompt, future).await
+}
+
+// -- the system prompt of the request's collection, the chain's prompt starts with `configured`
+pub struct SystemPromptLlm {
+    inner: Box<dyn LLM>,
+    configured: String,
+}
+
+impl SystemPromptLlm {
+    pub fn new(inner: Box<dyn LLM>, configured: &str) -> Self {
+        SystemPromptLlm {
+            inner,
+            configured: configured.to_string(),
+        }
+    }
+
+    // -- the --system-prompt-append lines after the configured prompt are kept
+    fn prompted(&self, messages: &[Message]) -> Vec<Message> {
+        let mut messages = messages.to_vec();
+        let Ok(requested) = REQUEST_SYSTEM_PROMPT.try_with(Clone::clone) else {
+            return messages;
+        };
+        let Some(system) = messages
+            .iter()
+            .position(|m| matches!(m.message_type, MessageType::SystemMessage))
+        else {
+            return messages;
+        };
+        if let Some(rest) = messages[system].content.strip_prefix(&self.configured) {
+            messages[system] = Message::new_system_message(format!("{}{}", requested, rest));
+        }
+        messages
+    }
+}
+
+impl Clone for SystemPromptLlm {
+    fn clone(&self) -> Self {
+        SystemPromptLlm {
+            inner: self.inner.clone_box(),
+            configured: self.configured.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for SystemPromptLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        self.inner.generate(&self.prompted(messages)).await
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        self.inner.stream(&self.prompted(messages)).await
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::test_llms::SystemEcho;
+    use langchain_rust::embedding::{Embedder, EmbedderError};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    // -- settings don't need embeddings
+    struct NoEmbedder;
+
+    #[async_trait]
+    impl Embedder for NoEmbedder {
+        async fn embed_documents(&self, _: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+            unimplemented!()
+        }
+
+        async fn embed_query(&self, _: &str) -> Result<Vec<f64>, EmbedderError> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_settings_round_trip_and_malformed_ones_are_errors() {
+        let store = MemoryStore::new(Arc::new(NoEmbedder));
+        assert_eq!(load(&store, "hr").await, Ok(None));
+
+        let mut settings = CollectionSettings::default();
+        settings.set("system_prompt", "Jsi HR asistent.").unwrap();
+        settings.set("top_k", "8").unwrap();
+        settings.set("score_threshold", "0.75").unwrap();
+        assert!(settings.set("top_k", "0").is_err());
+        assert!(settings.set("limit", "3").is_err());
+        save(&store, "hr", &settings).await.unwrap();
+        assert_eq!(load(&store, "hr").await, Ok(Some(settings.clone())));
+
+        settings.set("score_threshold", "").unwrap();
+        assert_eq!(
+            store.collection_settings("hr").await.unwrap(),
+            Some(
+                json!({ "system_prompt": "Jsi HR asistent.", "top_k": 8, "score_threshold": 0.75 })
+            )
+        );
+        save(&store, "hr", &settings).await.unwrap();
+        assert_eq!(
+            load(&store, "hr").await.unwrap().unwrap().score_threshold,
+            None
+        );
+
+        // -- the flags win, the stored settings fill in the rest
+        let flags = CollectionSettings {
+            top_k: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            flags.or(settings),
+            CollectionSettings {
+                system_prompt: Some("Jsi HR asistent.".to_string()),
+                top_k: Some(3),
+                score_threshold: None,
+            }
+        );
+
+        for malformed in [
+            json!({ "top_k": "many" }),
+            json!({ "limit": 3 }),
+            json!({ "top_k": 0 }),
+        ] {
+            store
+                .save_collection_settings("it", &malformed)
+                .await
+                .unwrap();
+            assert!(load(&store, "it").await.is_err());
+        }
+        let warnings = Mutex::new(vec![]);
+        let warn = |warning: &str| warnings.lock().unwrap().push(warning.to_string());
+        assert_eq!(
+            load_or_warn(&store, "it", warn).await,
+            CollectionSettings::default()
+        );
+        assert_eq!(
+            load_or_warn(&store, "finance", warn).await,
+            CollectionSettings::default()
+        );
+        assert_eq!(load_or_warn(&store, "hr", warn).await.top_k, Some(8));
+        let warnings = warnings.into_inner().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("invalid stored settings of collection it"));
+        assert!(warnings[1].starts_with("collection finance has no stored settings"));
+    }
+
+    #[tokio::test]
+    async fn the_request_system_prompt_replaces_the_configured_one() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        let llm = SystemPromptLlm::new(Box::new(SystemEcho(prompts.clone())), "Systém.");
+        let messages = [
+            Message::new_system_message("Systém.\nBuď stručný."),
+            Message::new_human_message("Otázka?"),
+        ];
+        llm.generate(&messages).await.unwrap();
+        prompted("HR systém.".to_string(), llm.generate(&messages))
+            .await
+            .unwrap();
+        // -- prompts without a system message are the rephrased questions
+        prompted("HR systém.".to_string(), llm.generate(&messages[1..]))
+            .await
+            .unwrap();
+
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(
+            *prompts,
+            vec![
+                "Systém.\nBuď stručný.",
+                "HR systém.\nBuď stručný.",
+                "Otázka?"
+            ]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// -------------------------------------
+// -- `--compress-context`: only the sentences of a chunk the question needs
+//
+// Every retrieved chunk is split into sentences and the relevant ones are
+// picked by `--compression-model` (numbers of the sentences it lists) or,
+// without it, by their embedding similarity to the question (above the mean
+// of the chunk). The picked sentences are kept verbatim in their order, and
+// the next ones fill the chunk up to `--compression-min-tokens` so conditions
+// and exceptions next to the relevant sentence aren't lost. A chunk is kept
+// whole when picking fails or nothing is picked.
+
+use std::sync::{Arc, OnceLock};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use langchain_rust::{
+    embedding::Embedder,
+    language_models::llm::LLM,
+    schemas::{Document, Message},
+};
+use regex::Regex;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+use crate::{
+    chunking::split_sentences,
+    config,
+    retrieval::Compressor,
+    store::{chunk_text, cosine_similarity},
+};
+
+pub enum Selection {
+    Llm(Box<dyn LLM>),
+    Embedding(Arc<dyn Embedder>),
+}
+
+pub struct ContextCompressor {
+    selection: Selection,
+    min_tokens: usize,
+}
+
+fn tokens(text: &str) -> usize {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| cl100k_base().unwrap())
+        .encode_ordinary(text)
+        .len()
+}
+
+// -- the 1-based sentence numbers of the answer, `0` or no number for none
+fn parse_numbers(answer: &str, count: usize) -> Vec<bool> {
+    // -- reasoning models think before they answer
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer);
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let number = NUMBER.get_or_init(|| Regex::new(r"\d+").unwrap());
+    let mut relevant = vec![false; count];
+    for found in number.find_iter(answer) {
+        if let Some(n) = found.as_str().parse::<usize>().ok().filter(|n| *n >= 1) {
+            if let Some(sentence) = relevant.get_mut(n - 1) {
+                *sentence = true;
+            }
+        }
+    }
+    relevant
+}
+
+// -- the relevant sentences, the following ones (then the preceding) added up to `min_tokens`
+fn compressed(
+    sentences: &[String],
+    relevant: &[bool],
+    size: impl Fn(&str) -> usize,
+    min_tokens: usize,
+) -> String {
+    let mut kept = relevant.to_vec();
+    let mut kept_size: usize = sentences
+        .iter()
+        .zip(&kept)
+        .filter(|(_, kept)| **kept)
+        .map(|(sentence, _)| size(sentence))
+        .sum();
+    let first = kept.iter().position(|kept| *kept).unwrap_or(0);
+    let fill: Vec<usize> = (first..sentences.len()).chain((0..first).rev()).collect();
+    for index in fill {
+        if kept_size >= min_tokens {
+            break;
+        }
+        if !kept[index] {
+            kept[index] = true;
+            kept_size += size(&sentences[index]);
+        }
+    }
+    sentences
+        .iter()
+        .zip(kept)
+        .filter(|(_, kept)| *kept)
+        .map(|(sentence, _)| sentence.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl ContextCompressor {
+    pub fn new(selection: Selection, min_tokens: usize) -> Self {
+        ContextCompressor {
+            selection,
+            min_tokens,
+        }
+    }
+
+    async fn relevant(&self, question: &str, sentences: &[String]) -> Option<Vec<bool>> {
+        match &self.selection {
+            Selection::Llm(llm) => {
+                let numbered: Vec<String> = sentences
+                    .iter()
+                    .enumerate()
+                    .map(|(i, sentence)| format!("{}. {}", i + 1, sentence))
+                    .collect();
+                let messages = [
+                    Message::new_system_message(config::COMPRESSION_PROMPT_STR),
+                    Message::new_human_message(format!(
+                        "Otázka: {}\n\nVěty:\n{}",
+                        question,
+                        numbered.join("\n")
+                    )),
+                ];
+                match llm.generate(&messages).await {
+                    Ok(result) => Some(parse_numbers(&result.generation, sentences.len())),
+                    Err(e) => {
+                        log::warn!("compressing a chunk failed: {}", e);
+                        None
+                    }
+                }
+            }
+            Selection::Embedding(embedder) => {
+                let embedded = async {
+                    let question = embedder.embed_query(question).await?;
+                    let sentences = embedder.embed_documents(sentences).await?;
+                    Ok::<_, langchain_rust::embedding::EmbedderError>((question, sentences))
+                };
+                let (question, vectors) = match embedded.await {
+                    Ok(embedded) => embedded,
+                    Err(e) => {
+                        log::warn!("compressing a chunk failed: {}", e);
+                        return None;
+                    }
+                };
+                let similarities: Vec<f64> = vectors
+                    .iter()
+                    .map(|vector| cosine_similarity(&question, vector))
+                    .collect();
+                let mean = similarities.iter().sum::<f64>() / similarities.len().max(1) as f64;
+                Some(similarities.iter().map(|s| *s >= mean).collect())
+            }
+        }
+    }
+
+    async fn compress_chunk(&self, question: &str, mut doc: Document) -> Document {
+        let text = chunk_text(&doc.page_content);
+        let sentences = split_sentences(&text);
+        if tokens(&text) <= self.min_tokens || sentences.len() < 2 {
+            return doc;
+        }
+        let Some(relevant) = self
+            .relevant(question, &sentences)
+            .await
+            .filter(|relevant| relevant.contains(&true))
+        else {
+            return doc;
+        };
+        let compressed = compressed(&sentences, &relevant, tokens, self.min_tokens);
+        // -- the prompt gets the same json encoded text as without compression
+        doc.page_content = match text == doc.page_content {
+            true => compressed,
+            false => serde_json::to_string(&compressed).unwrap(),
+        };
+        doc
+    }
+}
+
+#[async_trait]
+impl Compressor for ContextCompressor {
+    async fn compress(&self, question: &str, docs: Vec<Document>) -> Vec<Document> {
+        let before: usize = docs.iter().map(|d| tokens(&d.page_content)).sum();
+        let docs = join_all(docs.into_iter().map(|d| self.compress_chunk(question, d))).await;
+        let after: usize = docs.iter().map(|d| tokens(&d.page_content)).sum();
+        log::info!(
+            "context compressed from {} to {} tokens ({} chunks)",
+            before,
+            after,
+            docs.len()
+        );
+        docs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_sentence_numbers_are_relevant() {
+        assert_eq!(parse_numbers("2, 4", 4), [false, true, false, true]);
+        assert_eq!(parse_numbers("<think>1?</think>3", 3), [false, false, true]);
+        assert_eq!(parse_numbers("0", 2), [false, false]);
+        assert_eq!(parse_numbers("5, 12", 3), [false, false, false]);
+    }
+
+    #[test]
+    fn relevant_sentences_are_filled_up_to_the_floor() {
+        let sentences: Vec<String> = ["a a.", "b b.", "c c.", "d d."]
+            .map(str::to_string)
+            .to_vec();
+        let words = |text: &str| text.split_whitespace().count();
+        let relevant = [false, true, false, false];
+        assert_eq!(compressed(&sentences, &relevant, words, 0), "b b.");
+        assert_eq!(compressed(&sentences, &relevant, words, 4), "b b. c c.");
+        assert_eq!(
+            compressed(&sentences, &[false, false, false, true], words, 4),
+            "c c. d d."
+        );
+    }
+}
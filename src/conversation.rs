@@ -0,0 +1,180 @@
+// -------------------------------------
+// -- conversation memory with --memory-max-turns, --memory-max-tokens and --memory-max-age-mins
+//
+// The chains of web and slack keep their conversation for as long as the
+// server runs, so without limits it grows with every question. A turn is a
+// question with its answer; when a limit is exceeded the oldest turns are
+// dropped once the answer is stored. Tokens are counted with cl100k_base.
+// Turns older than the age limit aren't returned as history even before
+// the next answer drops them.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use langchain_rust::schemas::{memory::BaseMemory, Message, MessageType};
+use tiktoken_rs::{cl100k_base, CoreBPE};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryLimits {
+    pub max_turns: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+pub struct BoundedMemory {
+    limits: MemoryLimits,
+    // messages with the time they were stored
+    messages: Vec<(Instant, Message)>,
+    tokenizer: CoreBPE,
+}
+
+impl BoundedMemory {
+    pub fn new(limits: MemoryLimits) -> Self {
+        BoundedMemory {
+            limits,
+            messages: Vec::new(),
+            tokenizer: cl100k_base().unwrap(),
+        }
+    }
+
+    // -- length of the oldest turn, up to and including its answer
+    fn oldest_turn(&self) -> usize {
+        self.messages
+            .iter()
+            .position(|(_, message)| message.message_type == MessageType::AIMessage)
+            .map_or(self.messages.len(), |answer| answer + 1)
+    }
+
+    fn expired(&self, stored: Instant) -> bool {
+        self.limits
+            .max_age
+            .is_some_and(|max_age| stored.elapsed() > max_age)
+    }
+
+    fn exceeded(&self) -> bool {
+        let messages: Vec<Message> = self.messages.iter().map(|(_, m)| m.clone()).collect();
+        let size = size(&self.tokenizer, &messages);
+        self.limits.max_turns.is_some_and(|max| size.turns > max)
+            || self.limits.max_tokens.is_some_and(|max| size.tokens > max)
+            || self
+                .messages
+                .first()
+                .is_some_and(|(stored, _)| self.expired(*stored))
+    }
+
+    fn evict(&mut self) {
+        let mut evicted = 0;
+        while !self.messages.is_empty() && self.exceeded() {
+            let turn = self.oldest_turn();
+            self.messages.drain(..turn);
+            evicted += 1;
+        }
+        if evicted > 0 {
+            log::debug!(
+                "conversation memory evicted {} turns, {} messages are kept",
+                evicted,
+                self.messages.len()
+            );
+        }
+    }
+}
+
+impl From<BoundedMemory> for Arc<Mutex<dyn BaseMemory>> {
+    fn from(memory: BoundedMemory) -> Self {
+        Arc::new(Mutex::new(memory))
+    }
+}
+
+impl BaseMemory for BoundedMemory {
+    fn messages(&self) -> Vec<Message> {
+        // -- a turn is as old as its question
+        let mut expired = true;
+        self.messages
+            .iter()
+            .filter(|(stored, message)| {
+                if message.message_type == MessageType::HumanMessage {
+                    expired = self.expired(*stored);
+                }
+                !expired
+            })
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    fn add_message(&mut self, message: Message) {
+        let answered = message.message_type == MessageType::AIMessage;
+        self.messages.push((Instant::now(), message));
+        // -- a question alone isn't dropped before its answer
+        if answered {
+            self.evict();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+pub struct MemorySize {
+    pub turns: usize,
+    pub tokens: usize,
+}
+
+// -- size of a conversation as the limits count it
+pub fn size(tokenizer: &CoreBPE, messages: &[Message]) -> MemorySize {
+    MemorySize {
+        turns: messages
+            .iter()
+            .filter(|message| message.message_type == MessageType::HumanMessage)
+            .count(),
+        tokens: messages
+            .iter()
+            .map(|message| tokenizer.encode_ordinary(&message.content).len())
+            .sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turns(memory: &mut BoundedMemory, count: usize) {
+        for turn in 0..count {
+            memory.add_user_message(&format!("otázka {}", turn));
+            memory.add_ai_message(&format!("odpověď {}", turn));
+        }
+    }
+
+    #[test]
+    fn oldest_turns_are_evicted() {
+        let mut memory = BoundedMemory::new(MemoryLimits {
+            max_turns: Some(2),
+            ..Default::default()
+        });
+        turns(&mut memory, 3);
+        let messages = memory.messages();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].content, "otázka 1");
+
+        let tokenizer = cl100k_base().unwrap();
+        let turn_tokens = size(&tokenizer, &messages[..2]).tokens;
+        let mut memory = BoundedMemory::new(MemoryLimits {
+            max_tokens: Some(turn_tokens),
+            ..Default::default()
+        });
+        turns(&mut memory, 3);
+        assert_eq!(size(&tokenizer, &memory.messages()).turns, 1);
+
+        let mut memory = BoundedMemory::new(MemoryLimits {
+            max_age: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        memory.add_user_message(&"otázka");
+        assert!(memory.messages().is_empty());
+        memory.add_ai_message(&"odpověď");
+        assert!(memory.messages.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+// -------------------------------------
+// -- `--save-embeddings-to`: the embeddings of a generate run in a file, for analysis
+//
+// `json` is the array of `{"text", "vector", "metadata"}` objects --dry-embed
+// prints. `npz` is numpy's archive of `vectors.npy` (float32, one row per
+// chunk), `texts.npy` and `metadata.npy` (unicode strings, the metadata as
+// json), readable by `numpy.load` without pickles. The archive is written
+// here without compression, there's no hdf5 or zip library in the build.
+
+use std::fs;
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum EmbeddingsFormat {
+    #[default]
+    Json,
+    Npz,
+}
+
+impl EmbeddingsFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EmbeddingsFormat::Json => "json",
+            EmbeddingsFormat::Npz => "npz",
+        }
+    }
+}
+
+// -- `.npy` header of the array, padded so the data starts at a multiple of 64 bytes
+fn npy_header(descr: &str, shape: &str) -> Vec<u8> {
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // -- magic, version and the header length come before the dict, a newline ends it
+    while (10 + dict.len() + 1) % 64 != 0 {
+        dict.push(' ');
+    }
+    dict.push('\n');
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend((dict.len() as u16).to_le_bytes());
+    header.extend(dict.as_bytes());
+    header
+}
+
+fn npy_vectors(vectors: &[Vec<f32>]) -> Result<Vec<u8>, String> {
+    let dimensions = vectors.first().map_or(0, Vec::len);
+    if let Some(vector) = vectors.iter().find(|vector| vector.len() != dimensions) {
+        return Err(format!(
+            "vectors of {} and {} dimensions can't be one array",
+            dimensions,
+            vector.len()
+        ));
+    }
+    let mut npy = npy_header("<f4", &format!("({}, {})", vectors.len(), dimensions));
+    for value in vectors.iter().flatten() {
+        npy.extend(value.to_le_bytes());
+    }
+    Ok(npy)
+}
+
+// -- fixed width UTF-32 strings, numpy's `<U` dtype
+fn npy_strings(strings: &[String]) -> Vec<u8> {
+    let width = strings
+        .iter()
+        .map(|string| string.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut npy = npy_header(&format!("<U{}", width), &format!("({},)", strings.len()));
+    for string in strings {
+        let chars = string.chars().count();
+        for c in string.chars() {
+            npy.extend((c as u32).to_le_bytes());
+        }
+        npy.extend(vec![0; (width - chars) * 4]);
+    }
+    npy
+}
+
+// -- a zip archive of the files, stored without compression
+fn zip(files: &[(&str, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    // 1980-01-01, the earliest date of a zip entry
+    const DOS_DATE: u16 = (1 << 5) | 1;
+    let mut archive: Vec<u8> = vec![];
+    let mut directory: Vec<u8> = vec![];
+    for (name, data) in files {
+        let size = u32::try_from(data.len())
+            .map_err(|_| format!("{} is over the 4 GB of a zip entry", name))?;
+        let offset = u32::try_from(archive.len())
+            .map_err(|_| "the archive is over the 4 GB of a zip file".to_string())?;
+        let crc = crc32fast::hash(data);
+        // -- version, flags, method, time, date, crc, sizes and the name's length
+        let mut fields: Vec<u8> = vec![];
+        fields.extend(20_u16.to_le_bytes());
+        fields.extend(0_u16.to_le_bytes());
+        fields.extend(0_u16.to_le_bytes());
+        fields.extend(0_u16.to_le_bytes());
+        fields.extend(DOS_DATE.to_le_bytes());
+        fields.extend(crc.to_le_bytes());
+        fields.extend(size.to_le_bytes());
+        fields.extend(size.to_le_bytes());
+        fields.extend((name.len() as u16).to_le_bytes());
+        fields.extend(0_u16.to_le_bytes());
+
+        archive.extend(0x04034b50_u32.to_le_bytes());
+        archive.extend(&fields);
+        archive.extend(name.as_bytes());
+        archive.extend(data);
+
+        directory.extend(0x02014b50_u32.to_le_bytes());
+        directory.extend(20_u16.to_le_bytes());
+        directory.extend(&fields);
+        // -- comment length, disk, internal and external attributes
+        directory.extend([0; 10]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+    let directory_offset = archive.len() as u32;
+    let entries = files.len() as u16;
+    archive.extend(&directory);
+    archive.extend(0x06054b50_u32.to_le_bytes());
+    archive.extend([0; 4]);
+    archive.extend(entries.to_le_bytes());
+    archive.extend(entries.to_le_bytes());
+    archive.extend((directory.len() as u32).to_le_bytes());
+    archive.extend(directory_offset.to_le_bytes());
+    archive.extend(0_u16.to_le_bytes());
+    Ok(archive)
+}
+
+fn npz(records: &[Value]) -> Result<Vec<u8>, String> {
+    let vectors: Vec<Vec<f32>> = records
+        .iter()
+        .map(|record| {
+            record["vector"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|value| value.as_f64().unwrap_or_default() as f32)
+                .collect()
+        })
+        .collect();
+    let texts: Vec<String> = records
+        .iter()
+        .map(|record| record["text"].as_str().unwrap_or_default().to_string())
+        .collect();
+    let metadata: Vec<String> = records
+        .iter()
+        .map(|record| record["metadata"].to_string())
+        .collect();
+    zip(&[
+        ("vectors.npy", npy_vectors(&vectors)?),
+        ("texts.npy", npy_strings(&texts)),
+        ("metadata.npy", npy_strings(&metadata)),
+    ])
+}
+
+// -- `records` are the `{"text", "vector", "metadata"}` objects of the chunks
+pub fn write(path: &str, format: EmbeddingsFormat, records: &[Value]) -> Result<(), String> {
+    let content = match format {
+        EmbeddingsFormat::Json => serde_json::to_vec(records).map_err(|e| e.to_string())?,
+        EmbeddingsFormat::Npz => npz(records)?,
+    };
+    fs::write(path, content)
+        .map_err(|e| format!("writing the embeddings to {} failed: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn npz_holds_the_vectors_texts_and_metadata_as_npy_arrays() {
+        let records = [
+            json!({ "text": "Dovolená", "vector": [0.5, -1.0], "metadata": { "chunk_index": 0 } }),
+            json!({ "text": "Mzda 🚀", "vector": [0.25, 2.0], "metadata": { "chunk_index": 1 } }),
+        ];
+        let archive = npz(&records).unwrap();
+
+        // -- the first entry is vectors.npy, its data right after its local header
+        assert_eq!(u32_at(&archive, 0), 0x04034b50);
+        let size = u32_at(&archive, 18) as usize;
+        let name_length = u16::from_le_bytes([archive[26], archive[27]]) as usize;
+        assert_eq!(&archive[30..30 + name_length], b"vectors.npy");
+        let npy = &archive[30 + name_length..30 + name_length + size];
+        assert_eq!(u32_at(&archive, 14), crc32fast::hash(npy));
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_length = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_length) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_length]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 2), }"));
+        assert!(header.ends_with('\n'));
+        let values: Vec<f32> = npy[10 + header_length..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![0.5, -1.0, 0.25, 2.0]);
+
+        // -- the end of the central directory counts the three arrays
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), 0x06054b50);
+        assert_eq!(
+            u16::from_le_bytes([archive[end + 10], archive[end + 11]]),
+            3
+        );
+        let strings = npy_strings(&["Mzda 🚀".to_string(), "a".to_string()]);
+        assert!(String::from_utf8_lossy(&strings).contains("'descr': '<U6'"));
+        assert_eq!(
+            &strings[strings.len() - 28..strings.len() - 24],
+            &[0x80, 0xf6, 0x01, 0x00]
+        );
+
+        assert!(npz(&[json!({ "vector": [1.0] }), json!({ "vector": [1.0, 2.0] })]).is_err());
+    }
+}
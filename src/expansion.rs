@@ -0,0 +1,120 @@
+// -------------------------------------
+// -- `--query-expansion N`: the question searched in N more phrasings
+//
+// Colloquial questions may miss chunks written in formal language. The model
+// rephrases the question N times, every phrasing is searched and the chunks
+// found by any of them are ranked by their best score.
+//
+// `--step-back` asks the model for the broader principle behind the question
+// (step-back prompting). It is searched as well and its chunks get
+// `--step-back-weight` of the prompt, so a question about one case also
+// brings the general rule.
+
+use langchain_rust::{language_models::llm::LLM, schemas::Message};
+
+use crate::config;
+
+pub struct QueryExpander {
+    llm: Box<dyn LLM>,
+    phrasings: usize,
+}
+
+impl QueryExpander {
+    pub fn new(llm: Box<dyn LLM>, phrasings: usize) -> Self {
+        QueryExpander { llm, phrasings }
+    }
+
+    // -- the question and its phrasings, only the question when the model fails
+    pub async fn expand(&self, question: &str) -> Vec<String> {
+        let messages = [
+            Message::new_system_message(
+                config::EXPANSION_PROMPT_STR.replace("{{count}}", &self.phrasings.to_string()),
+            ),
+            Message::new_human_message(question),
+        ];
+        let phrasings = match self.llm.generate(&messages).await {
+            Ok(result) => parse_phrasings(&result.generation, question, self.phrasings),
+            Err(e) => {
+                log::warn!("query expansion failed: {}", e);
+                vec![]
+            }
+        };
+        log::debug!("query phrasings: {:?}", phrasings);
+        std::iter::once(question.to_string())
+            .chain(phrasings)
+            .collect()
+    }
+}
+
+pub struct StepBack {
+    llm: Box<dyn LLM>,
+    // share of the retrieved chunks taken from the step-back question
+    pub weight: f32,
+}
+
+impl StepBack {
+    pub fn new(llm: Box<dyn LLM>, weight: f32) -> Self {
+        StepBack { llm, weight }
+    }
+
+    // -- the broader question, None when the model fails or says nothing
+    pub async fn question(&self, question: &str) -> Option<String> {
+        let prompt = config::STEP_BACK_PROMPT_STR.replace("{{query}}", question);
+        match self
+            .llm
+            .generate(&[Message::new_human_message(prompt)])
+            .await
+        {
+            Ok(result) => {
+                let step_back = parse_phrasings(&result.generation, question, 1).pop();
+                log::debug!("step-back question: {:?}", step_back);
+                step_back
+            }
+            Err(e) => {
+                log::warn!("step-back question failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// -- one phrasing per line, list markers stripped, repeats of the question dropped
+fn parse_phrasings(answer: &str, question: &str, count: usize) -> Vec<String> {
+    // -- reasoning models think before they answer
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer);
+    let mut phrasings: Vec<String> = vec![];
+    for line in answer.lines() {
+        let phrasing = line
+            .trim()
+            .trim_start_matches(|c: char| c.is_ascii_digit() || matches!(c, '.' | ')' | '-' | '*'))
+            .trim()
+            .trim_matches('"');
+        let repeated = phrasing.eq_ignore_ascii_case(question.trim())
+            || phrasings.iter().any(|p| p.eq_ignore_ascii_case(phrasing));
+        if !phrasing.is_empty() && !repeated {
+            phrasings.push(phrasing.to_string());
+        }
+    }
+    phrasings.truncate(count);
+    phrasings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrasings_are_one_per_line_without_markers() {
+        let answer = "<think>hmm</think>\n1. Jaký je rozsah dovolené?\n\n- \"Kolik dní dovolené náleží zaměstnanci?\"\n2) kolik mám dovolené?\n* Jaký je rozsah dovolené?\n3. Nárok na dovolenou";
+        assert_eq!(
+            parse_phrasings(answer, "Kolik mám dovolené?", 5),
+            vec![
+                "Jaký je rozsah dovolené?",
+                "Kolik dní dovolené náleží zaměstnanci?",
+                "Nárok na dovolenou"
+            ]
+        );
+        assert_eq!(parse_phrasings(answer, "Kolik mám dovolené?", 1).len(), 1);
+        assert!(parse_phrasings("", "otázka", 3).is_empty());
+    }
+}
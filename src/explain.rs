@@ -0,0 +1,137 @@
+// -------------------------------------
+// -- `--explain`: which retrieved chunk every sentence of the answer came from
+//
+// After the answer, the model gets the chunks (numbered as in the chat
+// prompt) with the answer and returns the source of each sentence as json.
+// Chat prints the answer with `[N]` citations, web sends the attribution as
+// an `attribution` event. Sentences the model doesn't copy exactly are left
+// without a citation.
+
+use langchain_rust::{
+    language_models::llm::LLM,
+    schemas::{Document, Message},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Attribution {
+    pub sentence: String,
+    // 1-based number of the chunk, None for sentences not taken from any
+    pub source_chunk_index: Option<usize>,
+}
+
+pub struct Explainer {
+    llm: Box<dyn LLM>,
+}
+
+impl Explainer {
+    pub fn new(llm: Box<dyn LLM>) -> Self {
+        Explainer { llm }
+    }
+
+    // -- `chunks` as quoted into the prompt, numbered by their `<<<DOKUMENT N>>>` delimiters
+    pub async fn attribute(
+        &self,
+        answer: &str,
+        chunks: &[Document],
+    ) -> Result<Vec<Attribution>, String> {
+        let documents: Vec<&str> = chunks.iter().map(|d| d.page_content.as_str()).collect();
+        let messages = [
+            Message::new_system_message(config::EXPLAIN_PROMPT_STR),
+            Message::new_human_message(format!(
+                "{}\n\nOdpověď:\n{}",
+                documents.join("\n\n"),
+                answer
+            )),
+        ];
+        let result = self
+            .llm
+            .generate(&messages)
+            .await
+            .map_err(|e| format!("attributing the answer failed: {}", e))?;
+        parse_attributions(&result.generation, chunks.len())
+    }
+}
+
+// -- the json array of the answer, indices outside of the chunks are dropped
+fn parse_attributions(answer: &str, chunks: usize) -> Result<Vec<Attribution>, String> {
+    // -- reasoning models think first, others like to wrap json in a code block
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer);
+    let json = match (answer.find('['), answer.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &answer[start..=end],
+        _ => return Err(format!("no attribution json in {:?}", answer)),
+    };
+    let mut attributions: Vec<Attribution> =
+        serde_json::from_str(json).map_err(|e| format!("invalid attribution json: {}", e))?;
+    for attribution in attributions.iter_mut() {
+        attribution.source_chunk_index = attribution
+            .source_chunk_index
+            .filter(|index| (1..=chunks).contains(index));
+    }
+    Ok(attributions)
+}
+
+// -- the answer with `[N]` after every attributed sentence, looked up in order
+pub fn cite(answer: &str, attributions: &[Attribution]) -> String {
+    let mut cited = String::new();
+    let mut cursor = 0;
+    for attribution in attributions {
+        let sentence = attribution.sentence.trim();
+        let Some(index) = attribution.source_chunk_index else {
+            continue;
+        };
+        if sentence.is_empty() {
+            continue;
+        }
+        if let Some(start) = answer[cursor..].find(sentence) {
+            let end = cursor + start + sentence.len();
+            cited.push_str(&answer[cursor..end]);
+            cited.push_str(&format!(" [{}]", index));
+            cursor = end;
+        }
+    }
+    cited.push_str(&answer[cursor..]);
+    cited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribution_json_is_found_and_indices_checked() {
+        let answer = "<think>[1]?</think>\n```json\n[\
+            {\"sentence\": \"Dovolená je 25 dní.\", \"source_chunk_index\": 2},\
+            {\"sentence\": \"Pěkný den.\", \"source_chunk_index\": null},\
+            {\"sentence\": \"Nevím.\", \"source_chunk_index\": 7}\
+        ]\n```";
+        let attributions = parse_attributions(answer, 3).unwrap();
+        assert_eq!(attributions[0].source_chunk_index, Some(2));
+        assert_eq!(attributions[1].source_chunk_index, None);
+        assert_eq!(attributions[2].source_chunk_index, None);
+        assert!(parse_attributions("nevím", 3).is_err());
+        assert!(parse_attributions("[{\"věta\": 1}]", 3).is_err());
+    }
+
+    #[test]
+    fn citations_follow_their_sentences() {
+        let attribution = |sentence: &str, index| Attribution {
+            sentence: sentence.to_string(),
+            source_chunk_index: index,
+        };
+        let answer = "Dovolená je 25 dní. Přenáší se do března.\nPěkný den.";
+        let attributions = [
+            attribution("Dovolená je 25 dní.", Some(1)),
+            attribution("Tahle věta v odpovědi není.", Some(3)),
+            attribution(" Přenáší se do března. ", Some(2)),
+            attribution("Pěkný den.", None),
+        ];
+        assert_eq!(
+            cite(answer, &attributions),
+            "Dovolená je 25 dní. [1] Přenáší se do března. [2]\nPěkný den."
+        );
+        assert_eq!(cite(answer, &[]), answer);
+    }
+}
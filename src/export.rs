@@ -0,0 +1,109 @@
+// -------------------------------------
+// -- `/export <path.md>` and `--export-on-exit`: the chat conversation as Markdown
+//
+// The turns come from the chain's memory, so `/reset` starts the export over
+// and turns the memory holds from elsewhere are exported too. Questions are
+// headings, answers are copied verbatim (they usually are Markdown already)
+// and followed by the documents they were answered from. Turns whose sources
+// weren't seen by this session have no list.
+
+use langchain_rust::schemas::{Message, MessageType};
+use serde_json::Value;
+
+// -- `path, page N` of every source document, in retrieval order without repeats
+pub fn turn_sources(source_documents: &Value) -> Vec<String> {
+    let mut sources: Vec<String> = vec![];
+    for doc in source_documents.as_array().into_iter().flatten() {
+        let Some(path) = doc["metadata"]["path"].as_str() else {
+            continue;
+        };
+        let source = match &doc["metadata"]["page"] {
+            Value::Null => path.to_string(),
+            page => format!("{}, page {}", path, page),
+        };
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+    sources
+}
+
+// -- `sources` belong to the last answers of the memory, one list per answer
+pub fn markdown(messages: &[Message], sources: &[Vec<String>]) -> String {
+    let answers = messages
+        .iter()
+        .filter(|m| m.message_type == MessageType::AIMessage)
+        .count();
+    let mut unsourced = answers.saturating_sub(sources.len());
+    let mut sources = sources[sources.len().saturating_sub(answers)..].iter();
+
+    let mut out = String::from("# Conversation\n");
+    for message in messages {
+        match message.message_type {
+            MessageType::HumanMessage => {
+                let question: Vec<&str> = message.content.split_whitespace().collect();
+                out.push_str(&format!("\n## {}\n", question.join(" ")));
+            }
+            MessageType::AIMessage => {
+                out.push_str(&format!("\n{}\n", message.content.trim_end()));
+                let turn = match unsourced {
+                    0 => sources.next(),
+                    _ => {
+                        unsourced -= 1;
+                        None
+                    }
+                };
+                if let Some(turn) = turn.filter(|turn| !turn.is_empty()) {
+                    out.push_str("\nSources:\n\n");
+                    for source in turn {
+                        out.push_str(&format!("- {}\n", source));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn turns_are_exported_with_the_sources_of_the_last_answers() {
+        let messages = [
+            Message::new_human_message("Co je směrnice 7?"),
+            Message::new_ai_message("Směrnice o *dovolené*."),
+            Message::new_human_message("A kolik\n dní?"),
+            Message::new_ai_message("# 25 dní\n\n| rok | dny |"),
+        ];
+        let sources = turn_sources(&json!([
+            { "metadata": { "path": "a.pdf", "page": 3 } },
+            { "metadata": { "path": "a.pdf", "page": 3 } },
+            { "metadata": { "path": "b.txt" } },
+            { "metadata": {} },
+        ]));
+        assert_eq!(
+            markdown(&messages, &[sources]),
+            "# Conversation
+
+## Co je směrnice 7?
+
+Směrnice o *dovolené*.
+
+## A kolik dní?
+
+# 25 dní
+
+| rok | dny |
+
+Sources:
+
+- a.pdf, page 3
+- b.txt
+"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// -------------------------------------
+// -- `--fallback-model`: a smaller model answering when the primary one can't
+//
+// The primary model gets `--fallback-timeout-secs` to answer (or to start
+// streaming). Timeouts and ollama / connection errors send the same messages
+// to the fallback model, other errors are returned as they are.
+
+use std::{pin::Pin, time::Duration};
+
+use async_trait::async_trait;
+use futures::Stream;
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, StreamData},
+};
+
+use crate::ollama::OllamaWithOptions;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+#[derive(Clone)]
+pub struct FallbackLlm {
+    primary: OllamaWithOptions,
+    fallback: OllamaWithOptions,
+    // fallback model name for the log
+    fallback_model: String,
+    timeout: Duration,
+}
+
+impl FallbackLlm {
+    pub fn new(
+        primary: OllamaWithOptions,
+        fallback: OllamaWithOptions,
+        fallback_model: &str,
+        timeout: Duration,
+    ) -> Self {
+        FallbackLlm {
+            primary,
+            fallback,
+            fallback_model: fallback_model.to_string(),
+            timeout,
+        }
+    }
+
+    // -- the primary result, None when the fallback should be asked instead
+    async fn primary<T>(
+        &self,
+        request: impl std::future::Future<Output = Result<T, LLMError>>,
+    ) -> Option<Result<T, LLMError>> {
+        match tokio::time::timeout(self.timeout, request).await {
+            Ok(Err(e)) if is_unavailable(&e) => {
+                log::warn!(
+                    "primary model failed ({}), using fallback model {}",
+                    e,
+                    self.fallback_model
+                );
+                None
+            }
+            Ok(result) => Some(result),
+            Err(_) => {
+                log::warn!(
+                    "primary model timed out after {:?}, using fallback model {}",
+                    self.timeout,
+                    self.fallback_model
+                );
+                None
+            }
+        }
+    }
+}
+
+// -- errors of an overloaded or unreachable model, not of a bad request
+fn is_unavailable(error: &LLMError) -> bool {
+    matches!(
+        error,
+        LLMError::OllamaError(_)
+            | LLMError::RequestError(_)
+            | LLMError::IoError(_)
+            | LLMError::Timeout(_)
+    )
+}
+
+#[async_trait]
+impl LLM for FallbackLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        match self.primary(self.primary.generate(messages)).await {
+            Some(result) => result,
+            None => self.fallback.generate(messages).await,
+        }
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        match self.primary(self.primary.stream(messages)).await {
+            Some(result) => result,
+            None => self.fallback.stream(messages).await,
+        }
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.primary.add_options(options.clone());
+        self.fallback.add_options(options);
+    }
+}
@@ -0,0 +1,109 @@
+// -------------------------------------
+// -- images embedded in pdf documents, described by a vision model
+//
+// Ollama takes jpeg and png images. DCTDecode streams are jpegs already,
+// 8 bit rgb / gray FlateDecode streams are re-encoded to png. Other image
+// encodings are skipped.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use langchain_rust::llm::client::{GenerationOptions, OllamaClient};
+use ollama_rs::generation::{completion::request::GenerationRequest, images::Image};
+use pdf_extract::{xobject::PdfImage, Document as PdfDocument};
+
+use crate::config;
+
+pub struct PageImage {
+    pub page: u32,
+    // order of the image on its page
+    pub index: usize,
+    pub base64: String,
+}
+
+// -- jpeg or png bytes of the image, None for unsupported encodings
+fn encode_image(image: &PdfImage) -> Result<Option<Vec<u8>>, String> {
+    let filters = image.filters.clone().unwrap_or_default();
+    if filters.iter().any(|f| f == "DCTDecode") {
+        return Ok(Some(image.content.to_vec()));
+    }
+    if filters != ["FlateDecode"] || image.bits_per_component != Some(8) {
+        return Ok(None);
+    }
+    let color = match image.color_space.as_deref() {
+        Some("DeviceRGB") => png::ColorType::Rgb,
+        Some("DeviceGray") => png::ColorType::Grayscale,
+        _ => return Ok(None),
+    };
+
+    let pixels = pdf_extract::Stream::new(image.origin_dict.clone(), image.content.to_vec())
+        .decompressed_content()
+        .map_err(|e| format!("decompressing failed: {}", e))?;
+    let mut png_bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut png_bytes, image.width as u32, image.height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| format!("png encoding failed: {}", e))?;
+    Ok(Some(png_bytes))
+}
+
+// -- images of at least `min_size` pixels in both dimensions, broken ones are logged and skipped
+pub fn extract_images(doc_path: &str, max_pages: Option<usize>, min_size: i64) -> Vec<PageImage> {
+    let pdf = match PdfDocument::load(doc_path) {
+        Ok(pdf) => pdf,
+        Err(e) => {
+            log::warn!("{} - loading images failed: {}", doc_path, e);
+            return vec![];
+        }
+    };
+
+    let mut images = vec![];
+    for (page, page_id) in pdf
+        .get_pages()
+        .into_iter()
+        .take(max_pages.unwrap_or(usize::MAX))
+    {
+        let Ok(page_images) = pdf.get_page_images(page_id) else {
+            continue;
+        };
+        for (index, image) in page_images.iter().enumerate() {
+            if image.width < min_size || image.height < min_size {
+                continue;
+            }
+            match encode_image(image) {
+                Ok(Some(bytes)) => images.push(PageImage {
+                    page,
+                    index,
+                    base64: STANDARD.encode(bytes),
+                }),
+                Ok(None) => log::debug!(
+                    "{} - page {} image {} has unsupported encoding {:?}",
+                    doc_path,
+                    page,
+                    index,
+                    image.filters
+                ),
+                Err(e) => log::warn!("{} - page {} image {}: {}", doc_path, page, index, e),
+            }
+        }
+    }
+    images
+}
+
+pub async fn describe_image(
+    client: &OllamaClient,
+    model: &str,
+    options: GenerationOptions,
+    image: &PageImage,
+) -> Result<String, String> {
+    let request =
+        GenerationRequest::new(model.to_string(), config::IMAGE_DESCRIPTION_STR.to_string())
+            .images(vec![Image::from_base64(image.base64.clone())])
+            .options(options);
+    client
+        .generate(request)
+        .await
+        .map(|response| response.response.trim().to_string())
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,217 @@
+// -------------------------------------
+// -- background ingestion jobs
+//
+// Uploaded documents are queued and ingested by a fixed number of workers.
+// Every job runs in its own task, so a panic while ingesting (the loaders and
+// chains still unwrap a lot) only fails that job instead of the worker.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, watch},
+    task::{JoinError, JoinHandle},
+};
+use uuid::Uuid;
+
+use crate::{Ingest, IngestOutcome};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub path: String,
+    pub status: JobStatus,
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+    pub error: Option<String>,
+    // unix seconds
+    pub queued_at: u64,
+    pub finished_at: Option<u64>,
+    #[serde(skip)]
+    finished: Option<Instant>,
+}
+
+pub struct JobQueue {
+    jobs: Mutex<HashMap<String, Job>>,
+    sender: mpsc::UnboundedSender<String>,
+    // finished jobs are forgotten after this time
+    retention: Duration,
+}
+
+impl JobQueue {
+    pub fn new(retention: Duration) -> (Arc<Self>, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            sender,
+            retention,
+        };
+        (Arc::new(queue), receiver)
+    }
+
+    pub fn enqueue(&self, path: String) -> Job {
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            path,
+            status: JobStatus::Queued,
+            chunks_done: 0,
+            chunks_total: 0,
+            error: None,
+            queued_at: unix_now(),
+            finished_at: None,
+            finished: None,
+        };
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), job.clone());
+        self.sender.send(job.id.clone()).ok();
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    // -- oldest first
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by(|a, b| a.queued_at.cmp(&b.queued_at).then(a.id.cmp(&b.id)));
+        jobs
+    }
+
+    pub fn cleanup(&self) {
+        self.jobs.lock().unwrap().retain(|_, job| {
+            job.finished
+                .is_none_or(|finished| finished.elapsed() < self.retention)
+        });
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    fn finish(&self, id: &str, error: Option<String>) {
+        self.update(id, |job| {
+            job.status = match error {
+                Some(_) => JobStatus::Failed,
+                None => JobStatus::Done,
+            };
+            job.error = error;
+            job.finished_at = Some(unix_now());
+            job.finished = Some(Instant::now());
+        });
+    }
+}
+
+// -- workers stop taking new jobs once `shutdown` is set, the running job is finished
+pub fn spawn_workers(
+    queue: Arc<JobQueue>,
+    receiver: mpsc::UnboundedReceiver<String>,
+    workers: usize,
+    ingest: Arc<Ingest>,
+    shutdown: watch::Receiver<bool>,
+) -> Vec<JoinHandle<()>> {
+    let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+    (0..workers.max(1))
+        .map(|worker| {
+            let queue = queue.clone();
+            let receiver = receiver.clone();
+            let ingest = ingest.clone();
+            let mut shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                    let id = {
+                        let mut receiver = receiver.lock().await;
+                        tokio::select! {
+                            id = receiver.recv() => id,
+                            _ = shutdown.changed() => None,
+                        }
+                    };
+                    let Some(id) = id else {
+                        break;
+                    };
+                    run_job(&queue, &ingest, &id).await;
+                }
+                log::info!("ingestion worker {} stopped", worker);
+            })
+        })
+        .collect()
+}
+
+async fn run_job(queue: &Arc<JobQueue>, ingest: &Arc<Ingest>, id: &str) {
+    let Some(job) = queue.get(id) else {
+        return;
+    };
+    queue.update(id, |job| job.status = JobStatus::Running);
+    println!("{} - ingestion job {} started", job.path, id);
+
+    let progress_queue = queue.clone();
+    let progress_id = id.to_string();
+    let ingest = ingest.clone();
+    let path = job.path.clone();
+    let task = tokio::spawn(async move {
+        let progress = move |done: usize, total: usize| {
+            progress_queue.update(&progress_id, |job| {
+                job.chunks_done = done;
+                job.chunks_total = total;
+            })
+        };
+        ingest
+            .ingest_document(&path, &HashMap::new(), &progress)
+            .await
+    });
+
+    let error = match task.await {
+        Ok(IngestOutcome::Stored(stats)) => {
+            log::info!("{} - {} chunks stored", job.path, stats.chunks);
+            None
+        }
+        Ok(IngestOutcome::Skipped(reason)) => Some(format!("document skipped: {}", reason)),
+        Ok(IngestOutcome::Failed(e)) => Some(e),
+        Err(e) => Some(panic_message(e)),
+    };
+    match &error {
+        Some(error) => log::error!("{} - ingestion job {} failed: {}", job.path, id, error),
+        None => println!("{} - ingestion job {} done", job.path, id),
+    }
+    queue.finish(id, error);
+}
+
+// -- ingestion still panics on most errors, the panic message is the error
+pub fn panic_message(e: JoinError) -> String {
+    if !e.is_panic() {
+        return e.to_string();
+    }
+    let panic = e.into_panic();
+    panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "ingestion panicked".to_string())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,200 @@
+// -------------------------------------
+// -- `--ollama-keep-alive 30m`: how long ollama keeps the models loaded after a request
+//
+// langchain's chat requests (ollama-rs `ChatMessageRequest`) have no
+// `keep_alive` field, so ollama unloads the models after its default
+// (5 minutes, or `OLLAMA_KEEP_ALIVE` of the server). After every chat and
+// embedding request the model is loaded once more by an empty request
+// carrying `keep_alive`, which resets its unload timer to the duration.
+
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use langchain_rust::{
+    embedding::{Embedder, EmbedderError},
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    llm::client::OllamaClient,
+    schemas::{Message, StreamData},
+};
+use ollama_rs::generation::{
+    completion::request::GenerationRequest,
+    embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest},
+    parameters::{KeepAlive, TimeUnit},
+};
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// -- `-1` (forever), `0` (unload right away), `300` (seconds), `90s`, `30m`, `2h`
+pub fn parse_keep_alive(value: &str) -> Result<KeepAlive, String> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(match seconds {
+            s if s < 0 => KeepAlive::Indefinitely,
+            0 => KeepAlive::UnloadOnCompletion,
+            s => KeepAlive::Until {
+                time: s as u64,
+                unit: TimeUnit::Seconds,
+            },
+        });
+    }
+    let invalid = || format!("expected e.g. 30m, 2h, 90s or -1, got {}", value);
+    let (time, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    let time = time.parse::<u64>().map_err(|_| invalid())?;
+    // -- ollama-rs sends hours as `2hr`, which ollama doesn't parse
+    let (time, unit) = match unit {
+        "s" => (time, TimeUnit::Seconds),
+        "m" => (time, TimeUnit::Minutes),
+        "h" => (time * 60, TimeUnit::Minutes),
+        _ => return Err(invalid()),
+    };
+    Ok(KeepAlive::Until { time, unit })
+}
+
+// -- a model whose unload timer is reset after use
+#[derive(Clone)]
+pub struct KeptAlive {
+    client: Arc<OllamaClient>,
+    model: String,
+    keep_alive: KeepAlive,
+}
+
+impl KeptAlive {
+    pub fn new(client: Arc<OllamaClient>, model: &str, keep_alive: KeepAlive) -> Self {
+        KeptAlive {
+            client,
+            model: model.to_string(),
+            keep_alive,
+        }
+    }
+
+    // -- in the background, the answer doesn't wait for it
+    fn refresh_chat(&self) {
+        let model = self.clone();
+        tokio::spawn(async move {
+            let request = GenerationRequest::new(model.model.clone(), String::new())
+                .keep_alive(model.keep_alive.clone());
+            if let Err(e) = model.client.generate(request).await {
+                log::warn!("keeping {} loaded failed: {}", model.model, e);
+            }
+        });
+    }
+
+    fn refresh_embedding(&self) {
+        let model = self.clone();
+        tokio::spawn(async move {
+            let request = GenerateEmbeddingsRequest::new(
+                model.model.clone(),
+                EmbeddingsInput::Multiple(vec![]),
+            )
+            .keep_alive(model.keep_alive.clone());
+            if let Err(e) = model.client.generate_embeddings(request).await {
+                log::warn!("keeping {} loaded failed: {}", model.model, e);
+            }
+        });
+    }
+}
+
+pub struct KeepAliveLlm {
+    inner: Box<dyn LLM>,
+    model: KeptAlive,
+}
+
+impl KeepAliveLlm {
+    pub fn new(inner: Box<dyn LLM>, model: KeptAlive) -> Self {
+        KeepAliveLlm { inner, model }
+    }
+}
+
+impl Clone for KeepAliveLlm {
+    fn clone(&self) -> Self {
+        KeepAliveLlm {
+            inner: self.inner.clone_box(),
+            model: self.model.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for KeepAliveLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        let result = self.inner.generate(messages).await;
+        self.model.refresh_chat();
+        result
+    }
+
+    // -- refreshed once the answer is streamed, the stream request sets the timer until then
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        let answer = self.inner.stream(messages).await?;
+        let model = self.model.clone();
+        let refresh = stream::once(async move {
+            model.refresh_chat();
+            None
+        })
+        .filter_map(|item| async { item });
+        Ok(Box::pin(answer.chain(refresh)))
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+pub struct KeepAliveEmbedder<E> {
+    inner: E,
+    model: Option<KeptAlive>,
+}
+
+impl<E> KeepAliveEmbedder<E> {
+    // -- no refreshing for `None`
+    pub fn new(inner: E, model: Option<KeptAlive>) -> Self {
+        KeepAliveEmbedder { inner, model }
+    }
+
+    fn refresh(&self) {
+        if let Some(model) = &self.model {
+            model.refresh_embedding();
+        }
+    }
+}
+
+#[async_trait]
+impl<E: Embedder> Embedder for KeepAliveEmbedder<E> {
+    async fn embed_documents(&self, documents: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        let result = self.inner.embed_documents(documents).await;
+        self.refresh();
+        result
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+        let result = self.inner.embed_query(text).await;
+        self.refresh();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialized(value: &str) -> String {
+        serde_json::to_string(&parse_keep_alive(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keep_alive_durations_are_passed_as_ollama_expects_them() {
+        assert_eq!(serialized("-1"), "-1");
+        assert_eq!(serialized("0"), "0");
+        assert_eq!(serialized("300"), "\"300s\"");
+        assert_eq!(serialized("30m"), "\"30m\"");
+        assert_eq!(serialized(" 2h "), "\"120m\"");
+        assert!(parse_keep_alive("30 minutes").is_err());
+        assert!(parse_keep_alive("m").is_err());
+        assert!(parse_keep_alive("30ž").is_err());
+        assert!(parse_keep_alive("").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+// -------------------------------------
+// -- `--keywords tfidf|llm`: keywords of every chunk, stored as its `keywords` list
+//
+// `tfidf` scores the words of a chunk against the other chunks of its
+// document, without any model call: words frequent in the chunk and rare
+// in the rest of the document rank first. `llm` asks --model for them, one
+// more call per chunk. A chunk whose extraction fails is stored without
+// keywords.
+
+use clap::ValueEnum;
+use langchain_rust::{language_models::llm::LLM, schemas::Message};
+use std::collections::{HashMap, HashSet};
+
+use crate::config;
+
+// keywords kept per chunk
+pub const MAX_KEYWORDS: usize = 7;
+// shorter words are mostly conjunctions and prepositions
+const MIN_WORD_CHARS: usize = 4;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeywordExtractor {
+    #[default]
+    Off,
+    Tfidf,
+    Llm,
+}
+
+impl KeywordExtractor {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeywordExtractor::Off => "off",
+            KeywordExtractor::Tfidf => "tfidf",
+            KeywordExtractor::Llm => "llm",
+        }
+    }
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .filter(|word| !word.chars().all(|c| c.is_numeric()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// -- keywords of every text, each scored against all `texts`
+pub fn tfidf(texts: &[&str]) -> Vec<Vec<String>> {
+    let words: Vec<Vec<String>> = texts.iter().map(|text| words(text)).collect();
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    for text_words in &words {
+        let unique: HashSet<&str> = text_words.iter().map(String::as_str).collect();
+        for word in unique {
+            *document_frequency.entry(word).or_default() += 1;
+        }
+    }
+    let texts_count = texts.len() as f64;
+    words
+        .iter()
+        .map(|text_words| {
+            let mut frequency: HashMap<&str, usize> = HashMap::new();
+            for word in text_words {
+                *frequency.entry(word).or_default() += 1;
+            }
+            let mut scored: Vec<(&str, f64)> = frequency
+                .into_iter()
+                .map(|(word, count)| {
+                    // -- smoothed, so words of a single chunk document still score
+                    let idf =
+                        ((1.0 + texts_count) / (1.0 + document_frequency[word] as f64)).ln() + 1.0;
+                    (word, count as f64 * idf)
+                })
+                .collect();
+            // -- ties by the word, for the same keywords on every run
+            scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+            scored
+                .into_iter()
+                .take(MAX_KEYWORDS)
+                .map(|(word, _)| word.to_string())
+                .collect()
+        })
+        .collect()
+}
+
+// -- comma or line separated keywords of the model's answer
+fn parse_keywords(answer: &str) -> Vec<String> {
+    let answer = answer.rsplit("</think>").next().unwrap_or_default();
+    let mut keywords: Vec<String> = vec![];
+    for keyword in answer.split([',', ';', '\n']) {
+        let keyword = keyword
+            .trim()
+            .trim_start_matches(['-', '*', '•'])
+            .trim_matches(|c: char| c.is_whitespace() || c == '"' || c == '.')
+            .to_lowercase();
+        if !keyword.is_empty() && !keywords.contains(&keyword) {
+            keywords.push(keyword);
+        }
+    }
+    keywords.truncate(MAX_KEYWORDS);
+    keywords
+}
+
+pub async fn llm(llm: &dyn LLM, text: &str) -> Result<Vec<String>, String> {
+    let messages = [
+        Message::new_system_message(config::KEYWORDS_PROMPT_STR),
+        Message::new_human_message(text),
+    ];
+    let answer = llm
+        .generate(&messages)
+        .await
+        .map_err(|e| format!("keyword extraction failed: {}", e))?;
+    match parse_keywords(&answer.generation) {
+        keywords if keywords.is_empty() => Err("keyword extraction returned none".to_string()),
+        keywords => Ok(keywords),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_are_frequent_in_the_chunk_and_rare_elsewhere() {
+        let keywords = tfidf(&[
+            "Dovolená se čerpá po dohodě. Dovolená se plánuje na celý rok.",
+            "Mzda se vyplácí měsíčně. Mzda je splatná do konce měsíce.",
+        ]);
+        assert_eq!(keywords[0][0], "dovolená");
+        assert_eq!(keywords[1][0], "mzda");
+        assert!(keywords[0].len() <= MAX_KEYWORDS);
+        assert!(!keywords[0].iter().any(|word| word == "se" || word == "po"));
+        assert!(tfidf(&[]).is_empty());
+
+        assert_eq!(
+            parse_keywords(
+                "<think>...</think>\n- Dovolená\n- čerpání, \"plán dovolené\".\n- dovolená"
+            ),
+            vec!["dovolená", "čerpání", "plán dovolené"]
+        );
+    }
+}
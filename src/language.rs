@@ -0,0 +1,183 @@
+// -------------------------------------
+// -- `--answer-lang`: the language of the answers, whatever the language of the documents
+//
+// The instruction is added to the system prompt of the answer, so the
+// retrieval and the rephrased follow-up questions (prompts without a system
+// message) are left as they are. By default the answer is in the language
+// of the question. A web request may ask for another language with
+// `answer_lang`; the chain is shared by all requests, so the request's
+// language is set for the task generating the answer, as the request filter.
+
+use std::{future::Future, pin::Pin};
+
+use async_trait::async_trait;
+use futures::Stream;
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, MessageType, StreamData},
+};
+
+use crate::config;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// names of the languages asked for by their ISO 639-1 code
+const LANGUAGE_NAMES: [(&str, &str); 10] = [
+    ("cs", "čeština"),
+    ("sk", "slovenština"),
+    ("en", "angličtina"),
+    ("de", "němčina"),
+    ("pl", "polština"),
+    ("uk", "ukrajinština"),
+    ("ru", "ruština"),
+    ("fr", "francouzština"),
+    ("es", "španělština"),
+    ("it", "italština"),
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AnswerLanguage {
+    // the language the question was asked in
+    #[default]
+    Question,
+    // an ISO 639-1 code or the name of a language
+    Named(String),
+}
+
+pub fn parse_answer_language(value: &str) -> Result<AnswerLanguage, String> {
+    match value.trim() {
+        "" => Err("expected question, a language code or a language name".to_string()),
+        "question" => Ok(AnswerLanguage::Question),
+        language => Ok(AnswerLanguage::Named(language.to_lowercase())),
+    }
+}
+
+impl AnswerLanguage {
+    pub fn name(&self) -> &str {
+        match self {
+            AnswerLanguage::Question => "question",
+            AnswerLanguage::Named(language) => language,
+        }
+    }
+
+    fn instruction(&self) -> String {
+        match self {
+            AnswerLanguage::Question => config::ANSWER_QUESTION_LANGUAGE_STR.to_string(),
+            AnswerLanguage::Named(language) => {
+                let name = LANGUAGE_NAMES
+                    .iter()
+                    .find(|(code, _)| code == language)
+                    .map_or(language.as_str(), |(_, name)| name);
+                config::ANSWER_LANGUAGE_STR.replace("{{language}}", name)
+            }
+        }
+    }
+}
+
+tokio::task_local! {
+    static REQUEST_LANGUAGE: AnswerLanguage;
+}
+
+// -- runs the future with answers in the language
+pub async fn answering<F: Future>(language: AnswerLanguage, future: F) -> F::Output {
+    REQUEST_LANGUAGE.scope(language, future).await
+}
+
+pub struct AnswerLanguageLlm {
+    inner: Box<dyn LLM>,
+    // --answer-lang, for answers outside of `answering`
+    language: AnswerLanguage,
+}
+
+impl AnswerLanguageLlm {
+    pub fn new(inner: Box<dyn LLM>, language: AnswerLanguage) -> Self {
+        AnswerLanguageLlm { inner, language }
+    }
+
+    // -- the messages with the instruction at the end of the system prompt
+    fn instructed(&self, messages: &[Message]) -> Vec<Message> {
+        let mut messages = messages.to_vec();
+        let Some(system) = messages
+            .iter()
+            .position(|m| matches!(m.message_type, MessageType::SystemMessage))
+        else {
+            return messages;
+        };
+        let instruction = REQUEST_LANGUAGE
+            .try_with(AnswerLanguage::instruction)
+            .unwrap_or_else(|_| self.language.instruction());
+        let content = format!("{}\n{}", messages[system].content, instruction);
+        messages[system] = Message::new_system_message(content);
+        messages
+    }
+}
+
+impl Clone for AnswerLanguageLlm {
+    fn clone(&self) -> Self {
+        AnswerLanguageLlm {
+            inner: self.inner.clone_box(),
+            language: self.language.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for AnswerLanguageLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        self.inner.generate(&self.instructed(messages)).await
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        self.inner.stream(&self.instructed(messages)).await
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::test_llms::SystemEcho;
+
+    #[tokio::test]
+    async fn the_request_language_overrides_the_configured_one() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        let llm = AnswerLanguageLlm::new(
+            Box::new(SystemEcho(prompts.clone())),
+            parse_answer_language("question").unwrap(),
+        );
+        let messages = [
+            Message::new_system_message("Systém."),
+            Message::new_human_message("Otázka?"),
+        ];
+        llm.generate(&messages).await.unwrap();
+        answering(
+            parse_answer_language("EN").unwrap(),
+            llm.generate(&messages),
+        )
+        .await
+        .unwrap();
+        answering(
+            AnswerLanguage::Named("esperanto".to_string()),
+            llm.generate(&messages),
+        )
+        .await
+        .unwrap();
+        // -- prompts without a system message are the rephrased questions
+        llm.generate(&messages[1..]).await.unwrap();
+
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 4);
+        assert_eq!(
+            prompts[0],
+            format!("Systém.\n{}", config::ANSWER_QUESTION_LANGUAGE_STR)
+        );
+        assert!(prompts[1].ends_with("angličtina."));
+        assert!(prompts[2].ends_with("esperanto."));
+        assert_eq!(prompts[3], "Otázka?");
+    }
+}
@@ -0,0 +1,150 @@
+// -------------------------------------
+// -- bounds of the answer length
+//
+// `--num-predict-cap` is the most tokens ollama generates for an answer, also
+// when `--num-predict` asks for more or for no limit, so a model that doesn't
+// stop can't run past its context window. Answers shorter than
+// `--min-response-tokens` are only logged as a warning. As with
+// `--temperature-schedule` only answers to prompts with retrieved chunks are
+// checked; streamed answers are counted by their messages, about a token each.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, StreamData},
+};
+
+use crate::injection;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// -- `num_predict` sent to ollama, negative ones (no limit, fill the context) are capped too.
+// -- A cap of 0 leaves it as it is
+pub fn num_predict(num_predict: Option<i32>, cap: i32) -> Option<i32> {
+    if cap <= 0 {
+        return num_predict;
+    }
+    match num_predict {
+        Some(num_predict) if num_predict >= 0 => Some(num_predict.min(cap)),
+        _ => Some(cap),
+    }
+}
+
+// -- `--num-predict` of an answer to a request with a token limit, the limit only lowers it
+pub fn limited(num_predict: Option<i32>, limit: Option<usize>) -> Option<i32> {
+    let Some(limit) = limit else {
+        return num_predict;
+    };
+    let limit = i32::try_from(limit).unwrap_or(i32::MAX);
+    match num_predict {
+        Some(num_predict) if num_predict >= 0 => Some(num_predict.min(limit)),
+        _ => Some(limit),
+    }
+}
+
+pub struct MinTokensLlm {
+    inner: Box<dyn LLM>,
+    min_tokens: usize,
+}
+
+impl MinTokensLlm {
+    pub fn new(inner: Box<dyn LLM>, min_tokens: usize) -> Self {
+        MinTokensLlm { inner, min_tokens }
+    }
+}
+
+impl Clone for MinTokensLlm {
+    fn clone(&self) -> Self {
+        MinTokensLlm {
+            inner: self.inner.clone_box(),
+            min_tokens: self.min_tokens,
+        }
+    }
+}
+
+fn warn_short(tokens: usize, min_tokens: usize) {
+    if tokens < min_tokens {
+        log::warn!(
+            "answer of {} tokens is shorter than --min-response-tokens {}",
+            tokens,
+            min_tokens
+        );
+    }
+}
+
+#[async_trait]
+impl LLM for MinTokensLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        let result = self.inner.generate(messages).await?;
+        if !injection::retrieved_chunks(messages).is_empty() {
+            let tokens = match &result.tokens {
+                Some(usage) => usage.completion_tokens as usize,
+                None => result.generation.split_whitespace().count(),
+            };
+            warn_short(tokens, self.min_tokens);
+        }
+        Ok(result)
+    }
+
+    // -- checked once the answer is streamed, answers cut short by the client aren't
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        let answer = self.inner.stream(messages).await?;
+        if injection::retrieved_chunks(messages).is_empty() {
+            return Ok(answer);
+        }
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let counted = tokens.clone();
+        let answer = answer.inspect(move |data| {
+            if matches!(data, Ok(data) if !data.content.is_empty()) {
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let min_tokens = self.min_tokens;
+        let check = stream::once(async move {
+            warn_short(tokens.load(Ordering::Relaxed), min_tokens);
+            None
+        })
+        .filter_map(|item| async { item });
+        Ok(Box::pin(answer.chain(check)))
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn num_predict_is_capped() {
+        assert_eq!(num_predict(None, 2048), Some(2048));
+        assert_eq!(num_predict(Some(500), 2048), Some(500));
+        assert_eq!(num_predict(Some(4096), 2048), Some(2048));
+        assert_eq!(num_predict(Some(-1), 2048), Some(2048));
+        assert_eq!(num_predict(Some(-1), 0), Some(-1));
+        assert_eq!(num_predict(None, 0), None);
+    }
+
+    #[test]
+    fn request_limit_lowers_num_predict() {
+        assert_eq!(limited(None, None), None);
+        assert_eq!(limited(Some(500), None), Some(500));
+        assert_eq!(limited(None, Some(200)), Some(200));
+        assert_eq!(limited(Some(-1), Some(200)), Some(200));
+        assert_eq!(limited(Some(500), Some(200)), Some(200));
+        assert_eq!(limited(Some(100), Some(200)), Some(100));
+        // -- --num-predict-cap still applies to the limited one
+        assert_eq!(num_predict(limited(None, Some(4096)), 2048), Some(2048));
+    }
+}
@@ -0,0 +1,174 @@
+// -------------------------------------
+// -- `--cpu-limit` and `--memory-limit-mb` of the ingestion, Linux only
+//
+// The CPU limit is a priority, not a hard cap: 100% keeps the normal nice
+// level, lower shares raise it up to 19 and below 50% the disk I/O goes to
+// the idle class too, so interactive programs come first while the machine is
+// busy and ingestion still gets the whole CPU when it is idle. The memory limit
+// is the address space (RLIMIT_AS), allocations over it fail. While the
+// resident memory (`VmRSS`) is close to it, ingestion waits between chunks for
+// the other documents in flight to free theirs. Waiting alone frees nothing, so
+// without other documents it goes on, and after `MEMORY_WAIT` the document
+// fails instead of waiting forever.
+
+use std::time::Duration;
+
+// share of --memory-limit-mb ingestion waits at
+const MEMORY_PAUSE_SHARE: f64 = 0.9;
+const MEMORY_POLL: Duration = Duration::from_secs(1);
+// longest wait for the memory to be freed
+const MEMORY_WAIT: Duration = Duration::from_secs(5 * 60);
+
+// -- nice level of a CPU share in percent, 0 for 100% and 19 for 1%
+pub fn niceness(percent: u8) -> i32 {
+    let percent = percent.clamp(1, 100) as i32;
+    ((100 - percent) * 19 + 49) / 99
+}
+
+// -- `VmRSS` of a /proc/<pid>/status, in kB
+pub fn vm_rss_kb(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+pub fn apply(cpu_percent: Option<u8>, memory_limit_mb: Option<u64>) -> Result<(), String> {
+    // -- ioprio_set(IOPRIO_WHO_PROCESS, self, IOPRIO_CLASS_IDLE)
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3 << 13;
+
+    if let Some(percent) = cpu_percent {
+        let nice = niceness(percent);
+        // SAFETY: plain syscalls on the own process, no memory is passed
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(format!(
+                "setting nice {} failed: {}",
+                nice,
+                std::io::Error::last_os_error()
+            ));
+        }
+        if percent < 50
+            && unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    IOPRIO_CLASS_IDLE,
+                )
+            } != 0
+        {
+            log::warn!(
+                "setting the idle I/O class failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        log::info!("--cpu-limit {}%: nice {}", percent, nice);
+    }
+    if let Some(mb) = memory_limit_mb {
+        let bytes = mb * 1024 * 1024;
+        let limit = libc::rlimit {
+            rlim_cur: bytes,
+            rlim_max: bytes,
+        };
+        // SAFETY: `limit` outlives the call
+        if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } != 0 {
+            return Err(format!(
+                "setting the {} MB address space limit failed: {}",
+                mb,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(cpu_percent: Option<u8>, memory_limit_mb: Option<u64>) -> Result<(), String> {
+    match cpu_percent.is_some() || memory_limit_mb.is_some() {
+        true => Err("--cpu-limit and --memory-limit-mb work on Linux only".to_string()),
+        false => Ok(()),
+    }
+}
+
+// -- returns once the process is below `MEMORY_PAUSE_SHARE` of the limit or no other document
+// -- is in flight, Err when the memory isn't freed within `MEMORY_WAIT`
+pub async fn wait_for_memory(
+    memory_limit_mb: Option<u64>,
+    others_in_flight: impl Fn() -> bool,
+) -> Result<(), String> {
+    let Some(mb) = memory_limit_mb else {
+        return Ok(());
+    };
+    let over = || {
+        std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| vm_rss_kb(&status))
+            .is_some_and(|kb| kb as f64 > (mb * 1024) as f64 * MEMORY_PAUSE_SHARE)
+    };
+    match wait_while(over, others_in_flight, MEMORY_WAIT).await {
+        true => Ok(()),
+        false => Err(format!(
+            "memory stayed close to --memory-limit-mb {} for {} s",
+            mb,
+            MEMORY_WAIT.as_secs()
+        )),
+    }
+}
+
+// -- false when still `over` after `timeout`
+async fn wait_while(
+    over: impl Fn() -> bool,
+    others_in_flight: impl Fn() -> bool,
+    timeout: Duration,
+) -> bool {
+    if !over() || !others_in_flight() {
+        return true;
+    }
+    log::warn!("memory close to --memory-limit-mb, ingestion paused");
+    let started = tokio::time::Instant::now();
+    while over() && others_in_flight() {
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        tokio::time::sleep(MEMORY_POLL).await;
+    }
+    log::info!("memory freed, ingestion resumed");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_share_maps_to_nice_levels() {
+        assert_eq!(niceness(100), 0);
+        assert_eq!(niceness(50), 10);
+        assert_eq!(niceness(1), 19);
+        assert_eq!(niceness(0), 19);
+    }
+
+    #[test]
+    fn vm_rss_is_read_from_the_status() {
+        let status = "Name:\tchunk_contextor\nVmPeak:\t  912344 kB\nVmSize:\t  834100 kB\nVmRSS:\t  120000 kB\n";
+        assert_eq!(vm_rss_kb(status), Some(120000));
+        assert_eq!(vm_rss_kb("Name:\tkthreadd\n"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_never_freed_fails_after_the_wait() {
+        let started = tokio::time::Instant::now();
+        assert!(!wait_while(|| true, || true, MEMORY_WAIT).await);
+        assert!(started.elapsed() >= MEMORY_WAIT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_is_only_waited_for_with_other_documents_in_flight() {
+        let started = tokio::time::Instant::now();
+        assert!(wait_while(|| true, || false, MEMORY_WAIT).await);
+        assert!(wait_while(|| false, || true, MEMORY_WAIT).await);
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+}
@@ -1,120 +1,339 @@
-use clap::{Parser, ValueEnum};
+mod answer;
+mod capacity;
+mod chunk_graph;
+mod chunking;
+mod collection_settings;
+mod compression;
+mod config;
+mod conversation;
+mod effective;
+mod embeddings;
+mod expansion;
+mod explain;
+mod export;
+mod fallback;
+mod images;
+mod injection;
+mod jobs;
+mod keep_alive;
+mod keywords;
+mod language;
+mod length;
+mod limits;
+mod mcp;
+mod metadata;
+mod models;
+mod ollama;
+mod output;
+mod plan;
+mod reconnect;
+mod recontext;
+mod report;
+mod rerank;
+mod retrieval;
+mod schema;
+mod selftest;
+mod show;
+mod slack;
+mod sources;
+mod speakers;
+mod store;
+mod summarize;
+mod tables;
+mod temperature;
+#[cfg(test)]
+mod test_llms;
+mod transcript;
+mod watcher;
+mod wire;
+
+use clap::{
+    builder::{PossibleValue, TypedValueParser},
+    error::ErrorKind,
+    CommandFactory, Parser, ValueEnum,
+};
 // use futures_util::StreamExt;
-use futures_util::TryStream;
 use reqwest::Url;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::{json, Value};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 // use tokio_stream::wrappers::ReceiverStream;
+use reconnect::{Reconnect, ReconnectingEmbedder, ReconnectingLlm};
+use store::{
+    cosine_similarity, l2_norm, ChunkStore, MemoryStore, MetadataFilter, QdrantStore,
+    ResilientStore, SimilarityMetric, SqliteStore, TruncatedEmbedder,
+};
 use unescape::unescape;
+use uuid::Uuid;
 
-use std::{collections::HashMap, convert::Infallible, fs, io::Write, sync::Arc, time::Duration};
-use text_splitter::{ChunkConfig, TextSplitter};
-use tiktoken_rs::cl100k_base;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use text_splitter::{Characters, ChunkConfig, ChunkSizer, TextSplitter};
+use tiktoken_rs::{cl100k_base, o200k_base, CoreBPE};
 
 use axum::{
-    extract::{Json, State},
-    response::{sse::Event, IntoResponse, Sse},
+    extract::{DefaultBodyLimit, Json, Multipart, Path as UrlPath, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{sse::Event, IntoResponse, Response, Sse},
     routing::{get, post},
     Router,
 };
 use langchain_rust::{
     chain::{
-        builder::ConversationalChainBuilder, Chain, ConversationalRetrieverChain,
+        builder::ConversationalChainBuilder, Chain, ChainError, CondenseQuestionGeneratorChain,
+        CondenseQuestionPromptBuilder, ConversationalChain, ConversationalRetrieverChain,
         ConversationalRetrieverChainBuilder,
     },
-    document_loaders::{pdf_extract_loader::PdfExtractLoader, Loader},
-    embedding::OllamaEmbedder,
+    embedding::{Embedder, OllamaEmbedder},
     fmt_message, fmt_template,
+    language_models::llm::LLM,
     llm::client::{GenerationOptions, Ollama, OllamaClient},
-    memory::SimpleMemory,
-    message_formatter, output_parsers,
-    prompt::HumanMessagePromptTemplate,
+    message_formatter,
+    prompt::{HumanMessagePromptTemplate, PromptArgs, PromptTemplate},
     prompt_args,
-    schemas::{Document, Message, Retriever},
+    schemas::{Document, Message},
     template_jinja2,
-    vectorstore::{
-        qdrant::{Qdrant, StoreBuilder},
-        // Retriever, VecStoreOptions, VectorStore,
-        VecStoreOptions,
-        VectorStore,
-    },
+    vectorstore::qdrant::Qdrant,
 };
 
-// pub const CONTEXT_CHUNK_STR: &str = "
-// Jsi asistent pro zpracování textu. Tvým úkolem je rozšířit daný chunk textu pomocí kontextu z celého dokumentu tak, aby byl co nejvíce srozumitelný a informativní i při samostatném použití. Doplněním kontextu zajistíš, že chunk obsahuje klíčové informace, které mu chybí, a zároveň zůstane stručný a relevantní.
-//
-// Vstup:
-//
-// Celý dokument:
-// {{document}}
-//
-// Původní chunk:
-// {{input}}
-//
-// Požadavky na výstup:
-//     Doplnění kontextu – Pokud chunk odkazuje na nejasné subjekty, události nebo pojmy, doplň je z kontextu celého dokumentu.
-//     Konzistence – Zachovej styl a terminologii dokumentu.
-//     Stručnost – Chunk nesmí být příliš dlouhý, ale měl by obsahovat všechny klíčové informace.
-//     Koherence – Chunk by měl dávat smysl i sám o sobě, bez nutnosti číst celý dokument.
-//
-// Výstup:
-// Vrátíš přeformulovaný chunk s doplněným kontextem. Nepřidávej žádné zbytečné informace, které nejsou v dokumentu.
-// ";
-
-const CONTEXT_CHUNK_STR: &str = "
-Jsi asistent pro zpracování textu. Tvým úkolem je rozšířit daný chunk textu pomocí jeho nejbližšího kontextu (dva předchozí a dva následující chunky). Cílem je zajistit, aby byl chunk srozumitelný a informativní i při samostatném použití, a to bez zbytečného opakování.
-
-Vstup:
-    Předchozí chunky:
-    {{previous_chunks}}
-
-    Aktuální chunk:
-    {{input}}
-
-    Následující chunky:
-    ({next_chunks}}
-
-Požadavky na výstup:
-    Doplnění kontextu – Pokud aktuálnímu chunku chybí důležité informace (např. subjekty, události, definice), doplň je pomocí sousedních chunků.
-    Konzistence – Zachovej styl a terminologii původního dokumentu.
-    Stručnost – Chunk by měl být co nejkratší, ale zároveň obsahovat všechny klíčové informace.
-    Koherence – Výstup by měl dávat smysl i bez přístupu k okolním chunkům.
-    Neopakuj obsah – Nevkládej celé věty z okolních chunků, pouze doplň chybějící informace.
-
-Výstup:
-    Vytvoř přeformulovaný chunk, který zahrnuje potřebný kontext z předchozích a následujících částí textu. Nezahrnuj žádné informace, které nejsou obsaženy v poskytnutých textech.
-";
-
-const CHAT_PROMPT_STR: &str = "
-Jsi pokročilý AI asistent, který odpovídá na otázky na základě poskytnutého kontextu.  
-Tvoje úloha je analyzovat poskytnuté informace a vybrat **pouze ty nejrelevantnější** pro odpověď.  
-
-📌 **Otázka uživatele:**  
-{{question}}
-
-📌 **Poskytnuté informace (může obsahovat irelevantní části):**  
-{{context}}
-
-📌 **Instrukce pro odpověď:**  
-1. **Používej historii konverzace k udržení kontextu.** Pokud otázka odkazuje na předchozí část dialogu, zohledni ji.  
-2. **Pečlivě vyhodnoť, které části poskytnutého textu jsou relevantní.** Nepoužívej irelevantní informace.  
-3. **Odpověz podrobně a strukturovaně.** Pokud je to vhodné, použij odstavce, seznamy nebo příklady.  
-4. **Zahrň související informace, které mohou být užitečné pro odpověď.**  
-5. **Nevyužívej žádné jiné znalosti mimo poskytnutý kontext a historii konverzace.**  
-6. **Pokud v poskytnutých informacích odpověď chybí, přiznej to, ale nabídni užitečné doplňující informace, pokud to dává smysl.**  
-
-**Tvoje odpověď:**";
+// number of chunks retrieved for a question and their minimal similarity score
+const RETRIEVED_DOCUMENTS: usize = 5;
+const SCORE_THRESHOLD: f32 = 0.55;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Mode {
     Chat,
     Generate,
+    // --document directory ingested again whenever its files change
+    Watch,
     Web,
+    Mcp,
+    Slack,
+    EmbedTest,
+    // chunks stored for --path
+    Show,
+    // stored chunk --chunk of --path (or every --all-failed one) enriched again
+    Recontextualize,
+    // --payload-indexes of an existing qdrant collection
+    ReindexPayload,
+    // effective configuration as JSON, the one of GET /config
+    ConfigShow,
+    // KEY VALUE setting of --collection stored in the chunk store, chat and web defaults
+    ConfigSet,
+    // --question as the chain rephrases it after the --history conversation, to stdout
+    Rephrase,
+    // shell completion script for --shell, to stdout
+    Completions,
+    // manpage, to stdout
+    Man,
+    // a bundled document ingested, retrieved and answered from, against a mock with --offline
+    Selftest,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ContextStrategy {
+    // 2 previous and 2 next chunks
+    Window,
+    // whole document text
+    FullDocument,
+    // generated document summary
+    Summary,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DocType {
+    // `*.pdf`, split by --split-strategy
+    Pdf,
+    // `*.txt` meeting transcripts of `Speaker: text` lines, split by speaker turns
+    Transcript,
+    // `*.pdf` of a kind with its own prompt in --prompts-dir (directive, contract, ...)
+    Named(String),
+}
+
+fn parse_doc_type(value: &str) -> Result<DocType, String> {
+    let name = value.trim().to_lowercase();
+    match name.as_str() {
+        "pdf" => Ok(DocType::Pdf),
+        "transcript" => Ok(DocType::Transcript),
+        _ if !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_') =>
+        {
+            Ok(DocType::Named(name))
+        }
+        _ => Err("expected pdf, transcript or a name of letters, digits, - and _".to_string()),
+    }
+}
+
+impl DocType {
+    // -- stored as the `doc_type` metadata of the chunks
+    fn name(&self) -> &str {
+        match self {
+            DocType::Pdf => "pdf",
+            DocType::Transcript => "transcript",
+            DocType::Named(name) => name,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            DocType::Pdf | DocType::Named(_) => "pdf",
+            DocType::Transcript => "txt",
+        }
+    }
+
+    // -- built-in prompt of the window strategy
+    fn window_template(&self) -> &'static str {
+        match self {
+            DocType::Pdf | DocType::Named(_) => config::CONTEXT_CHUNK_STR,
+            DocType::Transcript => config::TRANSCRIPT_CHUNK_STR,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SplitStrategy {
+    // fixed size chunks
+    Token,
+    // chunk boundaries where adjacent sentences stop being similar
+    Semantic,
+    // single sentences are embedded, N sentences around them are stored for the prompt
+    SentenceWindow(usize),
+}
+
+// -- parser function of a flag with parameters, with the values shell completions offer
+#[derive(Clone)]
+struct HintedParser<T> {
+    parse: fn(&str) -> Result<T, String>,
+    hints: &'static [&'static str],
+}
+
+impl<T: Clone + Send + Sync + 'static> TypedValueParser for HintedParser<T> {
+    type Value = T;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<T, clap::Error> {
+        let value = value
+            .to_str()
+            .ok_or_else(|| clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd))?;
+        (self.parse)(value).map_err(|e| {
+            let arg = arg.map(ToString::to_string).unwrap_or_default();
+            clap::Error::raw(
+                ErrorKind::ValueValidation,
+                format!("invalid value '{}' for '{}': {}\n", value, arg, e),
+            )
+            .with_cmd(cmd)
+        })
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(self.hints.iter().map(PossibleValue::new)))
+    }
+}
+
+fn parse_split_strategy(value: &str) -> Result<SplitStrategy, String> {
+    match value.split_once(':') {
+        None if value == "token" => Ok(SplitStrategy::Token),
+        None if value == "semantic" => Ok(SplitStrategy::Semantic),
+        Some(("sentence-window", sentences)) => sentences
+            .parse()
+            .map(SplitStrategy::SentenceWindow)
+            .map_err(|_| format!("invalid sentence count '{}'", sentences)),
+        _ => Err("expected token, semantic or sentence-window:<N>".to_string()),
+    }
+}
+
+impl SplitStrategy {
+    fn name(&self) -> String {
+        match self {
+            SplitStrategy::Token => "token".to_string(),
+            SplitStrategy::Semantic => "semantic".to_string(),
+            SplitStrategy::SentenceWindow(sentences) => format!("sentence-window:{}", sentences),
+        }
+    }
+}
+
+// how the sources of an answer are cited
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SourceFormat {
+    // one entry per document
+    #[default]
+    Path,
+    // one entry per page of a document, for chunks that store their `page`
+    PathPage,
+    // the whole metadata of every chunk as JSON, for debugging
+    Full,
+}
+
+impl SourceFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            SourceFormat::Path => "path",
+            SourceFormat::PathPage => "path-page",
+            SourceFormat::Full => "full",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Sizer {
+    // tiktoken cl100k_base tokens
+    Cl100k,
+    // tiktoken o200k_base tokens
+    O200k,
+    // plain character count
+    Chars,
+}
+
+// how the end of the previous chunk is repeated at the start of the next one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum OverlapStrategy {
+    None,
+    // last N tokens of the previous chunk
+    Token(usize),
+    // last complete sentence of the previous chunk
+    Sentence,
+}
+
+const DEFAULT_OVERLAP_TOKENS: usize = 50;
+
+fn parse_overlap_strategy(value: &str) -> Result<OverlapStrategy, String> {
+    match value.split_once(':') {
+        None if value == "none" => Ok(OverlapStrategy::None),
+        None if value == "token" => Ok(OverlapStrategy::Token(DEFAULT_OVERLAP_TOKENS)),
+        None if value == "sentence" => Ok(OverlapStrategy::Sentence),
+        Some(("token", tokens)) => tokens
+            .parse()
+            .map(OverlapStrategy::Token)
+            .map_err(|_| format!("invalid token count '{}'", tokens)),
+        _ => Err("expected none, token, token:<N> or sentence".to_string()),
+    }
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(version, about, long_about = None)]
 struct Cli {
     // chatting and generating model
@@ -123,58 +342,1097 @@ struct Cli {
     // embedding model
     #[arg(short, long, default_value = "paraphrase-multilingual")]
     embed: Option<String>,
-    // qdrant db url
+    // store only the first N (normalized) embedding dimensions, must match the ingestion
+    #[arg(long)]
+    embed_dimensions: Option<usize>,
+    // L2-normalize the embeddings of models that don't, before storing and searching
+    #[arg(long)]
+    embed_normalize: bool,
+    // qdrant gRPC url, `sqlite:<file>` for a local store, or `memory` for one lost on exit
     #[arg(long, default_value = "http://localhost:6334")]
     db: Option<String>,
     #[arg(short, long)]
     document: Option<String>,
+    // how qdrant compares embeddings, set when generate creates the collection and checked
+    // against it by every mode
+    #[arg(long, value_enum, default_value_t = SimilarityMetric::Cosine)]
+    similarity_metric: SimilarityMetric,
+    // metadata fields generate indexes in qdrant for filtering, `field:integer` for numbers
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "path,kind,lang,doc_type,keywords,version:integer",
+        value_parser = store::parse_payload_index
+    )]
+    payload_indexes: Vec<store::PayloadIndex>,
     #[arg(short, long, default_value = "http://localhost:11434")]
     ollama: Option<String>,
-    #[arg(value_enum)]
-    mode: Mode,
+    // documents bigger than this are skipped in generate mode
+    #[arg(long, default_value_t = 100)]
+    max_document_size_mb: u64,
+    // CPU share of generate and watch in percent, lowers their priority (Linux)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    cpu_limit: Option<u8>,
+    // address space limit of generate and watch, chunks wait while 90% of it is resident (Linux)
+    #[arg(long)]
+    memory_limit_mb: Option<u64>,
+    // only the first N pages of a document are processed
+    #[arg(long)]
+    max_page_count: Option<usize>,
+    // address of the web and slack server
+    #[arg(long, default_value = "127.0.0.1:3003")]
+    listen: Option<String>,
+    // slack bot token (xoxb-...) used for posting answers
+    #[arg(long, env = "SLACK_BOT_TOKEN", hide_env_values = true)]
+    slack_bot_token: Option<String>,
+    // slack app signing secret for verifying incoming requests
+    #[arg(long, env = "SLACK_SIGNING_SECRET", hide_env_values = true)]
+    slack_signing_secret: Option<String>,
+    // unit of --chunk-size, cl100k (o200k for non-latin scripts) when not set
+    #[arg(long, value_enum)]
+    sizer: Option<Sizer>,
+    // context window of the generative model in tokens, model default when not set
+    #[arg(long)]
+    num_ctx: Option<u32>,
+    // maximal number of generated tokens, model default when not set
+    #[arg(long)]
+    num_predict: Option<i32>,
+    // ceiling of --num-predict and of the model default against runaway answers, 0 for none
+    #[arg(long, default_value_t = 2048)]
+    num_predict_cap: i32,
+    // answers of fewer tokens are logged as a warning, 0 for none
+    #[arg(long, default_value_t = 0)]
+    min_response_tokens: usize,
+    // answers end before any of these, escapes like `\n` are decoded, e.g. "\n---\n"
+    #[arg(long, value_delimiter = ',')]
+    stop_words: Vec<String>,
+    // no stop words at all, also none of the model's Modelfile
+    #[arg(long, conflicts_with = "stop_words")]
+    no_stop: bool,
+    // JSON Schema file the answers have to match, they are JSON then
+    #[arg(long)]
+    output_schema: Option<String>,
+    // answers not matching --output-schema are generated again with the error this many times
+    #[arg(long, default_value_t = 2)]
+    output_schema_retries: usize,
+    // maximal chunk size in --sizer units
+    #[arg(long, default_value_t = 512)]
+    chunk_size: usize,
+    // kind of the ingested documents, stored as `doc_type`: pdf, transcript (ignoring
+    // --split-strategy and tables) or the name of a kind of pdfs, e.g. directive
+    #[arg(long, default_value = "pdf", value_parser = HintedParser {
+        parse: parse_doc_type,
+        hints: &["pdf", "transcript", "directive", "contract", "minutes"],
+    })]
+    doc_type: DocType,
+    // `context_chunk.<doc type>.txt` and `context_chunk.txt` prompts replacing the built-in
+    // window prompt
+    #[arg(long)]
+    prompts_dir: Option<String>,
+    // token, semantic or sentence-window:<N>
+    #[arg(long, default_value = "token", value_parser = HintedParser {
+        parse: parse_split_strategy,
+        hints: &["token", "semantic", "sentence-window:3"],
+    })]
+    split_strategy: SplitStrategy,
+    // semantic strategy starts a new chunk below this sentence similarity
+    #[arg(long, default_value_t = 0.6)]
+    semantic_threshold: f64,
+    // none, token (last 50 tokens), token:<N> or sentence
+    #[arg(long, default_value = "none", value_parser = HintedParser {
+        parse: parse_overlap_strategy,
+        hints: &["none", "token", "token:50", "sentence"],
+    })]
+    chunk_overlap_strategy: OverlapStrategy,
+    // stream `<think>` reasoning of reasoning models as `thinking` SSE events in web mode, at
+    // most this many tokens of it. ollama-rs has no thinking option, the model isn't limited
+    #[arg(long)]
+    thinking_budget: Option<usize>,
+    // web answers stop after this many tokens (sent as num_predict), requests' `max_tokens` can
+    // only lower it
+    #[arg(long)]
+    max_answer_tokens: Option<usize>,
+    // rewrite follow-up questions with the chat history before retrieval
+    #[arg(long, value_enum, default_value = "on")]
+    rephrase: Switch,
+    // how long ollama keeps the chat and embedding models loaded after a request: 30m, 2h,
+    // 300 (seconds), -1 (forever), 0 (unload right away); ollama's default (5m) if not set
+    #[arg(long, value_parser = keep_alive::parse_keep_alive)]
+    ollama_keep_alive: Option<ollama_rs::generation::parameters::KeepAlive>,
+    // wait between retries of ollama requests that couldn't connect
+    #[arg(long, default_value_t = 5)]
+    ollama_reconnect_secs: u64,
+    // retries of ollama requests that couldn't connect, 0 fails right away
+    #[arg(long, default_value_t = 3)]
+    ollama_reconnect_attempts: u32,
+    // search that finds nothing above the score threshold is not retried with a lower one
+    #[arg(long)]
+    no_adaptive_retrieval: bool,
+    // print retrieval and generation timings, chunk scores and context size after every
+    // chat answer
+    #[arg(long)]
+    verbose_retrieval: bool,
+    // attribute every answer sentence to its source chunk with another --model call: chat
+    // prints `[N]` citations, web sends an `attribution` event
+    #[arg(long)]
+    explain: bool,
+    // alternative phrasings of the question generated by --model and searched as well,
+    // 0 searches the question only
+    #[arg(long, default_value_t = 0)]
+    query_expansion: usize,
+    // the broader question behind the question, generated by --model, is searched as well
+    #[arg(long)]
+    step_back: bool,
+    // the prompt gets only the sentences of the retrieved chunks relevant to the question
+    #[arg(long)]
+    compress_context: bool,
+    // fast model picking the relevant sentences for --compress-context, sentences similar to
+    // the question by their embedding when not set
+    #[arg(long, requires = "compress_context")]
+    compression_model: Option<String>,
+    // --compress-context keeps at least this many tokens of every chunk
+    #[arg(long, default_value_t = 64)]
+    compression_min_tokens: usize,
+    // share of the retrieved chunks coming from the --step-back question, 0 to 1
+    #[arg(long, default_value_t = 0.5, value_parser = parse_share)]
+    step_back_weight: f32,
+    // temperatures of the retries of answers shorter than --min-answer-chars or not grounded
+    // in the retrieved chunks, comma separated, e.g. 0.7,0.4,0.1
+    #[arg(long, value_delimiter = ',', value_parser = temperature::parse_temperature)]
+    temperature_schedule: Vec<f32>,
+    // shorter answers are retried with --temperature-schedule
+    #[arg(long, default_value_t = 50)]
+    min_answer_chars: usize,
+    // colored chat and generate output: auto (on a terminal without NO_COLOR), always, never
+    #[arg(long, value_enum, default_value = "auto")]
+    color: output::ColorChoice,
+    // generate prints only the summary, no per-chunk progress
+    #[arg(long)]
+    quiet: bool,
+    // generate estimates its token budget without enriching a few chunks to time them
+    #[arg(long)]
+    no_calibrate: bool,
+    // generate asks before enriching the chunks, after printing the token budget
+    #[arg(long)]
+    confirm: bool,
+    // generate prints this many randomly sampled chunks of every document with their enriched
+    // text and asks before storing them, 0 for none
+    #[arg(long, default_value_t = 0)]
+    chunk_preview_n: usize,
+    // documents generate enriches and stores at the same time. Ollama runs only
+    // OLLAMA_NUM_PARALLEL requests at once and each of them needs its own --num-ctx of GPU
+    // memory, so more workers than that only queue
+    #[arg(long, default_value_t = 1, conflicts_with = "chunk_preview_n")]
+    num_workers: usize,
+    // generate prints the token budget and stops
+    #[arg(long)]
+    plan_only: bool,
+    // pull the configured ollama models that are missing instead of stopping
+    #[arg(long)]
+    auto_pull: bool,
+    // models web chat requests may choose instead of --model, comma separated
+    #[arg(long, value_delimiter = ',')]
+    allowed_models: Vec<String>,
+    // only chunks whose metadata match this qdrant filter json are retrieved, e.g.
+    // '{"must": [{"key": "department", "match": {"value": "HR"}}]}'
+    #[arg(long)]
+    filter_by_payload: Option<String>,
+    // only chunks whose metadata has this value are retrieved, repeatable; `keyword=dovolená`
+    // matches one of the chunk's --keywords
+    #[arg(long, value_parser = parse_meta)]
+    filter: Vec<(String, String)>,
+    // prepended to every retrieved chunk in the prompt, `{field}` is replaced by the chunk's
+    // metadata, e.g. "Source: {path}, Page: {page}\n---\n"
+    #[arg(long)]
+    context_header: Option<String>,
+    // retrieved chunks more similar than this to a better matching one are left out of the
+    // prompt, above 1 keeps them all
+    #[arg(long, default_value_t = 0.95)]
+    dedup_threshold: f64,
+    // similarity of chunks is multiplied by the freshness of their document (created_at or
+    // last_modified metadata) this much, 0 disables it and 1 multiplies by it fully
+    #[arg(long, default_value_t = 0.0)]
+    freshness_weight: f64,
+    // key=value added to the metadata of every ingested chunk, repeat for more keys;
+    // `<document>.meta.toml` (or `.meta.json`) next to a document overrides them
+    #[arg(long, value_parser = parse_meta)]
+    meta: Vec<(String, String)>,
+    // keywords stored with every chunk as `keywords`, llm asks --model once more per chunk
+    #[arg(long, value_enum, default_value_t = keywords::KeywordExtractor::Off)]
+    keywords: keywords::KeywordExtractor,
+    // JSON object of payload keys computed per file, --meta and the sidecar override them:
+    // `$ENV`, `{filename}`, `{extension}`, `{size_bytes}`, `{modified_date}`, `{ingest_time}`,
+    // `{model}`, `{embed_model}` and `{chunk_strategy}` are replaced in its strings
+    #[arg(long, value_parser = metadata::parse_metadata_template)]
+    chunk_metadata_template: Option<metadata::MetadataTemplate>,
+    // metadata fields shown with the sources in chat and in the web `sources` event,
+    // comma separated, e.g. department,directive
+    #[arg(long, value_delimiter = ',')]
+    source_fields: Vec<String>,
+    // citations of chat answers and of the web `documents`, web requests may choose another
+    #[arg(long, value_enum, default_value_t = SourceFormat::Path)]
+    source_documents_format: SourceFormat,
+    // freshness of a document is exp(-age in days / this)
+    #[arg(long, default_value_t = 90.0)]
+    freshness_half_life_days: f64,
+    // re-order retrieved chunks by the relevance scored by --rerank-model
+    #[arg(long)]
+    rerank: bool,
+    // model scoring chunks for --rerank, --model if not set
+    #[arg(long)]
+    rerank_model: Option<String>,
+    // --rerank scores this many times more chunks than end up in the prompt
+    #[arg(long, default_value_t = 3)]
+    rerank_candidates: usize,
+    // retrieved chunks matching this regex are flagged in the sources, repeatable
+    #[arg(long)]
+    injection_denylist: Vec<String>,
+    // web answers are high confidence when the best retrieved chunk scores at least this
+    #[arg(long, default_value_t = 0.7)]
+    confidence_high_score: f64,
+    // ... and at least this many chunks are retrieved
+    #[arg(long, default_value_t = 2)]
+    confidence_high_hits: usize,
+    // web answers --refusal-message instead of asking the model when no chunk was retrieved
+    #[arg(long)]
+    refuse_without_sources: bool,
+    #[arg(
+        long,
+        default_value = "V dokumentech jsem k této otázce nenašel žádné informace."
+    )]
+    refusal_message: String,
+    // the oldest turns of chat, web and slack conversations are dropped above these limits
+    #[arg(long)]
+    memory_max_turns: Option<usize>,
+    // cl100k_base tokens of the questions and answers kept
+    #[arg(long)]
+    memory_max_tokens: Option<usize>,
+    #[arg(long)]
+    memory_max_age_mins: Option<u64>,
+    // web questions matching an injection pattern are rejected or logged (--injection-action)
+    #[arg(long)]
+    prompt_inject_guard: bool,
+    // --prompt-inject-guard regexes, one per line, instead of the built-in ones
+    #[arg(long, requires = "prompt_inject_guard")]
+    injection_patterns_file: Option<String>,
+    // what --prompt-inject-guard does with a matching question
+    #[arg(long, value_enum, default_value_t = injection::InjectionAction::Block)]
+    injection_action: injection::InjectionAction,
+    // the retry lowers the score threshold by this much
+    #[arg(long, default_value_t = 0.15)]
+    relaxed_threshold_delta: f32,
+    // and retrieves this many times more chunks
+    #[arg(long, default_value_t = 2)]
+    relaxed_limit_factor: usize,
+    // smaller model answering when --model times out or can't be reached
+    #[arg(long)]
+    fallback_model: Option<String>,
+    // seconds --model gets to answer (or start streaming) before --fallback-model is used
+    #[arg(long, default_value_t = 120, requires = "fallback_model")]
+    fallback_timeout_secs: u64,
+    // chat mode appends every exchange to this jsonl file
+    #[arg(long)]
+    transcript: Option<String>,
+    // one-sentence summary of every answer by --model, one more call per answer, shown under
+    // the chat sources, stored in the --transcript and listed by GET /session
+    #[arg(long)]
+    summarize_answers: bool,
+    // question of the rephrase mode
+    #[arg(long)]
+    question: Option<String>,
+    // --transcript file the rephrase mode reads as the conversation history
+    #[arg(long)]
+    history: Option<String>,
+    // chat mode writes the conversation as Markdown to this file when it ends
+    #[arg(long)]
+    export_on_exit: Option<String>,
+    // show `[src:<hash>]` instead of document paths in answers, resolved by GET /sources/{hash}
+    #[arg(long)]
+    anonymize_sources: bool,
+    // bearer token of the admin endpoints (GET /sources/{hash}, GET /config), disabled without it
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    // collection (`name` of its _collection.toml) chat, web, slack and mcp retrieve from, with
+    // its stored settings as defaults; generate and config-set store the settings for it
+    #[arg(long)]
+    collection: Option<String>,
+    // chat system prompt, the --collection's stored one or the built-in one when not set
+    #[arg(long)]
+    system_prompt: Option<String>,
+    // chunks retrieved for a question, the --collection's stored top_k or 5 when not set
+    #[arg(long, value_parser = parse_top_k)]
+    top_k: Option<usize>,
+    // minimal score of a retrieved chunk, the --collection's stored one or 0.55 when not set
+    #[arg(long)]
+    score_threshold: Option<f32>,
+    // text added to the end of the chat system prompt, can be repeated
+    #[arg(long)]
+    system_prompt_append: Vec<String>,
+    // language of the answers, `question` for the language of the question, or a language
+    // code or name (en, English), whatever the language of the documents
+    #[arg(long, default_value = "question", value_parser = language::parse_answer_language)]
+    answer_lang: language::AnswerLanguage,
+    // toml mapping detected document language to a system prompt file (`ces = "prompts/cs.txt"`)
+    #[arg(long)]
+    language_prompts: Option<String>,
+    // enriched chunks shorter than this ratio of the original are rejected
+    #[arg(long, default_value_t = 0.5)]
+    min_enrichment_ratio: f32,
+    // enriched chunks longer than this ratio of the original are rejected
+    #[arg(long, default_value_t = 5.0)]
+    max_enrichment_ratio: f32,
+    // fail on rejected enriched chunks instead of keeping the original
+    #[arg(long)]
+    strict_enrichment: bool,
+    // describe images embedded in documents with --vision-model
+    #[arg(long)]
+    describe_images: bool,
+    // vision capable model for --describe-images
+    #[arg(long, default_value = "llava")]
+    vision_model: String,
+    // smaller images (logos, decorations) are not described
+    #[arg(long, default_value_t = 100)]
+    min_image_size: i64,
+    // enrich this text as a chunk, print the result and exit
+    #[arg(long)]
+    test_prompt: Option<String>,
+    // previous chunk for --test-prompt
+    #[arg(long, requires = "test_prompt")]
+    test_prev: Option<String>,
+    // next chunk for --test-prompt
+    #[arg(long, requires = "test_prompt")]
+    test_next: Option<String>,
+    // what the chunks are enriched with in generate mode
+    #[arg(long, value_enum, default_value_t = ContextStrategy::Window)]
+    context_strategy: ContextStrategy,
+    // the window strategy takes as many neighbour chunks as fit --context-window-tokens at the
+    // document's average chunk size, instead of 2 on each side
+    #[arg(long)]
+    context_window_dynamic: bool,
+    // neighbour text on each side of a chunk with --context-window-dynamic, in --sizer units
+    #[arg(long, default_value_t = 1024)]
+    context_window_tokens: usize,
+    // token limit of the document text used by full-document and summary strategies
+    #[arg(long, default_value_t = 8192)]
+    context_max_tokens: usize,
+    // prompt size of an enriched chunk in --sizer units, the neighbour chunks are trimmed to
+    // fit. --num-ctx less the expected enriched chunk when not set, none with --sizer chars
+    #[arg(long)]
+    context_prompt_budget: Option<usize>,
+    // generate prints the enriched chunks with their embeddings as json instead of storing them
+    #[arg(long)]
+    dry_embed: bool,
+    // --dry-embed prints the first N dimensions of every vector, all when not set
+    #[arg(long, requires = "dry_embed")]
+    dry_embed_dims: Option<usize>,
+    // generate writes the embedding of every chunk with its text and metadata to this file,
+    // without --dry-embed the chunks are embedded once more for it
+    #[arg(long)]
+    save_embeddings_to: Option<String>,
+    #[arg(long, value_enum, default_value_t = embeddings::EmbeddingsFormat::Json)]
+    save_embeddings_format: embeddings::EmbeddingsFormat,
+    // generate writes the chunks as a graph to this file before enriching them, every chunk
+    // joined to the chunks of its window prompt
+    #[arg(long)]
+    chunk_graph_export: Option<String>,
+    #[arg(long, value_enum, default_value_t = chunk_graph::GraphFormat::Graphml)]
+    chunk_graph_format: chunk_graph::GraphFormat,
+    // generate writes an HTML report of the run to this file: the documents, a histogram of
+    // the chunk lengths, the longest and shortest chunks and the configuration
+    #[arg(long)]
+    generate_report: Option<String>,
+    // chunks sent to qdrant in one upsert request
+    #[arg(long, default_value_t = 100)]
+    qdrant_batch_size: usize,
+    // pause between upsert batches
+    #[arg(long, default_value_t = 0)]
+    qdrant_batch_delay_ms: u64,
+    // the collection generate creates keeps the chunks' metadata on disk instead of in memory
+    #[arg(long)]
+    qdrant_on_disk_payload: bool,
+    // HNSW index parameters of the collection generate creates, qdrant's 100 and 16 when not
+    // set: higher ef_construct builds a more accurate index more slowly, higher m links
+    // more neighbours per vector for better recall at more memory
+    #[arg(long, value_parser = clap::value_parser!(u64).range(4..))]
+    qdrant_hnsw_ef_construct: Option<u64>,
+    #[arg(long)]
+    qdrant_hnsw_m: Option<u64>,
+    // chunks the collection may hold: generate warns before going over it, web when the
+    // collection is 80% full. Unlimited when not set
+    #[arg(long)]
+    max_collection_points: Option<u64>,
+    // how often web checks the collection size against --max-collection-points
+    #[arg(long, default_value_t = 60)]
+    collection_check_interval_mins: u64,
+    // documents ingested in parallel by the web server
+    #[arg(long, default_value_t = 1)]
+    ingest_workers: usize,
+    // finished ingestion jobs are kept for this many minutes
+    #[arg(long, default_value_t = 60)]
+    job_retention_mins: u64,
+    // where documents uploaded to the web server are stored
+    #[arg(long, default_value = "uploads")]
+    upload_dir: String,
+    // how often watch mode scans the --document directory for changed files
+    #[arg(long, default_value_t = 30)]
+    watch_interval_secs: u64,
+    // content hashes of the files ingested by watch mode, kept across restarts
+    #[arg(long, default_value = "watch_state.json")]
+    watch_state_file: String,
+    // toml with sources re-ingested periodically by the web server (`[[source]] url, interval`)
+    #[arg(long)]
+    sources: Option<String>,
+    // document whose stored chunks the show and recontextualize modes use, as stored in the
+    // `path` metadata
+    #[arg(long)]
+    path: Option<String>,
+    // show or recontextualize only the chunk of this index
+    #[arg(long)]
+    chunk: Option<u64>,
+    // recontextualize every chunk stored with `context_rejected` (of --path when given)
+    #[arg(long)]
+    all_failed: bool,
+    // instruction added to the system prompt of recontextualize, e.g. "Name the directive."
+    #[arg(long)]
+    hint: Option<String>,
+    // show the chunks as split, before enrichment
+    #[arg(long)]
+    original: bool,
+    // show the chunks as a json array
+    #[arg(long)]
+    json: bool,
+    // shell of the completions mode
+    #[arg(long, value_enum)]
+    shell: Option<clap_complete::Shell>,
+    // selftest against a local mock of ollama and the memory store, as `cargo test` runs it
+    #[arg(long)]
+    offline: bool,
+    // not needed with --test-prompt
+    #[arg(value_enum, required_unless_present = "test_prompt")]
+    mode: Option<Mode>,
+    // KEY VALUE of config-set, an empty VALUE removes the setting
+    #[arg(num_args = 2, value_names = ["KEY", "VALUE"])]
+    setting: Vec<String>,
 }
 
-async fn chat(ollama_url: String, model: String, embed: String, db_url: String) {
-    // -- llm
-    let ollama_client = Arc::new(OllamaClient::from_url(Url::parse(&ollama_url).unwrap()));
-    let ollama = Ollama::new(
-        ollama_client.clone(),
-        &model,
-        Some(GenerationOptions::default()),
-    );
+// qdrant-client talks to qdrant over gRPC only, REST on this port can't be used
+const QDRANT_REST_PORT: u16 = 6333;
+
+fn qdrant_client(db_url: &str) -> Qdrant {
+    if Url::parse(db_url).is_ok_and(|url| url.port() == Some(QDRANT_REST_PORT)) {
+        log::warn!(
+            "--db {} points to the qdrant REST port, the client needs the gRPC port (6334 by default)",
+            db_url
+        );
+    }
+    Qdrant::from_url(db_url).build().unwrap()
+}
+
+// -- memory and sqlite stores
+fn local_db(db_url: &str) -> bool {
+    db_url == store::MEMORY_DB || db_url.starts_with(store::SQLITE_DB_PREFIX)
+}
+
+// -- the missing --payload-indexes of the qdrant collection are created
+async fn payload_indexes(cli: &Cli) -> Result<(), String> {
+    let client = qdrant_client(cli.db.as_deref().unwrap());
+    for key in store::ensure_payload_indexes(&client, &cli.payload_indexes).await? {
+        output::detail(&format!("payload index on {} created", key));
+    }
+    Ok(())
+}
+
+async fn vector_store(ollama_client: Arc<OllamaClient>, cli: &Cli) -> Arc<dyn ChunkStore> {
+    match open_vector_store(ollama_client, cli).await {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Error opening the chunk store: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// -- --embed model, the same for the stores and --dry-embed
+fn embedder(ollama_client: Arc<OllamaClient>, cli: &Cli) -> impl Embedder + 'static {
+    let embed_model = cli.embed.clone().unwrap();
+    let kept_alive = cli.ollama_keep_alive.as_ref().map(|keep_alive| {
+        keep_alive::KeptAlive::new(ollama_client.clone(), &embed_model, keep_alive.clone())
+    });
+    TruncatedEmbedder::new(
+        keep_alive::KeepAliveEmbedder::new(
+            ReconnectingEmbedder::new(
+                OllamaEmbedder::new(
+                    ollama_client.clone(),
+                    embed_model,
+                    Some(GenerationOptions::default()),
+                ),
+                reconnect(cli),
+            ),
+            kept_alive,
+        ),
+        cli.embed_dimensions,
+    )
+    .normalized(cli.embed_normalize)
+}
+
+async fn open_vector_store(
+    ollama_client: Arc<OllamaClient>,
+    cli: &Cli,
+) -> Result<Arc<dyn ChunkStore>, String> {
+    let db_url = cli.db.clone().unwrap();
+    let db_url = db_url.as_str();
+    let ollama_embed = embedder(ollama_client, cli);
+    if local_db(db_url) && cli.similarity_metric != SimilarityMetric::Cosine {
+        eprintln!(
+            "--similarity-metric {} needs qdrant, the memory and sqlite stores compare by cosine.",
+            cli.similarity_metric.name()
+        );
+        std::process::exit(1);
+    }
+    if db_url == store::MEMORY_DB {
+        return Ok(MemoryStore::shared(Arc::new(ollama_embed)));
+    }
+    if let Some(path) = db_url.strip_prefix(store::SQLITE_DB_PREFIX) {
+        return Ok(SqliteStore::shared(path, Arc::new(ollama_embed))?);
+    }
+    qdrant_store(
+        db_url,
+        ollama_embed,
+        cli.similarity_metric,
+        &collection_params(cli),
+    )
+    .await
+}
+
+fn collection_params(cli: &Cli) -> store::CollectionParams {
+    store::CollectionParams {
+        on_disk_payload: cli.qdrant_on_disk_payload,
+        hnsw_ef_construct: cli.qdrant_hnsw_ef_construct,
+        hnsw_m: cli.qdrant_hnsw_m,
+    }
+}
+
+// -- --system-prompt, --top-k and --score-threshold as given
+fn settings_flags(cli: &Cli) -> collection_settings::CollectionSettings {
+    collection_settings::CollectionSettings {
+        system_prompt: cli.system_prompt.clone(),
+        top_k: cli.top_k,
+        score_threshold: cli.score_threshold,
+    }
+}
+
+// -- the cli with the stored settings of --collection where their flags aren't given
+async fn with_collection_settings(cli: &Cli, store: &dyn ChunkStore) -> Cli {
+    let Some(collection) = cli.collection.as_deref() else {
+        return cli.clone();
+    };
+    let stored = collection_settings::load_or_warn(store, collection, output::warning).await;
+    let settings = settings_flags(cli).or(stored);
+    Cli {
+        system_prompt: settings.system_prompt,
+        top_k: settings.top_k,
+        score_threshold: settings.score_threshold,
+        ..cli.clone()
+    }
+}
+
+// -- the future answering from a request's collection with its settings instead of the chain's
+async fn with_request_settings<F: Future>(
+    collection: Option<(String, collection_settings::CollectionSettings)>,
+    future: F,
+) -> F::Output {
+    let Some((collection, settings)) = collection else {
+        return future.await;
+    };
+    let search = retrieval::Search {
+        limit: settings.top_k.unwrap_or(RETRIEVED_DOCUMENTS),
+        score_threshold: settings.score_threshold.unwrap_or(SCORE_THRESHOLD),
+        collection: Some(collection),
+    };
+    let system_prompt = settings
+        .system_prompt
+        .unwrap_or_else(|| config::SYSTEM_PROMPT_STR.to_string());
+    retrieval::searching(search, collection_settings::prompted(system_prompt, future)).await
+}
+
+// -- generate's --system-prompt, --top-k and --score-threshold stored for --collection
+async fn store_collection_settings(cli: &Cli, store: &dyn ChunkStore) {
+    let Some(collection) = cli.collection.as_deref() else {
+        return;
+    };
+    let flags = settings_flags(cli);
+    if flags == collection_settings::CollectionSettings::default() {
+        return;
+    }
+    let stored =
+        collection_settings::load_or_warn(store, collection, |warning| log::debug!("{}", warning))
+            .await;
+    match collection_settings::save(store, collection, &flags.or(stored)).await {
+        Ok(()) => output::note(&format!("settings of collection {} stored", collection)),
+        Err(e) => output::warning(&format!(
+            "storing the settings of collection {} failed: {}",
+            collection, e
+        )),
+    }
+}
+
+// -- KEY VALUE of config-set stored for the collection, the settings printed as json
+async fn config_set(cli: &Cli, collection: &str, key: &str, value: &str) -> bool {
+    let mut settings = collection_settings::CollectionSettings::default();
+    if let Err(e) = settings.set(key, value) {
+        output::error(&e);
+        return false;
+    }
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let store = vector_store(ollama_client, cli).await;
+    let mut settings = match collection_settings::load(store.as_ref(), collection).await {
+        Ok(settings) => settings.unwrap_or_default(),
+        Err(e) => {
+            output::warning(&format!("{}, replacing them", e));
+            collection_settings::CollectionSettings::default()
+        }
+    };
+    settings.set(key, value).unwrap();
+    if let Err(e) = collection_settings::save(store.as_ref(), collection, &settings).await {
+        output::error(&e);
+        return false;
+    }
+    println!("{}", serde_json::to_string_pretty(&settings).unwrap());
+    true
+}
+
+// -- a collection created with another metric stops the program, its scores would be off
+async fn qdrant_store<E: Embedder + 'static>(
+    db_url: &str,
+    embedder: E,
+    metric: SimilarityMetric,
+    params: &store::CollectionParams,
+) -> Result<Arc<dyn ChunkStore>, String> {
+    let db_client = qdrant_client(db_url);
+    match store::collection_metric(&db_client).await? {
+        Some(created) if created != metric => {
+            eprintln!(
+                "The {} collection compares chunks by {}, not by --similarity-metric {}. Use {} or ingest into a new collection.",
+                store::COLLECTION,
+                created.name(),
+                metric.name(),
+                created.name()
+            );
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+    Ok(Arc::new(
+        QdrantStore::open(db_client, embedder, metric, params).await?,
+    ))
+}
+
+fn reconnect(cli: &Cli) -> Reconnect {
+    Reconnect {
+        delay: Duration::from_secs(cli.ollama_reconnect_secs),
+        attempts: cli.ollama_reconnect_attempts,
+    }
+}
+
+// -- options of the generative model, unset ones are left to ollama's model defaults
+fn generation_options(cli: &Cli) -> GenerationOptions {
+    let mut options = GenerationOptions::default();
+    if let Some(num_ctx) = cli.num_ctx {
+        options = options.num_ctx(num_ctx);
+    }
+    if let Some(num_predict) = length::num_predict(cli.num_predict, cli.num_predict_cap) {
+        options = options.num_predict(num_predict);
+    }
+    options
+}
+
+// -- options of the answering model, with --stop-words. `stop: []` of --no-stop replaces
+// -- the stop words of the model's Modelfile
+fn chat_options(cli: &Cli) -> GenerationOptions {
+    let options = generation_options(cli);
+    match (cli.no_stop, cli.stop_words.is_empty()) {
+        (true, _) => options.stop(vec![]),
+        (false, true) => options,
+        (false, false) => options.stop(
+            cli.stop_words
+                .iter()
+                .map(|word| unescape(word).unwrap_or_else(|| word.clone()))
+                .collect(),
+        ),
+    }
+}
 
-    let msg_template = template_jinja2!(CHAT_PROMPT_STR, "context", "question");
+// -- --system-prompt or the built-in one, without the --system-prompt-append lines
+fn base_system_prompt(cli: &Cli) -> &str {
+    cli.system_prompt
+        .as_deref()
+        .unwrap_or(config::SYSTEM_PROMPT_STR)
+}
+
+// -- chat system prompt with every --system-prompt-append on its own line
+fn chat_system_prompt(cli: &Cli) -> String {
+    std::iter::once(base_system_prompt(cli))
+        .chain(cli.system_prompt_append.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    let ollama_embed = OllamaEmbedder::new(
+// -- the answering model with its fallback, retries and checks, also rephrasing the follow-ups
+fn chat_llm(ollama_client: Arc<OllamaClient>, cli: &Cli) -> ReconnectingLlm {
+    let ollama = ollama::OllamaWithOptions::new(
         ollama_client.clone(),
-        &embed,
-        Some(GenerationOptions::default()),
+        cli.model.as_deref().unwrap(),
+        chat_options(cli),
     );
-    let db_client = Qdrant::from_url(&db_url).build().unwrap();
-    let vector_store = StoreBuilder::new()
-        .recreate_collection(false)
-        .embedder(ollama_embed)
-        .client(db_client)
-        .collection_name("documents")
-        .build()
-        .await
-        .unwrap();
+
+    let llm: Box<dyn LLM> = match &cli.fallback_model {
+        Some(fallback_model) => Box::new(fallback::FallbackLlm::new(
+            ollama,
+            ollama::OllamaWithOptions::new(
+                ollama_client.clone(),
+                fallback_model,
+                chat_options(cli),
+            ),
+            fallback_model,
+            Duration::from_secs(cli.fallback_timeout_secs),
+        )),
+        None => Box::new(ollama),
+    };
+    let llm: Box<dyn LLM> = match &cli.ollama_keep_alive {
+        Some(keep_alive) => Box::new(keep_alive::KeepAliveLlm::new(
+            llm,
+            keep_alive::KeptAlive::new(
+                ollama_client.clone(),
+                cli.model.as_deref().unwrap(),
+                keep_alive.clone(),
+            ),
+        )),
+        None => llm,
+    };
+    let llm: Box<dyn LLM> = match cli.temperature_schedule.is_empty() {
+        true => llm,
+        false => {
+            let model = cli.model.as_deref().unwrap();
+            let retries = cli
+                .temperature_schedule
+                .iter()
+                .map(|&temperature| {
+                    let options = chat_options(cli).temperature(temperature);
+                    let retry =
+                        ollama::OllamaWithOptions::new(ollama_client.clone(), model, options);
+                    (temperature, Box::new(retry) as Box<dyn LLM>)
+                })
+                .collect();
+            Box::new(temperature::ScheduledLlm::new(
+                llm,
+                retries,
+                cli.min_answer_chars,
+            ))
+        }
+    };
+    let llm: Box<dyn LLM> = match cli.min_response_tokens {
+        0 => llm,
+        min_tokens => Box::new(length::MinTokensLlm::new(llm, min_tokens)),
+    };
+    let llm: Box<dyn LLM> = Box::new(language::AnswerLanguageLlm::new(
+        llm,
+        cli.answer_lang.clone(),
+    ));
+    let llm: Box<dyn LLM> = Box::new(collection_settings::SystemPromptLlm::new(
+        llm,
+        base_system_prompt(cli),
+    ));
+    let llm: Box<dyn LLM> = match cli.output_schema.as_deref().map(schema::OutputSchema::load) {
+        Some(Ok(schema)) => Box::new(schema::SchemaLlm::new(
+            llm,
+            Arc::new(schema),
+            cli.output_schema_retries,
+        )),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        None => llm,
+    };
+    ReconnectingLlm::new(Box::new(retrieval::RefusingLlm::new(llm)), reconnect(cli))
+}
+
+fn chat_chain(
+    ollama_client: Arc<OllamaClient>,
+    cli: &Cli,
+    vector_store: Arc<dyn ChunkStore>,
+) -> ConversationalRetrieverChain {
+    let reranker = cli.rerank.then(|| {
+        let rerank_model = cli.rerank_model.clone().or(cli.model.clone()).unwrap();
+        let rerank_llm = Ollama::new(ollama_client.clone(), rerank_model, None);
+        Arc::new(rerank::Reranker::new(
+            Box::new(ReconnectingLlm::new(Box::new(rerank_llm), reconnect(cli))),
+            cli.rerank_candidates,
+        ))
+    });
+    let expander = (cli.query_expansion > 0).then(|| {
+        let expansion_llm = Ollama::new(ollama_client.clone(), cli.model.clone().unwrap(), None);
+        Arc::new(expansion::QueryExpander::new(
+            Box::new(ReconnectingLlm::new(
+                Box::new(expansion_llm),
+                reconnect(cli),
+            )),
+            cli.query_expansion,
+        ))
+    });
+    let step_back = cli.step_back.then(|| {
+        let step_back_llm = Ollama::new(ollama_client.clone(), cli.model.clone().unwrap(), None);
+        Arc::new(expansion::StepBack::new(
+            Box::new(ReconnectingLlm::new(
+                Box::new(step_back_llm),
+                reconnect(cli),
+            )),
+            cli.step_back_weight,
+        ))
+    });
+    let compressor = cli.compress_context.then(|| {
+        let selection = match &cli.compression_model {
+            Some(model) => compression::Selection::Llm(Box::new(ReconnectingLlm::new(
+                Box::new(Ollama::new(ollama_client.clone(), model.clone(), None)),
+                reconnect(cli),
+            ))),
+            None => {
+                compression::Selection::Embedding(Arc::new(embedder(ollama_client.clone(), cli)))
+            }
+        };
+        Arc::new(compression::ContextCompressor::new(
+            selection,
+            cli.compression_min_tokens,
+        )) as Arc<dyn retrieval::Compressor>
+    });
+    conversational_chain(
+        chat_llm(ollama_client, cli),
+        cli,
+        vector_store,
+        reranker,
+        expander,
+        step_back,
+        compressor,
+    )
+}
+
+fn answer_summarizer(
+    ollama_client: Arc<OllamaClient>,
+    cli: &Cli,
+) -> Option<Arc<summarize::AnswerSummarizer>> {
+    cli.summarize_answers.then(|| {
+        let llm = ollama::OllamaWithOptions::new(
+            ollama_client,
+            cli.model.as_deref().unwrap(),
+            GenerationOptions::default().num_predict(summarize::SUMMARY_TOKENS),
+        );
+        Arc::new(summarize::AnswerSummarizer::new(Box::new(llm)))
+    })
+}
+
+fn explainer(ollama_client: Arc<OllamaClient>, cli: &Cli) -> Option<Arc<explain::Explainer>> {
+    cli.explain.then(|| {
+        let llm = Ollama::new(ollama_client, cli.model.clone().unwrap(), None);
+        Arc::new(explain::Explainer::new(Box::new(ReconnectingLlm::new(
+            Box::new(llm),
+            reconnect(cli),
+        ))))
+    })
+}
+
+fn payload_filter(json: &str) -> Result<MetadataFilter, String> {
+    let filter = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    MetadataFilter::from_json(&filter)
+}
+
+// -- --filter key=value conditions, `keyword` is one item of the `keywords` list
+fn field_filter(fields: &[(String, String)]) -> MetadataFilter {
+    let mut filter = MetadataFilter::default();
+    for (key, value) in fields {
+        let key = match key.as_str() {
+            "keyword" => "keywords",
+            key => key,
+        };
+        filter.must.push((key.to_string(), json!(value)));
+    }
+    filter
+}
+
+// -- the chain of chat, web and slack around any llm
+fn conversational_chain<L: LLM + 'static>(
+    llm: L,
+    cli: &Cli,
+    vector_store: Arc<dyn ChunkStore>,
+    reranker: Option<Arc<rerank::Reranker>>,
+    expander: Option<Arc<expansion::QueryExpander>>,
+    step_back: Option<Arc<expansion::StepBack>>,
+    compressor: Option<Arc<dyn retrieval::Compressor>>,
+) -> ConversationalRetrieverChain {
+    let msg_template = template_jinja2!(config::CHAT_PROMPT_STR, "context", "question");
 
     let prompt = message_formatter![
-        fmt_message!(Message::new_system_message("Jsi AI pomocnik ve firme S&W pro odpovedi na dotazy z dodanych documentu internich smernic a pravidel. Odpovidej co nepresneji dle dodaneho textu.")),
+        fmt_message!(Message::new_system_message(chat_system_prompt(cli))),
         fmt_template!(HumanMessagePromptTemplate::new(msg_template))
     ];
-    let retviever = langchain_rust::vectorstore::Retriever::new(vector_store, 5)
-        .with_options(VecStoreOptions::new().with_score_threshold(0.55));
-    let chain = ConversationalRetrieverChainBuilder::new()
-        .llm(ollama)
-        .rephrase_question(true)
-        .memory(SimpleMemory::new().into())
+    let guard = injection::ChunkGuard::new(&cli.injection_denylist).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+    let filter = match cli.filter_by_payload.as_deref().map(payload_filter) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => {
+            eprintln!("Invalid --filter-by-payload: {}", e);
+            std::process::exit(1);
+        }
+        None => MetadataFilter::default(),
+    };
+    let retviever = retrieval::StoreRetriever::new(
+        vector_store,
+        cli.top_k.unwrap_or(RETRIEVED_DOCUMENTS),
+        cli.score_threshold.unwrap_or(SCORE_THRESHOLD),
+    )
+    .anonymize_sources(cli.anonymize_sources)
+    .relaxed((!cli.no_adaptive_retrieval).then_some(retrieval::Relaxed {
+        threshold_delta: cli.relaxed_threshold_delta,
+        limit_factor: cli.relaxed_limit_factor,
+    }))
+    .guard(guard)
+    .filter(filter.and(&field_filter(&cli.filter)))
+    .collection(cli.collection.clone())
+    .reranker(reranker)
+    .expander(expander)
+    .step_back(step_back)
+    .compressor(compressor)
+    .dedup_threshold(Some(cli.dedup_threshold))
+    .freshness(Some(retrieval::Freshness {
+        weight: cli.freshness_weight,
+        half_life_days: cli.freshness_half_life_days,
+    }))
+    .context_header(
+        cli.context_header
+            .as_deref()
+            .map(|header| unescape(header).unwrap_or_else(|| header.to_string())),
+    );
+    ConversationalRetrieverChainBuilder::new()
+        .llm(llm)
+        .rephrase_question(cli.rephrase == Switch::On)
+        .memory(conversation::BoundedMemory::new(memory_limits(cli)).into())
         .retriever(retviever)
         .return_source_documents(true)
         .prompt(prompt)
         .build()
-        .expect("Error building ConversationalChain");
+        .expect("Error building ConversationalChain")
+}
+
+fn memory_limits(cli: &Cli) -> conversation::MemoryLimits {
+    conversation::MemoryLimits {
+        max_turns: cli.memory_max_turns,
+        max_tokens: cli.memory_max_tokens,
+        max_age: cli
+            .memory_max_age_mins
+            .map(|mins| Duration::from_secs(mins * 60)),
+    }
+}
+
+// -- interactive chat state, changed by `/` commands
+struct ChatSession {
+    cli: Cli,
+    ollama_client: Arc<OllamaClient>,
+    vector_store: Arc<dyn ChunkStore>,
+    chain: ConversationalRetrieverChain,
+    show_sources: bool,
+    // source documents of every answer since the last /reset, for /export
+    sources: Vec<Vec<String>>,
+}
+
+const CHAT_COMMANDS_HELP: &str = "/reset           forget the conversation history
+/sources on|off  show or hide the source documents of answers
+/model <name>    switch the chatting model
+/export <path>   write the conversation to a Markdown file
+/help            list commands";
+
+// -- returns true when the input was a command and not a question
+async fn handle_command(cmd: &str, session: &mut ChatSession) -> bool {
+    let Some(cmd) = cmd.strip_prefix('/') else {
+        return false;
+    };
+    let (name, argument) = cmd
+        .split_once(char::is_whitespace)
+        .map(|(name, argument)| (name, argument.trim()))
+        .unwrap_or((cmd, ""));
+
+    match (name, argument) {
+        ("reset", "") => {
+            session.chain.memory.lock().await.clear();
+            session.sources.clear();
+            output::note("Conversation history cleared.");
+        }
+        ("sources", "on") => {
+            session.show_sources = true;
+            output::note("Source documents are shown.");
+        }
+        ("sources", "off") => {
+            session.show_sources = false;
+            output::note("Source documents are hidden.");
+        }
+        ("model", model) if !model.is_empty() => {
+            // -- the new chain keeps the conversation history
+            session.cli.model = Some(model.to_string());
+            let memory = session.chain.memory.clone();
+            session.chain = chat_chain(
+                session.ollama_client.clone(),
+                &session.cli,
+                session.vector_store.clone(),
+            );
+            session.chain.memory = memory;
+            output::note(&format!("Switched to model {}.", model));
+        }
+        ("export", path) if !path.is_empty() => export(session, path).await,
+        ("help", "") => output::note(CHAT_COMMANDS_HELP),
+        _ => output::warning(&format!(
+            "Unknown command /{}. Type /help for the list of commands.",
+            cmd
+        )),
+    }
+    true
+}
+
+async fn export(session: &ChatSession, path: &str) {
+    let messages = session.chain.memory.lock().await.messages();
+    match fs::write(path, export::markdown(&messages, &session.sources)) {
+        Ok(()) => output::note(&format!("Conversation exported to {}.", path)),
+        Err(e) => output::error(&format!("Exporting to {} failed: {}", path, e)),
+    }
+}
+
+async fn chat(cli: &Cli) {
+    // -- llm
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let vector_store = vector_store(ollama_client.clone(), cli).await;
+    let cli = &with_collection_settings(cli, vector_store.as_ref()).await;
+    let mut transcript = match cli.transcript.as_deref().map(transcript::Transcript::open) {
+        Some(Ok(transcript)) => Some(transcript),
+        Some(Err(e)) => {
+            output::error(&e);
+            return;
+        }
+        None => None,
+    };
+    let chain = chat_chain(ollama_client.clone(), cli, vector_store.clone());
+    let summarizer = answer_summarizer(ollama_client.clone(), cli);
+    let mut session = ChatSession {
+        cli: cli.clone(),
+        ollama_client,
+        vector_store,
+        chain,
+        show_sources: true,
+        sources: vec![],
+    };
+    output::note("Type /help for commands.");
 
     loop {
         // Ask for user input
@@ -186,39 +1444,87 @@ async fn chat(ollama_url: String, model: String, embed: String, db_url: String)
 
         let query = query.trim(); // Trim input to avoid issues with empty queries
         if query.is_empty() {
-            println!("Empty query. Exiting...");
+            output::note("Empty query. Exiting...");
+            if let Some(path) = &cli.export_on_exit {
+                export(&session, path).await;
+            }
             break;
         }
+        if handle_command(query, &mut session).await {
+            continue;
+        }
 
         let input_variables = prompt_args! {
             "question" => &query,
         };
 
-        let result = chain.execute(input_variables).await;
-        match result {
+        let started = Instant::now();
+        let (result, retrieval) =
+            retrieval::recording(session.chain.execute(input_variables)).await;
+        let elapsed = started.elapsed();
+        match &result {
             Ok(data) => {
-                let output = data["output"].as_str().unwrap();
-                let out_formatted = unescape(output).unwrap();
+                let out_formatted = answer::answer_text(&data["output"]);
 
-                let mut used_docs: Vec<String> = data["source_documents"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|d| {
-                        // -- path with score
-                        // format!("{} (s:{})", d["metadata"]["path"], d["score"])
-                        // -- only path
-                        format!("{}", d["metadata"]["path"])
-                    })
-                    .collect();
-                used_docs.sort();
-                used_docs.dedup();
-
-                println!("{}", out_formatted);
-                println!("-------\ndocuments:[{}]", used_docs.join(", "));
+                let used_docs = source_labels(
+                    &data["source_documents"],
+                    &session.cli.source_fields,
+                    session.cli.source_documents_format,
+                );
+                session
+                    .sources
+                    .push(export::turn_sources(&data["source_documents"]));
+                if let Some(rephrased) = data.get("generated_question") {
+                    log::debug!("rephrased question: {}", rephrased);
+                }
+
+                let explained = match explainer(session.ollama_client.clone(), &session.cli)
+                    .zip(retrieval.as_ref().filter(|r| !r.documents.is_empty()))
+                {
+                    Some((explainer, retrieval)) => explainer
+                        .attribute(&out_formatted, &retrieval.documents)
+                        .await
+                        .map(|attributions| explain::cite(&out_formatted, &attributions))
+                        .inspect_err(|e| output::warning(e))
+                        .ok(),
+                    None => None,
+                };
+                output::answer(explained.as_deref().unwrap_or(&out_formatted));
+                if session.show_sources {
+                    output::sources(&format!("-------\ndocuments:[{}]", used_docs.join(", ")));
+                }
+                if retrieval
+                    .as_ref()
+                    .is_some_and(|retrieval| retrieval.relaxed)
+                {
+                    output::warning("retrieval: relaxed (nothing matched the score threshold)");
+                }
+                if session.cli.verbose_retrieval {
+                    output::note(&timing_summary(retrieval.as_ref(), elapsed));
+                }
             }
             Err(e) => {
-                println!("Error: {:?}", e);
+                output::error(&format!("Error: {:?}", e));
+            }
+        }
+        // -- summarized once the answer is shown
+        let summary = match (&summarizer, &result) {
+            (Some(summarizer), Ok(data)) => {
+                let answer = answer::answer_text(&data["output"]);
+                let summary = summarizer.summarize(&answer).await;
+                if session.show_sources {
+                    output::sources(&format!("summary: {}", summary));
+                }
+                Some(summary)
+            }
+            _ => None,
+        };
+        if let Some(transcript) = transcript.as_mut() {
+            let model = session.cli.model.clone().unwrap_or_default();
+            let recorded = result.as_ref().map_err(|e| e.to_string());
+            if let Err(e) = transcript.record(&model, query, recorded, elapsed, summary.as_deref())
+            {
+                log::error!("{}", e);
             }
         }
 
@@ -235,331 +1541,4400 @@ async fn chat(ollama_url: String, model: String, embed: String, db_url: String)
     }
 }
 
-fn get_pdf_files(directory: &str) -> Vec<String> {
-    let mut pdf_files = Vec::new();
-    if let Ok(entries) = fs::read_dir(directory) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    if extension == "pdf" {
-                        if let Some(path_str) = path.to_str() {
-                            pdf_files.push(path_str.to_string());
-                        }
-                    }
-                }
-            }
+// -- the standalone question the chain queries the store with. The chain only rephrases
+// -- follow-ups, a question without history is used as it is
+async fn rephrase(cli: &Cli, question: &str) -> bool {
+    let history = match cli.history.as_deref().map(transcript::history) {
+        Some(Ok(history)) => history,
+        Some(Err(e)) => {
+            output::error(&e);
+            return false;
+        }
+        None => vec![],
+    };
+    if cli.rephrase == Switch::Off {
+        output::note("--rephrase off, the store is queried with the question as it is.");
+        println!("{}", question);
+        return true;
+    }
+    if history.is_empty() {
+        output::note("No --history, the first question of a conversation isn't rephrased.");
+        println!("{}", question);
+        return true;
+    }
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let chain = CondenseQuestionGeneratorChain::new(chat_llm(ollama_client, cli));
+    let input = CondenseQuestionPromptBuilder::new()
+        .question(question)
+        .chat_history(&history)
+        .build();
+    match chain.call(input).await {
+        Ok(result) => {
+            println!("{}", result.generation.trim());
+            true
+        }
+        Err(e) => {
+            output::error(&format!("Rephrasing failed: {}", e));
+            false
         }
     }
-    pdf_files
 }
 
-async fn generate(
-    document: String,
-    ollama_url: String,
-    model: String,
-    embed: String,
-    db_url: String,
-) {
-    // -------------------------------------
-    // -- VARIABLES
-    // let documents = get_pdf_files("./assets");
-    // println!("{:?} - documents", documents);
-
-    let documents = vec![document];
+// -- `--verbose-retrieval` footer, everything but the retrieval is counted as generation
+// -- (the follow-up rephrasing included)
+fn timing_summary(retrieval: Option<&retrieval::Retrieval>, total: Duration) -> String {
+    let Some(retrieval) = retrieval else {
+        return format!(
+            "-------\nretrieval: none\ngeneration: {} ms",
+            total.as_millis()
+        );
+    };
+    let scores: Vec<String> = retrieval
+        .documents
+        .iter()
+        .map(|d| format!("{:.3}", d.score))
+        .collect();
+    let context: String = retrieval
+        .documents
+        .iter()
+        .map(|d| d.page_content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "-------\nretrieval: {} ms, {} chunks, scores [{}]\ncontext: {} tokens\ngeneration: {} ms",
+        retrieval.elapsed.as_millis(),
+        retrieval.documents.len(),
+        scores.join(", "),
+        cl100k_base().unwrap().encode_ordinary(&context).len(),
+        total.saturating_sub(retrieval.elapsed).as_millis()
+    )
+}
 
-    let ollama_client = Arc::new(OllamaClient::from_url(Url::parse(&ollama_url).unwrap()));
-    let ollama = Ollama::new(
-        ollama_client.clone(),
-        &model,
-        Some(GenerationOptions::default()),
-    );
+const COLLECTION_SIDECAR: &str = "_collection.toml";
 
-    let chunk_msg_template =
-        template_jinja2!(CONTEXT_CHUNK_STR, "previous_chunks", "input", "next_chunks");
-    let prompt = message_formatter![fmt_template!(HumanMessagePromptTemplate::new(
-        chunk_msg_template
-    ))];
-    let chain = ConversationalChainBuilder::new()
-        .llm(ollama.clone())
-        .prompt(prompt)
-        .build()
-        .expect("Error building ConversationalChain");
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Array(a.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(t) => {
+            Value::Object(t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect())
+        }
+    }
+}
 
-    for doc_path in documents {
-        // -------------------------------------
-        // -- documents loader text extractor
-        let loader = PdfExtractLoader::from_path(doc_path.clone()).unwrap();
-        let doc = loader
-            .load()
-            .await
-            .unwrap()
-            .map(|d| d.unwrap())
-            .collect::<Vec<_>>()
-            .await;
-        log::info!("{:?}", doc);
+fn parse_top_k(value: &str) -> Result<usize, String> {
+    let mut settings = collection_settings::CollectionSettings::default();
+    settings.set("top_k", value)?;
+    settings
+        .top_k
+        .ok_or_else(|| "expected a positive number".to_string())
+}
 
-        // -------------------------------------
-        // -- spliting into a meaningful chunks
-        let mut chunks_vec: Vec<Document> = vec![];
-        let mut doc_text: String = "".to_string();
+fn parse_share(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(share),
+        _ => Err(format!("expected a number from 0 to 1, got {}", value)),
+    }
+}
 
-        let tokenizer = cl100k_base().unwrap();
-        let max_tokens = 512;
-        let chunk_config = ChunkConfig::new(max_tokens).with_sizer(tokenizer);
-        let splitter = TextSplitter::new(chunk_config);
-        for doc_entry in doc.iter() {
-            doc_text += &doc_entry.page_content;
-            let chunks = splitter
-                .chunks(&doc_entry.page_content)
-                .map(|d| Document::new(d))
-                .collect::<Vec<_>>();
-            chunks_vec.extend(chunks);
+fn parse_meta(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
         }
+        _ => Err(format!("expected key=value, got {}", value)),
+    }
+}
 
-        let mut context_chunks: Vec<Document> = vec![];
+// -- {model}, {embed_model} and {chunk_strategy} of --chunk-metadata-template
+fn run_metadata(cli: &Cli) -> metadata::RunMetadata {
+    metadata::RunMetadata {
+        model: cli.model.clone().unwrap_or_default(),
+        embed_model: cli.embed.clone().unwrap_or_default(),
+        chunk_strategy: cli.split_strategy.name(),
+    }
+}
 
-        for (index, chunk) in chunks_vec.iter().enumerate() {
-            // Získání kontextu: 2 předchozí, aktuální, 2 následující
-            let previous_chunks = chunks_vec
-                .get(index.saturating_sub(2)..index)
-                .unwrap_or(&[]);
-            let next_chunks = chunks_vec
-                .get(index + 1..=(index + 2).min(chunks_vec.len() - 1))
-                .unwrap_or(&[]);
-
-            // Spojení textu do stringu
-            let previous_text = previous_chunks
-                .iter()
-                .map(|c| c.page_content.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
-            let next_text = next_chunks
-                .iter()
-                .map(|c| c.page_content.to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
+// -- keys of the `<document>.meta.toml` or `<document>.meta.json` sidecar of the document
+fn sidecar_metadata(doc_path: &str) -> HashMap<String, Value> {
+    let toml_path = format!("{}.meta.toml", doc_path);
+    let json_path = format!("{}.meta.json", doc_path);
+    let parsed = if let Ok(content) = fs::read_to_string(&toml_path) {
+        content
+            .parse::<toml::Table>()
+            .map(|table| toml_to_json(toml::Value::Table(table)))
+            .map_err(|e| format!("ignoring invalid {}: {}", toml_path, e))
+    } else if let Ok(content) = fs::read_to_string(&json_path) {
+        serde_json::from_str::<Value>(&content)
+            .map_err(|e| format!("ignoring invalid {}: {}", json_path, e))
+    } else {
+        return HashMap::new();
+    };
+    match parsed {
+        Ok(Value::Object(metadata)) => metadata.into_iter().collect(),
+        Ok(_) => {
+            log::warn!("ignoring {}: not a table of keys", json_path);
+            HashMap::new()
+        }
+        Err(e) => {
+            log::warn!("{}", e);
+            HashMap::new()
+        }
+    }
+}
 
-            // Vytvoření vstupních proměnných pro LLM
-            let input_vars = prompt_args! {
-                "previous_chunks" => previous_text,
-                "input" => chunk.page_content,
-                "next_chunks" => next_text,
-            };
+// -- `[collection]` table of the `_collection.toml` next to the document
+fn collection_metadata(doc_path: &str) -> Option<Value> {
+    let sidecar = Path::new(doc_path).parent()?.join(COLLECTION_SIDECAR);
+    let content = fs::read_to_string(&sidecar).ok()?;
+    let mut table = match content.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(e) => {
+            log::warn!("ignoring invalid {}: {}", sidecar.display(), e);
+            return None;
+        }
+    };
+    match table.remove("collection") {
+        Some(collection @ toml::Value::Table(_)) => Some(toml_to_json(collection)),
+        _ => {
+            log::warn!("ignoring {}: missing [collection] table", sidecar.display());
+            None
+        }
+    }
+}
 
-            println!("----------------------------");
-            println!("CHUNK:");
-            println!("{:?}", chunk.page_content);
-            println!("---\n");
+fn collection_label(collection: &Value) -> Option<String> {
+    let label = ["name", "owner", "classification"]
+        .iter()
+        .filter_map(|key| collection[key].as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    (!label.is_empty()).then_some(label)
+}
 
-            match chain.invoke(input_vars).await {
-                Ok(result) => {
-                    println!("RESULT:");
-                    println!("{:?}", result);
-                    let mut metadata = HashMap::new();
-                    metadata.insert("path".to_string(), Value::String(doc_path.clone()));
+const UNKNOWN_SOURCE: &str = "<unknown source>";
 
-                    let d = Document::new(result).with_metadata(metadata);
-                    context_chunks.push(d);
+// -- `department: HR, directive: 12` of the --source-fields the metadata has
+fn fields_label(metadata: &Value, fields: &[String]) -> Option<String> {
+    let label = fields
+        .iter()
+        .filter_map(|field| match &metadata[field] {
+            Value::Null => None,
+            Value::String(value) => Some(format!("{}: {}", field, value)),
+            value => Some(format!("{}: {}", field, value)),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    (!label.is_empty()).then_some(label)
+}
+
+// -- strings of a list in the metadata (speakers, keywords), `A, B`
+fn list_label(list: &Value) -> Option<String> {
+    let items: Vec<&str> = list.as_array()?.iter().filter_map(Value::as_str).collect();
+    (!items.is_empty()).then(|| items.join(", "))
+}
+
+// -- path of every source document (with its collection and --source-fields when it has
+// -- them), sorted and deduplicated, chunks stored without a path by other tools are
+// -- `<unknown source>`. `path-page` adds the page and keywords of the chunk, `full` is
+// -- the metadata json of every chunk
+fn source_labels(source_documents: &Value, fields: &[String], format: SourceFormat) -> Vec<String> {
+    let Some(docs) = source_documents.as_array() else {
+        if !source_documents.is_null() {
+            log::debug!("source documents are not a list: {}", source_documents);
+        }
+        return vec![];
+    };
+    let mut labels: Vec<String> = docs
+        .iter()
+        .map(|d| {
+            if format == SourceFormat::Full {
+                return d["metadata"].to_string();
+            }
+            let path = match &d["metadata"]["path"] {
+                Value::String(_) => d["metadata"]["path"].to_string(),
+                _ => {
+                    log::debug!("source document without path: {}", d);
+                    UNKNOWN_SOURCE.to_string()
                 }
-                Err(e) => panic!("Error invoking LLMChain: {:?}", e),
+            };
+            let path = match &d["metadata"]["page"] {
+                Value::Null => path,
+                _ if format == SourceFormat::Path => path,
+                Value::String(page) => format!("{} (page {})", path, page),
+                page => format!("{} (page {})", path, page),
+            };
+            let label = match collection_label(&d["metadata"]["collection"]) {
+                Some(label) => format!("{} [{}]", path, label),
+                None => path,
+            };
+            let label = match fields_label(&d["metadata"], fields) {
+                Some(fields) => format!("{} {{{}}}", label, fields),
+                None => label,
+            };
+            let label = match list_label(&d["metadata"]["speakers"]) {
+                Some(speakers) => format!("{} (speakers: {})", label, speakers),
+                None => label,
+            };
+            // -- chunks of a document have different keywords, `path` lists the document once
+            let label = match list_label(&d["metadata"]["keywords"]) {
+                Some(keywords) if format == SourceFormat::PathPage => {
+                    format!("{} (keywords: {})", label, keywords)
+                }
+                _ => label,
+            };
+            match d["metadata"]["injection_suspect"].as_str() {
+                Some(pattern) => format!("{} (injection suspect: {})", label, pattern),
+                None => label,
             }
+        })
+        .collect();
+    labels.sort();
+    labels.dedup();
+    labels
+}
 
-            // Pauza mezi iteracemi, aby se šetřila GPU
-            // time::sleep(Duration::from_secs(20)).await;
+// -- `{"text", "vector", "metadata"}` of every chunk, vectors cut to `dims` dimensions
+fn dry_embedded(chunks: &[Document], vectors: Vec<Vec<f64>>, dims: Option<usize>) -> Vec<Value> {
+    chunks
+        .iter()
+        .zip(vectors)
+        .map(|(chunk, mut vector)| {
+            vector.truncate(dims.unwrap_or(vector.len()));
+            json!({ "text": chunk.page_content, "vector": vector, "metadata": chunk.metadata })
+        })
+        .collect()
+}
+
+// -- files of the directory with the extension
+fn get_files(directory: &str, wanted: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_file() {
+                if let Some(extension) = path.extension() {
+                    if extension == wanted {
+                        if let Some(path_str) = path.to_str() {
+                            files.push(path_str.to_string());
+                        }
+                    }
+                }
+            }
         }
+    }
+    files
+}
 
-        // // -------------------------------------
-        // // -- rephrase document to questions with contextual wrapping
-        // let mut context_chunks: Vec<Document> = vec![];
-        // for chunk in chunks_vec.iter() {
-        //     let input_vars = prompt_args! {
-        //         "document" => doc_text,
-        //         "input" => &chunk.page_content,
-        //
-        //     };
-        //
-        //     println!("----------------------------");
-        //     println!("CHUNK:");
-        //     println!("{:?}", chunk.page_content);
-        //     println!("---\n");
-        //
-        //     match chain.invoke(input_vars).await {
-        //         Ok(result) => {
-        //             println!("RESULT:");
-        //             println!("{:?}", result);
-        //             let mut h = HashMap::new();
-        //             h.insert("path".to_string(), Value::String(doc_path.clone()));
-        //             let d = Document::new(result).with_metadata(h);
-        //             context_chunks.push(d);
-        //         }
-        //         Err(e) => panic!("Error invoking LLMChain: {:?}", e),
-        //     }
-        //
-        //     // -------------------------------------
-        //     // -- sleep between chunks so poor GPU don't blow up
-        //     // tokio::time::sleep(Duration::from_secs(20)).await;
-        // }
+// -- text of the pdf's first `max_page_count` pages. The pdf is parsed once and the pages over
+// -- the limit deleted from it, the text is extracted as PdfExtractLoader does, which can only
+// -- be built by parsing the pdf (again)
+fn pdf_text(doc_path: &str, max_page_count: Option<usize>) -> Result<Document, String> {
+    let mut pdf = pdf_extract::Document::load(doc_path)
+        .map_err(|e| format!("reading the pdf failed: {}", e))?;
+    if let Some(max_pages) = max_page_count {
+        let page_numbers: Vec<u32> = pdf.get_pages().keys().copied().collect();
+        if page_numbers.len() > max_pages {
+            log::warn!(
+                "{} has {} pages, processing only the first {}",
+                doc_path,
+                page_numbers.len(),
+                max_pages
+            );
+            pdf.delete_pages(&page_numbers[max_pages..]);
+        }
+    }
+    let mut buffer: Vec<u8> = vec![];
+    let mut output = pdf_extract::PlainTextOutput::new(&mut buffer as &mut dyn std::io::Write);
+    pdf_extract::output_doc(&pdf, &mut output)
+        .map_err(|e| format!("extracting the text of the pdf failed: {}", e))?;
+    String::from_utf8(buffer)
+        .map(Document::new)
+        .map_err(|e| format!("the text of the pdf isn't utf-8: {}", e))
+}
 
-        // -------------------------------------
-        // -- embeddings & vector store
-        let db_client = Qdrant::from_url(&db_url).build().unwrap();
-        let ollama_embed = OllamaEmbedder::new(
-            ollama_client.clone(),
-            &embed,
-            Some(GenerationOptions::default()),
-        );
-        let vector_store = StoreBuilder::new()
-            .embedder(ollama_embed)
-            // .recreate_collection(true)
-            .client(db_client)
-            .collection_name("documents")
-            .build()
-            .await
-            .unwrap();
-        vector_store
-            .add_documents(&context_chunks, &VecStoreOptions::default())
-            .await
-            .unwrap();
+// -- first `max_tokens` of the text, cut on a semantic boundary
+fn truncate_tokens(text: &str, max_tokens: usize) -> String {
+    let tokenizer = cl100k_base().unwrap();
+    let splitter = TextSplitter::new(ChunkConfig::new(max_tokens).with_sizer(tokenizer));
+    let truncated = splitter.chunks(text).next().unwrap_or_default();
+    truncated.to_string()
+}
+
+fn apply_overlap(chunks: Vec<Document>, strategy: OverlapStrategy) -> Vec<Document> {
+    if strategy == OverlapStrategy::None {
+        return chunks;
     }
+    let tokenizer = cl100k_base().unwrap();
+
+    let overlaps: Vec<String> = chunks
+        .iter()
+        .map(|previous| match strategy {
+            OverlapStrategy::None => String::new(),
+            OverlapStrategy::Token(count) => last_tokens(&tokenizer, &previous.page_content, count),
+            OverlapStrategy::Sentence => last_sentence(&previous.page_content).to_string(),
+        })
+        .collect();
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut chunk)| {
+            let overlap = index.checked_sub(1).map(|i| overlaps[i].trim());
+            if let Some(overlap) = overlap.filter(|o| !o.is_empty()) {
+                chunk.page_content = format!("{} {}", overlap, chunk.page_content);
+            }
+            chunk
+        })
+        .collect()
 }
 
-struct WebState {
-    chain: ConversationalRetrieverChain, // Example of a parameter passed from main
+fn last_tokens(tokenizer: &CoreBPE, text: &str, count: usize) -> String {
+    let tokens = tokenizer.encode_ordinary(text);
+    // -- a cut through a multi-byte character is not decodable, move past it
+    (tokens.len().saturating_sub(count)..tokens.len())
+        .find_map(|start| tokenizer.decode(tokens[start..].to_vec()).ok())
+        .unwrap_or_default()
 }
 
-async fn web(ollama_url: String, model: String, embed: String, db_url: String) {
-    // -- llm
-    let ollama_client = Arc::new(OllamaClient::from_url(Url::parse(&ollama_url).unwrap()));
-    let ollama = Ollama::new(
-        ollama_client.clone(),
-        &model,
-        Some(GenerationOptions::default()),
-    );
+// -- text between the last two `.`, `!` or `?` sentence boundaries
+fn last_sentence(text: &str) -> &str {
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .filter(|(i, c)| {
+            matches!(c, '.' | '!' | '?')
+                && text[i + c.len_utf8()..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace)
+        })
+        .map(|(i, c)| i + c.len_utf8())
+        .collect();
 
-    let msg_template = template_jinja2!(CHAT_PROMPT_STR, "context", "question");
+    match boundaries.as_slice() {
+        [] => "",
+        [end] => &text[..*end],
+        [.., start, end] => &text[*start..*end],
+    }
+}
 
-    let ollama_embed = OllamaEmbedder::new(
-        ollama_client.clone(),
-        &embed,
-        Some(GenerationOptions::default()),
-    );
-    let db_client = Qdrant::from_url(&db_url).build().unwrap();
-    let vector_store = StoreBuilder::new()
-        .recreate_collection(false)
-        .embedder(ollama_embed)
-        .client(db_client)
-        .collection_name("documents")
-        .build()
-        .await
-        .unwrap();
+// context window of ollama models without --num-ctx
+const OLLAMA_NUM_CTX: usize = 4096;
 
-    let prompt = message_formatter![
-        fmt_message!(Message::new_system_message("Jsi AI pomocnik ve firme S&W pro odpovedi na dotazy z dodanych documentu internich smernic a pravidel. Odpovidej co nepresneji dle dodaneho textu.")),
-        fmt_template!(HumanMessagePromptTemplate::new(msg_template))
-    ];
-    let retviever = langchain_rust::vectorstore::Retriever::new(vector_store, 5)
-        .with_options(VecStoreOptions::new().with_score_threshold(0.55));
-    let chain = ConversationalRetrieverChainBuilder::new()
-        .llm(ollama)
-        .rephrase_question(true)
-        .memory(SimpleMemory::new().into())
-        .retriever(retviever)
-        .return_source_documents(true)
+// neighbour chunks on each side of a chunk in the window prompt
+const WINDOW_NEIGHBOURS: usize = 2;
+
+// -- --context-window-dynamic: as many neighbours on each side as fit --context-window-tokens
+// -- at the document's average chunk size, at least one
+fn dynamic_neighbours(target_tokens: usize, sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    if total == 0 {
+        return WINDOW_NEIGHBOURS;
+    }
+    let average = total as f64 / sizes.len() as f64;
+    ((target_tokens as f64 / average).floor() as usize).max(1)
+}
+
+fn window_input(chunks_vec: &[Document], index: usize, neighbours: usize) -> PromptArgs {
+    // Získání kontextu: `neighbours` předchozích, aktuální, `neighbours` následujících
+    let previous_chunks = chunks_vec
+        .get(index.saturating_sub(neighbours)..index)
+        .unwrap_or(&[]);
+    let next_chunks = chunks_vec
+        .get(index + 1..=(index + neighbours).min(chunks_vec.len() - 1))
+        .unwrap_or(&[]);
+
+    // Spojení textu do stringu
+    let previous_text = previous_chunks
+        .iter()
+        .map(|c| c.page_content.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    let next_text = next_chunks
+        .iter()
+        .map(|c| c.page_content.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    prompt_args! {
+        "previous_chunks" => previous_text,
+        "input" => chunks_vec[index].page_content,
+        "next_chunks" => next_text,
+    }
+}
+
+// -- the sentences of the neighbour chunks nearest to the chunk that fit `available`, split
+// -- evenly between both sides. A side needing less than its half leaves the rest to the other
+fn trimmed_neighbours(
+    previous: &str,
+    next: &str,
+    available: usize,
+    size: impl Fn(&str) -> usize,
+) -> (String, String) {
+    let half = available / 2;
+    let (previous_size, next_size) = (size(previous), size(next));
+    let (previous_budget, next_budget) = if previous_size <= half {
+        (previous_size, available - previous_size)
+    } else if next_size <= available - half {
+        (available - next_size, next_size)
+    } else {
+        (half, available - half)
+    };
+    let nearest = |sentences: Vec<String>, budget: usize| {
+        let mut used = 0;
+        sentences
+            .into_iter()
+            .take_while(|sentence| {
+                used += size(sentence);
+                used <= budget
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut previous: Vec<String> = chunking::split_sentences(previous);
+    previous.reverse();
+    let mut previous = nearest(previous, previous_budget);
+    previous.reverse();
+    let next = nearest(chunking::split_sentences(next), next_budget);
+    (previous.join(" "), next.join(" "))
+}
+
+// answers of a model that refused or misunderstood the enrichment task
+const REFUSAL_PHRASES: &[&str] = &[
+    "omlouvám se",
+    "nemohu",
+    "nemůžu",
+    "jako ai",
+    "i'm sorry",
+    "i am sorry",
+    "i cannot",
+    "i can't",
+    "as an ai",
+];
+// share of the original words that must appear in the enriched chunk
+const MIN_LEXICAL_OVERLAP: f32 = 0.2;
+
+// -- quality gate for enriched chunks: a rejected one is retried and then replaced by
+// -- the original, or fails the document with --strict-enrichment
+struct ChunkEnrichmentValidator {
+    min_ratio: f32,
+    max_ratio: f32,
+    strict: bool,
+}
+
+impl ChunkEnrichmentValidator {
+    fn new(cli: &Cli) -> Self {
+        ChunkEnrichmentValidator {
+            min_ratio: cli.min_enrichment_ratio,
+            max_ratio: cli.max_enrichment_ratio,
+            strict: cli.strict_enrichment,
+        }
+    }
+
+    // -- why the enriched chunk can't replace the original, None when it can
+    fn rejection(&self, original: &str, enriched: &str) -> Option<String> {
+        let original_len = original.chars().count();
+        let enriched_len = enriched.trim().chars().count();
+        if enriched_len == 0 {
+            return Some("enriched chunk is empty".to_string());
+        }
+        if (enriched_len as f32) < self.min_ratio * original_len as f32 {
+            return Some(format!(
+                "enriched chunk has {} chars, less than {} of the original {} chars",
+                enriched_len, self.min_ratio, original_len
+            ));
+        }
+        if (enriched_len as f32) > self.max_ratio * original_len as f32 {
+            return Some(format!(
+                "enriched chunk has {} chars, more than {} times the original {} chars",
+                enriched_len, self.max_ratio, original_len
+            ));
+        }
+
+        let lowercase = enriched.to_lowercase();
+        if let Some(phrase) = REFUSAL_PHRASES.iter().find(|p| lowercase.contains(*p)) {
+            return Some(format!(
+                "enriched chunk contains refusal phrase '{}'",
+                phrase
+            ));
+        }
+
+        let overlap = lexical_overlap(original, enriched);
+        if overlap < MIN_LEXICAL_OVERLAP {
+            return Some(format!(
+                "enriched chunk shares only {:.0}% of the original words",
+                overlap * 100.0
+            ));
+        }
+        None
+    }
+}
+
+// -- share of the original words (4+ chars) that are in the other text
+fn lexical_overlap(original: &str, other: &str) -> f32 {
+    let words = |text: &str| -> HashSet<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.chars().count() >= 4)
+            .map(str::to_lowercase)
+            .collect()
+    };
+    let original_words = words(original);
+    if original_words.is_empty() {
+        return 1.0;
+    }
+    let other_words = words(other);
+    original_words.intersection(&other_words).count() as f32 / original_words.len() as f32
+}
+
+fn enrichment_chain(
+    ollama: &ollama::OllamaWithOptions,
+    strategy: ContextStrategy,
+    window_template: &str,
+    system_prompt: Option<&str>,
+) -> ConversationalChain {
+    let chunk_msg_template = match strategy {
+        ContextStrategy::Window => {
+            template_jinja2!(window_template, "previous_chunks", "input", "next_chunks")
+        }
+        ContextStrategy::FullDocument => {
+            template_jinja2!(config::FULL_DOCUMENT_CHUNK_STR, "document", "input")
+        }
+        ContextStrategy::Summary => {
+            template_jinja2!(config::SUMMARY_CHUNK_STR, "summary", "input")
+        }
+    };
+    chunk_chain(ollama, chunk_msg_template, system_prompt)
+}
+
+fn table_chain(
+    ollama: &ollama::OllamaWithOptions,
+    system_prompt: Option<&str>,
+) -> ConversationalChain {
+    let table_msg_template = template_jinja2!(config::TABLE_CHUNK_STR, "section", "input");
+    chunk_chain(ollama, table_msg_template, system_prompt)
+}
+
+fn chunk_chain(
+    ollama: &ollama::OllamaWithOptions,
+    chunk_msg_template: PromptTemplate,
+    system_prompt: Option<&str>,
+) -> ConversationalChain {
+    let prompt = match system_prompt {
+        Some(system_prompt) => message_formatter![
+            fmt_message!(Message::new_system_message(system_prompt)),
+            fmt_template!(HumanMessagePromptTemplate::new(chunk_msg_template))
+        ],
+        None => message_formatter![fmt_template!(HumanMessagePromptTemplate::new(
+            chunk_msg_template
+        ))],
+    };
+    ConversationalChainBuilder::new()
+        .llm(ollama.clone())
         .prompt(prompt)
         .build()
-        .expect("Error building ConversationalChain");
+        .expect("Error building ConversationalChain")
+}
 
-    let web_state = Arc::new(WebState { chain });
+// -- language of the first 1000 characters of the document
+fn detect_language(doc: &[Document]) -> Option<whatlang::Info> {
+    let sample: String = doc
+        .iter()
+        .flat_map(|d| d.page_content.chars())
+        .take(1000)
+        .collect();
+    whatlang::detect(&sample)
+}
 
-    let app = Router::new()
-        .route("/", get(web_root_handle))
-        .route("/chat", post(web_chat_handler).with_state(web_state));
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3003")
-        .await
-        .unwrap();
-    println!("web listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+fn language_tokenizer(script: Option<whatlang::Script>) -> CoreBPE {
+    match script {
+        Some(whatlang::Script::Latin) | None => cl100k_base().unwrap(),
+        // -- o200k splits non latin scripts into far fewer tokens
+        Some(_) => o200k_base().unwrap(),
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatRequest {
-    message: String,
+// -- how chunk size is measured, picked by --sizer or by the document language
+enum DocumentSizer {
+    Tokens(CoreBPE),
+    Chars,
 }
 
-async fn web_chat_handler(
-    State(state): State<Arc<WebState>>,
-    Json(payload): Json<ChatRequest>,
-    // ) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
-) -> impl IntoResponse {
-    let (tx, rx) = mpsc::channel(10);
-    println!("{:?} - user message", payload);
-    let state = Arc::clone(&state);
-    let query = payload.message;
-    let input_variables = prompt_args! {
-        "question" => &query,
-    };
+impl DocumentSizer {
+    fn new(sizer: Option<Sizer>, script: Option<whatlang::Script>) -> Self {
+        match sizer {
+            Some(Sizer::Cl100k) => DocumentSizer::Tokens(cl100k_base().unwrap()),
+            Some(Sizer::O200k) => DocumentSizer::Tokens(o200k_base().unwrap()),
+            Some(Sizer::Chars) => DocumentSizer::Chars,
+            None => DocumentSizer::Tokens(language_tokenizer(script)),
+        }
+    }
 
-    let mut stream = state.chain.stream(input_variables).await.unwrap();
-    tokio::spawn(async move {
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(data) => {
-                    let data_content = data.value["message"]["content"].to_string();
-                    // let t = tx.send(Ok(Event::default().data(data_content))).await;
-                    // let json_p = json!({"msg": data_content});
-                    tx.send(Event::default().json_data(data.value)).await.ok();
-                }
-                Err(e) => {
-                    println!("Error: {:?}", e);
+    fn unit(&self) -> &'static str {
+        match self {
+            DocumentSizer::Tokens(_) => "tokens",
+            DocumentSizer::Chars => "chars",
+        }
+    }
+}
+
+impl ChunkSizer for DocumentSizer {
+    fn size(&self, chunk: &str) -> usize {
+        match self {
+            DocumentSizer::Tokens(tokenizer) => tokenizer.size(chunk),
+            DocumentSizer::Chars => Characters.size(chunk),
+        }
+    }
+}
+
+impl ChunkSizer for &DocumentSizer {
+    fn size(&self, chunk: &str) -> usize {
+        (*self).size(chunk)
+    }
+}
+
+// -- the page without its tables, its token splitter chunks and the row chunks of its tables
+fn split_page(
+    page: &str,
+    splitter: &TextSplitter<&DocumentSizer>,
+    sizer: &DocumentSizer,
+    chunk_size: usize,
+) -> (String, Vec<Document>, Vec<Document>) {
+    let (page_text, tables) = tables::extract_tables(page);
+    let mut table_chunks = vec![];
+    for table in tables {
+        let mut metadata = HashMap::from([("kind".to_string(), json!("table"))]);
+        if let Some(section) = &table.section {
+            metadata.insert("section".to_string(), json!(section));
+        }
+        table_chunks.extend(
+            table
+                .split(|text| sizer.size(text), chunk_size)
+                .into_iter()
+                .map(|part| Document::new(part).with_metadata(metadata.clone())),
+        );
+    }
+    let chunks = splitter
+        .chunks(&page_text)
+        .map(Document::new)
+        .collect::<Vec<_>>();
+    (page_text, chunks, table_chunks)
+}
+
+// -- min / median / max chunk size, for checking the --sizer and --chunk-size setting
+fn size_distribution(sizer: &DocumentSizer, chunks: &[Document]) -> Option<(usize, usize, usize)> {
+    let mut sizes: Vec<usize> = chunks.iter().map(|c| sizer.size(&c.page_content)).collect();
+    sizes.sort_unstable();
+    Some((*sizes.first()?, sizes[sizes.len() / 2], *sizes.last()?))
+}
+
+// -- `lang_code = "prompt_path"` toml, paths are relative to the file
+fn load_language_prompts(path: &str) -> HashMap<String, String> {
+    let content = fs::read_to_string(path).expect("Error reading language prompts");
+    let table = content
+        .parse::<toml::Table>()
+        .expect("Error parsing language prompts");
+    let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    table
+        .into_iter()
+        .map(|(lang, prompt_path)| {
+            let prompt_path = base_dir.join(
+                prompt_path
+                    .as_str()
+                    .expect("Language prompt path must be a string"),
+            );
+            let prompt = fs::read_to_string(&prompt_path).unwrap_or_else(|e| {
+                panic!("Error reading prompt {}: {:?}", prompt_path.display(), e)
+            });
+            (lang, prompt)
+        })
+        .collect()
+}
+
+// -- window prompt of the doc type: `context_chunk.<doc type>.txt` of --prompts-dir, then its
+// -- `context_chunk.txt`, then the built-in one. Doc types without a prompt of their own warn
+fn load_window_template(prompts_dir: Option<&str>, doc_type: &DocType) -> String {
+    let read = |file: String| {
+        let path = Path::new(prompts_dir?).join(file);
+        fs::read_to_string(&path)
+            .inspect_err(|e| {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    output::warning(&format!("Error reading {}: {}", path.display(), e));
                 }
-            }
+            })
+            .ok()
+    };
+    if let Some(template) = read(format!("context_chunk.{}.txt", doc_type.name())) {
+        return template;
+    }
+    if let DocType::Named(name) = doc_type {
+        output::warning(&format!(
+            "No context_chunk.{}.txt prompt for --doc-type {}, using the default one.",
+            name, name
+        ));
+    }
+    read("context_chunk.txt".to_string()).unwrap_or_else(|| doc_type.window_template().to_string())
+}
+
+fn summary_chain(ollama: &ollama::OllamaWithOptions) -> ConversationalChain {
+    let summary_msg_template = template_jinja2!(config::DOCUMENT_SUMMARY_STR, "document");
+    let summary_prompt = message_formatter![fmt_template!(HumanMessagePromptTemplate::new(
+        summary_msg_template
+    ))];
+    ConversationalChainBuilder::new()
+        .llm(ollama.clone())
+        .prompt(summary_prompt)
+        .build()
+        .expect("Error building ConversationalChain")
+}
+
+// -------------------------------------
+// -- single document ingestion, shared by generate mode and web ingestion jobs
+struct Ingest {
+    cli: Cli,
+    ollama_client: Arc<OllamaClient>,
+    ollama: ollama::OllamaWithOptions,
+    language_prompts: HashMap<String, String>,
+    // window prompt of --doc-type
+    window_template: String,
+    // --dry-embed chunks with their vectors instead of storing them, or the stored ones
+    // for --save-embeddings-to
+    dry_embedded: Mutex<Vec<Value>>,
+    // chunks that failed to embed or store even one by one, for the summary of the run
+    unstored: Mutex<Vec<UnstoredChunk>>,
+    // chunks of the --generate-report
+    report: Option<Mutex<report::IngestionReport>>,
+    // --chunk-preview-n of generate, the other modes don't ask
+    preview_chunks: usize,
+    // documents being enriched and stored, --memory-limit-mb waits for the others to free memory
+    in_flight: AtomicUsize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct IngestStats {
+    // stored chunks
+    chunks: usize,
+    // enriched chunks rejected by the quality gate (at least once)
+    rejected: usize,
+    // rejected even after the retry, stored as the original text
+    fallbacks: usize,
+    // not stored, declined after the --chunk-preview-n
+    declined: bool,
+    // failed to embed or store, listed in `Ingest::unstored`
+    unstored: usize,
+}
+
+struct UnstoredChunk {
+    path: String,
+    chunk_index: Option<u64>,
+    error: String,
+}
+
+// attempts to store a chunk of a failed batch on its own
+const CHUNK_STORE_ATTEMPTS: usize = 2;
+// pause before storing a chunk again, a busy model may have freed up
+const CHUNK_STORE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+enum IngestOutcome {
+    Stored(IngestStats),
+    // why the document isn't ingested: over --max-document-size-mb or unreadable
+    Skipped(String),
+    // why the document failed, none of its chunks are stored
+    Failed(String),
+}
+
+// -- a document loaded and split into chunks, not enriched yet
+struct PreparedDocument {
+    doc_path: String,
+    collection: Option<Value>,
+    // --meta and the sidecar's keys
+    metadata: HashMap<String, Value>,
+    language: Option<whatlang::Info>,
+    sizer: DocumentSizer,
+    doc_text: String,
+    chunks: Vec<Document>,
+    // sentence windows are stored as they are
+    enrich: bool,
+    // neighbour chunks on each side in the window prompt
+    neighbours: usize,
+    // unix seconds, for --freshness-weight
+    last_modified: Option<u64>,
+}
+
+// -- modification time of the file in unix seconds
+fn last_modified(path: &str) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|age| age.as_secs())
+}
+
+impl PreparedDocument {
+    // -- metadata of the chunk `index` stored as `enriched`, `extra_metadata` is added to
+    // -- (and overrides) it
+    fn chunk_metadata(
+        &self,
+        index: usize,
+        enriched: &str,
+        doc_type: &DocType,
+        rejected: bool,
+        keywords: Vec<String>,
+        extra_metadata: &HashMap<String, Value>,
+    ) -> HashMap<String, Value> {
+        let chunk = &self.chunks[index];
+        let mut metadata = chunk.metadata.clone();
+        metadata.extend(self.metadata.clone());
+        if rejected {
+            metadata.insert("context_rejected".to_string(), json!(true));
         }
-    });
-    Sse::new(ReceiverStream::new(rx))
+        metadata.insert("path".to_string(), json!(self.doc_path));
+        metadata.insert("chunk_index".to_string(), json!(index));
+        metadata.insert("doc_type".to_string(), json!(doc_type.name()));
+        if enriched != chunk.page_content {
+            metadata.insert("original_text".to_string(), json!(chunk.page_content));
+        }
+        if let Some(modified) = self.last_modified {
+            metadata.insert("last_modified".to_string(), json!(modified));
+        }
+        if let Some(collection) = &self.collection {
+            metadata.insert("collection".to_string(), collection.clone());
+        }
+        if let Some(info) = &self.language {
+            metadata.insert("language".to_string(), json!(info.lang().code()));
+        }
+        if !keywords.is_empty() {
+            metadata.insert("keywords".to_string(), json!(keywords));
+        }
+        metadata.extend(extra_metadata.clone());
+        if let Some(path) = metadata.get("path").and_then(Value::as_str) {
+            metadata.insert("source_id".to_string(), json!(store::source_id(path)));
+        }
+        metadata
+    }
 }
 
-async fn web_root_handle() -> axum::response::Html<&'static str> {
-    axum::response::Html(include_str!("./html/index.html"))
+struct EnrichmentChains {
+    chunk: ConversationalChain,
+    // firmer instruction for chunks rejected by the quality gate
+    retry: ConversationalChain,
+    table: ConversationalChain,
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+impl Ingest {
+    fn new(cli: &Cli) -> Self {
+        let ollama_client = Arc::new(OllamaClient::from_url(
+            Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+        ));
+        let ollama = ollama::OllamaWithOptions::new(
+            ollama_client.clone(),
+            cli.model.as_deref().unwrap(),
+            generation_options(cli),
+        );
+        let language_prompts = cli
+            .language_prompts
+            .as_deref()
+            .map(load_language_prompts)
+            .unwrap_or_default();
+        let window_template = load_window_template(cli.prompts_dir.as_deref(), &cli.doc_type);
 
-    let cli = Cli::parse();
-    match cli.mode {
-        Mode::Chat => {
-            chat(
-                cli.ollama.unwrap(),
-                cli.model.unwrap(),
-                cli.embed.unwrap(),
-                cli.db.unwrap(),
-            )
-            .await;
+        Ingest {
+            cli: cli.clone(),
+            ollama_client,
+            ollama,
+            language_prompts,
+            window_template,
+            dry_embedded: Mutex::new(vec![]),
+            unstored: Mutex::new(vec![]),
+            report: cli
+                .generate_report
+                .as_ref()
+                .map(|_| Mutex::new(report::IngestionReport::new())),
+            preview_chunks: 0,
+            in_flight: AtomicUsize::new(0),
         }
-        Mode::Generate => {
-            if cli.document.is_none() {
-                println!("Missing document for generating chunks. \nAdd --document [path_to_document] into aruments.");
-                return;
-            }
-            generate(
-                cli.document.unwrap(),
-                cli.ollama.unwrap(),
-                cli.model.unwrap(),
-                cli.embed.unwrap(),
-                cli.db.unwrap(),
+    }
+
+    // -- descriptions of the images in the document, failed images are skipped
+    async fn image_chunks(&self, doc_path: &str) -> Vec<Document> {
+        let images =
+            images::extract_images(doc_path, self.cli.max_page_count, self.cli.min_image_size);
+        let mut chunks = vec![];
+        for image in images.iter() {
+            let description = images::describe_image(
+                &self.ollama_client,
+                &self.cli.vision_model,
+                generation_options(&self.cli),
+                image,
             )
             .await;
+            match description {
+                Ok(description) if !description.is_empty() => {
+                    output::detail(&format!(
+                        "{} - page {} image {} described",
+                        doc_path, image.page, image.index
+                    ));
+                    let metadata = HashMap::from([
+                        ("kind".to_string(), json!("image")),
+                        ("page".to_string(), json!(image.page)),
+                        ("image_index".to_string(), json!(image.index)),
+                    ]);
+                    chunks.push(Document::new(description).with_metadata(metadata));
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!(
+                    "{} - describing page {} image {} failed: {}",
+                    doc_path,
+                    image.page,
+                    image.index,
+                    e
+                ),
+            }
         }
-        Mode::Web => {
-            web(
-                cli.ollama.unwrap(),
-                cli.model.unwrap(),
-                cli.embed.unwrap(),
-                cli.db.unwrap(),
-            )
-            .await;
+        chunks
+    }
+
+    // -- enriched chunk that passed the quality gate, the chunk is retried once with
+    // -- a firmer instruction and the original text is kept when it fails again
+    async fn enrich_chunk(
+        &self,
+        (chain, retry_chain): (&ConversationalChain, &ConversationalChain),
+        validator: &ChunkEnrichmentValidator,
+        input_vars: PromptArgs,
+        original: &str,
+        stats: &mut IngestStats,
+    ) -> Result<String, ChainError> {
+        let result = chain.invoke(input_vars.clone()).await?;
+        let Some(reason) = validator.rejection(original, &result) else {
+            return Ok(result);
+        };
+        stats.rejected += 1;
+        log::warn!("enriched chunk rejected ({}), retrying", reason);
+
+        let result = retry_chain.invoke(input_vars).await?;
+        let Some(reason) = validator.rejection(original, &result) else {
+            return Ok(result);
+        };
+        if validator.strict {
+            return Err(ChainError::OtherError(format!(
+                "invalid enriched chunk: {reason}"
+            )));
+        }
+        log::warn!(
+            "enriched chunk rejected again ({}), keeping the original",
+            reason
+        );
+        stats.fallbacks += 1;
+        Ok(original.to_string())
+    }
+
+    // -- `--language-prompts` prompt of the document's language
+    fn system_prompt(&self, language: Option<&whatlang::Info>) -> Option<&str> {
+        language
+            .and_then(|info| self.language_prompts.get(info.lang().code()))
+            .map(String::as_str)
+    }
+
+    // -- chains enriching the chunks of a document, in its language
+    fn chains(&self, language: Option<&whatlang::Info>) -> EnrichmentChains {
+        let system_prompt = self.system_prompt(language);
+        let retry_system_prompt = match system_prompt {
+            Some(system_prompt) => format!("{}\n\n{}", system_prompt, config::ENRICHMENT_RETRY_STR),
+            None => config::ENRICHMENT_RETRY_STR.to_string(),
+        };
+        EnrichmentChains {
+            chunk: enrichment_chain(
+                &self.ollama,
+                self.cli.context_strategy,
+                &self.window_template,
+                system_prompt,
+            ),
+            retry: enrichment_chain(
+                &self.ollama,
+                self.cli.context_strategy,
+                &self.window_template,
+                Some(&retry_system_prompt),
+            ),
+            table: table_chain(&self.ollama, system_prompt),
+        }
+    }
+
+    // -- loads and splits the document, why it's skipped as error
+    async fn prepare_document(&self, doc_path: &str) -> Result<PreparedDocument, String> {
+        // -------------------------------------
+        // -- skip documents that would exhaust memory while loading
+        let doc_size = fs::metadata(doc_path).map(|m| m.len()).unwrap_or(0);
+        if doc_size > self.cli.max_document_size_mb * 1024 * 1024 {
+            log::warn!(
+                "skipping {}: {} bytes exceeds --max-document-size-mb {}",
+                doc_path,
+                doc_size,
+                self.cli.max_document_size_mb
+            );
+            return Err(format!(
+                "{:.1} MB, over --max-document-size-mb {}",
+                doc_size as f64 / (1024.0 * 1024.0),
+                self.cli.max_document_size_mb
+            ));
         }
+
+        let collection = collection_metadata(doc_path);
+        if let Some(collection) = &collection {
+            log::info!("{} belongs to collection {}", doc_path, collection);
+        }
+        let mut metadata: HashMap<String, Value> = match &self.cli.chunk_metadata_template {
+            Some(template) => template.evaluate(doc_path, &run_metadata(&self.cli)),
+            None => HashMap::new(),
+        };
+        metadata.extend(
+            self.cli
+                .meta
+                .iter()
+                .map(|(key, value)| (key.clone(), json!(value))),
+        );
+        metadata.extend(sidecar_metadata(doc_path));
+        if self.cli.doc_type == DocType::Transcript {
+            return self.prepare_transcript(doc_path, collection, metadata);
+        }
+
+        // -------------------------------------
+        // -- documents loader text extractor
+        let doc = match pdf_text(doc_path, self.cli.max_page_count) {
+            Ok(doc) => vec![doc],
+            Err(e) => {
+                output::warning(&format!("skipping {}: {}", doc_path, e));
+                return Err(e);
+            }
+        };
+        log::info!("{:?}", doc);
+
+        // -------------------------------------
+        // -- language decides the tokenizer and the enrichment system prompt
+        let language = detect_language(&doc);
+        match &language {
+            Some(info) => output::detail(&format!(
+                "{} - language {} ({}, confidence {:.2})",
+                doc_path,
+                info.lang().code(),
+                info.lang().eng_name(),
+                info.confidence()
+            )),
+            None => output::detail(&format!("{} - language not detected", doc_path)),
+        }
+
+        // -------------------------------------
+        // -- spliting into a meaningful chunks
+        let mut chunks_vec: Vec<Document> = vec![];
+        let mut doc_text: String = "".to_string();
+
+        let sizer = DocumentSizer::new(self.cli.sizer, language.as_ref().map(|info| info.script()));
+        let unit = sizer.unit();
+        let chunk_config = ChunkConfig::new(self.cli.chunk_size).with_sizer(&sizer);
+        let splitter = TextSplitter::new(chunk_config);
+        let embedder = OllamaEmbedder::new(
+            self.ollama_client.clone(),
+            self.cli.embed.clone().unwrap(),
+            Some(GenerationOptions::default()),
+        );
+        // -- token splitter chunks, kept for comparison with the other strategies
+        let mut token_chunks: Vec<Document> = vec![];
+        let mut sentences: Vec<String> = vec![];
+        // -- tables are chunked by rows, never together with the text around them
+        let mut table_chunks: Vec<Document> = vec![];
+        for doc_entry in doc.iter() {
+            doc_text += &doc_entry.page_content;
+            let (page_text, chunks, tables) = split_page(
+                &doc_entry.page_content,
+                &splitter,
+                &sizer,
+                self.cli.chunk_size,
+            );
+            table_chunks.extend(tables);
+            match self.cli.split_strategy {
+                SplitStrategy::Token => chunks_vec.extend(chunks),
+                SplitStrategy::Semantic => {
+                    let semantic = chunking::semantic_chunks(
+                        &page_text,
+                        &embedder,
+                        &sizer,
+                        self.cli.chunk_size,
+                        self.cli.semantic_threshold,
+                    )
+                    .await;
+                    match semantic {
+                        Ok(semantic) => chunks_vec.extend(semantic.into_iter().map(Document::new)),
+                        Err(e) => {
+                            log::warn!(
+                                "{} - semantic split of a page failed, using the token splitter: {}",
+                                doc_path,
+                                e
+                            );
+                            chunks_vec.extend(chunks.clone());
+                        }
+                    }
+                    token_chunks.extend(chunks);
+                }
+                SplitStrategy::SentenceWindow(_) => {
+                    sentences.extend(chunking::split_sentences(&page_text));
+                    token_chunks.extend(chunks);
+                }
+            }
+        }
+        if let SplitStrategy::SentenceWindow(size) = self.cli.split_strategy {
+            let windows = chunking::sentence_windows(&sentences, size);
+            chunks_vec = sentences
+                .into_iter()
+                .zip(windows)
+                .map(|(sentence, window)| {
+                    Document::new(sentence)
+                        .with_metadata(HashMap::from([("window".to_string(), json!(window))]))
+                })
+                .collect();
+        }
+        if let Some((min, median, max)) = size_distribution(&sizer, &chunks_vec) {
+            output::detail(&format!(
+                "{} - {} chunks, size min {} / median {} / max {} {}",
+                doc_path,
+                chunks_vec.len(),
+                min,
+                median,
+                max,
+                unit
+            ));
+        }
+        if let Some((min, median, max)) = size_distribution(&sizer, &token_chunks) {
+            output::detail(&format!(
+                "{} - token splitter would give {} chunks, size min {} / median {} / max {} {}",
+                doc_path,
+                token_chunks.len(),
+                min,
+                median,
+                max,
+                unit
+            ));
+        }
+        // -- sentences already carry their context in the window, they are stored as they are
+        let enrich = !matches!(self.cli.split_strategy, SplitStrategy::SentenceWindow(_));
+        let mut chunks_vec = match enrich {
+            true => apply_overlap(chunks_vec, self.cli.chunk_overlap_strategy),
+            false => chunks_vec,
+        };
+        if !table_chunks.is_empty() {
+            output::detail(&format!(
+                "{} - {} table chunks",
+                doc_path,
+                table_chunks.len()
+            ));
+        }
+        chunks_vec.extend(table_chunks);
+        let neighbours = self.neighbours(doc_path, &chunks_vec, &sizer);
+
+        Ok(PreparedDocument {
+            doc_path: doc_path.to_string(),
+            collection,
+            metadata,
+            language,
+            sizer,
+            doc_text,
+            chunks: chunks_vec,
+            enrich,
+            neighbours,
+            last_modified: last_modified(doc_path),
+        })
+    }
+
+    // -- transcript split by speaker turns, the chunks are enriched without overlap
+    fn prepare_transcript(
+        &self,
+        doc_path: &str,
+        collection: Option<Value>,
+        metadata: HashMap<String, Value>,
+    ) -> Result<PreparedDocument, String> {
+        let doc_text = match fs::read_to_string(doc_path) {
+            Ok(text) => text,
+            Err(e) => {
+                output::warning(&format!("skipping {}: {}", doc_path, e));
+                return Err(format!("reading the transcript failed: {}", e));
+            }
+        };
+        let language = whatlang::detect(&doc_text.chars().take(1000).collect::<String>());
+        let sizer = DocumentSizer::new(self.cli.sizer, language.as_ref().map(|info| info.script()));
+        let turns = speakers::parse_turns(&doc_text);
+        let chunks =
+            speakers::transcript_chunks(&turns, |text| sizer.size(text), self.cli.chunk_size);
+        output::detail(&format!(
+            "{} - {} speaker turns in {} chunks",
+            doc_path,
+            turns.len(),
+            chunks.len()
+        ));
+        let neighbours = self.neighbours(doc_path, &chunks, &sizer);
+        Ok(PreparedDocument {
+            doc_path: doc_path.to_string(),
+            collection,
+            metadata,
+            language,
+            sizer,
+            doc_text,
+            chunks,
+            enrich: true,
+            neighbours,
+            last_modified: last_modified(doc_path),
+        })
+    }
+
+    // -- neighbour chunks on each side in the window prompt of the document's chunks
+    fn neighbours(&self, doc_path: &str, chunks: &[Document], sizer: &DocumentSizer) -> usize {
+        if !self.cli.context_window_dynamic {
+            return WINDOW_NEIGHBOURS;
+        }
+        let sizes: Vec<usize> = chunks.iter().map(|c| sizer.size(&c.page_content)).collect();
+        let neighbours = dynamic_neighbours(self.cli.context_window_tokens, &sizes);
+        output::detail(&format!(
+            "{} - {} neighbour chunks on each side",
+            doc_path, neighbours
+        ));
+        neighbours
+    }
+
+    // -- document wide context for full-document and summary strategies
+    async fn document_context(&self, prepared: &PreparedDocument) -> String {
+        match self.cli.context_strategy {
+            _ if !prepared.enrich => String::new(),
+            ContextStrategy::Window => String::new(),
+            ContextStrategy::FullDocument => {
+                truncate_tokens(&prepared.doc_text, self.cli.context_max_tokens)
+            }
+            ContextStrategy::Summary => {
+                let input_vars = prompt_args! {
+                    "document" => truncate_tokens(&prepared.doc_text, self.cli.context_max_tokens),
+                };
+                match summary_chain(&self.ollama).invoke(input_vars).await {
+                    Ok(summary) => {
+                        output::detail(&format!("SUMMARY:\n{:?}", summary));
+                        summary
+                    }
+                    Err(e) => panic!("Error invoking LLMChain: {:?}", e),
+                }
+            }
+        }
+    }
+
+    // -- prompt variables of the chunk at `index`, tables get the table prompt's
+    // -- true when the neighbour chunks were trimmed to the prompt budget
+    fn enrichment_input(
+        &self,
+        prepared: &PreparedDocument,
+        index: usize,
+        document_context: &str,
+    ) -> (PromptArgs, bool) {
+        let chunk = &prepared.chunks[index];
+        if chunk.metadata.get("kind") == Some(&json!("table")) {
+            let section = chunk.metadata.get("section").and_then(Value::as_str);
+            let input = prompt_args! {
+                "section" => section.unwrap_or_default(),
+                "input" => chunk.page_content,
+            };
+            return (input, false);
+        }
+        let input = match self.cli.context_strategy {
+            ContextStrategy::Window => return self.budgeted_window_input(prepared, index),
+            ContextStrategy::FullDocument => prompt_args! {
+                "document" => document_context,
+                "input" => chunk.page_content,
+            },
+            ContextStrategy::Summary => prompt_args! {
+                "summary" => document_context,
+                "input" => chunk.page_content,
+            },
+        };
+        (input, false)
+    }
+
+    // -- the window prompt, with neighbours trimmed when the prompt exceeds the budget
+    fn budgeted_window_input(
+        &self,
+        prepared: &PreparedDocument,
+        index: usize,
+    ) -> (PromptArgs, bool) {
+        let input = window_input(&prepared.chunks, index, prepared.neighbours);
+        let sizer = &prepared.sizer;
+        let chunk_size = sizer.size(&prepared.chunks[index].page_content);
+        let budget = match (self.cli.context_prompt_budget, sizer) {
+            (Some(budget), _) => budget,
+            (None, DocumentSizer::Chars) => return (input, false),
+            (None, DocumentSizer::Tokens(_)) => {
+                let num_ctx = self.cli.num_ctx.map_or(OLLAMA_NUM_CTX, |n| n as usize);
+                num_ctx.saturating_sub(plan::completion(chunk_size))
+            }
+        };
+        let text = |key: &str| input[key].as_str().unwrap_or_default().to_string();
+        let (previous, next) = (text("previous_chunks"), text("next_chunks"));
+        let fixed = self
+            .system_prompt(prepared.language.as_ref())
+            .map_or(0, |p| sizer.size(p))
+            + sizer.size(&self.window_template)
+            + chunk_size;
+        if fixed + sizer.size(&previous) + sizer.size(&next) <= budget {
+            return (input, false);
+        }
+        let (previous, next) =
+            trimmed_neighbours(&previous, &next, budget.saturating_sub(fixed), |text| {
+                sizer.size(text)
+            });
+        let input = prompt_args! {
+            "previous_chunks" => previous,
+            "input" => prepared.chunks[index].page_content,
+            "next_chunks" => next,
+        };
+        (input, true)
+    }
+
+    // -- the text stored for the chunk at `index`
+    async fn enrich(
+        &self,
+        chains: &EnrichmentChains,
+        validator: &ChunkEnrichmentValidator,
+        prepared: &PreparedDocument,
+        index: usize,
+        document_context: &str,
+        stats: &mut IngestStats,
+    ) -> Result<String, ChainError> {
+        let chunk = &prepared.chunks[index];
+        let (input_vars, trimmed) = self.enrichment_input(prepared, index, document_context);
+        if trimmed {
+            output::detail(&format!(
+                "{} - neighbours of chunk {} trimmed to the prompt budget",
+                prepared.doc_path, index
+            ));
+        }
+        let is_table = chunk.metadata.get("kind") == Some(&json!("table"));
+        let is_image = chunk.metadata.get("kind") == Some(&json!("image"));
+        match prepared.enrich {
+            // -- image descriptions are complete on their own
+            true if is_image => Ok(chunk.page_content.clone()),
+            // -- the table description goes before the table itself
+            true if is_table => chains
+                .table
+                .invoke(input_vars)
+                .await
+                .map(|description| format!("{}\n\n{}", description.trim(), chunk.page_content)),
+            true => {
+                self.enrich_chunk(
+                    (&chains.chunk, &chains.retry),
+                    validator,
+                    input_vars,
+                    &chunk.page_content,
+                    stats,
+                )
+                .await
+            }
+            false => Ok(chunk.page_content.clone()),
+        }
+    }
+
+    // -- prompt and estimated completion size of every enriched chunk, in the document's sizer
+    // -- units. The summary isn't known before it's generated, its call is counted instead
+    fn plan_document(&self, prepared: &PreparedDocument) -> plan::DocumentPlan {
+        let sizer = &prepared.sizer;
+        let system_prompt = self
+            .system_prompt(prepared.language.as_ref())
+            .map_or(0, |p| sizer.size(p));
+        let template = match self.cli.context_strategy {
+            ContextStrategy::Window => &self.window_template,
+            ContextStrategy::FullDocument => config::FULL_DOCUMENT_CHUNK_STR,
+            ContextStrategy::Summary => config::SUMMARY_CHUNK_STR,
+        };
+        let document_context = match self.cli.context_strategy {
+            ContextStrategy::FullDocument => {
+                truncate_tokens(&prepared.doc_text, self.cli.context_max_tokens)
+            }
+            _ => String::new(),
+        };
+        let mut document = plan::DocumentPlan::default();
+        if !prepared.enrich {
+            return document;
+        }
+        if self.cli.context_strategy == ContextStrategy::Summary {
+            let summarized = truncate_tokens(&prepared.doc_text, self.cli.context_max_tokens);
+            document.add_call(
+                sizer.size(config::DOCUMENT_SUMMARY_STR) + sizer.size(&summarized),
+                0,
+            );
+        }
+        for (index, chunk) in prepared.chunks.iter().enumerate() {
+            let template = match chunk.metadata.get("kind").and_then(Value::as_str) {
+                Some("image") => continue,
+                Some("table") => config::TABLE_CHUNK_STR,
+                _ => template,
+            };
+            let (input, _) = self.enrichment_input(prepared, index, &document_context);
+            let input_size: usize = input
+                .values()
+                .map(|value| sizer.size(value.as_str().unwrap_or_default()))
+                .sum();
+            document.add_call(
+                system_prompt + sizer.size(template) + input_size,
+                sizer.size(&chunk.page_content),
+            );
+        }
+        document
+    }
+
+    // -- seconds per enriched chunk of the first chunks of the document, None without chunks
+    async fn calibrate(&self, prepared: &PreparedDocument, chunks: usize) -> Option<f64> {
+        let chunks = chunks.min(prepared.chunks.len());
+        if chunks == 0 || !prepared.enrich {
+            return None;
+        }
+        let chains = self.chains(prepared.language.as_ref());
+        let validator = ChunkEnrichmentValidator::new(&self.cli);
+        let document_context = self.document_context(prepared).await;
+        let started = Instant::now();
+        for index in 0..chunks {
+            let mut stats = IngestStats::default();
+            if let Err(e) = self
+                .enrich(
+                    &chains,
+                    &validator,
+                    prepared,
+                    index,
+                    &document_context,
+                    &mut stats,
+                )
+                .await
+            {
+                log::warn!("calibration of {} failed: {:?}", prepared.doc_path, e);
+                return None;
+            }
+        }
+        Some(started.elapsed().as_secs_f64() / chunks as f64)
+    }
+
+    // -- `progress` is called with (enriched chunks, total chunks),
+    // -- `extra_metadata` is added to (and overrides) the metadata of every chunk
+    async fn contextualize(
+        &self,
+        prepared: PreparedDocument,
+        extra_metadata: &HashMap<String, Value>,
+        progress: &(dyn Fn(usize, usize) + Send + Sync),
+    ) -> Result<IngestStats, String> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let stats = self
+            .enrich_and_store(prepared, extra_metadata, progress)
+            .await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        stats
+    }
+
+    async fn enrich_and_store(
+        &self,
+        mut prepared: PreparedDocument,
+        extra_metadata: &HashMap<String, Value>,
+        progress: &(dyn Fn(usize, usize) + Send + Sync),
+    ) -> Result<IngestStats, String> {
+        let doc_path = prepared.doc_path.clone();
+        if self.cli.describe_images {
+            prepared.chunks.extend(self.image_chunks(&doc_path).await);
+        }
+        let chains = self.chains(prepared.language.as_ref());
+        let document_context = self.document_context(&prepared).await;
+
+        let mut context_chunks: Vec<Document> = vec![];
+        let validator = ChunkEnrichmentValidator::new(&self.cli);
+        let mut stats = IngestStats::default();
+        // -- --keywords tfidf scores every chunk against the whole document
+        let tfidf_keywords = match self.cli.keywords {
+            keywords::KeywordExtractor::Tfidf => keywords::tfidf(
+                &prepared
+                    .chunks
+                    .iter()
+                    .map(|chunk| chunk.page_content.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            _ => vec![],
+        };
+        progress(0, prepared.chunks.len());
+
+        for index in 0..prepared.chunks.len() {
+            let others_in_flight = || self.in_flight.load(Ordering::SeqCst) > 1;
+            limits::wait_for_memory(self.cli.memory_limit_mb, others_in_flight).await?;
+            let chunk = &prepared.chunks[index];
+            output::detail(&format!(
+                "----------------------------\nCHUNK:\n{:?}\n---\n",
+                chunk.page_content
+            ));
+
+            let fallbacks = stats.fallbacks;
+            let enriched = self
+                .enrich(
+                    &chains,
+                    &validator,
+                    &prepared,
+                    index,
+                    &document_context,
+                    &mut stats,
+                )
+                .await;
+            match enriched {
+                Ok(result) => {
+                    output::detail(&format!("RESULT:\n{:?}", result));
+                    let chunk_keywords = match self.cli.keywords {
+                        keywords::KeywordExtractor::Off => vec![],
+                        keywords::KeywordExtractor::Tfidf => {
+                            tfidf_keywords.get(index).cloned().unwrap_or_default()
+                        }
+                        keywords::KeywordExtractor::Llm => {
+                            match keywords::llm(&self.ollama, &chunk.page_content).await {
+                                Ok(chunk_keywords) => chunk_keywords,
+                                Err(e) => {
+                                    log::warn!("{} chunk {}: {}", doc_path, index, e);
+                                    vec![]
+                                }
+                            }
+                        }
+                    };
+                    let metadata = prepared.chunk_metadata(
+                        index,
+                        &result,
+                        &self.cli.doc_type,
+                        stats.fallbacks > fallbacks,
+                        chunk_keywords,
+                        extra_metadata,
+                    );
+
+                    let d = Document::new(result).with_metadata(metadata);
+                    context_chunks.push(d);
+                }
+                Err(e) => return Err(format!("enriching chunk {} failed: {}", index, e)),
+            }
+
+            progress(index + 1, prepared.chunks.len());
+
+            // Pauza mezi iteracemi, aby se šetřila GPU
+            // time::sleep(Duration::from_secs(20)).await;
+        }
+
+        if self.preview_chunks > 0 {
+            let originals: Vec<&str> = prepared
+                .chunks
+                .iter()
+                .map(|chunk| chunk.page_content.as_str())
+                .collect();
+            let enriched: Vec<&str> = context_chunks
+                .iter()
+                .map(|chunk| chunk.page_content.as_str())
+                .collect();
+            let preview = chunk_preview(
+                &originals,
+                &enriched,
+                self.preview_chunks,
+                &mut rand::thread_rng(),
+            );
+            println!("-------\n{}\n{}", doc_path, preview);
+            if !confirmed("Continue?") {
+                stats.declined = true;
+                return Ok(stats);
+            }
+        }
+        stats.chunks = context_chunks.len();
+        if let Some(report) = &self.report {
+            let mut report = report.lock().unwrap();
+            for (index, chunk) in context_chunks.iter().enumerate() {
+                report.add_chunk(&doc_path, index, &chunk.page_content);
+            }
+        }
+        if self.cli.dry_embed || self.cli.save_embeddings_to.is_some() {
+            let texts: Vec<String> = context_chunks
+                .iter()
+                .map(|d| d.page_content.clone())
+                .collect();
+            match embedder(self.ollama_client.clone(), &self.cli)
+                .embed_documents(&texts)
+                .await
+            {
+                Ok(vectors) => self.dry_embedded.lock().unwrap().extend(dry_embedded(
+                    &context_chunks,
+                    vectors,
+                    self.cli.dry_embed_dims,
+                )),
+                Err(e) => output::warning(&format!(
+                    "{} - embedding the chunks failed, they aren't saved: {}",
+                    doc_path, e
+                )),
+            }
+        }
+        if self.cli.dry_embed {
+            return Ok(stats);
+        }
+
+        // -------------------------------------
+        // -- embeddings & vector store
+        let vector_store = vector_store(self.ollama_client.clone(), &self.cli).await;
+        // -- big documents are upserted in batches to stay under qdrant's request size limit
+        let batch_size = self.cli.qdrant_batch_size.max(1);
+        let batches = context_chunks.len().div_ceil(batch_size);
+        for (index, batch) in context_chunks.chunks(batch_size).enumerate() {
+            if index > 0 && self.cli.qdrant_batch_delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(self.cli.qdrant_batch_delay_ms)).await;
+            }
+            let started = Instant::now();
+            if let Err(e) = vector_store.add_documents(batch).await {
+                log::warn!(
+                    "{} - storing batch {}/{} failed, storing its chunks one by one: {}",
+                    doc_path,
+                    index + 1,
+                    batches,
+                    e
+                );
+                let unstored = self
+                    .store_one_by_one(vector_store.as_ref(), &doc_path, batch)
+                    .await;
+                stats.chunks -= unstored;
+                stats.unstored += unstored;
+                continue;
+            }
+            log::info!(
+                "{} - stored batch {}/{} ({} chunks) in {:?}",
+                doc_path,
+                index + 1,
+                batches,
+                batch.len(),
+                started.elapsed()
+            );
+        }
+
+        Ok(stats)
+    }
+
+    // -- the chunks stored each on its own, a chunk failing every attempt is recorded in
+    // -- `unstored` and the rest go on. Returns the count of unstored chunks
+    async fn store_one_by_one(
+        &self,
+        vector_store: &dyn ChunkStore,
+        doc_path: &str,
+        chunks: &[Document],
+    ) -> usize {
+        let mut unstored = 0;
+        for chunk in chunks {
+            let mut result = Ok(());
+            for attempt in 0..CHUNK_STORE_ATTEMPTS {
+                if attempt > 0 {
+                    tokio::time::sleep(CHUNK_STORE_RETRY_DELAY).await;
+                }
+                result = vector_store
+                    .add_documents(std::slice::from_ref(chunk))
+                    .await;
+                if result.is_ok() {
+                    break;
+                }
+            }
+            if let Err(error) = result {
+                let chunk_index = recontext::chunk_index(chunk);
+                log::warn!(
+                    "{} - chunk {:?} not stored: {}",
+                    doc_path,
+                    chunk_index,
+                    error
+                );
+                self.unstored.lock().unwrap().push(UnstoredChunk {
+                    path: doc_path.to_string(),
+                    chunk_index,
+                    error,
+                });
+                unstored += 1;
+            }
+        }
+        unstored
+    }
+
+    async fn ingest_document(
+        &self,
+        doc_path: &str,
+        extra_metadata: &HashMap<String, Value>,
+        progress: &(dyn Fn(usize, usize) + Send + Sync),
+    ) -> IngestOutcome {
+        match self.prepare_document(doc_path).await {
+            Ok(prepared) => match self.contextualize(prepared, extra_metadata, progress).await {
+                Ok(stats) => IngestOutcome::Stored(stats),
+                Err(e) => IngestOutcome::Failed(e),
+            },
+            Err(reason) => IngestOutcome::Skipped(reason),
+        }
+    }
+}
+
+// -- runs a single chunk through the window enrichment chain, for prompt tuning
+async fn test_prompt(cli: &Cli, text: &str) {
+    let ingest = Ingest::new(cli);
+    let language = whatlang::detect(text);
+    let system_prompt = ingest.system_prompt(language.as_ref());
+    let chain = enrichment_chain(
+        &ingest.ollama,
+        ContextStrategy::Window,
+        &ingest.window_template,
+        system_prompt,
+    );
+
+    let mut chunks = vec![];
+    if let Some(previous) = &cli.test_prev {
+        chunks.push(Document::new(previous));
+    }
+    let index = chunks.len();
+    chunks.push(Document::new(text));
+    if let Some(next) = &cli.test_next {
+        chunks.push(Document::new(next));
+    }
+
+    match chain
+        .invoke(window_input(&chunks, index, WINDOW_NEIGHBOURS))
+        .await
+    {
+        Ok(result) => println!("{}", result),
+        Err(e) => {
+            println!("Error invoking LLMChain: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// chunks enriched to time the model before a generate run
+const CALIBRATION_CHUNKS: usize = 3;
+
+// path of a document, how its ingestion ended and the time it took
+type IngestedDocument = (String, IngestOutcome, Duration);
+
+// -- stats of the prepared documents with the time they took, `workers` of them enriched and
+// -- stored at once. In document order, up to the one declined after --chunk-preview-n. The
+// -- documents are loaded and split before, for the plan of `generate`
+async fn contextualize_all(
+    ingest: Arc<Ingest>,
+    prepared: Vec<(String, Result<PreparedDocument, String>)>,
+    workers: usize,
+) -> Vec<IngestedDocument> {
+    let mut ingested: Vec<Option<IngestedDocument>> = prepared.iter().map(|_| None).collect();
+    let mut pending = prepared.into_iter().enumerate();
+    let mut running = tokio::task::JoinSet::new();
+    let mut declined = false;
+    loop {
+        while !declined && running.len() < workers.max(1) {
+            let Some((index, (doc_path, document))) = pending.next() else {
+                break;
+            };
+            match document {
+                Ok(document) => {
+                    let ingest = ingest.clone();
+                    running.spawn(async move {
+                        let started = Instant::now();
+                        let outcome = match ingest
+                            .contextualize(document, &HashMap::new(), &|_, _| {})
+                            .await
+                        {
+                            Ok(stats) => IngestOutcome::Stored(stats),
+                            Err(e) => IngestOutcome::Failed(e),
+                        };
+                        (index, doc_path, outcome, started.elapsed())
+                    });
+                }
+                Err(reason) => {
+                    ingested[index] =
+                        Some((doc_path, IngestOutcome::Skipped(reason), Duration::ZERO))
+                }
+            }
+        }
+        let Some(finished) = running.join_next().await else {
+            break;
+        };
+        // -- failed documents are in `ingested`, only a panic ends the run
+        let (index, doc_path, outcome, elapsed) =
+            finished.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        declined |= matches!(outcome, IngestOutcome::Stored(stats) if stats.declined);
+        ingested[index] = Some((doc_path, outcome, elapsed));
+    }
+    ingested
+        .into_iter()
+        .map_while(|document| document)
+        .collect()
+}
+
+// -- `ORIGINAL: ... | ENRICHED: ...` of `count` random chunks, in document order
+fn chunk_preview(
+    originals: &[&str],
+    enriched: &[&str],
+    count: usize,
+    rng: &mut impl rand::Rng,
+) -> String {
+    let flat = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let total = originals.len().min(enriched.len());
+    let mut sampled = rand::seq::index::sample(rng, total, count.min(total)).into_vec();
+    sampled.sort_unstable();
+    sampled
+        .into_iter()
+        .map(|index| {
+            format!(
+                "[{}/{}] ORIGINAL: {} | ENRICHED: {}",
+                index + 1,
+                total,
+                flat(originals[index]),
+                flat(enriched[index])
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// -- y/yes on stdin
+fn confirmed(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// -- --cpu-limit and --memory-limit-mb of the ingesting modes
+fn resource_limits(cli: &Cli) {
+    if let Err(e) = limits::apply(cli.cpu_limit, cli.memory_limit_mb) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn generate(cli: &Cli) {
+    // -- stdout is left to the --dry-embed json
+    if cli.dry_embed {
+        output::to_stderr();
+    }
+    resource_limits(cli);
+    let started = Instant::now();
+    // -------------------------------------
+    // -- VARIABLES
+    let document = cli.document.clone().unwrap();
+    let documents = if fs::metadata(&document).is_ok_and(|m| m.is_dir()) {
+        get_files(&document, cli.doc_type.extension())
+    } else {
+        vec![document]
+    };
+    output::note(&format!("{} documents", documents.len()));
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut total = IngestStats::default();
+    let mut skipped = 0;
+    let mut failed = vec![];
+
+    // -------------------------------------
+    // -- every document is loaded and split before any is enriched: the token budget, the
+    // -- calibration, --chunk-graph-export and --max-collection-points need the chunks of all of
+    // -- them. The workers only enrich, embed and store, so the text and the chunks of every
+    // -- document stay in memory until it is stored, about twice its extracted text; the peak,
+    // -- before the first one is enriched, holds that for all of them
+    let mut ingest = Ingest::new(cli);
+    ingest.preview_chunks = cli.chunk_preview_n;
+    let count = documents.len();
+    let mut prepared = vec![];
+    for (index, doc_path) in documents.into_iter().enumerate() {
+        output::detail(&format!("[{}/{}] {}", index + 1, count, doc_path));
+        prepared.push((doc_path.clone(), ingest.prepare_document(&doc_path).await));
+    }
+    let mut plan = plan::Plan::new(DocumentSizer::new(cli.sizer, None).unit());
+    for (_, document) in prepared.iter() {
+        match document {
+            Ok(document) => plan.add(document.chunks.len(), ingest.plan_document(document)),
+            Err(_) => plan.skip(),
+        }
+    }
+    if !cli.no_calibrate {
+        let first = prepared.iter().find_map(|(_, document)| {
+            document
+                .as_ref()
+                .ok()
+                .filter(|d| d.enrich && !d.chunks.is_empty())
+        });
+        if let Some(first) = first {
+            output::detail(&format!("calibrating on {}", first.doc_path));
+            plan.seconds_per_call = ingest.calibrate(first, CALIBRATION_CHUNKS).await;
+        }
+    }
+    output::note(&format!("-------\n{}", plan.render()));
+    if let Some(path) = &cli.chunk_graph_export {
+        let documents: Vec<&PreparedDocument> = prepared
+            .iter()
+            .filter_map(|(_, document)| document.as_ref().ok())
+            .collect();
+        match chunk_graph::write(path, cli.chunk_graph_format, &documents) {
+            Ok((nodes, edges)) => output::note(&format!(
+                "chunk graph of {} chunks and {} edges written to {} as {}",
+                nodes,
+                edges,
+                path,
+                cli.chunk_graph_format.name()
+            )),
+            Err(e) => output::error(&e),
+        }
+    }
+    // -- nothing is stored with --dry-embed, qdrant isn't needed
+    if let Some(max) = cli.max_collection_points.filter(|_| !cli.dry_embed) {
+        let adding: usize = prepared
+            .iter()
+            .filter_map(|(_, document)| document.as_ref().ok())
+            .map(|document| document.chunks.len())
+            .sum();
+        let store = vector_store(ingest.ollama_client.clone(), cli).await;
+        match store.count().await {
+            Ok(count) => {
+                if let Some(warning) = capacity::ingestion_warning(count, adding as u64, max) {
+                    output::warning(&warning);
+                }
+            }
+            Err(e) => output::warning(&format!("checking the collection size failed: {}", e)),
+        }
+    }
+    if cli.plan_only || (cli.confirm && !confirmed("Enrich the chunks?")) {
+        return;
+    }
+    // -- the collection exists once the store is open, filters need its payload indexes
+    if !cli.dry_embed && !local_db(cli.db.as_deref().unwrap()) {
+        vector_store(ingest.ollama_client.clone(), cli).await;
+        if let Err(e) = payload_indexes(cli).await {
+            output::warning(&format!("{}, filters will be slower", e));
+        }
+    }
+    if !cli.dry_embed && cli.collection.is_some() {
+        let store = vector_store(ingest.ollama_client.clone(), cli).await;
+        store_collection_settings(cli, store.as_ref()).await;
+    }
+
+    let ingest = Arc::new(ingest);
+    let ingested = contextualize_all(ingest.clone(), prepared, cli.num_workers).await;
+    let ingested_count = ingested.len();
+    for (doc_path, document, elapsed) in ingested {
+        let (stats, status) = match document {
+            IngestOutcome::Stored(stats) => {
+                total.chunks += stats.chunks;
+                total.rejected += stats.rejected;
+                total.fallbacks += stats.fallbacks;
+                let status = match (stats.declined, stats.unstored) {
+                    (true, _) => "declined".to_string(),
+                    (false, 0) => "stored".to_string(),
+                    (false, unstored) => format!("stored, {} chunks not", unstored),
+                };
+                (Some(stats), status)
+            }
+            IngestOutcome::Skipped(reason) => {
+                skipped += 1;
+                (None, format!("skipped ({})", reason))
+            }
+            IngestOutcome::Failed(e) => {
+                failed.push(format!("{}: {}", doc_path, e));
+                (None, "failed".to_string())
+            }
+        };
+        let counts = stats
+            .map(|stats| [stats.chunks, stats.rejected, stats.fallbacks].map(|n| n.to_string()))
+            .unwrap_or_default();
+        if let Some(report) = &ingest.report {
+            let stats = stats.unwrap_or_default();
+            report.lock().unwrap().add_document(report::ReportDocument {
+                path: doc_path.clone(),
+                chunks: stats.chunks,
+                rejected: stats.rejected,
+                fallbacks: stats.fallbacks,
+                status: status.clone(),
+                elapsed,
+            });
+        }
+        rows.push([vec![doc_path], counts.to_vec(), vec![status]].concat());
+    }
+    if ingested_count < count {
+        output::warning(&format!(
+            "stopped after the chunk preview, {} more documents not ingested",
+            count - ingested_count
+        ));
+    }
+    let counts = [total.chunks, total.rejected, total.fallbacks];
+    let status = [(skipped, "skipped"), (failed.len(), "failed")]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, status)| format!("{} {}", count, status))
+        .collect::<Vec<_>>()
+        .join(", ");
+    rows.push(
+        [
+            vec!["total".to_string()],
+            counts.map(|n| n.to_string()).to_vec(),
+            vec![status],
+        ]
+        .concat(),
+    );
+
+    let header = ["document", "chunks", "rejected", "original text", "status"];
+    match cli.dry_embed {
+        true => eprintln!("-------\n{}", output::table(&header, &rows)),
+        false => println!("-------\n{}", output::table(&header, &rows)),
+    }
+    if skipped > 0 {
+        output::warning(&format!(
+            "{} documents skipped, too large or unreadable",
+            skipped
+        ));
+    }
+    if !failed.is_empty() {
+        output::warning(&format!(
+            "{} documents failed, none of their chunks are stored:\n{}",
+            failed.len(),
+            failed.join("\n")
+        ));
+    }
+    let unstored = ingest.unstored.lock().unwrap();
+    if !unstored.is_empty() {
+        output::warning(&format!(
+            "{} chunks failed to embed or store and are missing from the collection:\n{}",
+            unstored.len(),
+            unstored_list(&unstored)
+        ));
+    }
+    drop(unstored);
+    if let (Some(path), Some(report)) = (&cli.generate_report, &ingest.report) {
+        let mut config = effective::config(cli);
+        config["ingestion"] = json!({
+            "document": cli.document,
+            "doc_type": cli.doc_type.name(),
+            "split_strategy": cli.split_strategy.name(),
+            "chunk_size": cli.chunk_size,
+            "keywords": cli.keywords.name(),
+            "save_embeddings_format": cli
+                .save_embeddings_to
+                .as_ref()
+                .map(|_| cli.save_embeddings_format.name()),
+        });
+        match report
+            .lock()
+            .unwrap()
+            .write(path, started.elapsed(), &config)
+        {
+            Ok(()) => output::note(&format!("report written to {}", path)),
+            Err(e) => output::error(&e),
+        }
+    }
+    let embedded = ingest.dry_embedded.lock().unwrap();
+    if let Some(path) = &cli.save_embeddings_to {
+        match embeddings::write(path, cli.save_embeddings_format, &embedded) {
+            Ok(()) => output::note(&format!(
+                "{} embeddings written to {}",
+                embedded.len(),
+                path
+            )),
+            Err(e) => output::error(&e),
+        }
+    }
+    if cli.dry_embed {
+        println!("{}", serde_json::to_string_pretty(&*embedded).unwrap());
+    }
+}
+
+// -- `path chunk N: error` lines of the chunks, for ingesting them again
+fn unstored_list(unstored: &[UnstoredChunk]) -> String {
+    unstored
+        .iter()
+        .map(|chunk| {
+            let index = chunk
+                .chunk_index
+                .map_or("?".to_string(), |index| index.to_string());
+            format!("  {} chunk {}: {}", chunk.path, index, chunk.error)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// generations not finished within this time are considered orphaned and cancelled
+const GENERATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// -- model and token limit of a /chat chain, and the chain builder of them
+type ChainKey = (String, Option<usize>);
+type NewChain = Box<dyn Fn(&str, Option<usize>) -> ConversationalRetrieverChain + Send + Sync>;
+
+struct WebState {
+    chain: ConversationalRetrieverChain, // Example of a parameter passed from main
+    // running generations with their start time and abort signal
+    generations: Mutex<HashMap<String, (Instant, oneshot::Sender<()>)>>,
+    jobs: Arc<jobs::JobQueue>,
+    upload_dir: PathBuf,
+    sources: Option<Arc<sources::Scheduler>>,
+    store: Arc<ResilientStore>,
+    // --system-prompt, --top-k and --score-threshold, over the settings of a request's collection
+    settings_flags: collection_settings::CollectionSettings,
+    admin_token: Option<String>,
+    // effective configuration of GET /config
+    config: Value,
+    thinking_budget: Option<usize>,
+    rephrase: bool,
+    max_answer_tokens: Option<usize>,
+    // --num-predict under --num-predict-cap, of answers without a token limit
+    num_predict: Option<i32>,
+    // `chain` answers with `model` and --max-answer-tokens, the other allowed models and token
+    // limits get their chains on first use
+    model: String,
+    allowed_models: Vec<String>,
+    model_chains: Mutex<HashMap<ChainKey, Arc<ConversationalRetrieverChain>>>,
+    new_chain: NewChain,
+    // ollama models reported by /health
+    ollama_client: Arc<OllamaClient>,
+    configured_models: Vec<String>,
+    // --explain, attributing with --model whichever model answered
+    explainer: Option<Arc<explain::Explainer>>,
+    source_fields: Vec<String>,
+    source_format: SourceFormat,
+    answer_lang: language::AnswerLanguage,
+    // --prompt-inject-guard
+    injection_guard: Option<injection::PromptInjectionGuard>,
+    confidence: retrieval::ConfidenceThresholds,
+    // --refusal-message with --refuse-without-sources
+    refusal: Option<String>,
+    // reported by GET /session
+    memory_limits: conversation::MemoryLimits,
+    // --summarize-answers, with the latest summaries listed by GET /session
+    summarizer: Option<Arc<summarize::AnswerSummarizer>>,
+    answer_summaries: Mutex<VecDeque<Value>>,
+}
+
+impl WebState {
+    // -- None for the default model and token limit
+    fn model_chain(
+        &self,
+        model: &str,
+        token_limit: Option<usize>,
+    ) -> Option<Arc<ConversationalRetrieverChain>> {
+        if model == self.model && token_limit == self.max_answer_tokens {
+            return None;
+        }
+        let mut chains = self.model_chains.lock().unwrap();
+        let key = (model.to_string(), token_limit);
+        // -- requests pick any limit, chains of lowered ones are dropped before they pile up
+        if !chains.contains_key(&key) && chains.len() >= MAX_MODEL_CHAINS {
+            chains.retain(|(_, limit), _| *limit == self.max_answer_tokens);
+        }
+        let chain = chains
+            .entry(key)
+            .or_insert_with(|| Arc::new((self.new_chain)(model, token_limit)));
+        Some(chain.clone())
+    }
+
+    // -- num_predict of an answer with the token limit, whether its eval_count was cut short
+    fn truncated(&self, token_limit: Option<usize>, eval_count: u64) -> bool {
+        length::limited(self.num_predict, token_limit)
+            .and_then(|num_predict| u64::try_from(num_predict).ok())
+            .is_some_and(|num_predict| eval_count >= num_predict)
+    }
+
+    // -- read for every request, config-set may change them while the server runs
+    async fn collection_settings(
+        &self,
+        collection: &str,
+    ) -> collection_settings::CollectionSettings {
+        let stored =
+            collection_settings::load_or_warn(self.store.as_ref(), collection, |warning| {
+                log::warn!("{}", warning)
+            })
+            .await;
+        self.settings_flags.clone().or(stored)
+    }
+
+    fn models(&self) -> Vec<String> {
+        let mut models = vec![self.model.clone()];
+        models.extend(
+            self.allowed_models
+                .iter()
+                .filter(|model| **model != self.model)
+                .cloned(),
+        );
+        models
+    }
+}
+
+async fn web(cli: &Cli) {
+    // -- llm
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    // -- built again after qdrant went away, the server answers 503 until it is back
+    let store_cli = cli.clone();
+    let store_client = ollama_client.clone();
+    let resilient_store = Arc::new(ResilientStore::new(Box::new(move || {
+        let cli = store_cli.clone();
+        let client = store_client.clone();
+        Box::pin(async move { open_vector_store(client, &cli).await })
+    })));
+    if let Err(e) = resilient_store.connect().await {
+        output::warning(&e);
+    }
+    let vector_store: Arc<dyn ChunkStore> = resilient_store.clone();
+    let settings_flags = settings_flags(cli);
+    let cli = &with_collection_settings(cli, vector_store.as_ref()).await;
+    if let Some(max) = cli.max_collection_points {
+        capacity::spawn_monitor(
+            vector_store.clone(),
+            max,
+            Duration::from_secs(cli.collection_check_interval_mins.max(1) * 60),
+        );
+    }
+    let chain = chat_chain(
+        ollama_client.clone(),
+        &answer_cli(cli, cli.model.as_deref().unwrap(), cli.max_answer_tokens),
+        vector_store.clone(),
+    );
+    let ingest = Arc::new(Ingest::new(cli));
+    // -- chains of other models and token limits share the store (and its embedder) with the
+    // -- default one
+    let chain_cli = cli.clone();
+    let chain_store = vector_store.clone();
+    let chain_client = ollama_client.clone();
+    let new_chain = Box::new(move |model: &str, token_limit: Option<usize>| {
+        let cli = answer_cli(&chain_cli, model, token_limit);
+        chat_chain(chain_client.clone(), &cli, chain_store.clone())
+    });
+    if cli.anonymize_sources && cli.admin_token.is_none() {
+        log::warn!("--anonymize-sources without --admin-token, source ids can't be resolved");
+    }
+
+    // -- sources are re-ingested periodically when their content changes
+    let sources = match cli.sources.as_deref().map(sources::load_sources) {
+        Some(Ok(sources)) => {
+            let scheduler = Arc::new(sources::Scheduler::new(
+                sources,
+                ingest.clone(),
+                vector_store.clone(),
+                Path::new(&cli.upload_dir).join("sources"),
+            ));
+            scheduler.spawn();
+            Some(scheduler)
+        }
+        Some(Err(e)) => {
+            println!("Invalid --sources: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    // -- uploaded documents are ingested in the background
+    let (job_queue, job_receiver) =
+        jobs::JobQueue::new(Duration::from_secs(cli.job_retention_mins * 60));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let workers = jobs::spawn_workers(
+        job_queue.clone(),
+        job_receiver,
+        cli.ingest_workers,
+        ingest,
+        shutdown_rx,
+    );
+
+    let injection_guard = match cli.prompt_inject_guard.then(|| {
+        injection::PromptInjectionGuard::new(
+            cli.injection_patterns_file.as_deref(),
+            cli.injection_action,
+        )
+    }) {
+        Some(Ok(guard)) => Some(guard),
+        Some(Err(e)) => {
+            println!("Invalid --injection-patterns-file: {}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let web_state = Arc::new(WebState {
+        chain,
+        generations: Mutex::new(HashMap::new()),
+        jobs: job_queue,
+        upload_dir: PathBuf::from(&cli.upload_dir),
+        sources,
+        store: resilient_store,
+        settings_flags,
+        admin_token: cli.admin_token.clone(),
+        config: effective::config(cli),
+        thinking_budget: cli.thinking_budget,
+        rephrase: cli.rephrase == Switch::On,
+        max_answer_tokens: cli.max_answer_tokens,
+        num_predict: length::num_predict(cli.num_predict, cli.num_predict_cap),
+        model: cli.model.clone().unwrap(),
+        allowed_models: cli.allowed_models.clone(),
+        model_chains: Mutex::new(HashMap::new()),
+        new_chain,
+        explainer: explainer(ollama_client.clone(), cli),
+        source_fields: cli.source_fields.clone(),
+        source_format: cli.source_documents_format,
+        answer_lang: cli.answer_lang.clone(),
+        injection_guard,
+        confidence: confidence_thresholds(cli),
+        refusal: cli
+            .refuse_without_sources
+            .then(|| cli.refusal_message.clone()),
+        memory_limits: memory_limits(cli),
+        summarizer: answer_summarizer(ollama_client.clone(), cli),
+        answer_summaries: Mutex::new(VecDeque::new()),
+        ollama_client,
+        configured_models: configured_models(cli, Mode::Web),
+    });
+
+    // -- cancel generations whose client never finished or aborted them
+    let cleanup_state = web_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            cleanup_state.jobs.cleanup();
+            let mut generations = cleanup_state.generations.lock().unwrap();
+            let orphaned: Vec<String> = generations
+                .iter()
+                .filter(|(_, (started, _))| started.elapsed() > GENERATION_TIMEOUT)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in orphaned {
+                log::warn!("cancelling orphaned generation {}", id);
+                if let Some((_, abort)) = generations.remove(&id) {
+                    abort.send(()).ok();
+                }
+            }
+        }
+    });
+
+    let app = Router::new()
+        .route("/", get(web_root_handle))
+        .route(
+            "/chat",
+            post(web_chat_handler).with_state(web_state.clone()),
+        )
+        .route(
+            "/chat/abort",
+            post(web_abort_handler).with_state(web_state.clone()),
+        )
+        .route(
+            "/ingest",
+            post(web_ingest_handler)
+                .layer(DefaultBodyLimit::max(
+                    (cli.max_document_size_mb * 1024 * 1024) as usize,
+                ))
+                .with_state(web_state.clone()),
+        )
+        .route(
+            "/models",
+            get(web_models_handler).with_state(web_state.clone()),
+        )
+        .route("/jobs", get(web_jobs_handler).with_state(web_state.clone()))
+        .route(
+            "/jobs/{id}",
+            get(web_job_handler).with_state(web_state.clone()),
+        )
+        .route(
+            "/sources/{hash}",
+            get(web_source_handler).with_state(web_state.clone()),
+        )
+        .route(
+            "/config",
+            get(web_config_handler).with_state(web_state.clone()),
+        )
+        .route(
+            "/session",
+            get(web_session_handler).with_state(web_state.clone()),
+        )
+        .route("/health", get(web_health_handler).with_state(web_state));
+    let listener = tokio::net::TcpListener::bind(cli.listen.clone().unwrap())
+        .await
+        .unwrap();
+    println!("web listening on {}", listener.local_addr().unwrap());
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .unwrap();
+
+    // -- let the running ingestion jobs finish, queued ones are dropped
+    println!("shutting down, waiting for running ingestion jobs");
+    shutdown_tx.send(true).ok();
+    for worker in workers {
+        worker.await.ok();
+    }
+}
+
+// -------------------------------------
+// -- embedding sanity check: similar sentences must score high, unrelated ones low
+const EMBED_TEST_SIMILAR: (&str, &str) = (
+    "The cat is sleeping on the sofa.",
+    "A cat naps on the couch.",
+);
+const EMBED_TEST_DIFFERENT: (&str, &str) = (
+    "The cat is sleeping on the sofa.",
+    "Quarterly tax returns must be filed by the end of April.",
+);
+const EMBED_TEST_MIN_SIMILAR: f64 = 0.7;
+const EMBED_TEST_MAX_DIFFERENT: f64 = 0.3;
+// embeddings with a norm further from 1.0 aren't normalized by the model
+const EMBED_NORM_TOLERANCE: f64 = 0.01;
+
+// -- runs until stopped, scanning the directory every --watch-interval-secs
+async fn watch(cli: &Cli, directory: &str) {
+    resource_limits(cli);
+    let state = match watcher::WatchState::load(&cli.watch_state_file) {
+        Ok(state) => state,
+        Err(e) => {
+            output::error(&e);
+            std::process::exit(1);
+        }
+    };
+    let ingest = Arc::new(Ingest::new(cli));
+    let store = vector_store(ingest.ollama_client.clone(), cli).await;
+    let (directory, extension) = (directory.to_string(), cli.doc_type.extension());
+    output::note(&format!(
+        "watching {} every {}s",
+        directory, cli.watch_interval_secs
+    ));
+    let watcher = watcher::Watcher {
+        files: Box::new(move || get_files(&directory, extension)),
+        ingest,
+        store,
+        state_file: cli.watch_state_file.clone(),
+        interval: Duration::from_secs(cli.watch_interval_secs.max(1)),
+    };
+    watcher.run(state).await;
+}
+
+// -- chunks stored for the document, sorted by their index
+async fn show(cli: &Cli, path: &str) -> bool {
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let store = vector_store(ollama_client, cli).await;
+    let docs = match store.scroll(&MetadataFilter::path(path), usize::MAX).await {
+        Ok(docs) => docs,
+        Err(e) => {
+            output::error(&e);
+            return false;
+        }
+    };
+    if docs.is_empty() {
+        output::error(&format!("no chunks stored for {}", path));
+        return false;
+    }
+    let sizer = DocumentSizer::new(cli.sizer, detect_language(&docs).map(|info| info.script()));
+    let mut chunks = show::stored_chunks(docs, cli.original, |text| sizer.size(text));
+    if let Some(index) = cli.chunk {
+        chunks.retain(|chunk| chunk.chunk_index == Some(index));
+        if chunks.is_empty() {
+            output::error(&format!("no chunk {} stored for {}", index, path));
+            return false;
+        }
+    }
+    match cli.json {
+        true => println!("{}", serde_json::to_string_pretty(&chunks).unwrap()),
+        false => println!("{}", show::render(&chunks, sizer.unit())),
+    }
+    true
+}
+
+async fn recontextualize(cli: &Cli) -> bool {
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let store = vector_store(ollama_client, cli).await;
+    // -- the chunks to enrich again, by their document
+    let targets: Vec<(String, Option<u64>)> = match (cli.all_failed, &cli.path, cli.chunk) {
+        (true, path, _) => {
+            let mut filter = MetadataFilter {
+                must: vec![("context_rejected".to_string(), json!(true))],
+                ..Default::default()
+            };
+            filter
+                .must
+                .extend(path.iter().map(|p| ("path".to_string(), json!(p))));
+            let failed = match store.scroll(&filter, usize::MAX).await {
+                Ok(failed) => failed,
+                Err(e) => {
+                    output::error(&e);
+                    return false;
+                }
+            };
+            let mut targets: Vec<(String, Option<u64>)> = failed
+                .iter()
+                .filter_map(|doc| {
+                    let path = doc.metadata.get("path")?.as_str()?.to_string();
+                    Some((path, recontext::chunk_index(doc)))
+                })
+                .collect();
+            targets.sort();
+            targets
+        }
+        (false, Some(path), Some(index)) => vec![(path.clone(), Some(index))],
+        _ => {
+            println!("Missing chunk to recontextualize. \nAdd --path [path_to_document] --chunk [index] or --all-failed into aruments.");
+            return false;
+        }
+    };
+    if targets.is_empty() {
+        output::note("No chunk is stored with context_rejected.");
+        return true;
+    }
+
+    let ingest = Ingest::new(cli);
+    let mut ok = true;
+    for (path, index) in targets {
+        let label = format!(
+            "{} chunk {}",
+            path,
+            index.map_or("-".to_string(), |i| i.to_string())
+        );
+        let docs = match store.scroll(&MetadataFilter::path(&path), usize::MAX).await {
+            Ok(docs) => docs,
+            Err(e) => {
+                output::error(&e);
+                return false;
+            }
+        };
+        let Some(target) = docs.iter().find(|doc| recontext::chunk_index(doc) == index) else {
+            output::error(&format!("no chunk stored for {}", label));
+            ok = false;
+            continue;
+        };
+        let version = target.metadata.get("version").cloned();
+        let originals = recontext::originals(docs, version.as_ref());
+        let position = originals
+            .iter()
+            .position(|doc| recontext::chunk_index(doc) == index)
+            .unwrap();
+        match recontext::recontextualize(
+            &ingest,
+            store.as_ref(),
+            &originals,
+            position,
+            cli.hint.as_deref(),
+        )
+        .await
+        {
+            Ok((doc, false)) => {
+                output::note(&format!("{} recontextualized", label));
+                output::detail(&doc.page_content);
+            }
+            Ok((_, true)) => {
+                output::warning(&format!("{} rejected again, the original is kept", label));
+            }
+            Err(e) => {
+                output::error(&format!("{}: {}", label, e));
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+async fn embed_test(cli: &Cli) -> bool {
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    match models::presence(&ollama_client, &configured_models(cli, Mode::Chat)).await {
+        Ok(presence) => {
+            for model in presence {
+                match model.present {
+                    true => println!("✓ model {} pulled", model.name),
+                    false => println!(
+                        "✗ model {} missing, run `ollama pull {}`",
+                        model.name, model.name
+                    ),
+                }
+            }
+        }
+        Err(e) => println!("✗ {}", e),
+    }
+    let embed = cli.embed.clone().unwrap();
+    let embedder = OllamaEmbedder::new(
+        ollama_client,
+        embed.clone(),
+        Some(GenerationOptions::default()),
+    );
+
+    let texts = [
+        EMBED_TEST_SIMILAR.0.to_string(),
+        EMBED_TEST_SIMILAR.1.to_string(),
+        EMBED_TEST_DIFFERENT.0.to_string(),
+        EMBED_TEST_DIFFERENT.1.to_string(),
+    ];
+    let vectors = match embedder.embed_documents(&texts).await {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            println!(
+                "✗ embedding pipeline may be misconfigured: embedding with {} at {} failed: {}",
+                embed,
+                cli.ollama.clone().unwrap(),
+                e
+            );
+            return false;
+        }
+    };
+    if vectors.len() != texts.len() || vectors.iter().any(|v| v.len() != vectors[0].len()) {
+        println!("✗ embedding pipeline may be misconfigured: inconsistent embedding dimensions");
+        return false;
+    }
+
+    let similar = cosine_similarity(&vectors[0], &vectors[1]);
+    let different = cosine_similarity(&vectors[2], &vectors[3]);
+    println!("model {} ({} dimensions)", embed, vectors[0].len());
+    let norm = l2_norm(&vectors[0]);
+    match (norm - 1.0).abs() <= EMBED_NORM_TOLERANCE {
+        true => println!("norm:           {:.3} (normalized)", norm),
+        false if cli.embed_normalize || cli.embed_dimensions.is_some() => {
+            println!("norm:           {:.3} (normalized before storing)", norm)
+        }
+        false => println!(
+            "norm:           {:.3} (not normalized, add --embed-normalize)",
+            norm
+        ),
+    }
+    println!(
+        "similar pair:   {:.3} (expected > {})",
+        similar, EMBED_TEST_MIN_SIMILAR
+    );
+    println!(
+        "different pair: {:.3} (expected < {})",
+        different, EMBED_TEST_MAX_DIFFERENT
+    );
+
+    let healthy = similar > EMBED_TEST_MIN_SIMILAR && different < EMBED_TEST_MAX_DIFFERENT;
+    if healthy {
+        println!("✓ embedding pipeline healthy");
+    } else {
+        println!("✗ embedding pipeline may be misconfigured");
+    }
+    healthy
+}
+
+// -- ollama models a mode runs with
+fn configured_models(cli: &Cli, mode: Mode) -> Vec<String> {
+    let mut models = vec![cli.model.clone().unwrap(), cli.embed.clone().unwrap()];
+    if matches!(mode, Mode::Generate | Mode::Watch | Mode::Recontextualize) {
+        if cli.describe_images {
+            models.push(cli.vision_model.clone());
+        }
+    } else {
+        models.extend(cli.fallback_model.clone());
+        if cli.rerank {
+            models.extend(cli.rerank_model.clone());
+        }
+        models.extend(cli.compression_model.clone());
+        if mode == Mode::Web {
+            models.extend(cli.allowed_models.iter().cloned());
+        }
+    }
+    models.sort();
+    models.dedup();
+    models
+}
+
+// -- warns about an embedding model whose vectors aren't normalized, unless they will be
+async fn check_embedding_norm(cli: &Cli) {
+    if cli.embed_normalize || cli.embed_dimensions.is_some() {
+        return;
+    }
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let embed = cli.embed.clone().unwrap();
+    let embedder = OllamaEmbedder::new(
+        ollama_client,
+        embed.clone(),
+        Some(GenerationOptions::default()),
+    );
+    let norm = match embedder.embed_query(EMBED_TEST_SIMILAR.0).await {
+        Ok(vector) => l2_norm(&vector),
+        Err(e) => {
+            log::warn!("{}, not checking the embedding norm", e);
+            return;
+        }
+    };
+    if (norm - 1.0).abs() > EMBED_NORM_TOLERANCE {
+        output::warning(&format!(
+            "{} embeddings have a norm of {:.3}, not 1.0; add --embed-normalize unless the similarity metric is cosine.",
+            embed, norm
+        ));
+    }
+}
+
+// -- missing models stop the start unless --auto-pull pulls them, an unreachable ollama doesn't
+async fn check_models(cli: &Cli, mode: Mode) {
+    let ollama_client = OllamaClient::from_url(Url::parse(&cli.ollama.clone().unwrap()).unwrap());
+    let presence = match models::presence(&ollama_client, &configured_models(cli, mode)).await {
+        Ok(presence) => presence,
+        Err(e) => {
+            log::warn!("{}, not checking the models", e);
+            return;
+        }
+    };
+    if let Err(e) = models::pull_missing(&ollama_client, &presence, cli.auto_pull).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn mcp(cli: &Cli) {
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let store = vector_store(ollama_client.clone(), cli).await;
+    let cli = &with_collection_settings(cli, store.as_ref()).await;
+    let chain = chat_chain(ollama_client, cli, store.clone());
+
+    log::info!("mcp server listening on stdio");
+    let server = mcp::McpServer {
+        store,
+        anonymize_sources: cli.anonymize_sources,
+        chain,
+        score_threshold: cli.score_threshold.unwrap_or(SCORE_THRESHOLD),
+    };
+    server.serve().await;
+}
+
+async fn slack(cli: &Cli) {
+    let Some(signing_secret) = cli.slack_signing_secret.clone() else {
+        println!("Missing slack credentials. \nAdd --slack-signing-secret (and --slack-bot-token for events) into arguments.");
+        return;
+    };
+    // -- slash commands answer through their response_url, only events need the bot token
+    if cli.slack_bot_token.is_none() {
+        log::warn!("no --slack-bot-token, only slash commands are answered");
+    }
+
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(&cli.ollama.clone().unwrap()).unwrap(),
+    ));
+    let store = vector_store(ollama_client.clone(), cli).await;
+    let chain_cli = with_collection_settings(cli, store.as_ref()).await;
+
+    let slack_state = Arc::new(slack::SlackState::new(
+        cli.slack_bot_token.clone(),
+        signing_secret,
+        Box::new(move || chat_chain(ollama_client.clone(), &chain_cli, store.clone())),
+    ));
+
+    let listener = tokio::net::TcpListener::bind(cli.listen.clone().unwrap())
+        .await
+        .unwrap();
+    println!(
+        "slack events and commands listening on {}",
+        listener.local_addr().unwrap()
+    );
+    axum::serve(listener, slack::router(slack_state))
+        .await
+        .unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatRequest {
+    message: String,
+    // answer length in tokens (num_predict), at most --max-answer-tokens
+    max_tokens: Option<usize>,
+    // one of `GET /models`, --model if not set
+    model: Option<String>,
+    // qdrant filter json restricting the retrieved chunks, on top of --filter-by-payload
+    filters: Option<Value>,
+    // `documents` of the sources event, --source-documents-format if not set
+    source_documents_format: Option<SourceFormat>,
+    // language of the answer, --answer-lang if not set
+    answer_lang: Option<String>,
+    // collection answering with its stored settings and chunks only, --collection if not set
+    collection: Option<String>,
+}
+
+// -- chains of /chat kept for the allowed models and the token limits of requests
+const MAX_MODEL_CHAINS: usize = 32;
+
+// -- flags of a chain answering with the model, the token limit is sent as num_predict
+fn answer_cli(cli: &Cli, model: &str, token_limit: Option<usize>) -> Cli {
+    let mut cli = cli.clone();
+    cli.model = Some(model.to_string());
+    cli.num_predict = length::limited(cli.num_predict, token_limit);
+    cli
+}
+
+// -- answer token limit of a request, None for no limit
+fn answer_token_limit(requested: Option<usize>, ceiling: Option<usize>) -> Option<usize> {
+    match (requested, ceiling) {
+        (Some(requested), Some(ceiling)) => Some(requested.min(ceiling)),
+        (requested, ceiling) => requested.or(ceiling),
+    }
+}
+
+async fn web_chat_handler(
+    State(state): State<Arc<WebState>>,
+    Json(payload): Json<ChatRequest>,
+    // ) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
+) -> Response {
+    if let Some(guard) = &state.injection_guard {
+        if let Some(pattern) = guard.check(&payload.message) {
+            log::warn!(
+                "suspicious question {:?} matches injection pattern {}",
+                payload.message,
+                pattern
+            );
+            if guard.action == injection::InjectionAction::Block {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": "suspicious input detected" })),
+                )
+                    .into_response();
+            }
+        }
+    }
+    if let Some(model) = payload.model.as_deref() {
+        if !state.models().iter().any(|allowed| allowed == model) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "error": format!("model {} is not allowed", model),
+                    "allowed_models": state.models(),
+                })),
+            )
+                .into_response();
+        }
+    }
+    let filter = match payload.filters.as_ref().map(MetadataFilter::from_json) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
+        }
+        None => MetadataFilter::default(),
+    };
+    // -- the request's collection is searched in place of --collection
+    let collection = match payload.collection.clone() {
+        Some(collection) => {
+            let settings = state.collection_settings(&collection).await;
+            Some((collection, settings))
+        }
+        None => None,
+    };
+    let answer_lang = match payload
+        .answer_lang
+        .as_deref()
+        .map(language::parse_answer_language)
+    {
+        Some(Ok(answer_lang)) => answer_lang,
+        Some(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response();
+        }
+        None => state.answer_lang.clone(),
+    };
+    // -- checked up front to answer a plain 503 while the store is known to be down
+    if let Err(e) = state.store.connect().await {
+        log::warn!("{}", e);
+        return store_unavailable();
+    }
+    let (tx, rx) = mpsc::channel(10);
+    println!("{:?} - user message", payload);
+    let state = Arc::clone(&state);
+    let query = payload.message;
+    let model = payload.model.unwrap_or_else(|| state.model.clone());
+    let token_limit = answer_token_limit(payload.max_tokens, state.max_answer_tokens);
+    let model_chain = state.model_chain(&model, token_limit);
+    let source_format = payload
+        .source_documents_format
+        .unwrap_or(state.source_format);
+
+    // -- the generation id is the first event so the client can abort it
+    let generation_id = Uuid::new_v4().to_string();
+    let (abort_tx, mut abort_rx) = oneshot::channel::<()>();
+    state
+        .generations
+        .lock()
+        .unwrap()
+        .insert(generation_id.clone(), (Instant::now(), abort_tx));
+    tx.send(wire::event(
+        "generation",
+        json!({ "generation_id": generation_id }),
+    ))
+    .await
+    .ok();
+
+    tokio::spawn(async move {
+        let input_variables = prompt_args! {
+            "question" => &query,
+        };
+        let mut thinking = state.thinking_budget.map(ThinkingSplitter::new);
+        let aborted = || wire::event("aborted", json!({ "generation_id": generation_id }));
+
+        let chain = model_chain.as_deref().unwrap_or(&state.chain);
+        // -- --refuse-without-sources: the model isn't asked without a chunk to answer from
+        let (stream, retrieval) = tokio::select! {
+            (stream, retrieval) = retrieval::recording(language::answering(
+                answer_lang.clone(),
+                retrieval::filtered(
+                    filter,
+                    retrieval::refusing(
+                        state.refusal.clone(),
+                        state.confidence,
+                        with_request_settings(collection, chain.stream(input_variables)),
+                    ),
+                ),
+            )) => {
+                if let Some(retrieval) = &retrieval {
+                    let event = sources_event(
+                        retrieval,
+                        state.rephrase,
+                        &state.source_fields,
+                        source_format,
+                        &answer_lang,
+                        state.confidence,
+                    );
+                    tx.send(event).await.ok();
+                }
+                (stream, retrieval)
+            }
+            _ = &mut abort_rx => {
+                tx.send(aborted()).await.ok();
+                return;
+            }
+        };
+        let mut truncated = false;
+        let mut answer = String::new();
+        match stream {
+            Ok(mut stream) => loop {
+                tokio::select! {
+                    _ = &mut abort_rx => {
+                        tx.send(aborted()).await.ok();
+                        state.generations.lock().unwrap().remove(&generation_id);
+                        return;
+                    }
+                    result = stream.next() => match result {
+                        Some(Ok(data)) => {
+                            // -- ollama stopped at num_predict
+                            if let Some(eval_count) = wire::eval_count(&data.value) {
+                                truncated = state.truncated(token_limit, eval_count);
+                            }
+                            let Some(text) = wire::stream_text(&data.value, &data.content) else {
+                                continue;
+                            };
+                            answer.push_str(&text);
+                            let events = match thinking.as_mut() {
+                                Some(thinking) => thinking.events(&text),
+                                None => vec![wire::token(&text)],
+                            };
+                            let mut sent = true;
+                            for event in events {
+                                sent = sent && tx.send(event).await.is_ok();
+                            }
+                            if !sent {
+                                // -- client went away, stop generating
+                                break;
+                            }
+                        }
+                        Some(Err(e)) => {
+                            println!("Error: {:?}", e);
+                        }
+                        None => break,
+                    },
+                }
+            },
+            // -- the store went away after the check
+            Err(ChainError::RetrieverError(e)) => {
+                log::warn!("{}", e);
+                tx.send(wire::event(
+                    "error",
+                    json!({
+                        "generation_id": generation_id,
+                        "error": store::UNAVAILABLE,
+                        "status": StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                    }),
+                ))
+                .await
+                .ok();
+            }
+            Err(e) => {
+                println!("Error: {:?}", e);
+            }
+        }
+        state.generations.lock().unwrap().remove(&generation_id);
+        let explained = state
+            .explainer
+            .as_ref()
+            .zip(retrieval.filter(|r| !r.documents.is_empty()));
+        if let Some((explainer, retrieval)) = explained {
+            // -- the thinking of reasoning models isn't part of the answer
+            let answer = answer.rsplit(THINK_END).next().unwrap_or_default().trim();
+            match explainer.attribute(answer, &retrieval.documents).await {
+                Ok(attributions) => {
+                    tx.send(wire::event(
+                        "attribution",
+                        json!({
+                            "generation_id": generation_id,
+                            "attributions": attributions,
+                        }),
+                    ))
+                    .await
+                    .ok();
+                }
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        tx.send(wire::event(
+            "done",
+            json!({
+                "generation_id": generation_id,
+                "truncated": truncated,
+                "answer_lang": answer_lang.name(),
+            }),
+        ))
+        .await
+        .ok();
+        // -- after `done`, the client has the whole answer already
+        drop(tx);
+        let answer = answer.rsplit(THINK_END).next().unwrap_or_default().trim();
+        if let Some(summarizer) = state.summarizer.as_ref().filter(|_| !answer.is_empty()) {
+            let summary = summarizer.summarize(answer).await;
+            log::info!("{} - answer summary: {}", generation_id, summary);
+            let mut summaries = state.answer_summaries.lock().unwrap();
+            if summaries.len() == summarize::SESSION_SUMMARIES {
+                summaries.pop_front();
+            }
+            summaries.push_back(json!({
+                "generation_id": generation_id,
+                "timestamp": jobs::unix_now(),
+                "model": model,
+                "question": query,
+                "summary": summary,
+            }));
+        }
+    });
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
+
+fn confidence_thresholds(cli: &Cli) -> retrieval::ConfidenceThresholds {
+    retrieval::ConfidenceThresholds {
+        high_score: cli.confidence_high_score,
+        high_hits: cli.confidence_high_hits,
+    }
+}
+
+// -- citations of the sources event, the metadata objects themselves for `full`
+fn source_documents(documents: &[Document], fields: &[String], format: SourceFormat) -> Vec<Value> {
+    let documents = serde_json::to_value(documents).unwrap_or_default();
+    match format {
+        SourceFormat::Full => {
+            let mut metadata: Vec<Value> = vec![];
+            for document in documents.as_array().into_iter().flatten() {
+                if !metadata.contains(&document["metadata"]) {
+                    metadata.push(document["metadata"].clone());
+                }
+            }
+            metadata
+        }
+        format => source_labels(&documents, fields, format)
+            .into_iter()
+            .map(Value::String)
+            .collect(),
+    }
+}
+
+// -- documents the answer is generated from, sent before the answer
+fn sources_event(
+    retrieval: &retrieval::Retrieval,
+    rephrase: bool,
+    fields: &[String],
+    format: SourceFormat,
+    answer_lang: &language::AnswerLanguage,
+    confidence: retrieval::ConfidenceThresholds,
+) -> Result<Event, axum::Error> {
+    let sources: Vec<Value> = retrieval
+        .documents
+        .iter()
+        .map(|d| {
+            json!({
+                "path": d.metadata.get("path"),
+                "collection": d.metadata.get("collection"),
+                "speakers": d.metadata.get("speakers"),
+                "keywords": d.metadata.get("keywords"),
+                "score": d.score,
+                "injection_suspect": d.metadata.get("injection_suspect"),
+                "metadata": fields
+                    .iter()
+                    .filter_map(|field| Some((field.clone(), d.metadata.get(field)?.clone())))
+                    .collect::<serde_json::Map<_, _>>(),
+            })
+        })
+        .collect();
+    wire::event(
+        "sources",
+        json!({
+            "rephrased_question": rephrase.then_some(&retrieval.question),
+            "retrieval": if retrieval.relaxed { "relaxed" } else { "strict" },
+            "confidence": retrieval.confidence(confidence).name(),
+            "top_score": retrieval.top_score(),
+            "sources": sources,
+            "documents": source_documents(&retrieval.documents, fields, format),
+            "answer_lang": answer_lang.name(),
+        }),
+    )
+}
+
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+
+// -- splits the `<think>...</think>` reasoning of reasoning models out of the answer stream
+struct ThinkingSplitter {
+    // thinking tokens (stream chunks) still sent to the client
+    budget: usize,
+    thinking: bool,
+}
+
+impl ThinkingSplitter {
+    fn new(budget: usize) -> Self {
+        ThinkingSplitter {
+            budget,
+            thinking: false,
+        }
+    }
+
+    // -- `thinking` events for the reasoning, `token` events with the rest of the chunk
+    fn events(&mut self, content: &str) -> Vec<Result<Event, axum::Error>> {
+        if !self.thinking && !content.contains(THINK_START) {
+            return vec![wire::token(content)];
+        }
+
+        let mut events = vec![];
+        let mut rest = content;
+        while !rest.is_empty() {
+            let tag = if self.thinking {
+                THINK_END
+            } else {
+                THINK_START
+            };
+            let (part, after) = match rest.find(tag) {
+                Some(at) => (&rest[..at], Some(&rest[at + tag.len()..])),
+                None => (rest, None),
+            };
+            if self.thinking && !part.is_empty() && self.budget > 0 {
+                self.budget -= 1;
+                events.push(wire::event("thinking", json!({ "content": part })));
+            } else if !self.thinking && !part.trim().is_empty() {
+                events.push(wire::token(part));
+            }
+            match after {
+                Some(after) => {
+                    self.thinking = !self.thinking;
+                    rest = after;
+                }
+                None => break,
+            }
+        }
+        events
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct AbortRequest {
+    generation_id: String,
+}
+
+async fn web_abort_handler(
+    State(state): State<Arc<WebState>>,
+    Json(payload): Json<AbortRequest>,
+) -> Json<Value> {
+    let generation = state
+        .generations
+        .lock()
+        .unwrap()
+        .remove(&payload.generation_id);
+    match generation {
+        Some((_, abort)) => {
+            abort.send(()).ok();
+            Json(json!({ "aborted": true, "already_finished": false }))
+        }
+        None => Json(json!({ "aborted": false, "already_finished": true })),
+    }
+}
+
+// -- multipart upload, every pdf file field becomes one ingestion job
+async fn web_ingest_handler(
+    State(state): State<Arc<WebState>>,
+    mut multipart: Multipart,
+) -> Response {
+    let mut queued = vec![];
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": e.body_text() })),
+                )
+                    .into_response()
+            }
+        };
+        // -- only the base name is used so uploads can't escape the upload dir
+        let Some(file_name) = field
+            .file_name()
+            .and_then(|name| Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().to_string())
+        else {
+            continue;
+        };
+        if !file_name.to_lowercase().ends_with(".pdf") {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("{} is not a pdf document", file_name) })),
+            )
+                .into_response();
+        }
+        let data = match field.bytes().await {
+            Ok(data) => data,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": e.body_text() })),
+                )
+                    .into_response()
+            }
+        };
+
+        let path = state.upload_dir.join(&file_name);
+        if let Err(e) = fs::create_dir_all(&state.upload_dir).and_then(|_| fs::write(&path, &data))
+        {
+            log::error!("storing upload {:?} failed: {:?}", path, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "storing the uploaded document failed" })),
+            )
+                .into_response();
+        }
+        queued.push(state.jobs.enqueue(path.to_string_lossy().to_string()));
+    }
+
+    if queued.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "no document uploaded" })),
+        )
+            .into_response();
+    }
+    (StatusCode::ACCEPTED, Json(json!({ "jobs": queued }))).into_response()
+}
+
+async fn web_jobs_handler(State(state): State<Arc<WebState>>) -> Json<Value> {
+    Json(json!({ "jobs": state.jobs.list() }))
+}
+
+async fn web_job_handler(
+    State(state): State<Arc<WebState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Response {
+    match state.jobs.get(&id) {
+        Some(job) => Json(job).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "unknown job" })),
+        )
+            .into_response(),
+    }
+}
+
+// -- the admin endpoints need `Authorization: Bearer <--admin-token>`, the 401 otherwise
+fn unauthorized(state: &WebState, headers: &HeaderMap) -> Option<Response> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (&state.admin_token, token) {
+        (Some(admin_token), Some(token)) if admin_token == token => None,
+        _ => Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "admin token required" })),
+            )
+                .into_response(),
+        ),
+    }
+}
+
+// -- effective configuration, for admins only
+async fn web_config_handler(State(state): State<Arc<WebState>>, headers: HeaderMap) -> Response {
+    match unauthorized(&state, &headers) {
+        Some(response) => response,
+        None => Json(state.config.clone()).into_response(),
+    }
+}
+
+// -- real path of an anonymized `[src:<hash>]` source, for admins only
+async fn web_source_handler(
+    State(state): State<Arc<WebState>>,
+    headers: HeaderMap,
+    UrlPath(hash): UrlPath<String>,
+) -> Response {
+    if let Some(response) = unauthorized(&state, &headers) {
+        return response;
+    }
+
+    let filter = MetadataFilter {
+        must: vec![("source_id".to_string(), json!(hash))],
+        ..Default::default()
+    };
+    match state.store.scroll(&filter, 1).await {
+        Ok(docs) => match docs.first().and_then(|doc| doc.metadata.get("path")) {
+            Some(path) => Json(json!({ "source_id": hash, "path": path })).into_response(),
+            None => (
+                StatusCode::NOT_FOUND,
+                Json(json!({ "error": "unknown source" })),
+            )
+                .into_response(),
+        },
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e })),
+        )
+            .into_response(),
+    }
+}
+
+// -- failing source refreshes don't stop the server from answering, it is only degraded
+async fn web_health_handler(State(state): State<Arc<WebState>>) -> Json<Value> {
+    let sources = state
+        .sources
+        .as_ref()
+        .map(|scheduler| scheduler.status())
+        .unwrap_or_default();
+    let models = models::presence(&state.ollama_client, &state.configured_models).await;
+    let store_error = state.store.probe().await;
+    let degraded = store_error.is_some()
+        || sources.iter().any(|source| source.error.is_some())
+        || models
+            .as_ref()
+            .map_or(true, |models| models.iter().any(|model| !model.present));
+    Json(json!({
+        "status": if degraded { "degraded" } else { "ok" },
+        "store": match store_error {
+            Some(e) => json!({ "status": "unavailable", "error": e }),
+            None => json!({ "status": "ok" }),
+        },
+        "sources": sources,
+        "models": match models {
+            Ok(models) => json!(models),
+            Err(e) => json!({ "error": e }),
+        },
+        // -- how the `confidence` of the sources event is bucketed
+        "config": {
+            "confidence": {
+                "high_score": state.confidence.high_score,
+                "high_hits": state.confidence.high_hits,
+            },
+            "refuse_without_sources": state.refusal.is_some(),
+        },
+    }))
+}
+
+fn store_unavailable() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({ "error": store::UNAVAILABLE })),
+    )
+        .into_response()
+}
+
+async fn memory_size(
+    chain: &ConversationalRetrieverChain,
+    tokenizer: &CoreBPE,
+) -> conversation::MemorySize {
+    let messages = chain.memory.lock().await.messages();
+    conversation::size(tokenizer, &messages)
+}
+
+// -- size of the conversation memory of every model's and token limit's chain
+async fn web_session_handler(State(state): State<Arc<WebState>>) -> Json<Value> {
+    let tokenizer = cl100k_base().unwrap();
+    let mut chains = vec![(
+        state.model.clone(),
+        state.max_answer_tokens,
+        memory_size(&state.chain, &tokenizer).await,
+    )];
+    let model_chains: Vec<(String, Option<usize>, Arc<ConversationalRetrieverChain>)> = state
+        .model_chains
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((model, limit), chain)| (model.clone(), *limit, chain.clone()))
+        .collect();
+    for (model, limit, chain) in model_chains {
+        chains.push((model, limit, memory_size(&chain, &tokenizer).await));
+    }
+    Json(json!({
+        "memory": chains
+            .into_iter()
+            .map(|(model, limit, size)| json!({
+                "model": model,
+                "max_tokens": limit,
+                "turns": size.turns,
+                "tokens": size.tokens,
+            }))
+            .collect::<Vec<_>>(),
+        "limits": {
+            "max_turns": state.memory_limits.max_turns,
+            "max_tokens": state.memory_limits.max_tokens,
+            "max_age_mins": state.memory_limits.max_age.map(|age| age.as_secs() / 60),
+        },
+        // -- with --summarize-answers, oldest first
+        "answers": state.answer_summaries.lock().unwrap().iter().collect::<Vec<_>>(),
+    }))
+}
+
+// -- models a chat request may ask for, the first one is the default
+async fn web_models_handler(State(state): State<Arc<WebState>>) -> Json<Value> {
+    Json(json!({ "models": state.models() }))
+}
+
+async fn web_root_handle() -> axum::response::Html<&'static str> {
+    axum::response::Html(include_str!("./html/index.html"))
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    output::init(cli.color, cli.quiet);
+    if let Some(text) = &cli.test_prompt {
+        test_prompt(&cli, text).await;
+        return;
+    }
+    let Some(mode) = cli.mode else {
+        return;
+    };
+    // -- the offline selftest starts its own mock of ollama
+    if !(matches!(
+        mode,
+        Mode::EmbedTest
+            | Mode::Show
+            | Mode::ReindexPayload
+            | Mode::ConfigShow
+            | Mode::ConfigSet
+            | Mode::Completions
+            | Mode::Man
+    ) || (mode == Mode::Selftest && cli.offline))
+    {
+        check_models(&cli, mode).await;
+        check_embedding_norm(&cli).await;
+    }
+    match mode {
+        Mode::Chat => {
+            chat(&cli).await;
+        }
+        Mode::Generate => {
+            if cli.document.is_none() {
+                println!("Missing document for generating chunks. \nAdd --document [path_to_document] into aruments.");
+                return;
+            }
+            generate(&cli).await;
+        }
+        Mode::Watch => {
+            let Some(directory) = cli.document.as_deref().filter(|d| Path::new(d).is_dir()) else {
+                println!("Missing directory to watch. \nAdd --document [path_to_directory] into aruments.");
+                return;
+            };
+            watch(&cli, directory).await;
+        }
+        Mode::Web => {
+            web(&cli).await;
+        }
+        Mode::Mcp => {
+            mcp(&cli).await;
+        }
+        Mode::Slack => {
+            slack(&cli).await;
+        }
+        Mode::EmbedTest => {
+            if !embed_test(&cli).await {
+                std::process::exit(1);
+            }
+        }
+        Mode::Show => {
+            let Some(path) = &cli.path else {
+                println!(
+                    "Missing path of the document. \nAdd --path [path_to_document] into aruments."
+                );
+                return;
+            };
+            if !show(&cli, path).await {
+                std::process::exit(1);
+            }
+        }
+        Mode::Recontextualize => {
+            if !recontextualize(&cli).await {
+                std::process::exit(1);
+            }
+        }
+        Mode::ReindexPayload => {
+            if local_db(cli.db.as_deref().unwrap()) {
+                println!(
+                    "Payload indexes are qdrant's, --db {} has none.",
+                    cli.db.unwrap()
+                );
+                return;
+            }
+            if let Err(e) = payload_indexes(&cli).await {
+                output::error(&e);
+                std::process::exit(1);
+            }
+        }
+        Mode::Rephrase => {
+            let Some(question) = &cli.question else {
+                println!("Missing question to rephrase. \nAdd --question [text] into aruments.");
+                return;
+            };
+            if !rephrase(&cli, question).await {
+                std::process::exit(1);
+            }
+        }
+        Mode::ConfigShow => {
+            let config = effective::config(&cli);
+            println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        }
+        Mode::ConfigSet => {
+            let Some(collection) = &cli.collection else {
+                println!(
+                    "Missing collection of the setting. \nAdd --collection [name] into aruments."
+                );
+                return;
+            };
+            let [key, value] = cli.setting.as_slice() else {
+                println!(
+                    "Missing setting. \nAdd [key] [value] into aruments, key one of {}.",
+                    collection_settings::KEYS.join(", ")
+                );
+                return;
+            };
+            if !config_set(&cli, collection, key, value).await {
+                std::process::exit(1);
+            }
+        }
+        Mode::Completions => {
+            let Some(shell) = cli.shell else {
+                println!("Missing shell for the completions. \nAdd --shell [bash|zsh|fish|powershell|elvish] into aruments.");
+                return;
+            };
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+        }
+        Mode::Man => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut std::io::stdout())
+                .unwrap();
+        }
+        Mode::Selftest => {
+            if !selftest::selftest(&cli).await {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::Stream;
+    use langchain_rust::{
+        embedding::EmbedderError,
+        language_models::{GenerateResult, LLMError},
+        schemas::StreamData,
+    };
+    use std::pin::Pin;
+
+    #[tokio::test]
+    async fn unavailable_store_is_a_503_with_a_json_error() {
+        let response = store_unavailable();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": "knowledge base temporarily unavailable" })
+        );
+    }
+
+    #[test]
+    fn neighbours_keep_the_sentences_nearest_the_chunk() {
+        let chars = |text: &str| text.chars().count();
+        let previous = "Aaaa aaaa. Bbbb bbbb.\nCccc cccc.";
+        let next = "Dddd dddd. Eeee eeee. Ffff ffff.";
+        assert_eq!(
+            trimmed_neighbours(previous, next, 40, chars),
+            (
+                "Bbbb bbbb. Cccc cccc.".to_string(),
+                "Dddd dddd. Eeee eeee.".to_string()
+            )
+        );
+        // -- the short side leaves the rest of the budget to the other
+        assert_eq!(
+            trimmed_neighbours("Xxx.", next, 30, chars),
+            ("Xxx.".to_string(), "Dddd dddd. Eeee eeee.".to_string())
+        );
+        assert_eq!(
+            trimmed_neighbours(previous, next, 5, chars),
+            (String::new(), String::new())
+        );
+    }
+
+    #[test]
+    fn source_labels_of_missing_or_non_list_documents_are_empty() {
+        assert!(source_labels(&Value::Null, &[], SourceFormat::Path).is_empty());
+        assert!(source_labels(&json!({ "path": "a.pdf" }), &[], SourceFormat::Path).is_empty());
+        assert!(source_labels(&json!("a.pdf"), &[], SourceFormat::Path).is_empty());
+    }
+
+    #[test]
+    fn source_labels_follow_the_source_documents_format() {
+        let docs = json!([
+            { "metadata": { "path": "a.pdf", "page": 2, "keywords": ["dovolená"] } },
+            { "metadata": { "path": "a.pdf", "page": 5 } },
+            { "metadata": { "path": "a.pdf", "page": 2, "keywords": ["dovolená"] } },
+        ]);
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::Path),
+            vec!["\"a.pdf\"".to_string()]
+        );
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::PathPage),
+            vec![
+                "\"a.pdf\" (page 2) (keywords: dovolená)".to_string(),
+                "\"a.pdf\" (page 5)".to_string()
+            ]
+        );
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::Full),
+            vec![
+                r#"{"keywords":["dovolená"],"page":2,"path":"a.pdf"}"#.to_string(),
+                r#"{"page":5,"path":"a.pdf"}"#.to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn source_labels_mark_documents_without_path() {
+        let docs = json!([
+            { "metadata": { "path": "a.pdf" }, "score": 0.8 },
+            { "metadata": {}, "score": 0.7 },
+            { "metadata": { "path": 42 } },
+            { "page_content": "no metadata at all" },
+            "not a document",
+        ]);
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::Path),
+            vec!["\"a.pdf\"".to_string(), UNKNOWN_SOURCE.to_string()]
+        );
+    }
+
+    #[test]
+    fn source_labels_keep_collection_and_deduplicate() {
+        let docs = json!([
+            { "metadata": { "path": "b.pdf", "collection": { "name": "HR" } } },
+            { "metadata": { "path": "a.pdf" } },
+            { "metadata": { "path": "b.pdf", "collection": { "name": "HR" } } },
+        ]);
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::Path),
+            vec!["\"a.pdf\"".to_string(), "\"b.pdf\" [HR]".to_string()]
+        );
+    }
+
+    #[test]
+    fn source_labels_show_the_selected_fields() {
+        let docs = json!([
+            { "metadata": { "path": "a.pdf", "department": "HR", "directive": 12, "draft": true } },
+            { "metadata": { "path": "b.pdf" } },
+        ]);
+        let fields = ["department".to_string(), "directive".to_string()];
+        assert_eq!(
+            source_labels(&docs, &fields, SourceFormat::Path),
+            vec![
+                "\"a.pdf\" {department: HR, directive: 12}".to_string(),
+                "\"b.pdf\"".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn parallel_documents_are_summarized_in_document_order() {
+        let url = selftest::mock_ollama().await.unwrap();
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--ollama",
+            &url,
+            "--db",
+            "memory",
+            "--model",
+            "selftest",
+            "--embed",
+            "selftest",
+            "--num-workers",
+            "3",
+            // -- the memory store is shared by the tests of the process
+            "--dry-embed",
+            "generate",
+        ]);
+        let ingest = Arc::new(Ingest::new(&cli));
+        let mut prepared = vec![];
+        for name in ["a", "b"] {
+            let path =
+                std::env::temp_dir().join(format!("workers-{}-{}.pdf", name, Uuid::new_v4()));
+            fs::write(&path, selftest::FIXTURE_PDF).unwrap();
+            let path = path.to_string_lossy().to_string();
+            prepared.push((path.clone(), ingest.prepare_document(&path).await));
+            fs::remove_file(&path).unwrap();
+        }
+        prepared.insert(1, ("big.pdf".to_string(), Err("too large".to_string())));
+        let paths: Vec<String> = prepared.iter().map(|(path, _)| path.clone()).collect();
+
+        let ingested = contextualize_all(ingest, prepared, cli.num_workers).await;
+        assert_eq!(
+            ingested
+                .iter()
+                .map(|(path, _, _)| path.clone())
+                .collect::<Vec<_>>(),
+            paths
+        );
+        assert!(matches!(&ingested[1].1, IngestOutcome::Skipped(reason) if reason == "too large"));
+        assert!(matches!(&ingested[0].1, IngestOutcome::Stored(stats) if stats.chunks > 0));
+        assert!(matches!(&ingested[2].1, IngestOutcome::Stored(stats) if stats.chunks > 0));
+    }
+
+    #[tokio::test]
+    async fn strict_enrichment_fails_the_document() {
+        let url = selftest::mock_ollama().await.unwrap();
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--ollama",
+            &url,
+            "--db",
+            "memory",
+            "--model",
+            "selftest",
+            "--embed",
+            "selftest",
+            // -- the mock answers with the chunk itself, always rejected
+            "--min-enrichment-ratio",
+            "2",
+            "--strict-enrichment",
+            "--dry-embed",
+            "generate",
+        ]);
+        let ingest = Ingest::new(&cli);
+        let path = std::env::temp_dir().join(format!("strict-{}.pdf", Uuid::new_v4()));
+        fs::write(&path, selftest::FIXTURE_PDF).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let outcome = ingest
+            .ingest_document(&path, &HashMap::new(), &|_, _| {})
+            .await;
+        fs::remove_file(&path).unwrap();
+        match outcome {
+            IngestOutcome::Failed(e) => assert!(e.contains("invalid enriched chunk"), "{}", e),
+            _ => panic!("the document wasn't failed"),
+        }
+        assert!(ingest.dry_embedded.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreadable_pdf_is_skipped_with_its_reason() {
+        let url = selftest::mock_ollama().await.unwrap();
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--ollama",
+            &url,
+            "--db",
+            "memory",
+            "--model",
+            "selftest",
+            "--embed",
+            "selftest",
+            "--dry-embed",
+            "generate",
+        ]);
+        let ingest = Ingest::new(&cli);
+        let path = std::env::temp_dir().join(format!("broken-{}.pdf", Uuid::new_v4()));
+        fs::write(&path, b"not a pdf").unwrap();
+        let path = path.to_string_lossy().to_string();
+        let outcome = ingest
+            .ingest_document(&path, &HashMap::new(), &|_, _| {})
+            .await;
+        fs::remove_file(&path).unwrap();
+        match outcome {
+            IngestOutcome::Skipped(reason) => {
+                assert!(reason.contains("reading the pdf failed"), "{}", reason)
+            }
+            _ => panic!("the document wasn't skipped"),
+        }
+    }
+
+    // -- fails batches of several chunks and every chunk containing `busy`
+    struct BusyStore(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl ChunkStore for BusyStore {
+        async fn add_documents(&self, docs: &[Document]) -> Result<(), String> {
+            if docs.len() > 1 || docs[0].page_content.contains("busy") {
+                return Err("model busy".to_string());
+            }
+            self.0.lock().unwrap().push(docs[0].page_content.clone());
+            Ok(())
+        }
+
+        async fn similarity_search_with_vectors(
+            &self,
+            _: &str,
+            _: usize,
+            _: f32,
+            _: &MetadataFilter,
+        ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+            Ok(vec![])
+        }
+
+        async fn delete(&self, _: &MetadataFilter) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn scroll(&self, _: &MetadataFilter, _: usize) -> Result<Vec<Document>, String> {
+            Ok(vec![])
+        }
+
+        async fn count(&self) -> Result<u64, String> {
+            Ok(self.0.lock().unwrap().len() as u64)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chunks_of_a_failed_batch_are_stored_one_by_one() {
+        let cli = Cli::parse_from(["chunk_contextor", "generate"]);
+        let ingest = Ingest::new(&cli);
+        let store = BusyStore(Mutex::new(vec![]));
+        let chunks: Vec<Document> = ["první", "busy", "třetí"]
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| {
+                Document::new(text)
+                    .with_metadata(HashMap::from([("chunk_index".to_string(), json!(index))]))
+            })
+            .collect();
+        assert!(store.add_documents(&chunks).await.is_err());
+
+        let unstored = ingest.store_one_by_one(&store, "a.pdf", &chunks).await;
+        assert_eq!(unstored, 1);
+        assert_eq!(*store.0.lock().unwrap(), vec!["první", "třetí"]);
+        let recorded = ingest.unstored.lock().unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].chunk_index, Some(1));
+        assert_eq!(unstored_list(&recorded), "  a.pdf chunk 1: model busy");
+    }
+
+    #[test]
+    fn chunk_preview_samples_distinct_chunks_in_order() {
+        use rand::SeedableRng;
+        let originals = ["první\nčást", "druhá", "třetí", "čtvrtá"];
+        let enriched = ["K: první část", "K: druhá", "K: třetí", "K: čtvrtá"];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let preview = chunk_preview(&originals, &enriched, 3, &mut rng);
+        let lines: Vec<&str> = preview.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let mut positions: Vec<usize> = lines
+            .iter()
+            .map(|line| line[1..line.find('/').unwrap()].parse().unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        positions.dedup();
+        assert_eq!(positions.len(), 3);
+        assert!(lines.iter().all(|line| line.contains(" | ENRICHED: K: ")));
+
+        let all = chunk_preview(&originals, &enriched, 10, &mut rng);
+        assert!(all.starts_with("[1/4] ORIGINAL: první část | ENRICHED: K: první část"));
+        assert_eq!(all.lines().count(), 4);
+    }
+
+    #[test]
+    fn dry_embedded_vectors_are_cut_to_the_dimensions() {
+        let chunks =
+            [Document::new("a").with_metadata(HashMap::from([("page".to_string(), json!(2))]))];
+        assert_eq!(
+            dry_embedded(&chunks, vec![vec![0.5, 0.25, 0.125]], Some(2)),
+            vec![json!({ "text": "a", "vector": [0.5, 0.25], "metadata": { "page": 2 } })]
+        );
+        assert_eq!(
+            dry_embedded(&chunks, vec![vec![0.5, 0.25, 0.125]], None)[0]["vector"],
+            json!([0.5, 0.25, 0.125])
+        );
+    }
+
+    #[test]
+    fn source_labels_name_the_speakers_of_transcripts() {
+        let docs = json!([
+            { "metadata": { "path": "porada.txt", "speakers": ["Jana", "Petr"] } },
+            { "metadata": { "path": "a.pdf", "speakers": [] } },
+        ]);
+        assert_eq!(
+            source_labels(&docs, &[], SourceFormat::Path),
+            vec![
+                "\"a.pdf\"".to_string(),
+                "\"porada.txt\" (speakers: Jana, Petr)".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn sidecar_metadata_is_read_next_to_the_document() {
+        let dir = std::env::temp_dir().join(format!("sidecar-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let toml_doc = dir.join("a.pdf").to_string_lossy().to_string();
+        let json_doc = dir.join("b.pdf").to_string_lossy().to_string();
+        fs::write(
+            format!("{}.meta.toml", toml_doc),
+            "department = \"HR\"\neffective = 2024-03-01\n",
+        )
+        .unwrap();
+        fs::write(format!("{}.meta.json", json_doc), r#"{"directive": 12}"#).unwrap();
+
+        let metadata = sidecar_metadata(&toml_doc);
+        assert_eq!(metadata["department"], json!("HR"));
+        assert_eq!(metadata["effective"], json!("2024-03-01"));
+        assert_eq!(sidecar_metadata(&json_doc)["directive"], json!(12));
+        assert!(sidecar_metadata(&dir.join("c.pdf").to_string_lossy()).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(
+            parse_meta("owner=Jana = HR"),
+            Ok(("owner".to_string(), "Jana = HR".to_string()))
+        );
+        assert!(parse_meta("=x").is_err());
+        assert!(parse_meta("owner").is_err());
+    }
+
+    #[test]
+    fn dynamic_windows_fit_the_target_size() {
+        assert_eq!(dynamic_neighbours(1024, &[512, 500, 520]), 2);
+        assert_eq!(dynamic_neighbours(1024, &[80, 100, 120]), 10);
+        assert_eq!(dynamic_neighbours(1024, &[2000, 1500]), 1);
+        assert_eq!(dynamic_neighbours(1024, &[]), WINDOW_NEIGHBOURS);
+
+        let chunks: Vec<Document> = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(Document::new)
+            .collect();
+        let input = window_input(&chunks, 2, 1);
+        assert_eq!(input["previous_chunks"], json!("b"));
+        assert_eq!(input["next_chunks"], json!("d"));
+        let input = window_input(&chunks, 1, 3);
+        assert_eq!(input["previous_chunks"], json!("a"));
+        assert_eq!(input["next_chunks"], json!("c\nd\ne"));
+    }
+
+    #[test]
+    fn window_prompts_of_doc_types_fall_back_to_the_default() {
+        let dir = std::env::temp_dir().join(format!("prompts-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("context_chunk.directive.txt"),
+            "směrnice {{input}}",
+        )
+        .unwrap();
+        let prompts_dir = dir.to_str();
+        let directive = parse_doc_type("Directive").unwrap();
+        let contract = parse_doc_type("contract").unwrap();
+
+        assert_eq!(directive, DocType::Named("directive".to_string()));
+        assert_eq!(directive.extension(), "pdf");
+        assert_eq!(
+            load_window_template(prompts_dir, &directive),
+            "směrnice {{input}}"
+        );
+        assert_eq!(
+            load_window_template(prompts_dir, &contract),
+            config::CONTEXT_CHUNK_STR
+        );
+        assert_eq!(
+            load_window_template(None, &DocType::Transcript),
+            config::TRANSCRIPT_CHUNK_STR
+        );
+        fs::write(dir.join("context_chunk.txt"), "výchozí {{input}}").unwrap();
+        assert_eq!(
+            load_window_template(prompts_dir, &contract),
+            "výchozí {{input}}"
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(parse_doc_type("transcript"), Ok(DocType::Transcript));
+        assert!(parse_doc_type("meeting minutes").is_err());
+    }
+
+    // -- answers "ok" and keeps the prompts it was sent
+    #[derive(Clone, Default)]
+    struct RecordingLlm {
+        prompts: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingLlm {
+        fn record(&self, messages: &[Message]) {
+            let prompt = messages
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            self.prompts.lock().unwrap().push(prompt);
+        }
+    }
+
+    #[async_trait]
+    impl LLM for RecordingLlm {
+        async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            self.record(messages);
+            Ok(GenerateResult {
+                tokens: None,
+                generation: "ok".to_string(),
+            })
+        }
+
+        async fn stream(
+            &self,
+            messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            self.record(messages);
+            let data = StreamData::new(json!("ok"), None, "ok");
+            Ok(Box::pin(futures::stream::iter([Ok(data)])))
+        }
+    }
+
+    // -- the state of `web` answering with the llm from the store
+    fn web_state(cli: &Cli, llm: RecordingLlm, store: Arc<MemoryStore>) -> Arc<WebState> {
+        let chain = conversational_chain(
+            retrieval::RefusingLlm::new(Box::new(collection_settings::SystemPromptLlm::new(
+                Box::new(llm),
+                base_system_prompt(cli),
+            ))),
+            cli,
+            store.clone(),
+            None,
+            None,
+            None,
+            None,
+        );
+        let (jobs, _) = jobs::JobQueue::new(Duration::from_secs(60));
+        let store: Arc<dyn ChunkStore> = store;
+        let store = ResilientStore::new(Box::new(move || {
+            let store = store.clone();
+            Box::pin(async move { Ok(store) })
+        }));
+        Arc::new(WebState {
+            chain,
+            generations: Mutex::new(HashMap::new()),
+            jobs,
+            upload_dir: PathBuf::from(&cli.upload_dir),
+            sources: None,
+            store: Arc::new(store),
+            settings_flags: settings_flags(cli),
+            admin_token: None,
+            config: effective::config(cli),
+            thinking_budget: None,
+            rephrase: false,
+            max_answer_tokens: cli.max_answer_tokens,
+            num_predict: length::num_predict(cli.num_predict, cli.num_predict_cap),
+            model: cli.model.clone().unwrap(),
+            allowed_models: vec![],
+            model_chains: Mutex::new(HashMap::new()),
+            new_chain: Box::new(|model, limit| panic!("no chain of {} {:?}", model, limit)),
+            ollama_client: Arc::new(OllamaClient::default()),
+            configured_models: vec![],
+            explainer: None,
+            source_fields: cli.source_fields.clone(),
+            source_format: cli.source_documents_format,
+            answer_lang: cli.answer_lang.clone(),
+            injection_guard: None,
+            confidence: confidence_thresholds(cli),
+            refusal: cli
+                .refuse_without_sources
+                .then(|| cli.refusal_message.clone()),
+            memory_limits: memory_limits(cli),
+            summarizer: None,
+            answer_summaries: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    // -- the server-sent events answering a POST /chat
+    async fn chat_events(state: Arc<WebState>, request: Value) -> String {
+        let request = serde_json::from_value(request).unwrap();
+        let response = web_chat_handler(State(state), Json(request)).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    // -- every text is equally similar to every other
+    struct ConstantEmbedder;
+
+    #[async_trait]
+    impl Embedder for ConstantEmbedder {
+        async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+            Ok(texts.iter().map(|_| vec![1.0]).collect())
+        }
+
+        async fn embed_query(&self, _text: &str) -> Result<Vec<f64>, EmbedderError> {
+            Ok(vec![1.0])
+        }
+    }
+
+    async fn adversarial_chain() -> (ConversationalRetrieverChain, RecordingLlm) {
+        let store = Arc::new(MemoryStore::new(Arc::new(ConstantEmbedder)));
+        let docs: Vec<Document> = injection::tests::ADVERSARIAL_CHUNKS
+            .iter()
+            .map(|chunk| Document::new(*chunk))
+            .collect();
+        store.add_documents(&docs).await.unwrap();
+        // -- the chunks' equal embeddings would be deduplicated to one
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--rephrase",
+            "off",
+            "--dedup-threshold",
+            "2",
+            "chat",
+        ]);
+        let llm = RecordingLlm::default();
+        (
+            conversational_chain(llm.clone(), &cli, store, None, None, None, None),
+            llm,
+        )
+    }
+
+    // -- the prompt names the delimiters before the context
+    fn after_delimiter_declaration(prompt: &str) -> &str {
+        prompt.split_once("<<<KONEC DOKUMENTU n>>>").unwrap().1
+    }
+
+    fn prompt_of(llm: &RecordingLlm) -> String {
+        let prompts = llm.prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 1);
+        prompts[0].clone()
+    }
+
+    #[tokio::test]
+    async fn chat_prompt_keeps_adversarial_chunks_quoted() {
+        let (chain, llm) = adversarial_chain().await;
+        chain
+            .execute(prompt_args! { "question" => "Co je v dokumentech?" })
+            .await
+            .unwrap();
+        let prompt = prompt_of(&llm);
+        injection::tests::assert_quoted(
+            after_delimiter_declaration(&prompt),
+            injection::tests::ADVERSARIAL_CHUNKS.len(),
+        );
+        assert!(!prompt.contains("<|im_start|>"));
+        assert!(prompt.contains("Co je v dokumentech?"));
+    }
+
+    #[tokio::test]
+    async fn collection_settings_apply_under_the_flags_and_per_request() {
+        let store = Arc::new(MemoryStore::new(Arc::new(ConstantEmbedder)));
+        let docs: Vec<Document> = ["hr", "hr", "hr", "it"]
+            .iter()
+            .enumerate()
+            .map(|(i, collection)| {
+                Document::new(format!("Kapitola {}.", i)).with_metadata(HashMap::from([(
+                    "collection".to_string(),
+                    json!({ "name": collection }),
+                )]))
+            })
+            .collect();
+        store.add_documents(&docs).await.unwrap();
+        let hr = collection_settings::CollectionSettings {
+            system_prompt: Some("Jsi HR asistent.".to_string()),
+            top_k: Some(2),
+            score_threshold: None,
+        };
+        collection_settings::save(store.as_ref(), "hr", &hr)
+            .await
+            .unwrap();
+        let args = [
+            "chunk_contextor",
+            "--rephrase",
+            "off",
+            "--dedup-threshold",
+            "2",
+        ];
+        let question = || prompt_args! { "question" => "Co je v kapitolách?" };
+
+        // -- chat: the --top-k flag over the stored top_k, only the collection's chunks
+        let cli =
+            Cli::parse_from([&args[..], &["--collection", "hr", "--top-k", "1", "chat"]].concat());
+        let cli = with_collection_settings(&cli, store.as_ref()).await;
+        assert_eq!(cli.system_prompt.as_deref(), Some("Jsi HR asistent."));
+        assert_eq!((cli.top_k, cli.score_threshold), (Some(1), None));
+        let llm = RecordingLlm::default();
+        let chain = conversational_chain(llm.clone(), &cli, store.clone(), None, None, None, None);
+        chain.execute(question()).await.unwrap();
+        let prompt = prompt_of(&llm);
+        assert!(prompt.starts_with("Jsi HR asistent."));
+        assert_eq!(prompt.matches("Kapitola").count(), 1);
+        assert!(!prompt.contains("Kapitola 3"));
+
+        // -- web: a request's collection replaces --collection, with its settings
+        let cli = Cli::parse_from([&args[..], &["--collection", "it", "web"]].concat());
+        let llm = RecordingLlm::default();
+        let state = web_state(&cli, llm.clone(), store.clone());
+        let events = chat_events(
+            state,
+            json!({ "message": "Co je v kapitolách?", "collection": "hr" }),
+        )
+        .await;
+        assert!(events.contains("event: done"));
+        let prompt = prompt_of(&llm);
+        assert!(prompt.starts_with("Jsi HR asistent."));
+        assert_eq!(prompt.matches("Kapitola").count(), 2);
+        assert!(!prompt.contains("Kapitola 3"));
+
+        // -- a collection without settings keeps the global defaults
+        let cli = Cli::parse_from([&args[..], &["--collection", "it", "chat"]].concat());
+        let cli = with_collection_settings(&cli, store.as_ref()).await;
+        assert_eq!(cli.system_prompt, None);
+        assert_eq!(cli.top_k, None);
+    }
+
+    #[tokio::test]
+    async fn web_refuses_without_chunks_before_asking_the_model() {
+        let store = Arc::new(MemoryStore::new(Arc::new(ConstantEmbedder)));
+        let cli = Cli::parse_from([
+            "chunk_contextor",
+            "--rephrase",
+            "off",
+            "--refuse-without-sources",
+            "--refusal-message",
+            "Nevím.",
+            "web",
+        ]);
+        let llm = RecordingLlm::default();
+        let state = web_state(&cli, llm.clone(), store.clone());
+        let events = chat_events(state.clone(), json!({ "message": "Co je v kapitolách?" })).await;
+        assert!(events.contains(r#"{"content":"Nevím.","type":"token"}"#));
+        assert!(llm.prompts.lock().unwrap().is_empty());
+
+        store
+            .add_documents(&[Document::new("Kapitola 1.")])
+            .await
+            .unwrap();
+        let events = chat_events(state, json!({ "message": "Co je v kapitolách?" })).await;
+        assert!(events.contains(r#"{"content":"ok","type":"token"}"#));
+        assert!(prompt_of(&llm).contains("Kapitola 1."));
+    }
+
+    #[tokio::test]
+    async fn web_prompt_keeps_adversarial_chunks_quoted() {
+        let (chain, llm) = adversarial_chain().await;
+        let mut stream = chain
+            .stream(prompt_args! { "question" => "Co je v dokumentech?" })
+            .await
+            .unwrap();
+        while stream.next().await.is_some() {}
+        let prompt = prompt_of(&llm);
+        injection::tests::assert_quoted(
+            after_delimiter_declaration(&prompt),
+            injection::tests::ADVERSARIAL_CHUNKS.len(),
+        );
+        assert!(!prompt.contains("[INST]"));
+    }
+
+    #[test]
+    fn timing_summary_splits_retrieval_from_generation() {
+        let mut documents = vec![Document::new("jedna dva"), Document::new("tři")];
+        documents[0].score = 0.81234;
+        documents[1].score = 0.7;
+        let retrieval = retrieval::Retrieval {
+            question: "otázka".to_string(),
+            documents,
+            relaxed: false,
+            elapsed: Duration::from_millis(120),
+        };
+        let summary = timing_summary(Some(&retrieval), Duration::from_millis(2000));
+        assert!(summary.contains("retrieval: 120 ms, 2 chunks, scores [0.812, 0.700]"));
+        assert!(summary.contains("generation: 1880 ms"));
+        assert!(!summary.contains("context: 0 tokens"));
+        assert!(timing_summary(None, Duration::from_millis(5)).contains("retrieval: none"));
+    }
+
+    #[test]
+    fn answer_token_limit_is_clamped_by_the_ceiling() {
+        assert_eq!(answer_token_limit(None, None), None);
+        assert_eq!(answer_token_limit(Some(100), None), Some(100));
+        assert_eq!(answer_token_limit(None, Some(500)), Some(500));
+        assert_eq!(answer_token_limit(Some(100), Some(500)), Some(100));
+        assert_eq!(answer_token_limit(Some(1000), Some(500)), Some(500));
+    }
+
+    #[test]
+    fn answer_token_limit_is_sent_as_num_predict() {
+        let cli = Cli::parse_from(["chunk_contextor", "--num-predict", "1000", "web"]);
+        let cli = answer_cli(&cli, "gemma3:4b", Some(200));
+        assert_eq!(cli.model.as_deref(), Some("gemma3:4b"));
+        let options = serde_json::to_value(generation_options(&cli)).unwrap();
+        assert_eq!(options["num_predict"], 200);
+
+        let cli = Cli::parse_from(["chunk_contextor", "web"]);
+        let cli = answer_cli(&cli, "gemma3:4b", Some(5000));
+        let options = serde_json::to_value(generation_options(&cli)).unwrap();
+        assert_eq!(options["num_predict"], 2048);
+    }
+
+    #[test]
+    fn answers_stopped_at_num_predict_are_truncated() {
+        let cli = Cli::parse_from(["chunk_contextor", "--max-answer-tokens", "500", "web"]);
+        let store = Arc::new(MemoryStore::new(Arc::new(ConstantEmbedder)));
+        let state = web_state(&cli, RecordingLlm::default(), store);
+        assert!(state.truncated(Some(200), 200));
+        assert!(!state.truncated(Some(200), 150));
+        assert!(state.truncated(None, 2048));
+        assert!(!state.truncated(None, 500));
+        // -- the default chain already answers with --max-answer-tokens
+        assert!(state.model_chain(&state.model, Some(500)).is_none());
+    }
+
+    #[test]
+    fn completion_hints_do_not_restrict_parameterized_values() {
+        let cli = Cli::try_parse_from([
+            "chunk_contextor",
+            "--split-strategy",
+            "sentence-window:5",
+            "--chunk-overlap-strategy",
+            "token:20",
+            "generate",
+        ])
+        .unwrap();
+        assert_eq!(cli.split_strategy, SplitStrategy::SentenceWindow(5));
+        assert_eq!(cli.chunk_overlap_strategy, OverlapStrategy::Token(20));
+        assert!(Cli::try_parse_from(["chunk_contextor", "--split-strategy", "x", "chat"]).is_err());
+    }
+
+    #[test]
+    fn stop_words_are_sent_with_the_chat_options() {
+        let stop = |args: &[&str]| {
+            let cli = Cli::parse_from([&["chunk_contextor"], args, &["chat"]].concat());
+            serde_json::to_value(chat_options(&cli)).unwrap()["stop"].clone()
+        };
+        assert_eq!(
+            stop(&["--stop-words", "\\n---\\n,📌 **Otázka"]),
+            json!(["\n---\n", "📌 **Otázka"])
+        );
+        assert_eq!(stop(&["--no-stop"]), json!([]));
+        assert_eq!(stop(&[]), Value::Null);
+        assert!(
+            Cli::try_parse_from(["chunk_contextor", "--no-stop", "--stop-words", "x", "chat"])
+                .is_err()
+        );
     }
 }
@@ -0,0 +1,274 @@
+// -------------------------------------
+// -- Model Context Protocol server (JSON-RPC 2.0 over stdio)
+//
+// stdout is the protocol channel, so nothing else may print there while
+// the server runs - diagnostics go through `log` (stderr).
+
+use std::sync::Arc;
+
+use langchain_rust::{
+    chain::{Chain, ConversationalRetrieverChain},
+    prompt_args,
+};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    answer::answer_text,
+    retrieval::anonymized_path,
+    store::{chunk_text, ChunkStore, MetadataFilter},
+};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+const DEFAULT_TOP_K: u64 = 5;
+const MAX_TOP_K: u64 = 50;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+pub struct McpServer {
+    pub store: Arc<dyn ChunkStore>,
+    // paths in the search results are replaced by `[src:<source id>]`
+    pub anonymize_sources: bool,
+    pub chain: ConversationalRetrieverChain,
+    pub score_threshold: f32,
+}
+
+impl McpServer {
+    pub async fn serve(&self) {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut stdout = tokio::io::stdout();
+
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("mcp: failed to read stdin: {:?}", e);
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if let Some(response) = self.handle_line(&line).await {
+                let mut out = response.to_string();
+                out.push('\n');
+                if stdout.write_all(out.as_bytes()).await.is_err() || stdout.flush().await.is_err()
+                {
+                    log::error!("mcp: client closed stdout");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle_line(&self, line: &str) -> Option<Value> {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("mcp: unparsable message {:?}: {}", line, e);
+                return Some(error_response(Value::Null, PARSE_ERROR, "Parse error"));
+            }
+        };
+
+        let Some(request) = message.as_object() else {
+            return Some(error_response(
+                Value::Null,
+                INVALID_REQUEST,
+                "Request must be a JSON object",
+            ));
+        };
+
+        // -- requests without an id are notifications and never get a response
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(|m| m.as_str());
+        if request.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") || method.is_none() {
+            return id.map(|id| error_response(id, INVALID_REQUEST, "Invalid JSON-RPC request"));
+        }
+        let method = method.unwrap();
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tool_definitions() })),
+            "tools/call" => self.call_tool(&params).await,
+            _ if method.starts_with("notifications/") => return None,
+            _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        };
+
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, &message),
+        })
+    }
+
+    async fn call_tool(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"]
+            .as_str()
+            .ok_or((INVALID_PARAMS, "Missing tool name".to_string()))?;
+        let arguments = &params["arguments"];
+
+        let output = match name {
+            "search_documents" => {
+                let query = arguments["query"].as_str().ok_or((
+                    INVALID_PARAMS,
+                    "Missing string argument 'query'".to_string(),
+                ))?;
+                let top_k = match &arguments["top_k"] {
+                    Value::Null => DEFAULT_TOP_K,
+                    v => v.as_u64().filter(|k| *k > 0).ok_or((
+                        INVALID_PARAMS,
+                        "'top_k' must be a positive integer".to_string(),
+                    ))?,
+                };
+                self.search_documents(query, top_k.min(MAX_TOP_K) as usize)
+                    .await
+            }
+            "ask" => {
+                let question = arguments["question"].as_str().ok_or((
+                    INVALID_PARAMS,
+                    "Missing string argument 'question'".to_string(),
+                ))?;
+                self.ask(question).await
+            }
+            _ => return Err((INVALID_PARAMS, format!("Unknown tool: {}", name))),
+        };
+
+        // -- tool failures are reported to the model, not as protocol errors
+        Ok(match output {
+            Ok(output) => json!({
+                "content": [{ "type": "text", "text": output.to_string() }],
+                "structuredContent": output,
+                "isError": false,
+            }),
+            Err(e) => {
+                log::error!("mcp: tool {} failed: {}", name, e);
+                json!({
+                    "content": [{ "type": "text", "text": e }],
+                    "isError": true,
+                })
+            }
+        })
+    }
+
+    async fn search_documents(&self, query: &str, top_k: usize) -> Result<Value, String> {
+        let docs = self
+            .store
+            .similarity_search(
+                query,
+                top_k,
+                self.score_threshold,
+                &MetadataFilter::default(),
+            )
+            .await
+            .map_err(|e| format!("Retrieval failed: {}", e))?;
+
+        let results: Vec<Value> = docs
+            .iter()
+            .map(|d| {
+                json!({
+                    "text": d
+                        .metadata
+                        .get("window")
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                        .unwrap_or_else(|| chunk_text(&d.page_content)),
+                    "path": match d.metadata.get("path").and_then(Value::as_str) {
+                        Some(path) if self.anonymize_sources => json!(anonymized_path(path)),
+                        Some(path) => json!(path),
+                        None => Value::Null,
+                    },
+                    "page": d.metadata.get("page").cloned().unwrap_or(Value::Null),
+                    "collection": d.metadata.get("collection").cloned().unwrap_or(Value::Null),
+                    "score": d.score,
+                })
+            })
+            .collect();
+        Ok(json!({ "results": results }))
+    }
+
+    async fn ask(&self, question: &str) -> Result<Value, String> {
+        // -- every tool call is an independent question
+        self.chain.memory.lock().await.clear();
+
+        let input_variables = prompt_args! {
+            "question" => question,
+        };
+        let data = self
+            .chain
+            .execute(input_variables)
+            .await
+            .map_err(|e| format!("Answering failed: {}", e))?;
+
+        let answer = answer_text(&data["output"]);
+
+        let mut sources: Vec<String> = data["source_documents"]
+            .as_array()
+            .map(|docs| {
+                docs.iter()
+                    .filter_map(|d| d["metadata"]["path"].as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        sources.sort();
+        sources.dedup();
+
+        Ok(json!({ "answer": answer, "sources": sources }))
+    }
+}
+
+fn tool_definitions() -> Value {
+    json!([
+        {
+            "name": "search_documents",
+            "description": "Search the document knowledge base and return the most relevant chunks with their source path, page and similarity score.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "Search query" },
+                    "top_k": {
+                        "type": "integer",
+                        "description": "Maximum number of chunks to return",
+                        "minimum": 1,
+                        "maximum": MAX_TOP_K,
+                        "default": DEFAULT_TOP_K,
+                    },
+                },
+                "required": ["query"],
+            },
+        },
+        {
+            "name": "ask",
+            "description": "Answer a question from the document knowledge base and list the source documents used.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "question": { "type": "string", "description": "Question to answer" },
+                },
+                "required": ["question"],
+            },
+        },
+    ])
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
@@ -0,0 +1,263 @@
+// -------------------------------------
+// -- --chunk-metadata-template: payload keys of every chunk computed per file
+//
+// The template is a JSON object. Its strings may reference environment
+// variables (`$HOSTNAME`, `${HOSTNAME}`), which are read once when the
+// arguments are parsed, and placeholders evaluated for every ingested file:
+// `{filename}`, `{extension}`, `{size_bytes}`, `{modified_date}`,
+// `{ingest_time}`, `{model}`, `{embed_model}` and `{chunk_strategy}`. Other
+// JSON values are stored as they are, and a string of `{size_bytes}` alone
+// is stored as a number so it can be range-filtered.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+// -- `${NAME}`, `$NAME` or `{placeholder}`
+fn reference() -> &'static Regex {
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    REFERENCE
+        .get_or_init(|| Regex::new(r"\$\{([A-Za-z_]\w*)\}|\$([A-Za-z_]\w*)|\{([a-z_]+)\}").unwrap())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Placeholder {
+    Filename,
+    Extension,
+    SizeBytes,
+    ModifiedDate,
+    IngestTime,
+    Model,
+    EmbedModel,
+    ChunkStrategy,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "filename" => Some(Placeholder::Filename),
+            "extension" => Some(Placeholder::Extension),
+            "size_bytes" => Some(Placeholder::SizeBytes),
+            "modified_date" => Some(Placeholder::ModifiedDate),
+            "ingest_time" => Some(Placeholder::IngestTime),
+            "model" => Some(Placeholder::Model),
+            "embed_model" => Some(Placeholder::EmbedModel),
+            "chunk_strategy" => Some(Placeholder::ChunkStrategy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Clone, Debug)]
+enum TemplateValue {
+    Json(Value),
+    Parts(Vec<Part>),
+}
+
+#[derive(Clone, Debug)]
+pub struct MetadataTemplate {
+    values: Vec<(String, TemplateValue)>,
+}
+
+// -- the ingestion run the chunks are stored by
+pub struct RunMetadata {
+    pub model: String,
+    pub embed_model: String,
+    pub chunk_strategy: String,
+}
+
+fn parts(template: &str) -> Result<Vec<Part>, String> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut last = 0;
+    for reference in reference().captures_iter(template) {
+        let whole = reference.get(0).unwrap();
+        text.push_str(&template[last..whole.start()]);
+        last = whole.end();
+        if let Some(name) = reference.get(1).or(reference.get(2)) {
+            let value = std::env::var(name.as_str())
+                .map_err(|_| format!("environment variable {} is not set", name.as_str()))?;
+            text.push_str(&value);
+            continue;
+        }
+        let name = &reference[3];
+        let placeholder =
+            Placeholder::parse(name).ok_or_else(|| format!("unknown placeholder {{{}}}", name))?;
+        if !text.is_empty() {
+            parts.push(Part::Text(std::mem::take(&mut text)));
+        }
+        parts.push(Part::Placeholder(placeholder));
+    }
+    text.push_str(&template[last..]);
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+pub fn parse_metadata_template(value: &str) -> Result<MetadataTemplate, String> {
+    let template: Value = serde_json::from_str(value).map_err(|e| e.to_string())?;
+    let Value::Object(object) = template else {
+        return Err("expected a JSON object".to_string());
+    };
+    let values = object
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(template) => {
+                    TemplateValue::Parts(parts(&template).map_err(|e| format!("{}: {}", key, e))?)
+                }
+                value => TemplateValue::Json(value),
+            };
+            Ok((key, value))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(MetadataTemplate { values })
+}
+
+// -- `YYYY-MM-DD` of unix seconds, days to civil (Howard Hinnant)
+fn date(secs: u64) -> String {
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// -- `YYYY-MM-DDTHH:MM:SSZ` of unix seconds
+fn time(secs: u64) -> String {
+    let of_day = secs % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        date(secs),
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|age| age.as_secs())
+}
+
+impl MetadataTemplate {
+    pub fn keys(&self) -> Vec<&str> {
+        self.values.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    // -- the template's keys for `doc_path`, values of missing file metadata are empty
+    pub fn evaluate(&self, doc_path: &str, run: &RunMetadata) -> HashMap<String, Value> {
+        let path = Path::new(doc_path);
+        let file = fs::metadata(path).ok();
+        let size = file.as_ref().map(|file| file.len());
+        let modified = file
+            .and_then(|file| file.modified().ok())
+            .and_then(unix_secs);
+        let text = |placeholder| match placeholder {
+            Placeholder::Filename => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            Placeholder::Extension => path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            Placeholder::SizeBytes => size.map(|size| size.to_string()).unwrap_or_default(),
+            Placeholder::ModifiedDate => modified.map(date).unwrap_or_default(),
+            Placeholder::IngestTime => unix_secs(SystemTime::now()).map(time).unwrap_or_default(),
+            Placeholder::Model => run.model.clone(),
+            Placeholder::EmbedModel => run.embed_model.clone(),
+            Placeholder::ChunkStrategy => run.chunk_strategy.clone(),
+        };
+        self.values
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    TemplateValue::Json(value) => value.clone(),
+                    TemplateValue::Parts(parts)
+                        if parts[..] == [Part::Placeholder(Placeholder::SizeBytes)] =>
+                    {
+                        size.map_or(Value::Null, |size| json!(size))
+                    }
+                    TemplateValue::Parts(parts) => json!(parts
+                        .iter()
+                        .map(|part| match part {
+                            Part::Text(text) => text.clone(),
+                            Part::Placeholder(placeholder) => text(*placeholder),
+                        })
+                        .collect::<String>()),
+                };
+                (key.clone(), value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_is_evaluated_per_file() {
+        std::env::set_var("CHUNKERBOT_TEMPLATE_HOST", "stroj");
+        let template = parse_metadata_template(
+            r#"{
+                "host": "$CHUNKERBOT_TEMPLATE_HOST",
+                "source": "${CHUNKERBOT_TEMPLATE_HOST}:{filename}",
+                "extension": "{extension}",
+                "size": "{size_bytes}",
+                "run": "{model}/{embed_model}/{chunk_strategy}",
+                "public": true
+            }"#,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("template-{}.pdf", uuid::Uuid::new_v4()));
+        fs::write(&path, "12345").unwrap();
+        let run = RunMetadata {
+            model: "gemma3:12b".to_string(),
+            embed_model: "bge-m3".to_string(),
+            chunk_strategy: "token".to_string(),
+        };
+        let metadata = template.evaluate(path.to_str().unwrap(), &run);
+        fs::remove_file(&path).unwrap();
+
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(metadata["host"], json!("stroj"));
+        assert_eq!(metadata["source"], json!(format!("stroj:{}", filename)));
+        assert_eq!(metadata["extension"], json!("pdf"));
+        assert_eq!(metadata["size"], json!(5));
+        assert_eq!(metadata["run"], json!("gemma3:12b/bge-m3/token"));
+        assert_eq!(metadata["public"], json!(true));
+
+        assert!(parse_metadata_template(r#"{"a": "{unknown}"}"#).is_err());
+        assert!(parse_metadata_template(r#"{"a": "$CHUNKERBOT_TEMPLATE_UNSET"}"#).is_err());
+        assert!(parse_metadata_template("[]").is_err());
+        assert_eq!(time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(date(1_709_251_200), "2024-03-01");
+    }
+}
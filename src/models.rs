@@ -0,0 +1,112 @@
+// -------------------------------------
+// -- ollama models the configuration needs, checked at startup
+//
+// A model that isn't pulled fails the first request with an unclear error,
+// so missing ones stop the start with the `ollama pull` command to run, or
+// are pulled with `--auto-pull`, the progress printed to stderr (stdout is
+// the mcp transport).
+
+use std::io::Write;
+
+use futures::StreamExt;
+use langchain_rust::llm::client::OllamaClient;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ModelPresence {
+    pub name: String,
+    pub present: bool,
+}
+
+// -- `paraphrase-multilingual` is listed by ollama as `paraphrase-multilingual:latest`
+fn full_name(model: &str) -> String {
+    if model.contains(':') {
+        model.to_string()
+    } else {
+        format!("{}:latest", model)
+    }
+}
+
+pub async fn presence(
+    client: &OllamaClient,
+    models: &[String],
+) -> Result<Vec<ModelPresence>, String> {
+    let local = client
+        .list_local_models()
+        .await
+        .map_err(|e| format!("listing ollama models failed: {}", e))?;
+    Ok(models
+        .iter()
+        .map(|model| ModelPresence {
+            name: model.clone(),
+            present: local
+                .iter()
+                .any(|local| full_name(&local.name) == full_name(model)),
+        })
+        .collect())
+}
+
+async fn pull(client: &OllamaClient, model: &str) -> Result<(), String> {
+    eprintln!("pulling {}", model);
+    let mut progress = client
+        .pull_model_stream(model.to_string(), false)
+        .await
+        .map_err(|e| format!("pulling {} failed: {}", model, e))?;
+    while let Some(status) = progress.next().await {
+        let status = status.map_err(|e| format!("pulling {} failed: {}", model, e))?;
+        match (status.completed, status.total) {
+            (Some(completed), Some(total)) if total > 0 => {
+                eprint!("\r{} {}%", status.message, completed * 100 / total);
+                std::io::stderr().flush().ok();
+            }
+            _ => eprintln!("\r{}", status.message),
+        }
+    }
+    eprintln!();
+    Ok(())
+}
+
+// -- missing models are pulled with `auto_pull`, otherwise they are an error
+pub async fn pull_missing(
+    client: &OllamaClient,
+    presence: &[ModelPresence],
+    auto_pull: bool,
+) -> Result<(), String> {
+    let missing: Vec<&str> = presence
+        .iter()
+        .filter(|model| !model.present)
+        .map(|model| model.name.as_str())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !auto_pull {
+        let commands: Vec<String> = missing
+            .iter()
+            .map(|model| format!("ollama pull {}", model))
+            .collect();
+        return Err(format!(
+            "ollama models {} are not pulled, run `{}` or start with --auto-pull",
+            missing.join(", "),
+            commands.join(" && ")
+        ));
+    }
+    for model in missing {
+        pull(client, model).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untagged_models_are_latest() {
+        assert_eq!(
+            full_name("paraphrase-multilingual"),
+            "paraphrase-multilingual:latest"
+        );
+        assert_eq!(full_name("gemma3:12b"), "gemma3:12b");
+    }
+}
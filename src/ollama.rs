@@ -0,0 +1,102 @@
+// -------------------------------------
+// -- ollama chat model sending generation options
+//
+// langchain's Ollama drops the `GenerationOptions` it is given, so
+// `--num-ctx`, `--num-predict` (capped by `--num-predict-cap`) and the
+// temperatures of `--temperature-schedule` are sent by `OllamaWithOptions`.
+
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use langchain_rust::{
+    language_models::{llm::LLM, GenerateResult, LLMError, TokenUsage},
+    llm::client::OllamaClient,
+    schemas::{Message, StreamData},
+};
+use ollama_rs::generation::{
+    chat::{request::ChatMessageRequest, ChatMessage},
+    options::GenerationOptions,
+};
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// -- chat model called with generation options
+#[derive(Clone)]
+pub struct OllamaWithOptions {
+    client: Arc<OllamaClient>,
+    model: String,
+    options: GenerationOptions,
+}
+
+impl OllamaWithOptions {
+    pub fn new(client: Arc<OllamaClient>, model: &str, options: GenerationOptions) -> Self {
+        OllamaWithOptions {
+            client,
+            model: model.to_string(),
+            options,
+        }
+    }
+
+    fn request(&self, messages: &[Message]) -> ChatMessageRequest {
+        let messages = messages.iter().map(ChatMessage::from).collect();
+        ChatMessageRequest::new(self.model.clone(), messages).options(self.options.clone())
+    }
+}
+
+#[async_trait]
+impl LLM for OllamaWithOptions {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        let response = self
+            .client
+            .send_chat_messages(self.request(messages))
+            .await?;
+        let generation = response
+            .message
+            .map(|message| message.content)
+            .ok_or_else(|| LLMError::ContentNotFound("No message in response".to_string()))?;
+        let tokens = response.final_data.map(|data| TokenUsage {
+            prompt_tokens: data.prompt_eval_count as u32,
+            completion_tokens: data.eval_count as u32,
+            total_tokens: (data.prompt_eval_count + data.eval_count) as u32,
+        });
+        Ok(GenerateResult { tokens, generation })
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        let response = self
+            .client
+            .send_chat_messages_stream(self.request(messages))
+            .await?;
+        Ok(Box::pin(response.map(|data| match data {
+            Ok(data) => match data.message.clone() {
+                Some(message) => Ok(StreamData::new(
+                    serde_json::to_value(data).unwrap_or_default(),
+                    None,
+                    message.content,
+                )),
+                None => Err(LLMError::ContentNotFound(
+                    "No message in response".to_string(),
+                )),
+            },
+            Err(_) => Err(LLMError::OtherError("Stream error".to_string())),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn options_are_sent_with_the_request() {
+        let options = GenerationOptions::default().num_ctx(8192).num_predict(256);
+        let llm = OllamaWithOptions::new(Arc::new(OllamaClient::default()), "gemma3:12b", options);
+        let request =
+            serde_json::to_value(llm.request(&[Message::new_human_message("Ahoj")])).unwrap();
+        assert_eq!(request["model"], json!("gemma3:12b"));
+        assert_eq!(request["options"]["num_ctx"], json!(8192));
+        assert_eq!(request["options"]["num_predict"], json!(256));
+    }
+}
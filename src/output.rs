@@ -0,0 +1,153 @@
+// -------------------------------------
+// -- user-facing output of chat and generate
+//
+// Answers, sources, notes, warnings and errors are told apart by ANSI styles
+// when the stream is a terminal, unless `NO_COLOR` is set or `--color` says
+// otherwise. Ingestion details (chunk dumps, enrichment results, per-document
+// statistics) are dimmed and left out with `--quiet`.
+
+use std::{
+    ffi::OsStr,
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    // colored on a terminal without NO_COLOR
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR: OnceLock<ColorChoice> = OnceLock::new();
+static QUIET: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn init(color: ColorChoice, quiet: bool) {
+    let _ = COLOR.set(color);
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+// -- an explicit --color wins over NO_COLOR, an empty NO_COLOR doesn't count
+fn colored(choice: ColorChoice, terminal: bool, no_color: Option<&OsStr>) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && no_color.is_none_or(|v| v.is_empty()),
+    }
+}
+
+fn paint(style: &str, text: &str, terminal: bool) -> String {
+    let choice = COLOR.get().copied().unwrap_or_default();
+    match colored(choice, terminal, std::env::var_os("NO_COLOR").as_deref()) {
+        true => format!("\x1b[{}m{}\x1b[0m", style, text),
+        false => text.to_string(),
+    }
+}
+
+// -- all of it goes to stderr, stdout is left to machine readable output
+pub fn to_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+fn stdout(style: &str, text: &str) {
+    match STDERR.load(Ordering::Relaxed) {
+        true => eprintln!("{}", paint(style, text, std::io::stderr().is_terminal())),
+        false => println!("{}", paint(style, text, std::io::stdout().is_terminal())),
+    }
+}
+
+pub fn answer(text: &str) {
+    stdout("1", text);
+}
+
+pub fn sources(text: &str) {
+    stdout("36", text);
+}
+
+// -- status of the session: command confirmations, retrieval notes, summaries
+pub fn note(text: &str) {
+    stdout("2", text);
+}
+
+pub fn warning(text: &str) {
+    stdout("33", text);
+}
+
+pub fn error(text: &str) {
+    eprintln!("{}", paint("31", text, std::io::stderr().is_terminal()));
+}
+
+// -- progress and dumps of the ingestion, hidden by --quiet
+pub fn detail(text: &str) {
+    if !QUIET.load(Ordering::Relaxed) {
+        stdout("2", text);
+    }
+}
+
+// -- columns padded to their widest cell, numbers aligned right
+pub fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| match cell.parse::<f64>() {
+                Ok(_) => format!("{:>width$}", cell),
+                Err(_) => format!("{:<width$}", cell),
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    std::iter::once(line(header.to_vec()))
+        .chain(
+            rows.iter()
+                .map(|row| line(row.iter().map(String::as_str).collect())),
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_only_applies_to_auto() {
+        let set = Some(OsStr::new("1"));
+        assert!(colored(ColorChoice::Auto, true, None));
+        assert!(colored(ColorChoice::Auto, true, Some(OsStr::new(""))));
+        assert!(!colored(ColorChoice::Auto, true, set));
+        assert!(!colored(ColorChoice::Auto, false, None));
+        assert!(colored(ColorChoice::Always, false, set));
+        assert!(!colored(ColorChoice::Never, true, None));
+    }
+
+    #[test]
+    fn table_columns_are_aligned() {
+        let rows = vec![
+            vec!["a.pdf".to_string(), "12".to_string(), "stored".to_string()],
+            vec!["long/b.pdf".to_string(), "3".to_string(), String::new()],
+        ];
+        assert_eq!(
+            table(&["document", "chunks", "status"], &rows),
+            "document    chunks  status\n\
+             a.pdf           12  stored\n\
+             long/b.pdf       3"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+// -------------------------------------
+// -- token budget of a generate run, printed before any chunk is enriched
+//
+// Prompts are measured with the documents' sizers (template, system prompt,
+// the chunk and its neighbours or document context). Completions are an
+// estimate: the enriched chunk repeats its chunk after a sentence or two of
+// context. The ETA comes from enriching the first chunks of the run, which
+// are enriched once more by the run itself.
+
+use std::time::Duration;
+
+// -- enriched chunk size to chunk size
+const COMPLETION_RATIO: f64 = 1.3;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct DocumentPlan {
+    // model calls
+    pub calls: usize,
+    pub prompt: usize,
+    pub completion: usize,
+}
+
+impl DocumentPlan {
+    // -- `repeated` is the size of the text the answer repeats
+    pub fn add_call(&mut self, prompt: usize, repeated: usize) {
+        self.calls += 1;
+        self.prompt += prompt;
+        self.completion += completion(repeated);
+    }
+}
+
+// -- expected size of an enriched chunk repeating a text of this size
+pub fn completion(repeated: usize) -> usize {
+    (repeated as f64 * COMPLETION_RATIO).ceil() as usize
+}
+
+#[derive(Default)]
+pub struct Plan {
+    documents: usize,
+    skipped: usize,
+    chunks: usize,
+    total: DocumentPlan,
+    // tokens, or chars with `--sizer chars`
+    unit: &'static str,
+    // measured by the calibration, no ETA without it
+    pub seconds_per_call: Option<f64>,
+}
+
+impl Plan {
+    pub fn new(unit: &'static str) -> Self {
+        Plan {
+            unit,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, chunks: usize, document: DocumentPlan) {
+        self.documents += 1;
+        self.chunks += chunks;
+        self.total.calls += document.calls;
+        self.total.prompt += document.prompt;
+        self.total.completion += document.completion;
+    }
+
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        self.seconds_per_call
+            .map(|seconds| Duration::from_secs_f64(seconds * self.total.calls as f64))
+    }
+
+    pub fn render(&self) -> String {
+        let documents = match self.skipped {
+            0 => self.documents.to_string(),
+            skipped => format!("{} ({} skipped)", self.documents, skipped),
+        };
+        let eta = match (self.eta(), self.seconds_per_call) {
+            (Some(eta), Some(seconds)) => {
+                format!("{} ({:.1} s per call)", human_duration(eta), seconds)
+            }
+            _ => "not calibrated".to_string(),
+        };
+        [
+            ("documents", documents),
+            ("chunks", self.chunks.to_string()),
+            ("model calls", self.total.calls.to_string()),
+            (
+                &format!("prompt {}", self.unit),
+                self.total.prompt.to_string(),
+            ),
+            (
+                &format!("completion {}", self.unit),
+                format!("~{}", self.total.completion),
+            ),
+            ("eta", eta),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{:<18}{}", name, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+// -- `1 h 05 min`, `12 min 30 s`, `45 s`
+fn human_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{} s", s),
+        (0, m, s) => format!("{} min {:02} s", m, s),
+        (h, m, _) => format!("{} h {:02} min", h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_sums_documents_and_estimates_the_run() {
+        let mut document = DocumentPlan::default();
+        document.add_call(300, 100);
+        document.add_call(250, 10);
+        assert_eq!(
+            document,
+            DocumentPlan {
+                calls: 2,
+                prompt: 550,
+                completion: 143,
+            }
+        );
+
+        let mut plan = Plan::new("tokens");
+        plan.add(2, document);
+        plan.add(3, document);
+        plan.skip();
+        assert_eq!(plan.eta(), None);
+        plan.seconds_per_call = Some(1000.0);
+        assert_eq!(plan.eta(), Some(Duration::from_secs(4000)));
+        let rendered = plan.render();
+        assert!(rendered.contains("documents         2 (1 skipped)"));
+        assert!(rendered.contains("prompt tokens     1100"));
+        assert!(rendered.contains("completion tokens ~286"));
+        assert!(rendered.contains("eta               1 h 06 min (1000.0 s per call)"));
+    }
+
+    #[test]
+    fn durations_are_rounded_to_two_units() {
+        assert_eq!(human_duration(Duration::from_secs(45)), "45 s");
+        assert_eq!(human_duration(Duration::from_secs(750)), "12 min 30 s");
+        assert_eq!(human_duration(Duration::from_secs(3900)), "1 h 05 min");
+    }
+}
@@ -0,0 +1,147 @@
+// -------------------------------------
+// -- retries of ollama requests that failed to connect
+//
+// Ollama restarting during a long chat session fails the requests sent
+// meanwhile. Requests that couldn't connect are retried every
+// `--ollama-reconnect-secs`, `--ollama-reconnect-attempts` times. langchain's
+// ollama types own the ollama-rs client, whose reqwest client (and its tcp
+// keepalive) can't be configured, so the llm and the embedder are wrapped
+// instead of the client.
+
+use std::{fmt::Display, future::Future, pin::Pin, time::Duration};
+
+use async_trait::async_trait;
+use futures::Stream;
+use langchain_rust::{
+    embedding::{Embedder, EmbedderError},
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, StreamData},
+};
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Reconnect {
+    pub delay: Duration,
+    pub attempts: u32,
+}
+
+impl Reconnect {
+    async fn run<T, E: Display, F: Future<Output = Result<T, E>>>(
+        &self,
+        is_connection_error: fn(&E) -> bool,
+        mut request: impl FnMut() -> F,
+    ) -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(e) if attempt < self.attempts && is_connection_error(&e) => {
+                    attempt += 1;
+                    log::warn!(
+                        "ollama unreachable ({}), reconnecting in {:?} ({}/{})",
+                        e,
+                        self.delay,
+                        attempt,
+                        self.attempts
+                    );
+                    tokio::time::sleep(self.delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// -- ollama-rs turns reqwest errors into plain messages
+fn is_connection_message(message: &str) -> bool {
+    let message = message.to_lowercase();
+    ["error sending request", "connection", "connect error"]
+        .iter()
+        .any(|needle| message.contains(needle))
+}
+
+fn is_llm_connection_error(error: &LLMError) -> bool {
+    match error {
+        LLMError::RequestError(e) => e.is_connect() || e.is_request(),
+        LLMError::IoError(_) => true,
+        LLMError::OllamaError(e) => is_connection_message(&e.to_string()),
+        _ => false,
+    }
+}
+
+fn is_embedder_connection_error(error: &EmbedderError) -> bool {
+    match error {
+        EmbedderError::RequestError(e) => e.is_connect() || e.is_request(),
+        EmbedderError::OllamaError(e) => is_connection_message(&e.to_string()),
+        _ => false,
+    }
+}
+
+pub struct ReconnectingLlm {
+    inner: Box<dyn LLM>,
+    reconnect: Reconnect,
+}
+
+impl ReconnectingLlm {
+    pub fn new(inner: Box<dyn LLM>, reconnect: Reconnect) -> Self {
+        ReconnectingLlm { inner, reconnect }
+    }
+}
+
+impl Clone for ReconnectingLlm {
+    fn clone(&self) -> Self {
+        ReconnectingLlm {
+            inner: self.inner.clone_box(),
+            reconnect: self.reconnect,
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for ReconnectingLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        self.reconnect
+            .run(is_llm_connection_error, || self.inner.generate(messages))
+            .await
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        self.reconnect
+            .run(is_llm_connection_error, || self.inner.stream(messages))
+            .await
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+pub struct ReconnectingEmbedder<E> {
+    inner: E,
+    reconnect: Reconnect,
+}
+
+impl<E> ReconnectingEmbedder<E> {
+    pub fn new(inner: E, reconnect: Reconnect) -> Self {
+        ReconnectingEmbedder { inner, reconnect }
+    }
+}
+
+#[async_trait]
+impl<E: Embedder> Embedder for ReconnectingEmbedder<E> {
+    async fn embed_documents(&self, documents: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        self.reconnect
+            .run(is_embedder_connection_error, || {
+                self.inner.embed_documents(documents)
+            })
+            .await
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+        self.reconnect
+            .run(is_embedder_connection_error, || {
+                self.inner.embed_query(text)
+            })
+            .await
+    }
+}
@@ -0,0 +1,177 @@
+// -------------------------------------
+// -- `recontextualize --path <p> --chunk <n>`: one stored chunk enriched again
+//
+// The chunk's original text (`original_text`, or the stored text of chunks
+// kept as they were split) is enriched with the window prompt between the
+// original texts of its stored neighbours, as `generate` did, and checked by
+// the same quality gate. Stored points have random ids, so the chunk is
+// replaced by deleting it by its `path`, `chunk_index` and `version` and
+// storing it again with its metadata. `--hint` is added to the system
+// prompt, `--model` enriches with another model. `--all-failed` does it for
+// every chunk stored with `context_rejected`.
+
+use langchain_rust::{chain::Chain, prompt_args, schemas::Document};
+use serde_json::{json, Value};
+
+use crate::{
+    config, enrichment_chain,
+    store::{chunk_text, ChunkStore, MetadataFilter},
+    table_chain, window_input, ChunkEnrichmentValidator, ContextStrategy, DocumentSizer, Ingest,
+    IngestStats,
+};
+
+// -- the chunks of a document as split, in `chunk_index` order. Chunks of other versions
+// -- than `version` aren't neighbours
+pub fn originals(docs: Vec<Document>, version: Option<&Value>) -> Vec<Document> {
+    let mut originals: Vec<Document> = docs
+        .into_iter()
+        .filter(|doc| doc.metadata.get("version") == version)
+        .map(|mut doc| {
+            doc.page_content = match doc.metadata.get("original_text").and_then(Value::as_str) {
+                Some(original) => original.to_string(),
+                None => chunk_text(&doc.page_content),
+            };
+            doc
+        })
+        .collect();
+    originals.sort_by_key(|doc| chunk_index(doc).unwrap_or(u64::MAX));
+    originals
+}
+
+pub fn chunk_index(doc: &Document) -> Option<u64> {
+    doc.metadata.get("chunk_index").and_then(Value::as_u64)
+}
+
+// -- the stored point of the chunk
+fn chunk_filter(doc: &Document) -> MetadataFilter {
+    let mut filter = MetadataFilter::default();
+    for key in ["path", "chunk_index", "version"] {
+        if let Some(value) = doc.metadata.get(key) {
+            filter.must.push((key.to_string(), value.clone()));
+        }
+    }
+    filter
+}
+
+// -- enriches `originals[position]` again and replaces its stored point, the stored chunk
+// -- is returned with whether the quality gate rejected it again
+pub async fn recontextualize(
+    ingest: &Ingest,
+    store: &dyn ChunkStore,
+    originals: &[Document],
+    position: usize,
+    hint: Option<&str>,
+) -> Result<(Document, bool), String> {
+    let chunk = &originals[position];
+    let kind = chunk.metadata.get("kind").and_then(Value::as_str);
+    if kind == Some("image") || chunk.metadata.contains_key("window") {
+        return Err("image descriptions and sentence windows aren't enriched".to_string());
+    }
+    let language = whatlang::detect(&chunk.page_content);
+    let sizer = DocumentSizer::new(
+        ingest.cli.sizer,
+        language.as_ref().map(|info| info.script()),
+    );
+    let path = chunk.metadata.get("path").and_then(Value::as_str);
+    let neighbours = ingest.neighbours(path.unwrap_or_default(), originals, &sizer);
+    let system_prompt = [ingest.system_prompt(language.as_ref()), hint]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let system_prompt = Some(system_prompt.as_str()).filter(|prompt| !prompt.is_empty());
+    let retry_system_prompt = match system_prompt {
+        Some(system_prompt) => format!("{}\n\n{}", system_prompt, config::ENRICHMENT_RETRY_STR),
+        None => config::ENRICHMENT_RETRY_STR.to_string(),
+    };
+
+    let mut stats = IngestStats::default();
+    let enriched = match kind {
+        Some("table") => {
+            let section = chunk.metadata.get("section").and_then(Value::as_str);
+            let input = prompt_args! {
+                "section" => section.unwrap_or_default(),
+                "input" => chunk.page_content,
+            };
+            table_chain(&ingest.ollama, system_prompt)
+                .invoke(input)
+                .await
+                .map(|description| format!("{}\n\n{}", description.trim(), chunk.page_content))
+        }
+        _ => {
+            let window = |system_prompt| {
+                enrichment_chain(
+                    &ingest.ollama,
+                    ContextStrategy::Window,
+                    &ingest.window_template,
+                    system_prompt,
+                )
+            };
+            let (chain, retry) = (window(system_prompt), window(Some(&retry_system_prompt)));
+            ingest
+                .enrich_chunk(
+                    (&chain, &retry),
+                    &ChunkEnrichmentValidator::new(&ingest.cli),
+                    window_input(originals, position, neighbours),
+                    &chunk.page_content,
+                    &mut stats,
+                )
+                .await
+        }
+    };
+    let enriched = enriched.map_err(|e| format!("enriching failed: {}", e))?;
+
+    let mut metadata = chunk.metadata.clone();
+    let rejected = stats.fallbacks > 0;
+    metadata.remove("context_rejected");
+    if rejected {
+        metadata.insert("context_rejected".to_string(), json!(true));
+    }
+    match enriched != chunk.page_content {
+        true => metadata.insert("original_text".to_string(), json!(chunk.page_content)),
+        false => metadata.remove("original_text"),
+    };
+    let doc = Document::new(enriched).with_metadata(metadata);
+    store.delete(&chunk_filter(chunk)).await?;
+    store.add_documents(std::slice::from_ref(&doc)).await?;
+    Ok((doc, rejected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn originals_are_the_split_chunks_of_one_version() {
+        let doc = |text: &str, metadata: Value| {
+            let metadata: HashMap<String, Value> = serde_json::from_value(metadata).unwrap();
+            Document::new(text).with_metadata(metadata)
+        };
+        let docs = vec![
+            doc(
+                "\"Kontext. Druhý\"",
+                json!({ "path": "a.pdf", "chunk_index": 1, "version": 2, "original_text": "Druhý" }),
+            ),
+            doc(
+                "\"Starý\"",
+                json!({ "path": "a.pdf", "chunk_index": 0, "version": 1 }),
+            ),
+            doc(
+                "\"První\"",
+                json!({ "path": "a.pdf", "chunk_index": 0, "version": 2, "context_rejected": true }),
+            ),
+        ];
+        let originals = originals(docs, Some(&json!(2)));
+        let texts: Vec<&str> = originals.iter().map(|d| d.page_content.as_str()).collect();
+        assert_eq!(texts, ["První", "Druhý"]);
+        assert_eq!(
+            chunk_filter(&originals[1]).must,
+            vec![
+                ("path".to_string(), json!("a.pdf")),
+                ("chunk_index".to_string(), json!(1)),
+                ("version".to_string(), json!(2)),
+            ]
+        );
+    }
+}
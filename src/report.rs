@@ -0,0 +1,286 @@
+// -------------------------------------
+// -- --generate-report: a self-contained HTML report of a generate run
+//
+// The report has the summary table of the run, a histogram of the chunk
+// lengths in cl100k tokens, the 10 longest and shortest chunks with the
+// start of their text and the effective configuration. It's a single file
+// without scripts or external styles, so it can be mailed or attached to a
+// ticket as it is.
+
+use std::{fs, time::Duration};
+
+use serde_json::Value;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+// bars of the chunk length histogram
+const HISTOGRAM_BUCKETS: usize = 10;
+// longest and shortest chunks listed
+const LISTED_CHUNKS: usize = 10;
+// characters of a chunk's text shown in the lists
+const PREVIEW_CHARS: usize = 300;
+
+pub struct ReportDocument {
+    pub path: String,
+    pub chunks: usize,
+    pub rejected: usize,
+    pub fallbacks: usize,
+    // stored, or why the document was skipped
+    pub status: String,
+    pub elapsed: Duration,
+}
+
+struct ReportChunk {
+    path: String,
+    index: usize,
+    tokens: usize,
+    preview: String,
+}
+
+pub struct IngestionReport {
+    documents: Vec<ReportDocument>,
+    // only the start of the text is kept, a run may store many chunks
+    chunks: Vec<ReportChunk>,
+    tokenizer: CoreBPE,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// -- chunk counts per bucket of equal width up to the longest chunk, with the bucket's upper bound
+fn histogram(lengths: &[usize]) -> Vec<(usize, usize)> {
+    let Some(&longest) = lengths.iter().max() else {
+        return vec![];
+    };
+    let width = longest.div_ceil(HISTOGRAM_BUCKETS).max(1);
+    let mut counts = vec![0; longest / width + 1];
+    for &length in lengths {
+        counts[length / width] += 1;
+    }
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(bucket, count)| ((bucket + 1) * width - 1, count))
+        .collect()
+}
+
+impl IngestionReport {
+    pub fn new() -> Self {
+        IngestionReport {
+            documents: vec![],
+            chunks: vec![],
+            tokenizer: cl100k_base().unwrap(),
+        }
+    }
+
+    pub fn add_document(&mut self, document: ReportDocument) {
+        self.documents.push(document);
+    }
+
+    pub fn add_chunk(&mut self, path: &str, index: usize, text: &str) {
+        self.chunks.push(ReportChunk {
+            path: path.to_string(),
+            index,
+            tokens: self.tokenizer.encode_ordinary(text).len(),
+            preview: preview(text),
+        });
+    }
+
+    fn summary(&self, elapsed: Duration) -> String {
+        let mut rows = String::new();
+        for document in &self.documents {
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1} s</td></tr>\n",
+                escape(&document.path),
+                document.chunks,
+                document.rejected,
+                document.fallbacks,
+                escape(&document.status),
+                document.elapsed.as_secs_f64()
+            ));
+        }
+        let total = |count: fn(&ReportDocument) -> usize| -> usize {
+            self.documents.iter().map(count).sum()
+        };
+        let skipped = self
+            .documents
+            .iter()
+            .filter(|document| document.status != "stored")
+            .count();
+        format!(
+            "<table>\n<tr><th>document</th><th>chunks</th><th>rejected</th>\
+             <th>original text</th><th>status</th><th>time</th></tr>\n{}\
+             <tr class=\"total\"><td>total: {} documents</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{} skipped</td><td>{:.1} s</td></tr>\n</table>",
+            rows,
+            self.documents.len(),
+            total(|document| document.chunks),
+            total(|document| document.rejected),
+            total(|document| document.fallbacks),
+            skipped,
+            elapsed.as_secs_f64()
+        )
+    }
+
+    fn histogram(&self) -> String {
+        let lengths: Vec<usize> = self.chunks.iter().map(|chunk| chunk.tokens).collect();
+        let buckets = histogram(&lengths);
+        let most = buckets.iter().map(|(_, count)| *count).max().unwrap_or(0);
+        let mut bars = String::new();
+        let mut lower = 0;
+        for (upper, count) in buckets {
+            let width = match most {
+                0 => 0.0,
+                most => count as f64 * 100.0 / most as f64,
+            };
+            bars.push_str(&format!(
+                "<tr><td>{}–{}</td><td class=\"bar\"><div style=\"width: {:.1}%\"></div></td>\
+                 <td>{}</td></tr>\n",
+                lower, upper, width, count
+            ));
+            lower = upper + 1;
+        }
+        format!(
+            "<table class=\"histogram\">\n<tr><th>tokens</th><th></th><th>chunks</th></tr>\n{}</table>",
+            bars
+        )
+    }
+
+    fn chunk_list(chunks: &[&ReportChunk]) -> String {
+        let mut rows = String::new();
+        for chunk in chunks {
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td class=\"preview\">{}</td></tr>\n",
+                escape(&chunk.path),
+                chunk.index,
+                chunk.tokens,
+                escape(&chunk.preview)
+            ));
+        }
+        format!(
+            "<table>\n<tr><th>document</th><th>chunk</th><th>tokens</th><th>text</th></tr>\n{}</table>",
+            rows
+        )
+    }
+
+    // -- the whole HTML page, `config` is the effective configuration of the run
+    pub fn render(&self, elapsed: Duration, config: &Value) -> String {
+        let mut by_length: Vec<&ReportChunk> = self.chunks.iter().collect();
+        by_length.sort_by_key(|chunk| std::cmp::Reverse(chunk.tokens));
+        let longest: Vec<&ReportChunk> = by_length.iter().take(LISTED_CHUNKS).copied().collect();
+        let shortest: Vec<&ReportChunk> = by_length
+            .iter()
+            .rev()
+            .take(LISTED_CHUNKS)
+            .copied()
+            .collect();
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Ingestion report</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }}
+tr.total {{ font-weight: bold; }}
+td.bar {{ width: 30em; }}
+td.bar div {{ background: #4a7bd0; height: 1em; }}
+td.preview {{ white-space: pre-wrap; max-width: 60em; }}
+pre {{ background: #f4f4f4; padding: 1em; }}
+</style>
+</head>
+<body>
+<h1>Ingestion report</h1>
+<h2>Summary</h2>
+{}
+<h2>Chunk lengths ({} chunks)</h2>
+{}
+<h2>Longest chunks</h2>
+{}
+<h2>Shortest chunks</h2>
+{}
+<h2>Configuration</h2>
+<pre>{}</pre>
+</body>
+</html>
+"#,
+            self.summary(elapsed),
+            self.chunks.len(),
+            self.histogram(),
+            Self::chunk_list(&longest),
+            Self::chunk_list(&shortest),
+            escape(&serde_json::to_string_pretty(config).unwrap())
+        )
+    }
+
+    pub fn write(&self, path: &str, elapsed: Duration, config: &Value) -> Result<(), String> {
+        fs::write(path, self.render(elapsed, config))
+            .map_err(|e| format!("writing the report {} failed: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn report_lists_documents_lengths_and_escaped_chunks() {
+        assert_eq!(histogram(&[]), vec![]);
+        assert_eq!(
+            histogram(&[0, 5, 9, 10, 95]),
+            vec![
+                (9, 3),
+                (19, 1),
+                (29, 0),
+                (39, 0),
+                (49, 0),
+                (59, 0),
+                (69, 0),
+                (79, 0),
+                (89, 0),
+                (99, 1),
+            ]
+        );
+
+        let mut report = IngestionReport::new();
+        report.add_document(ReportDocument {
+            path: "směrnice.pdf".to_string(),
+            chunks: 2,
+            rejected: 1,
+            fallbacks: 0,
+            status: "stored".to_string(),
+            elapsed: Duration::from_secs(3),
+        });
+        report.add_document(ReportDocument {
+            path: "velký.pdf".to_string(),
+            chunks: 0,
+            rejected: 0,
+            fallbacks: 0,
+            status: "skipped (120.0 MB)".to_string(),
+            elapsed: Duration::ZERO,
+        });
+        report.add_chunk("směrnice.pdf", 0, "Krátký <b>text</b>.");
+        report.add_chunk("směrnice.pdf", 1, &"dlouhý text ".repeat(100));
+        let html = report.render(Duration::from_secs(5), &json!({ "model": "gemma3:12b" }));
+
+        assert!(html.contains("<td>total: 2 documents</td><td>2</td><td>1</td><td>0</td>"));
+        assert!(html.contains("<td>1 skipped</td><td>5.0 s</td>"));
+        assert!(html.contains("Krátký &lt;b&gt;text&lt;/b&gt;."));
+        assert!(!html.contains("<b>text</b>"));
+        assert!(html.contains(&format!("{}…", "dlouhý text ".repeat(25))));
+        assert!(html.contains("&quot;model&quot;: &quot;gemma3:12b&quot;"));
+    }
+}
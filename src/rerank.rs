@@ -0,0 +1,146 @@
+// -------------------------------------
+// -- `--rerank`: retrieved chunks re-ordered by a model scoring their relevance
+//
+// The vector search fetches `candidates` chunks, the rerank model (`--rerank-model`,
+// `--model` by default) scores every (question, chunk) pair 0-10 and the best
+// scored ones are kept. Chunks whose score can't be parsed go last, in the
+// vector search order.
+
+use std::sync::OnceLock;
+
+use futures::future::join_all;
+use langchain_rust::{
+    language_models::llm::LLM,
+    schemas::{Document, Message},
+};
+use regex::Regex;
+use serde_json::json;
+
+use crate::config;
+
+pub struct Reranker {
+    llm: Box<dyn LLM>,
+    // chunks fetched by the vector search for every kept one
+    pub candidates_factor: usize,
+}
+
+impl Reranker {
+    pub fn new(llm: Box<dyn LLM>, candidates_factor: usize) -> Self {
+        Reranker {
+            llm,
+            candidates_factor: candidates_factor.max(1),
+        }
+    }
+
+    async fn score(&self, question: &str, chunk: &str) -> Option<f64> {
+        let messages = [
+            Message::new_system_message(config::RERANK_PROMPT_STR),
+            Message::new_human_message(format!("Otázka: {}\n\nText: {}", question, chunk)),
+        ];
+        match self.llm.generate(&messages).await {
+            Ok(result) => parse_score(&result.generation),
+            Err(e) => {
+                log::warn!("reranking failed: {}", e);
+                None
+            }
+        }
+    }
+
+    // -- the `limit` most relevant documents, with their `rerank_score` metadata
+    pub async fn rerank(&self, question: &str, docs: Vec<Document>, limit: usize) -> Vec<Document> {
+        let scores = join_all(docs.iter().map(|d| self.score(question, &d.page_content))).await;
+        let mut scored: Vec<(Option<f64>, Document)> = scores.into_iter().zip(docs).collect();
+        // -- stable, ties and unscored chunks keep the vector search order
+        scored.sort_by(|(a, _), (b, _)| b.unwrap_or(-1.0).total_cmp(&a.unwrap_or(-1.0)));
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(score, mut doc)| {
+                if let Some(score) = score {
+                    doc.metadata
+                        .insert("rerank_score".to_string(), json!(score));
+                }
+                doc
+            })
+            .collect()
+    }
+}
+
+// -- the first number of the answer, clamped to 0-10
+fn parse_score(answer: &str) -> Option<f64> {
+    // -- reasoning models think before they score
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer);
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let number = NUMBER.get_or_init(|| Regex::new(r"\d+(?:[.,]\d+)?").unwrap());
+    let score = number.find(answer)?.as_str().replace(',', ".");
+    score
+        .parse::<f64>()
+        .ok()
+        .map(|score| score.clamp(0.0, 10.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::Stream;
+    use langchain_rust::{
+        language_models::{GenerateResult, LLMError},
+        schemas::StreamData,
+    };
+    use std::pin::Pin;
+
+    // -- answers with the chunk text itself, "Text: 7" scores 7
+    #[derive(Clone)]
+    struct EchoLlm;
+
+    #[async_trait]
+    impl LLM for EchoLlm {
+        async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            let chunk = messages[1]
+                .content
+                .split("Text: ")
+                .nth(1)
+                .unwrap_or_default();
+            Ok(GenerateResult {
+                tokens: None,
+                generation: chunk.to_string(),
+            })
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn rerank_keeps_the_best_scored_and_unscored_last() {
+        let docs = ["2", "nic", "9", "5"].map(Document::new).to_vec();
+        let reranked = Reranker::new(Box::new(EchoLlm), 2)
+            .rerank("otázka", docs.clone(), 3)
+            .await;
+        let contents: Vec<&str> = reranked.iter().map(|d| d.page_content.as_str()).collect();
+        assert_eq!(contents, vec!["9", "5", "2"]);
+        assert_eq!(reranked[0].metadata.get("rerank_score"), Some(&json!(9.0)));
+
+        let all = Reranker::new(Box::new(EchoLlm), 2)
+            .rerank("otázka", docs, 10)
+            .await;
+        assert_eq!(all.last().unwrap().page_content, "nic");
+        assert!(!all.last().unwrap().metadata.contains_key("rerank_score"));
+    }
+
+    #[test]
+    fn score_is_the_first_number_of_the_answer() {
+        assert_eq!(parse_score("8"), Some(8.0));
+        assert_eq!(parse_score("Skóre: 7,5/10"), Some(7.5));
+        assert_eq!(parse_score(" 10.\n"), Some(10.0));
+        assert_eq!(parse_score("42"), Some(10.0));
+        assert_eq!(parse_score("<think>1 or 2?</think>\n3"), Some(3.0));
+        assert_eq!(parse_score("nevím"), None);
+    }
+}
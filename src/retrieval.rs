@@ -0,0 +1,769 @@
+// -------------------------------------
+// -- retriever over a shared vector store
+//
+// langchain's `Retriever` owns its store, so every chain would need its own
+// qdrant connection. This one only holds an `Arc`, so chains with their own
+// memory (per slack thread, per mcp call, ...) are cheap to build.
+//
+// The chain doesn't expose the (rephrased) question it retrieved with when
+// streaming, so retrievals can be recorded for the current task instead.
+// The chain also asks the model right after retrieving, so a request that
+// refuses without chunks is answered by `RefusingLlm` in the model's place.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures::Stream;
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Document, Message, Retriever, StreamData},
+};
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+
+use crate::{
+    expansion::{QueryExpander, StepBack},
+    injection::ChunkGuard,
+    rerank::Reranker,
+    store::{cosine_similarity, source_id, ChunkStore, MetadataFilter},
+};
+
+// -- (rephrased) question and documents of a retrieval
+pub struct Retrieval {
+    pub question: String,
+    pub documents: Vec<Document>,
+    // found only by the relaxed retry, less confident
+    pub relaxed: bool,
+    // searching, reranking and quoting the chunks
+    pub elapsed: Duration,
+}
+
+// -- how well the retrieved chunks back an answer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Confidence {
+    High,
+    Low,
+    None,
+}
+
+impl Confidence {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Confidence::High => "high",
+            Confidence::Low => "low",
+            Confidence::None => "none",
+        }
+    }
+}
+
+// -- high confidence needs `high_hits` chunks and a best one scoring `high_score`
+#[derive(Clone, Copy)]
+pub struct ConfidenceThresholds {
+    pub high_score: f64,
+    pub high_hits: usize,
+}
+
+impl Retrieval {
+    pub fn top_score(&self) -> Option<f64> {
+        self.documents.iter().map(|d| d.score).reduce(f64::max)
+    }
+
+    // -- none without chunks, chunks of a relaxed retrieval are low at best
+    pub fn confidence(&self, thresholds: ConfidenceThresholds) -> Confidence {
+        match self.top_score() {
+            None => Confidence::None,
+            Some(top)
+                if !self.relaxed
+                    && top >= thresholds.high_score
+                    && self.documents.len() >= thresholds.high_hits =>
+            {
+                Confidence::High
+            }
+            Some(_) => Confidence::Low,
+        }
+    }
+}
+
+// -- `--compress-context`: retrieved chunks cut down to what the question needs
+#[async_trait]
+pub trait Compressor: Send + Sync {
+    async fn compress(&self, question: &str, docs: Vec<Document>) -> Vec<Document>;
+}
+
+// -- retry of a search that found nothing above the threshold
+#[derive(Clone, Copy)]
+pub struct Relaxed {
+    pub threshold_delta: f32,
+    pub limit_factor: usize,
+}
+
+// -- `--freshness-weight`: chunks of older documents rank lower
+#[derive(Clone, Copy)]
+pub struct Freshness {
+    // 0 keeps the similarity, 1 multiplies it by the freshness
+    pub weight: f64,
+    pub half_life_days: f64,
+}
+
+// -- chunks retrieved and their minimal score, a collection's settings for one request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Search {
+    pub limit: usize,
+    pub score_threshold: f32,
+    // searched in place of the retriever's collection
+    pub collection: Option<String>,
+}
+
+tokio::task_local! {
+    static LAST_RETRIEVAL: RefCell<Option<Retrieval>>;
+    static REQUEST_FILTER: MetadataFilter;
+    static REQUEST_SEARCH: Search;
+    static REQUEST_REFUSAL: (String, ConfidenceThresholds);
+}
+
+// -- runs the future and returns the last retrieval made in it
+pub async fn recording<F: Future>(future: F) -> (F::Output, Option<Retrieval>) {
+    LAST_RETRIEVAL
+        .scope(RefCell::new(None), async {
+            let output = future.await;
+            (output, LAST_RETRIEVAL.with(|last| last.take()))
+        })
+        .await
+}
+
+// -- runs the future with retrievals restricted by the filter as well
+pub async fn filtered<F: Future>(filter: MetadataFilter, future: F) -> F::Output {
+    REQUEST_FILTER.scope(filter, future).await
+}
+
+// -- runs the future with retrievals of the search's collection, limit and threshold instead
+pub async fn searching<F: Future>(search: Search, future: F) -> F::Output {
+    REQUEST_SEARCH.scope(search, future).await
+}
+
+// -- runs the future answering the refusal instead of the model when the chunks give no confidence
+pub async fn refusing<F: Future>(
+    refusal: Option<String>,
+    thresholds: ConfidenceThresholds,
+    future: F,
+) -> F::Output {
+    match refusal {
+        Some(refusal) => REQUEST_REFUSAL.scope((refusal, thresholds), future).await,
+        None => future.await,
+    }
+}
+
+// -- the refusal of `refusing` when the last retrieval of `recording` found nothing to answer from
+fn refusal() -> Option<String> {
+    let (refusal, thresholds) = REQUEST_REFUSAL.try_with(Clone::clone).ok()?;
+    let unconfident = LAST_RETRIEVAL
+        .try_with(|last| {
+            last.borrow()
+                .as_ref()
+                .is_some_and(|retrieval| retrieval.confidence(thresholds) == Confidence::None)
+        })
+        .unwrap_or(false);
+    unconfident.then_some(refusal)
+}
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// -- streams the refusal of `refusing` without asking the model, the rephrasing still asks it
+pub struct RefusingLlm {
+    inner: Box<dyn LLM>,
+}
+
+impl RefusingLlm {
+    pub fn new(inner: Box<dyn LLM>) -> Self {
+        RefusingLlm { inner }
+    }
+}
+
+impl Clone for RefusingLlm {
+    fn clone(&self) -> Self {
+        RefusingLlm {
+            inner: self.inner.clone_box(),
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for RefusingLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        self.inner.generate(messages).await
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        match refusal() {
+            Some(refusal) => {
+                let data = StreamData::new(json!(refusal), None, &refusal);
+                Ok(Box::pin(futures::stream::iter([Ok(data)])))
+            }
+            None => self.inner.stream(messages).await,
+        }
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+pub struct StoreRetriever {
+    store: Arc<dyn ChunkStore>,
+    limit: usize,
+    score_threshold: f32,
+    anonymize_sources: bool,
+    relaxed: Option<Relaxed>,
+    guard: ChunkGuard,
+    reranker: Option<Arc<Reranker>>,
+    dedup_threshold: Option<f64>,
+    context_header: Option<String>,
+    filter: MetadataFilter,
+    collection: Option<String>,
+    freshness: Option<Freshness>,
+    expander: Option<Arc<QueryExpander>>,
+    step_back: Option<Arc<StepBack>>,
+    compressor: Option<Arc<dyn Compressor>>,
+}
+
+impl StoreRetriever {
+    pub fn new(store: Arc<dyn ChunkStore>, limit: usize, score_threshold: f32) -> Self {
+        StoreRetriever {
+            store,
+            limit,
+            score_threshold,
+            anonymize_sources: false,
+            relaxed: None,
+            guard: ChunkGuard::default(),
+            reranker: None,
+            dedup_threshold: None,
+            context_header: None,
+            filter: MetadataFilter::default(),
+            collection: None,
+            freshness: None,
+            expander: None,
+            step_back: None,
+            compressor: None,
+        }
+    }
+
+    pub fn relaxed(mut self, relaxed: Option<Relaxed>) -> Self {
+        self.relaxed = relaxed;
+        self
+    }
+
+    // -- every phrasing of the question is searched, the best scored chunks of all are kept
+    pub fn expander(mut self, expander: Option<Arc<QueryExpander>>) -> Self {
+        self.expander = expander;
+        self
+    }
+
+    // -- the step-back question is searched too and gets its share of the chunks
+    pub fn step_back(mut self, step_back: Option<Arc<StepBack>>) -> Self {
+        self.step_back = step_back;
+        self
+    }
+
+    // -- the kept chunks are cut down to what the question needs
+    pub fn compressor(mut self, compressor: Option<Arc<dyn Compressor>>) -> Self {
+        self.compressor = compressor;
+        self
+    }
+
+    // -- similarity scores are down-weighted by the age of the chunk's document
+    pub fn freshness(mut self, freshness: Option<Freshness>) -> Self {
+        self.freshness = freshness.filter(|f| f.weight > 0.0);
+        self
+    }
+
+    // -- only chunks matching the filter (and the one of `filtered`) are retrieved
+    pub fn filter(mut self, filter: MetadataFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // -- only chunks of the collection, unless `searching` names another one
+    pub fn collection(mut self, collection: Option<String>) -> Self {
+        self.collection = collection;
+        self
+    }
+
+    // -- prepended to every chunk, `{field}` is replaced by its metadata
+    pub fn context_header(mut self, context_header: Option<String>) -> Self {
+        self.context_header = context_header.filter(|header| !header.is_empty());
+        self
+    }
+
+    // -- chunks more similar than this to a higher ranked one are dropped
+    pub fn dedup_threshold(mut self, dedup_threshold: Option<f64>) -> Self {
+        self.dedup_threshold = dedup_threshold;
+        self
+    }
+
+    // -- the vector search fetches more candidates, the reranker keeps the best of them
+    pub fn reranker(mut self, reranker: Option<Arc<Reranker>>) -> Self {
+        self.reranker = reranker;
+        self
+    }
+
+    // -- chunks are always quoted as data, the guard's denylist flags suspicious ones
+    pub fn guard(mut self, guard: ChunkGuard) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    // -- `path` metadata of the retrieved chunks becomes `[src:<source id>]`
+    pub fn anonymize_sources(mut self, anonymize_sources: bool) -> Self {
+        self.anonymize_sources = anonymize_sources;
+        self
+    }
+}
+
+// -- days since the `created_at` or `last_modified` metadata, unix seconds or a date
+// -- (`2024-03-01`, `2024-03-01T10:00:00Z`)
+fn age_days(metadata: &HashMap<String, Value>, now: u64) -> Option<f64> {
+    let timestamp =
+        ["created_at", "last_modified"]
+            .iter()
+            .find_map(|key| match metadata.get(*key)? {
+                Value::Number(seconds) => seconds.as_f64(),
+                Value::String(date) => date_seconds(date),
+                _ => None,
+            })?;
+    Some((now as f64 - timestamp).max(0.0) / 86400.0)
+}
+
+// -- unix seconds of the day a `YYYY-MM-DD` date starts with
+fn date_seconds(date: &str) -> Option<f64> {
+    let mut parts = date.get(..10)?.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // -- days from civil (Howard Hinnant)
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(((era * 146097 + day_of_era - 719468) * 86400) as f64)
+}
+
+// -- re-ranked by similarity times `exp(-days / half life)`, weighted; undated chunks
+// -- keep their similarity
+fn freshened(
+    mut found: Vec<(Document, Vec<f64>)>,
+    freshness: Freshness,
+    now: u64,
+) -> Vec<(Document, Vec<f64>)> {
+    for (doc, _) in found.iter_mut() {
+        if let Some(days) = age_days(&doc.metadata, now) {
+            let fresh = (-days / freshness.half_life_days.max(f64::MIN_POSITIVE)).exp();
+            doc.score *= 1.0 - freshness.weight + freshness.weight * fresh;
+        }
+    }
+    found.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+    found
+}
+
+// -- results of several searches, every chunk once with its best score. Stores don't
+// -- return point ids, a chunk of the same text and source is the same point
+fn united(found: Vec<Vec<(Document, Vec<f64>)>>, limit: usize) -> Vec<(Document, Vec<f64>)> {
+    if found.len() == 1 {
+        return found.into_iter().flatten().collect();
+    }
+    let mut united: Vec<(Document, Vec<f64>)> = vec![];
+    for (doc, vector) in found.into_iter().flatten() {
+        match united.iter_mut().find(|(kept, _)| same_chunk(kept, &doc)) {
+            Some(kept) if kept.0.score < doc.score => *kept = (doc, vector),
+            Some(_) => {}
+            None => united.push((doc, vector)),
+        }
+    }
+    united.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+    united.truncate(limit);
+    united
+}
+
+fn same_chunk(a: &Document, b: &Document) -> bool {
+    a.page_content == b.page_content && a.metadata.get("path") == b.metadata.get("path")
+}
+
+// -- `weight` of the `limit` chunks are the best of the step-back search, the rest the best
+// -- of the question's. Slots one search can't fill go to the best left of the other, a
+// -- chunk found by both counts once with its best score
+fn stepped_back(
+    found: Vec<(Document, Vec<f64>)>,
+    stepped: Vec<(Document, Vec<f64>)>,
+    weight: f32,
+    limit: usize,
+) -> Vec<(Document, Vec<f64>)> {
+    let stepped_slots = ((limit as f32 * weight).round() as usize).min(limit);
+    let mut found = found.into_iter();
+    let mut stepped = stepped.into_iter();
+    let mut first: Vec<(Document, Vec<f64>)> = found.by_ref().take(limit - stepped_slots).collect();
+    first.extend(stepped.by_ref().take(stepped_slots));
+    let mut rest: Vec<(Document, Vec<f64>)> = found.chain(stepped).collect();
+    rest.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+
+    let mut merged: Vec<(Document, Vec<f64>)> = vec![];
+    for (doc, vector) in first.into_iter().chain(rest) {
+        let full = merged.len() >= limit;
+        match merged.iter_mut().find(|(kept, _)| same_chunk(kept, &doc)) {
+            Some(kept) if kept.0.score < doc.score => *kept = (doc, vector),
+            Some(_) => {}
+            None if !full => merged.push((doc, vector)),
+            None => {}
+        }
+    }
+    merged.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+    merged
+}
+
+// -- copy-pasted sections come back as near-identical chunks, only the best ranked one is kept
+fn deduplicated(found: Vec<(Document, Vec<f64>)>, threshold: Option<f64>) -> Vec<Document> {
+    let Some(threshold) = threshold else {
+        return found.into_iter().map(|(doc, _)| doc).collect();
+    };
+    let mut kept: Vec<(Document, Vec<f64>)> = vec![];
+    for (doc, vector) in found {
+        if kept
+            .iter()
+            .any(|(_, kept)| cosine_similarity(kept, &vector) > threshold)
+        {
+            log::debug!("dropping duplicate chunk {}", doc.page_content);
+            continue;
+        }
+        kept.push((doc, vector));
+    }
+    kept.into_iter().map(|(doc, _)| doc).collect()
+}
+
+// -- `{field}` of the template replaced by the metadata, strings without quotes,
+// -- missing fields by nothing
+fn context_header(template: &str, metadata: &HashMap<String, Value>) -> String {
+    static FIELD: OnceLock<Regex> = OnceLock::new();
+    let field = FIELD.get_or_init(|| Regex::new(r"\{(\w+)\}").unwrap());
+    field
+        .replace_all(template, |captures: &Captures| {
+            match metadata.get(&captures[1]) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            }
+        })
+        .into_owned()
+}
+
+pub fn anonymized_path(path: &str) -> String {
+    format!("[src:{}]", source_id(path))
+}
+
+impl StoreRetriever {
+    // -- the best scored chunks found by any of the queries, merged with the step-back ones
+    async fn search(
+        &self,
+        queries: &[String],
+        step_back: Option<&str>,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+        let mut found = vec![];
+        for query in queries {
+            found.push(
+                self.store
+                    .similarity_search_with_vectors(query, limit, score_threshold, filter)
+                    .await?,
+            );
+        }
+        let found = united(found, limit);
+        let (Some(question), Some(step_back)) = (step_back, &self.step_back) else {
+            return Ok(found);
+        };
+        let stepped = self
+            .store
+            .similarity_search_with_vectors(question, limit, score_threshold, filter)
+            .await?;
+        Ok(stepped_back(found, stepped, step_back.weight, limit))
+    }
+}
+
+#[async_trait]
+impl Retriever for StoreRetriever {
+    async fn get_relevant_documents(&self, query: &str) -> Result<Vec<Document>, Box<dyn Error>> {
+        let started = Instant::now();
+        let Search {
+            limit,
+            score_threshold,
+            collection,
+        } = REQUEST_SEARCH.try_with(Clone::clone).unwrap_or(Search {
+            limit: self.limit,
+            score_threshold: self.score_threshold,
+            collection: self.collection.clone(),
+        });
+        let mut filter = REQUEST_FILTER
+            .try_with(|request| self.filter.clone().and(request))
+            .unwrap_or_else(|_| self.filter.clone());
+        if let Some(collection) = &collection {
+            filter = filter.and(&MetadataFilter::collection(collection));
+        }
+        let candidates = match &self.reranker {
+            Some(reranker) => limit * reranker.candidates_factor,
+            None => limit,
+        };
+        let queries = match &self.expander {
+            Some(expander) => expander.expand(query).await,
+            None => vec![query.to_string()],
+        };
+        let step_back = match &self.step_back {
+            Some(step_back) => step_back.question(query).await,
+            None => None,
+        };
+        let step_back = step_back.as_deref();
+        let mut docs = self
+            .search(&queries, step_back, candidates, score_threshold, &filter)
+            .await?;
+        let mut relaxed = false;
+        if let Some(relax) = self.relaxed.filter(|_| docs.is_empty()) {
+            let threshold = score_threshold - relax.threshold_delta;
+            let limit = candidates * relax.limit_factor.max(1);
+            log::debug!(
+                "nothing above {}, retrying with threshold {} and limit {}",
+                score_threshold,
+                threshold,
+                limit
+            );
+            docs = self
+                .search(&queries, step_back, limit, threshold, &filter)
+                .await?;
+            relaxed = !docs.is_empty();
+        }
+        if let Some(freshness) = self.freshness {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            docs = freshened(docs, freshness, now);
+        }
+        let mut docs = deduplicated(docs, self.dedup_threshold);
+        // -- sentence-window chunks: the sentence was matched, the prompt gets its window
+        for doc in docs.iter_mut() {
+            if let Some(window) = doc.metadata.get("window").filter(|w| w.is_string()) {
+                doc.page_content = window.to_string();
+            }
+        }
+        if let Some(reranker) = &self.reranker {
+            docs = reranker.rerank(query, docs, limit).await;
+        }
+        if let Some(compressor) = &self.compressor {
+            docs = compressor.compress(query, docs).await;
+        }
+        for (i, doc) in docs.iter_mut().enumerate() {
+            if self.anonymize_sources {
+                if let Some(path) = doc.metadata.get("path").and_then(|p| p.as_str()) {
+                    let path = anonymized_path(path);
+                    doc.metadata.insert("path".to_string(), path.into());
+                }
+            }
+            if let Some(template) = &self.context_header {
+                doc.page_content = context_header(template, &doc.metadata) + &doc.page_content;
+            }
+            self.guard.quote(i + 1, doc);
+        }
+        // -- not recording outside of `recording`
+        let _ = LAST_RETRIEVAL.try_with(|last| {
+            last.replace(Some(Retrieval {
+                question: query.to_string(),
+                documents: docs.clone(),
+                relaxed,
+                elapsed: started.elapsed(),
+            }))
+        });
+        Ok(docs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn confidence_follows_the_top_score_and_the_hits() {
+        let retrieval = |scores: &[f64], relaxed| Retrieval {
+            question: String::new(),
+            documents: scores
+                .iter()
+                .map(|score| {
+                    let mut doc = Document::new("chunk");
+                    doc.score = *score;
+                    doc
+                })
+                .collect(),
+            relaxed,
+            elapsed: Duration::ZERO,
+        };
+        let thresholds = ConfidenceThresholds {
+            high_score: 0.7,
+            high_hits: 2,
+        };
+        let confidence =
+            |scores: &[f64], relaxed| retrieval(scores, relaxed).confidence(thresholds);
+        assert_eq!(confidence(&[0.6, 0.8], false), Confidence::High);
+        assert_eq!(confidence(&[0.8], false), Confidence::Low);
+        assert_eq!(confidence(&[0.65, 0.6], false), Confidence::Low);
+        assert_eq!(confidence(&[0.8, 0.75], true), Confidence::Low);
+        assert_eq!(confidence(&[], false), Confidence::None);
+        assert_eq!(retrieval(&[0.6, 0.8], false).top_score(), Some(0.8));
+    }
+
+    #[test]
+    fn context_header_fills_metadata_fields() {
+        let metadata = HashMap::from([
+            ("path".to_string(), json!("docs/a.pdf")),
+            ("page".to_string(), json!(3)),
+            ("section".to_string(), Value::Null),
+        ]);
+        assert_eq!(
+            context_header("Source: {path}, Page: {page}\n---\n", &metadata),
+            "Source: docs/a.pdf, Page: 3\n---\n"
+        );
+        assert_eq!(
+            context_header("{section}|{missing}|{ path }", &metadata),
+            "||{ path }"
+        );
+    }
+
+    #[test]
+    fn older_chunks_rank_lower_by_their_freshness() {
+        let now = 1_700_000_000;
+        let day = 86400;
+        let doc = |name: &str, score, metadata: Value| {
+            let mut doc = Document::new(name);
+            doc.score = score;
+            if let Value::Object(metadata) = metadata {
+                doc.metadata = metadata.into_iter().collect();
+            }
+            (doc, vec![])
+        };
+        let found = vec![
+            doc("old", 0.9, json!({ "last_modified": now - 180 * day })),
+            doc("new", 0.8, json!({ "created_at": now - day })),
+            doc("undated", 0.7, json!({})),
+        ];
+        let ranked = |weight| -> Vec<(String, f64)> {
+            let freshness = Freshness {
+                weight,
+                half_life_days: 90.0,
+            };
+            freshened(found.clone(), freshness, now)
+                .into_iter()
+                .map(|(doc, _)| (doc.page_content, (doc.score * 1000.0).round() / 1000.0))
+                .collect()
+        };
+        assert_eq!(
+            ranked(1.0),
+            vec![
+                ("new".to_string(), 0.791),
+                ("undated".to_string(), 0.7),
+                ("old".to_string(), 0.122)
+            ]
+        );
+        assert_eq!(ranked(0.5)[2], ("old".to_string(), 0.511));
+        assert_eq!(ranked(0.0)[0], ("old".to_string(), 0.9));
+    }
+
+    #[test]
+    fn dates_are_read_as_unix_seconds() {
+        assert_eq!(date_seconds("1970-01-01"), Some(0.0));
+        assert_eq!(date_seconds("2024-03-01T10:00:00Z"), Some(1_709_251_200.0));
+        assert_eq!(date_seconds("2024-13-01"), None);
+        assert_eq!(date_seconds("yesterday"), None);
+        let metadata = HashMap::from([("created_at".to_string(), json!("1970-01-11"))]);
+        assert_eq!(age_days(&metadata, 20 * 86400), Some(10.0));
+    }
+
+    #[test]
+    fn searches_are_united_by_best_score() {
+        let doc = |text: &str, path: &str, score| {
+            let mut doc = Document::new(text);
+            doc.metadata.insert("path".to_string(), json!(path));
+            doc.score = score;
+            (doc, vec![])
+        };
+        let found = vec![
+            vec![doc("a", "x.pdf", 0.6), doc("b", "x.pdf", 0.5)],
+            vec![doc("c", "x.pdf", 0.9), doc("a", "x.pdf", 0.8)],
+            vec![doc("a", "y.pdf", 0.4)],
+        ];
+        let united: Vec<(String, f64)> = united(found, 3)
+            .into_iter()
+            .map(|(doc, _)| (doc.page_content, doc.score))
+            .collect();
+        assert_eq!(
+            united,
+            vec![
+                ("c".to_string(), 0.9),
+                ("a".to_string(), 0.8),
+                ("b".to_string(), 0.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn step_back_chunks_get_their_share() {
+        let doc = |text: &str, score| {
+            let mut doc = Document::new(text);
+            doc.score = score;
+            (doc, vec![])
+        };
+        let texts = |docs: Vec<(Document, Vec<f64>)>| -> Vec<String> {
+            docs.into_iter().map(|(doc, _)| doc.page_content).collect()
+        };
+        let found = || vec![doc("a", 0.9), doc("b", 0.8), doc("c", 0.7), doc("d", 0.6)];
+        let stepped = || vec![doc("x", 0.75), doc("a", 0.95), doc("y", 0.5)];
+        assert_eq!(
+            texts(stepped_back(found(), stepped(), 0.5, 4)),
+            ["a", "b", "x", "c"]
+        );
+        assert_eq!(stepped_back(found(), stepped(), 0.5, 4)[0].0.score, 0.95);
+        assert_eq!(
+            texts(stepped_back(found(), stepped(), 0.0, 3)),
+            ["a", "b", "c"]
+        );
+        assert_eq!(
+            texts(stepped_back(found(), vec![], 0.5, 3)),
+            ["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn near_identical_chunks_keep_the_best_ranked() {
+        let found = vec![
+            (Document::new("a"), vec![1.0, 0.0]),
+            (Document::new("b"), vec![0.0, 1.0]),
+            (Document::new("a copy"), vec![0.99, 0.01]),
+            (Document::new("no vector"), vec![]),
+        ];
+        let kept = |threshold| -> Vec<String> {
+            deduplicated(found.clone(), threshold)
+                .into_iter()
+                .map(|doc| doc.page_content)
+                .collect()
+        };
+        assert_eq!(kept(Some(0.95)), vec!["a", "b", "no vector"]);
+        assert_eq!(kept(Some(1.01)).len(), 4);
+        assert_eq!(kept(None).len(), 4);
+    }
+}
@@ -0,0 +1,390 @@
+// -------------------------------------
+// -- `--output-schema schema.json`: answers as JSON matching a schema
+//
+// The schema is added to the system prompt of the answer, the answer is
+// parsed and validated against it and generated again with the validation
+// error (at most `--output-schema-retries` times). The chain then answers
+// the validated JSON, compact and without code fences. Streams are buffered,
+// only a validated answer is sent. Only the prompts with a system message
+// are answers, the rephrased follow-up questions are left as they are.
+//
+// The validation covers the keywords extraction schemas use: `type`, `enum`,
+// `const`, `properties`, `required`, `additionalProperties`, `items`,
+// `minItems`/`maxItems`, `minLength`/`maxLength`, `minimum`/`maximum` and
+// `pattern`. Other keywords are ignored.
+
+use std::{pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, MessageType, StreamData},
+};
+use regex::Regex;
+use serde_json::{json, Value};
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+pub struct OutputSchema {
+    schema: Value,
+}
+
+impl OutputSchema {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading --output-schema {}: {}", path, e))?;
+        let schema: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid --output-schema {}: {}", path, e))?;
+        if !schema.is_object() {
+            return Err(format!(
+                "Invalid --output-schema {}: not a JSON object",
+                path
+            ));
+        }
+        Ok(OutputSchema { schema })
+    }
+
+    pub fn instruction(&self) -> String {
+        format!(
+            "Respond only with valid JSON matching this schema: {}",
+            self.schema
+        )
+    }
+
+    // -- the JSON of the answer, or why it doesn't match
+    pub fn validated(&self, answer: &str) -> Result<Value, String> {
+        let value: Value = serde_json::from_str(json_text(answer))
+            .map_err(|e| format!("the answer is not JSON ({})", e))?;
+        let mut errors = vec![];
+        violations(&self.schema, &value, "$", &mut errors);
+        match errors.is_empty() {
+            true => Ok(value),
+            false => Err(errors.join("; ")),
+        }
+    }
+}
+
+// -- the answer without the thinking of reasoning models and a ```json fence around it
+fn json_text(answer: &str) -> &str {
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer).trim();
+    answer
+        .strip_prefix("```json")
+        .or_else(|| answer.strip_prefix("```"))
+        .and_then(|fenced| fenced.trim_end().strip_suffix("```"))
+        .unwrap_or(answer)
+        .trim()
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn violations(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(name) => vec![name.as_str()],
+        Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|name| type_matches(name, value)) {
+        errors.push(format!("{}: expected {}", path, types.join(" or ")));
+        return;
+    }
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            errors.push(format!("{}: expected one of {}", path, schema["enum"]));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            errors.push(format!("{}: expected {}", path, expected));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            for field in schema["required"].as_array().into_iter().flatten() {
+                if let Some(field) = field.as_str().filter(|f| !object.contains_key(*f)) {
+                    errors.push(format!("{}: missing {}", path, field));
+                }
+            }
+            for (field, field_value) in object {
+                let field_path = format!("{}.{}", path, field);
+                match (
+                    schema["properties"].get(field),
+                    &schema["additionalProperties"],
+                ) {
+                    (Some(field_schema), _) => {
+                        violations(field_schema, field_value, &field_path, errors)
+                    }
+                    (None, Value::Bool(false)) => {
+                        errors.push(format!("{}: not allowed", field_path))
+                    }
+                    (None, additional) if additional.is_object() => {
+                        violations(additional, field_value, &field_path, errors)
+                    }
+                    (None, _) => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            if schema["minItems"]
+                .as_u64()
+                .is_some_and(|min| (items.len() as u64) < min)
+            {
+                errors.push(format!("{}: fewer than {} items", path, schema["minItems"]));
+            }
+            if schema["maxItems"]
+                .as_u64()
+                .is_some_and(|max| items.len() as u64 > max)
+            {
+                errors.push(format!("{}: more than {} items", path, schema["maxItems"]));
+            }
+            if schema["items"].is_object() {
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, index);
+                    violations(&schema["items"], item, &item_path, errors);
+                }
+            }
+        }
+        Value::String(text) => {
+            let chars = text.chars().count() as u64;
+            if schema["minLength"].as_u64().is_some_and(|min| chars < min) {
+                errors.push(format!("{}: shorter than {}", path, schema["minLength"]));
+            }
+            if schema["maxLength"].as_u64().is_some_and(|max| chars > max) {
+                errors.push(format!("{}: longer than {}", path, schema["maxLength"]));
+            }
+            let pattern = schema["pattern"].as_str().and_then(|p| Regex::new(p).ok());
+            if pattern.is_some_and(|pattern| !pattern.is_match(text)) {
+                errors.push(format!("{}: doesn't match {}", path, schema["pattern"]));
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if schema["minimum"].as_f64().is_some_and(|min| number < min) {
+                errors.push(format!("{}: less than {}", path, schema["minimum"]));
+            }
+            if schema["maximum"].as_f64().is_some_and(|max| number > max) {
+                errors.push(format!("{}: more than {}", path, schema["maximum"]));
+            }
+        }
+        _ => {}
+    }
+}
+
+pub struct SchemaLlm {
+    inner: Box<dyn LLM>,
+    schema: Arc<OutputSchema>,
+    retries: usize,
+}
+
+impl SchemaLlm {
+    pub fn new(inner: Box<dyn LLM>, schema: Arc<OutputSchema>, retries: usize) -> Self {
+        SchemaLlm {
+            inner,
+            schema,
+            retries,
+        }
+    }
+
+    // -- the answer prompt with the schema instruction, None for other prompts
+    fn instructed(&self, messages: &[Message]) -> Option<Vec<Message>> {
+        let system = messages
+            .iter()
+            .position(|m| matches!(m.message_type, MessageType::SystemMessage))?;
+        let mut messages = messages.to_vec();
+        let content = format!(
+            "{}\n{}",
+            messages[system].content,
+            self.schema.instruction()
+        );
+        messages[system] = Message::new_system_message(content);
+        Some(messages)
+    }
+
+    // -- the rejected answer and its error, asked again; Err when there is no retry left
+    fn retry(
+        &self,
+        attempt: usize,
+        messages: &mut Vec<Message>,
+        answer: &str,
+        error: String,
+    ) -> Result<(), LLMError> {
+        if attempt >= self.retries {
+            return Err(LLMError::OtherError(format!(
+                "answer doesn't match --output-schema: {}",
+                error
+            )));
+        }
+        log::warn!("answer doesn't match --output-schema ({}), retrying", error);
+        messages.push(Message::new_ai_message(answer));
+        messages.push(Message::new_human_message(format!(
+            "Your answer is not valid: {}. {}",
+            error,
+            self.schema.instruction()
+        )));
+        Ok(())
+    }
+}
+
+impl Clone for SchemaLlm {
+    fn clone(&self) -> Self {
+        SchemaLlm {
+            inner: self.inner.clone_box(),
+            schema: self.schema.clone(),
+            retries: self.retries,
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for SchemaLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        let Some(mut messages) = self.instructed(messages) else {
+            return self.inner.generate(messages).await;
+        };
+        for attempt in 0.. {
+            let mut result = self.inner.generate(&messages).await?;
+            match self.schema.validated(&result.generation) {
+                Ok(value) => {
+                    result.generation = value.to_string();
+                    return Ok(result);
+                }
+                Err(e) => self.retry(attempt, &mut messages, &result.generation, e)?,
+            }
+        }
+        unreachable!("the attempts end with an answer or an error")
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        let Some(mut messages) = self.instructed(messages) else {
+            return self.inner.stream(messages).await;
+        };
+        for attempt in 0.. {
+            let mut answer = String::new();
+            let mut items = self.inner.stream(&messages).await?;
+            while let Some(item) = items.next().await {
+                answer.push_str(&item?.content);
+            }
+            match self.schema.validated(&answer) {
+                Ok(value) => {
+                    let content = value.to_string();
+                    let value = json!({
+                        "message": { "role": "assistant", "content": content },
+                        "done": true,
+                    });
+                    let data = StreamData::new(value, None, content);
+                    return Ok(Box::pin(stream::iter([Ok(data)])));
+                }
+                Err(e) => self.retry(attempt, &mut messages, &answer, e)?,
+            }
+        }
+        unreachable!("the attempts end with an answer or an error")
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_llms::ScriptedLlm;
+
+    fn schema() -> OutputSchema {
+        OutputSchema {
+            schema: json!({
+                "type": "object",
+                "properties": {
+                    "policy": { "type": "integer", "minimum": 1 },
+                    "title": { "type": "string", "minLength": 3 },
+                    "valid_from": { "type": "string", "pattern": "^\\d{4}-\\d{2}-\\d{2}$" },
+                    "owners": { "type": "array", "items": { "type": "string" }, "minItems": 1 },
+                    "status": { "enum": ["draft", "active"] },
+                },
+                "required": ["policy", "title"],
+                "additionalProperties": false,
+            }),
+        }
+    }
+
+    #[test]
+    fn answers_are_validated_against_the_schema() {
+        let schema = schema();
+        let valid = "```json\n{\"policy\": 42, \"title\": \"Dovolená\", \"owners\": [\"HR\"]}\n```";
+        assert_eq!(
+            schema.validated(valid),
+            Ok(json!({"policy": 42, "title": "Dovolená", "owners": ["HR"]}))
+        );
+        assert_eq!(
+            schema.validated("<think>hmm</think>{\"policy\": 1, \"title\": \"abc\"}"),
+            Ok(json!({"policy": 1, "title": "abc"}))
+        );
+        assert_eq!(
+            schema.validated(
+                r#"{"policy": "42", "valid_from": "1. 1. 2024", "owners": [], "status": "old", "x": 1}"#
+            ),
+            Err([
+                "$: missing title",
+                "$.owners: fewer than 1 items",
+                "$.policy: expected integer",
+                "$.status: expected one of [\"draft\",\"active\"]",
+                "$.valid_from: doesn't match \"^\\\\d{4}-\\\\d{2}-\\\\d{2}$\"",
+                "$.x: not allowed",
+            ]
+            .join("; "))
+        );
+        assert!(schema
+            .validated("Politika 42 je o dovolené.")
+            .unwrap_err()
+            .starts_with("the answer is not JSON"));
+    }
+
+    #[tokio::test]
+    async fn invalid_answers_are_retried_with_the_error() {
+        let scripted = ScriptedLlm::new(&[
+            "Politika 42.",
+            "{\"policy\": 42, \"title\": \"Dovolená\"}",
+            "{\"policy\": 0}",
+            "[]",
+        ]);
+        let llm = SchemaLlm::new(Box::new(scripted.clone()), Arc::new(schema()), 1);
+        let messages = [
+            Message::new_system_message("Odpovídej česky."),
+            Message::new_human_message("O čem je politika 42?"),
+        ];
+
+        let result = llm.generate(&messages).await.unwrap();
+        assert_eq!(result.generation, r#"{"policy":42,"title":"Dovolená"}"#);
+        let prompts = scripted.prompts.lock().unwrap().clone();
+        assert!(prompts[0][0].content.ends_with(&schema().instruction()));
+        assert_eq!(prompts[1].len(), 4);
+        assert!(prompts[1][3]
+            .content
+            .starts_with("Your answer is not valid: the answer is not JSON"));
+
+        // -- no retry left
+        let e = llm.stream(&messages).await.err().unwrap();
+        assert!(e.to_string().contains("$: expected object"));
+
+        // -- rephrased questions have no system message
+        let rephrase = [Message::new_human_message("Přeformuluj otázku.")];
+        scripted.answers.lock().unwrap().push("Co je politika 42?");
+        assert_eq!(
+            llm.generate(&rephrase).await.unwrap().generation,
+            "Co je politika 42?"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+// -------------------------------------
+// -- selftest: a bundled document ingested, retrieved and answered from
+//
+// A release check of the whole pipeline against the configured ollama and
+// --db: the fixture pdf is ingested by the code of generate, its chunks and
+// their metadata are read back, three questions about it have to retrieve it
+// and one has to be answered. The fixture is chunked the same way whatever
+// the chunking arguments, so the chunk count is known; its chunks are
+// deleted from the store afterwards, passed or not. With --offline ollama
+// is a local mock (enrichment returns the chunk as it is, embeddings are
+// hashed words) and the store is `memory`, which `cargo test` runs.
+
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use axum::{
+    routing::{get, post},
+    Json, Router,
+};
+use langchain_rust::{chain::Chain, prompt_args, schemas::Retriever};
+use ollama_rs::Ollama as OllamaClient;
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use crate::{
+    output, retrieval, store, Cli, ContextStrategy, DocType, Ingest, IngestOutcome, SplitStrategy,
+    RETRIEVED_DOCUMENTS, SCORE_THRESHOLD,
+};
+
+pub const FIXTURE_PDF: &[u8] = include_bytes!("./fixtures/selftest.pdf");
+// --chunk-size of the fixture, and the chunks it's split into
+const FIXTURE_CHUNK_SIZE: usize = 64;
+const FIXTURE_CHUNKS: usize = 6;
+// questions answered by the fixture only
+const QUESTIONS: [&str; 3] = [
+    "How many days of annual leave do employees of the Zelena Lhota Water Works have?",
+    "How much does the Zelena Lhota Water Works pay per kilometre driven in an own car?",
+    "What is the night shift allowance at the Zelena Lhota Water Works?",
+];
+
+// model names of the --offline mock
+const MOCK_MODEL: &str = "selftest";
+// dimensions of the mock embeddings, few enough for any two texts to be similar
+const MOCK_DIMENSIONS: usize = 16;
+
+// -- `Aktuální chunk` of the window prompt, the enriched chunk is the chunk itself
+fn mock_answer(request: &Value) -> String {
+    let prompt = request["messages"]
+        .as_array()
+        .and_then(|messages| messages.last())
+        .and_then(|message| message["content"].as_str())
+        .unwrap_or_default();
+    let chunk = prompt
+        .split_once("Aktuální chunk:")
+        .and_then(|(_, rest)| rest.split_once("Následující chunky:"))
+        .map(|(chunk, _)| chunk.trim());
+    match chunk {
+        Some(chunk) => chunk.to_string(),
+        None => "Employees have 27 days of annual leave.".to_string(),
+    }
+}
+
+// -- counts of the words hashed into a few dimensions
+pub fn mock_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; MOCK_DIMENSIONS];
+    vector[0] = 1.0;
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 2)
+    {
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(2_166_136_261_u32, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(16_777_619)
+            });
+        vector[hash as usize % MOCK_DIMENSIONS] += 1.0;
+    }
+    vector
+}
+
+fn chat_response(body: &str) -> Json<Value> {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    Json(json!({
+        "model": MOCK_MODEL,
+        "created_at": "2031-01-01T00:00:00Z",
+        "message": { "role": "assistant", "content": mock_answer(&request) },
+        "done": true,
+        "total_duration": 0,
+        "prompt_eval_count": 0,
+        "prompt_eval_duration": 0,
+        "eval_count": 0,
+        "eval_duration": 0,
+    }))
+}
+
+fn embed_response(body: &str) -> Json<Value> {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let texts: Vec<&str> = match &request["input"] {
+        Value::String(text) => vec![text],
+        Value::Array(texts) => texts.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    let embeddings: Vec<Vec<f32>> = texts.into_iter().map(mock_embedding).collect();
+    Json(json!({ "embeddings": embeddings }))
+}
+
+// -- url of an ollama answering chat and embedding requests, for --offline
+pub async fn mock_ollama() -> Result<String, String> {
+    // -- ollama-rs sends json without a content type, the bodies are read as text
+    let app = Router::new()
+        .route(
+            "/api/chat",
+            post(|body: String| async move { chat_response(&body) }),
+        )
+        .route(
+            "/api/embed",
+            post(|body: String| async move { embed_response(&body) }),
+        )
+        .route(
+            "/api/tags",
+            get(|| async { Json(json!({ "models": [{ "name": MOCK_MODEL }] })) }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| format!("starting the mock ollama failed: {}", e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(format!("http://{}", address))
+}
+
+// -- the arguments of the run, with the fixture's chunking
+fn selftest_cli(cli: &Cli, document: &str) -> Cli {
+    let mut cli = cli.clone();
+    cli.document = Some(document.to_string());
+    cli.doc_type = DocType::Pdf;
+    cli.split_strategy = SplitStrategy::Token;
+    cli.chunk_size = FIXTURE_CHUNK_SIZE;
+    cli.sizer = None;
+    cli.context_strategy = ContextStrategy::Window;
+    cli.prompts_dir = None;
+    cli.describe_images = false;
+    cli.filter = vec![];
+    cli.filter_by_payload = None;
+    cli
+}
+
+fn check(passed: bool, failure: impl FnOnce() -> String) -> Result<(), String> {
+    match passed {
+        true => Ok(()),
+        false => Err(failure()),
+    }
+}
+
+async fn cycle(
+    cli: &Cli,
+    document: &str,
+    stored: &Arc<dyn store::ChunkStore>,
+) -> Result<(), String> {
+    let ingest = Ingest::new(cli);
+    let stats = match ingest
+        .ingest_document(document, &HashMap::new(), &|_, _| {})
+        .await
+    {
+        IngestOutcome::Stored(stats) => stats,
+        IngestOutcome::Skipped(reason) => {
+            return Err(format!("the fixture was skipped: {}", reason));
+        }
+        IngestOutcome::Failed(e) => return Err(format!("ingesting the fixture failed: {}", e)),
+    };
+    check(stats.chunks == FIXTURE_CHUNKS, || {
+        format!(
+            "ingested {} chunks, expected {}",
+            stats.chunks, FIXTURE_CHUNKS
+        )
+    })?;
+    output::note(&format!("ok   ingested {} chunks", stats.chunks));
+
+    let filter = store::MetadataFilter::path(document);
+    let chunks = stored.scroll(&filter, FIXTURE_CHUNKS * 2).await?;
+    check(chunks.len() == FIXTURE_CHUNKS, || {
+        format!(
+            "the store holds {} chunks of the fixture, expected {}",
+            chunks.len(),
+            FIXTURE_CHUNKS
+        )
+    })?;
+    let mut indexes: Vec<u64> = vec![];
+    for chunk in &chunks {
+        for key in ["path", "chunk_index", "doc_type", "source_id"] {
+            check(chunk.metadata.contains_key(key), || {
+                format!("a stored chunk has no `{}`", key)
+            })?;
+        }
+        check(chunk.metadata["doc_type"] == json!("pdf"), || {
+            format!(
+                "a stored chunk has `doc_type` {}",
+                chunk.metadata["doc_type"]
+            )
+        })?;
+        indexes.extend(chunk.metadata["chunk_index"].as_u64());
+    }
+    indexes.sort_unstable();
+    check(
+        indexes == (0..FIXTURE_CHUNKS as u64).collect::<Vec<_>>(),
+        || format!("the stored chunk indexes are {:?}", indexes),
+    )?;
+    output::note("ok   stored chunks have their metadata");
+
+    let retriever =
+        retrieval::StoreRetriever::new(stored.clone(), RETRIEVED_DOCUMENTS, SCORE_THRESHOLD)
+            .relaxed((!cli.no_adaptive_retrieval).then_some(retrieval::Relaxed {
+                threshold_delta: cli.relaxed_threshold_delta,
+                limit_factor: cli.relaxed_limit_factor,
+            }));
+    for question in QUESTIONS {
+        let documents = retriever
+            .get_relevant_documents(question)
+            .await
+            .map_err(|e| format!("retrieving {:?} failed: {}", question, e))?;
+        check(
+            documents
+                .iter()
+                .any(|doc| doc.metadata.get("path") == Some(&json!(document))),
+            || format!("{:?} didn't retrieve the fixture", question),
+        )?;
+    }
+    output::note(&format!(
+        "ok   {} questions retrieved the fixture",
+        QUESTIONS.len()
+    ));
+
+    let chain = crate::chat_chain(ingest.ollama_client.clone(), cli, stored.clone());
+    let answer = chain
+        .invoke(prompt_args! { "question" => QUESTIONS[0] })
+        .await
+        .map_err(|e| format!("answering failed: {}", e))?;
+    check(!answer.trim().is_empty(), || {
+        "the answer is empty".to_string()
+    })?;
+    output::note("ok   answered");
+    Ok(())
+}
+
+// -- true when the whole cycle passed
+pub async fn selftest(cli: &Cli) -> bool {
+    let mut cli = cli.clone();
+    if cli.offline {
+        match mock_ollama().await {
+            Ok(url) => cli.ollama = Some(url),
+            Err(e) => {
+                output::error(&e);
+                return false;
+            }
+        }
+        cli.db = Some(store::MEMORY_DB.to_string());
+        cli.model = Some(MOCK_MODEL.to_string());
+        cli.embed = Some(MOCK_MODEL.to_string());
+        cli.fallback_model = None;
+    }
+    let document = std::env::temp_dir().join(format!("selftest-{}.pdf", uuid::Uuid::new_v4()));
+    if let Err(e) = fs::write(&document, FIXTURE_PDF) {
+        output::error(&format!("writing the fixture failed: {}", e));
+        return false;
+    }
+    let document = document.to_string_lossy().to_string();
+    let cli = selftest_cli(&cli, &document);
+    let ollama_client = Arc::new(OllamaClient::from_url(
+        Url::parse(cli.ollama.as_deref().unwrap()).unwrap(),
+    ));
+    let stored = crate::vector_store(ollama_client, &cli).await;
+
+    let result = cycle(&cli, &document, &stored).await;
+    if let Err(e) = stored.delete(&store::MetadataFilter::path(&document)).await {
+        output::warning(&format!("deleting the fixture's chunks failed: {}", e));
+    }
+    fs::remove_file(Path::new(&document)).ok();
+    match result {
+        Ok(()) => {
+            output::note("selftest passed");
+            true
+        }
+        Err(e) => {
+            output::error(&format!("selftest failed: {}", e));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn offline_selftest_ingests_retrieves_and_answers() {
+        let cli = Cli::parse_from(["chunk_contextor", "--offline", "selftest"]);
+        assert!(selftest(&cli).await);
+    }
+}
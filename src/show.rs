@@ -0,0 +1,122 @@
+// -------------------------------------
+// -- `show --path`: the chunks stored for a document, as retrieval gets them
+//
+// All chunks with the document's `path` are listed from the store (payload
+// only) and sorted by `chunk_index`, which `generate` stores since this
+// version; older chunks without it come last in store order. `--original`
+// prints the chunk as it was split, before enrichment added its context,
+// when `original_text` was stored with it.
+
+use langchain_rust::schemas::Document;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::store::chunk_text;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StoredChunk {
+    pub chunk_index: Option<u64>,
+    pub page: Option<Value>,
+    // --keywords of the chunk
+    pub keywords: Vec<String>,
+    // measured with --sizer
+    pub tokens: usize,
+    pub text: String,
+}
+
+// -- `original` takes `original_text` over the stored text where there is one
+pub fn stored_chunks(
+    docs: Vec<Document>,
+    original: bool,
+    size: impl Fn(&str) -> usize,
+) -> Vec<StoredChunk> {
+    let mut chunks: Vec<StoredChunk> = docs
+        .into_iter()
+        .map(|doc| {
+            let text = match doc.metadata.get("original_text").and_then(Value::as_str) {
+                Some(text) if original => text.to_string(),
+                _ => chunk_text(&doc.page_content),
+            };
+            StoredChunk {
+                chunk_index: doc.metadata.get("chunk_index").and_then(Value::as_u64),
+                page: doc.metadata.get("page").cloned(),
+                keywords: doc
+                    .metadata
+                    .get("keywords")
+                    .and_then(Value::as_array)
+                    .map(|keywords| {
+                        keywords
+                            .iter()
+                            .filter_map(Value::as_str)
+                            .map(str::to_string)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                tokens: size(&text),
+                text,
+            }
+        })
+        .collect();
+    chunks.sort_by_key(|chunk| chunk.chunk_index.unwrap_or(u64::MAX));
+    chunks
+}
+
+// -- a header line per chunk, `-` for what the chunk doesn't have
+pub fn render(chunks: &[StoredChunk], unit: &str) -> String {
+    chunks
+        .iter()
+        .map(|chunk| {
+            let index = chunk.chunk_index.map_or("-".to_string(), |i| i.to_string());
+            let page = match &chunk.page {
+                Some(Value::String(page)) => page.clone(),
+                Some(page) => page.to_string(),
+                None => "-".to_string(),
+            };
+            let keywords = match chunk.keywords.is_empty() {
+                true => String::new(),
+                false => format!(", keywords: {}", chunk.keywords.join(", ")),
+            };
+            format!(
+                "--- chunk {}, page {}, {} {}{}\n{}",
+                index, page, chunk.tokens, unit, keywords, chunk.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn chunks_are_sorted_and_decoded() {
+        let doc = |text: &str, metadata: Value| {
+            let metadata: HashMap<String, Value> = serde_json::from_value(metadata).unwrap();
+            Document::new(text).with_metadata(metadata)
+        };
+        let docs = vec![
+            doc("\"bez indexu\"", json!({})),
+            doc(
+                "\"Kontext.\\nDruhý\"",
+                json!({ "chunk_index": 1, "page": 2, "original_text": "Druhý", "keywords": ["druhý"] }),
+            ),
+            doc("\"První\"", json!({ "chunk_index": 0 })),
+        ];
+        let chars = |text: &str| text.chars().count();
+        let chunks = stored_chunks(docs.clone(), false, chars);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["První", "Kontext.\nDruhý", "bez indexu"]);
+        assert_eq!(chunks[1].tokens, 14);
+        assert_eq!(
+            render(&chunks[..2], "chars"),
+            "--- chunk 0, page -, 5 chars\nPrvní\n\n--- chunk 1, page 2, 14 chars, keywords: druhý\nKontext.\nDruhý"
+        );
+
+        let originals = stored_chunks(docs, true, chars);
+        assert_eq!(originals[1].text, "Druhý");
+        assert_eq!(originals[2].text, "bez indexu");
+    }
+}
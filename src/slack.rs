@@ -0,0 +1,359 @@
+// -------------------------------------
+// -- slack events api and slash command integration
+//
+// Slack expects every event to be acknowledged within 3 seconds, so the
+// handler only verifies and acks the request. The answer is generated in a
+// spawned task and posted into the thread of the triggering message, or to
+// the `response_url` of a slash command.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use hmac::{Hmac, Mac};
+use langchain_rust::{
+    chain::{Chain, ConversationalRetrieverChain},
+    prompt_args,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::answer::answer_text;
+
+const POST_MESSAGE_URL: &str = "https://slack.com/api/chat.postMessage";
+// requests older than this are rejected as possible replays
+const MAX_REQUEST_AGE_SECS: i64 = 60 * 5;
+// slack limit for a section block text
+const MAX_SECTION_CHARS: usize = 3000;
+// slack limit for context block elements
+const MAX_CONTEXT_ELEMENTS: usize = 10;
+// conversations kept in memory, the least recently used thread is dropped first
+const MAX_THREADS: usize = 1000;
+
+pub type ChainFactory = Box<dyn Fn() -> ConversationalRetrieverChain + Send + Sync>;
+
+pub struct SlackState {
+    // events are answered with chat.postMessage, slash commands don't need it
+    bot_token: Option<String>,
+    signing_secret: String,
+    new_chain: ChainFactory,
+    threads: Mutex<HashMap<String, (Instant, Arc<ConversationalRetrieverChain>)>>,
+    http: reqwest::Client,
+}
+
+impl SlackState {
+    pub fn new(bot_token: Option<String>, signing_secret: String, new_chain: ChainFactory) -> Self {
+        SlackState {
+            bot_token,
+            signing_secret,
+            new_chain,
+            threads: Mutex::new(HashMap::new()),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // -- every slack thread has its own conversation memory
+    fn thread_chain(&self, thread: &str) -> Arc<ConversationalRetrieverChain> {
+        let mut threads = self.threads.lock().unwrap();
+        if !threads.contains_key(thread) && threads.len() >= MAX_THREADS {
+            let oldest = threads
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                threads.remove(&oldest);
+            }
+        }
+        let entry = threads
+            .entry(thread.to_string())
+            .or_insert_with(|| (Instant::now(), Arc::new((self.new_chain)())));
+        entry.0 = Instant::now();
+        entry.1.clone()
+    }
+
+    // -- text and blocks of the answer message
+    async fn answer_message(
+        &self,
+        chain: &ConversationalRetrieverChain,
+        question: &str,
+    ) -> (String, Value) {
+        let input_variables = prompt_args! {
+            "question" => question,
+        };
+        match chain.execute(input_variables).await {
+            Ok(data) => {
+                let answer = answer_text(&data["output"]);
+                let sources = source_names(&data["source_documents"]);
+                let blocks = answer_blocks(&answer, &sources);
+                (answer, blocks)
+            }
+            Err(e) => {
+                log::error!("slack: answering {:?} failed: {:?}", question, e);
+                let text = "Sorry, I could not answer this question right now.".to_string();
+                let blocks = answer_blocks(&text, &[]);
+                (text, blocks)
+            }
+        }
+    }
+
+    async fn answer(&self, bot_token: &str, channel: String, thread_ts: String, question: String) {
+        let chain = self.thread_chain(&format!("{}:{}", channel, thread_ts));
+        let (text, blocks) = self.answer_message(&chain, &question).await;
+
+        let message = json!({
+            "channel": channel,
+            "thread_ts": thread_ts,
+            "text": text,
+            "blocks": blocks,
+        });
+        let response = self
+            .http
+            .post(POST_MESSAGE_URL)
+            .bearer_auth(bot_token)
+            .json(&message)
+            .send()
+            .await;
+        match response {
+            Ok(response) => match response.json::<Value>().await {
+                Ok(body) if body["ok"].as_bool() == Some(true) => {}
+                Ok(body) => log::error!("slack: chat.postMessage rejected: {}", body["error"]),
+                Err(e) => log::error!("slack: invalid chat.postMessage response: {:?}", e),
+            },
+            Err(e) => log::error!("slack: chat.postMessage failed: {:?}", e),
+        }
+    }
+
+    // -- slash commands have no thread, every one is answered without memory
+    async fn answer_command(&self, response_url: String, question: String) {
+        let chain = (self.new_chain)();
+        let (text, blocks) = self.answer_message(&chain, &question).await;
+
+        let message = json!({
+            "response_type": "in_channel",
+            "replace_original": false,
+            "text": text,
+            "blocks": blocks,
+        });
+        let response = self
+            .http
+            .post(&response_url)
+            .json(&message)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = response {
+            log::error!("slack: posting to response_url failed: {:?}", e);
+        }
+    }
+}
+
+pub fn router(state: Arc<SlackState>) -> Router {
+    Router::new()
+        .route("/slack/events", post(slack_events_handler))
+        .route("/slack/commands", post(slack_command_handler))
+        .with_state(state)
+}
+
+// -- form fields of a slash command request that are used
+#[derive(Deserialize)]
+struct SlashCommand {
+    #[serde(default)]
+    text: String,
+    response_url: String,
+}
+
+async fn slack_command_handler(
+    State(state): State<Arc<SlackState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !verify_signature(&state.signing_secret, &headers, &body) {
+        log::warn!("slack: rejected request with invalid signature");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let Ok(command) = serde_urlencoded::from_bytes::<SlashCommand>(&body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let question = command.text.trim().to_string();
+    if question.is_empty() {
+        return Json(json!({
+            "response_type": "ephemeral",
+            "text": "Ask a question after the command, e.g. `/ask What is the vacation policy?`",
+        }))
+        .into_response();
+    }
+
+    println!("{:?} - slack command", question);
+    let ack = json!({
+        "response_type": "ephemeral",
+        "text": format!("Looking for an answer to: {}", question),
+    });
+    tokio::spawn(async move {
+        state.answer_command(command.response_url, question).await;
+    });
+    Json(ack).into_response()
+}
+
+async fn slack_events_handler(
+    State(state): State<Arc<SlackState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !verify_signature(&state.signing_secret, &headers, &body) {
+        log::warn!("slack: rejected request with invalid signature");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    match payload["type"].as_str() {
+        Some("url_verification") => {
+            return Json(json!({ "challenge": payload["challenge"] })).into_response();
+        }
+        Some("event_callback") => {}
+        _ => return StatusCode::OK.into_response(),
+    }
+
+    // -- slack retries events it thinks were not acked, the original is already being answered
+    if headers.contains_key("x-slack-retry-num") {
+        return StatusCode::OK.into_response();
+    }
+
+    let event = &payload["event"];
+    // -- ignore bots (including our own replies) and edits, joins, ...
+    if event["bot_id"].is_string() || event["subtype"].is_string() {
+        return StatusCode::OK.into_response();
+    }
+    let is_mention = event["type"] == "app_mention";
+    let is_direct_message = event["type"] == "message" && event["channel_type"] == "im";
+    if !is_mention && !is_direct_message {
+        return StatusCode::OK.into_response();
+    }
+
+    let (Some(channel), Some(ts), Some(text)) = (
+        event["channel"].as_str(),
+        event["ts"].as_str(),
+        event["text"].as_str(),
+    ) else {
+        return StatusCode::OK.into_response();
+    };
+    let thread_ts = event["thread_ts"].as_str().unwrap_or(ts).to_string();
+    let question = strip_mentions(text);
+    if question.is_empty() {
+        return StatusCode::OK.into_response();
+    }
+
+    let Some(bot_token) = state.bot_token.clone() else {
+        log::warn!("slack: event ignored, answering events needs --slack-bot-token");
+        return StatusCode::OK.into_response();
+    };
+
+    println!("{:?} - slack message", question);
+    let channel = channel.to_string();
+    tokio::spawn(async move {
+        state.answer(&bot_token, channel, thread_ts, question).await;
+    });
+    StatusCode::OK.into_response()
+}
+
+// -- https://api.slack.com/authentication/verifying-requests-from-slack
+pub fn verify_signature(signing_secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let (Some(timestamp), Some(signature)) = (
+        header("x-slack-request-timestamp"),
+        header("x-slack-signature"),
+    ) else {
+        return false;
+    };
+
+    let Ok(request_time) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    if (now - request_time).abs() > MAX_REQUEST_AGE_SECS {
+        return false;
+    }
+
+    let Some(signature) = signature
+        .strip_prefix("v0=")
+        .and_then(|s| hex::decode(s).ok())
+    else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes()) else {
+        return false;
+    };
+    mac.update(b"v0:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b":");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+// -- drops `<@U123ABC>` user mentions from the message text
+fn strip_mentions(text: &str) -> String {
+    text.split_whitespace()
+        .filter(|word| !(word.starts_with("<@") && word.ends_with('>')))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn source_names(source_documents: &Value) -> Vec<String> {
+    let mut names: Vec<String> = source_documents
+        .as_array()
+        .map(|docs| {
+            docs.iter()
+                .filter_map(|d| d["metadata"]["path"].as_str())
+                .map(|path| {
+                    std::path::Path::new(path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_else(|| path.to_string())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// -- answer as section blocks followed by a context block with the source file names
+pub fn answer_blocks(answer: &str, sources: &[String]) -> Value {
+    let chars: Vec<char> = answer.chars().collect();
+    let mut blocks: Vec<Value> = chars
+        .chunks(MAX_SECTION_CHARS)
+        .map(|part| {
+            json!({
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": part.iter().collect::<String>() },
+            })
+        })
+        .collect();
+
+    if !sources.is_empty() {
+        let elements: Vec<Value> = sources
+            .iter()
+            .take(MAX_CONTEXT_ELEMENTS)
+            .map(|name| json!({ "type": "mrkdwn", "text": format!(":page_facing_up: {}", name) }))
+            .collect();
+        blocks.push(json!({ "type": "context", "elements": elements }));
+    }
+    Value::Array(blocks)
+}
@@ -0,0 +1,303 @@
+// -------------------------------------
+// -- scheduled re-ingestion of the sources listed in `--sources`
+//
+// [[source]]
+// url = "https://example.com/handbook.pdf"   # or path = "docs/handbook.pdf"
+// interval = "24h"
+//
+// Every source is refreshed by its own task, one refresh after another, so
+// refreshes of the same source never overlap. The content hash and version
+// are stored in the chunk metadata, so an unchanged source is not ingested
+// again after a restart either. A failed refresh is retried on the next tick.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    jobs::{panic_message, unix_now},
+    store::{ChunkStore, MetadataFilter},
+    Ingest, IngestOutcome,
+};
+
+#[derive(Deserialize)]
+struct SourcesFile {
+    #[serde(default)]
+    source: Vec<SourceConfig>,
+}
+
+#[derive(Deserialize)]
+struct SourceConfig {
+    url: Option<String>,
+    path: Option<String>,
+    interval: String,
+}
+
+#[derive(Clone, Debug)]
+enum Location {
+    Url(String),
+    Path(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Source {
+    location: Location,
+    interval: Duration,
+}
+
+impl Source {
+    // -- url or path, also stored as the `path` metadata of the chunks
+    fn name(&self) -> &str {
+        match &self.location {
+            Location::Url(url) => url,
+            Location::Path(path) => path,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SourceStatus {
+    pub source: String,
+    pub version: Option<i64>,
+    pub content_hash: Option<String>,
+    // unix seconds
+    pub last_checked_at: Option<u64>,
+    pub ingested_at: Option<u64>,
+    // error of the last refresh, cleared by the next successful one
+    pub error: Option<String>,
+}
+
+// -- "90s", "30m", "24h", "7d"
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let unit_at = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("interval '{}' is missing a unit (s, m, h, d)", value))?;
+    let (amount, unit) = value.split_at(unit_at);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid interval '{}'", value))?;
+    let secs = match unit {
+        "s" => amount,
+        "m" => amount * 60,
+        "h" => amount * 60 * 60,
+        "d" => amount * 60 * 60 * 24,
+        _ => return Err(format!("unknown interval unit '{}'", unit)),
+    };
+    if secs == 0 {
+        return Err("interval must be greater than zero".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+pub fn load_sources(path: &str) -> Result<Vec<Source>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("reading {} failed: {}", path, e))?;
+    let file: SourcesFile =
+        toml::from_str(&content).map_err(|e| format!("parsing {} failed: {}", path, e))?;
+
+    file.source
+        .into_iter()
+        .map(|source| {
+            let location = match (source.url, source.path) {
+                (Some(url), None) => Location::Url(url),
+                (None, Some(path)) => Location::Path(path),
+                _ => return Err("every [[source]] needs exactly one of url or path".to_string()),
+            };
+            Ok(Source {
+                location,
+                interval: parse_interval(&source.interval)?,
+            })
+        })
+        .collect()
+}
+
+pub struct Scheduler {
+    sources: Vec<Source>,
+    status: Mutex<Vec<SourceStatus>>,
+    ingest: Arc<Ingest>,
+    store: Arc<dyn ChunkStore>,
+    // downloaded url sources
+    download_dir: PathBuf,
+    http: reqwest::Client,
+}
+
+impl Scheduler {
+    pub fn new(
+        sources: Vec<Source>,
+        ingest: Arc<Ingest>,
+        store: Arc<dyn ChunkStore>,
+        download_dir: PathBuf,
+    ) -> Self {
+        let status = sources
+            .iter()
+            .map(|source| SourceStatus {
+                source: source.name().to_string(),
+                ..Default::default()
+            })
+            .collect();
+        Scheduler {
+            sources,
+            status: Mutex::new(status),
+            ingest,
+            store,
+            download_dir,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn spawn(self: &Arc<Self>) {
+        for index in 0..self.sources.len() {
+            let scheduler = self.clone();
+            tokio::spawn(async move {
+                let source = &scheduler.sources[index];
+                let mut interval = tokio::time::interval(source.interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                loop {
+                    interval.tick().await;
+                    let result = scheduler.refresh(index).await;
+                    if let Err(e) = &result {
+                        log::error!("refreshing source {} failed: {}", source.name(), e);
+                    }
+                    scheduler.status.lock().unwrap()[index].error = result.err();
+                }
+            });
+        }
+    }
+
+    pub fn status(&self) -> Vec<SourceStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    async fn refresh(&self, index: usize) -> Result<(), String> {
+        let source = &self.sources[index];
+        let (doc_path, content) = self.fetch(index, source).await?;
+        let content_hash = hex::encode(Sha256::digest(&content));
+        self.update_status(index, |status| status.last_checked_at = Some(unix_now()));
+
+        let stored = self.stored_version(source.name()).await?;
+        if let Some((version, hash)) = &stored {
+            if *hash == content_hash {
+                log::info!("source {} unchanged (version {})", source.name(), version);
+                self.update_status(index, |status| {
+                    status.version = Some(*version);
+                    status.content_hash = Some(hash.clone());
+                });
+                return Ok(());
+            }
+        }
+
+        let version = stored.map(|(version, _)| version + 1).unwrap_or(1);
+        let ingested_at = unix_now();
+        println!(
+            "{} - source changed, ingesting version {}",
+            source.name(),
+            version
+        );
+        let extra_metadata = HashMap::from([
+            ("path".to_string(), json!(source.name())),
+            ("content_hash".to_string(), json!(content_hash)),
+            ("version".to_string(), json!(version)),
+            ("ingested_at".to_string(), json!(ingested_at)),
+        ]);
+
+        let ingest = self.ingest.clone();
+        let task = tokio::spawn(async move {
+            ingest
+                .ingest_document(&doc_path, &extra_metadata, &|_, _| {})
+                .await
+        });
+        match task.await {
+            Ok(IngestOutcome::Stored(_)) => {}
+            Ok(IngestOutcome::Skipped(reason)) => {
+                return Err(format!("document skipped: {}", reason))
+            }
+            Ok(IngestOutcome::Failed(e)) => return Err(e),
+            Err(e) => return Err(panic_message(e)),
+        }
+
+        // -- the new version is stored, chunks of the previous ones can go
+        self.delete_other_versions(source.name(), version).await?;
+        self.update_status(index, |status| {
+            status.version = Some(version);
+            status.content_hash = Some(content_hash);
+            status.ingested_at = Some(ingested_at);
+        });
+        Ok(())
+    }
+
+    // -- local path of the document and its content
+    async fn fetch(&self, index: usize, source: &Source) -> Result<(String, Vec<u8>), String> {
+        match &source.location {
+            Location::Path(path) => fs::read(path)
+                .map(|content| (path.clone(), content))
+                .map_err(|e| format!("reading {} failed: {}", path, e)),
+            Location::Url(url) => {
+                let content = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("downloading {} failed: {}", url, e))?
+                    .bytes()
+                    .await
+                    .map_err(|e| format!("downloading {} failed: {}", url, e))?;
+
+                let file_name = url
+                    .rsplit('/')
+                    .find(|segment| !segment.is_empty())
+                    .and_then(|segment| Path::new(segment).file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "document.pdf".to_string());
+                let path = self
+                    .download_dir
+                    .join(format!("source-{}-{}", index, file_name));
+                fs::create_dir_all(&self.download_dir)
+                    .and_then(|_| fs::write(&path, &content))
+                    .map_err(|e| format!("storing {:?} failed: {}", path, e))?;
+                Ok((path.to_string_lossy().to_string(), content.to_vec()))
+            }
+        }
+    }
+
+    // -- version and content hash of the chunks already stored for this source
+    async fn stored_version(&self, name: &str) -> Result<Option<(i64, String)>, String> {
+        let stored = self
+            .store
+            .scroll(&MetadataFilter::path(name), 1)
+            .await
+            .map_err(|e| format!("looking up stored version failed: {}", e))?;
+
+        Ok(stored.into_iter().next().and_then(|doc| {
+            Some((
+                doc.metadata.get("version")?.as_i64()?,
+                doc.metadata.get("content_hash")?.as_str()?.to_string(),
+            ))
+        }))
+    }
+
+    async fn delete_other_versions(&self, name: &str, version: i64) -> Result<(), String> {
+        let filter = MetadataFilter {
+            must_not: vec![("version".to_string(), json!(version))],
+            ..MetadataFilter::path(name)
+        };
+        self.store
+            .delete(&filter)
+            .await
+            .map_err(|e| format!("deleting previous versions failed: {}", e))
+    }
+
+    fn update_status(&self, index: usize, f: impl FnOnce(&mut SourceStatus)) {
+        f(&mut self.status.lock().unwrap()[index]);
+    }
+}
@@ -0,0 +1,212 @@
+// -------------------------------------
+// -- `--doc-type transcript`: meeting transcripts chunked by speaker turns
+//
+// Every `Speaker: text` line starts a turn, other lines continue the turn
+// before them and consecutive turns of one speaker are joined. Chunks end on
+// turn boundaries, only a turn longer than the chunk size is split (by
+// sentences, every part keeping its speaker). A `[00:12:30]` or `00:12:30`
+// before the speaker is the time of the turn. Chunks store their `speakers`
+// and, with timestamps, the `time_start` and `time_end` of their turns.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use langchain_rust::schemas::Document;
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::chunking;
+
+// longest speaker name, longer `...:` prefixes are part of the text
+const MAX_SPEAKER_CHARS: usize = 40;
+
+#[derive(Debug, PartialEq)]
+pub struct Turn {
+    // empty for the text before the first speaker
+    pub speaker: String,
+    pub time: Option<String>,
+    pub text: String,
+}
+
+impl Turn {
+    fn line(&self, text: &str) -> String {
+        match self.speaker.is_empty() {
+            true => text.to_string(),
+            false => format!("{}: {}", self.speaker, text),
+        }
+    }
+}
+
+fn turn_start() -> &'static Regex {
+    static TURN_START: OnceLock<Regex> = OnceLock::new();
+    TURN_START.get_or_init(|| {
+        Regex::new(&format!(
+            r"^(?:\[?(?<time>\d{{1,2}}:\d{{2}}(?::\d{{2}})?)\]?\s+)?(?<speaker>\p{{L}}[^:]{{0,{}}}):\s*(?<text>.*)$",
+            MAX_SPEAKER_CHARS - 1
+        ))
+        .unwrap()
+    })
+}
+
+pub fn parse_turns(text: &str) -> Vec<Turn> {
+    let mut turns: Vec<Turn> = vec![];
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let Some(start) = turn_start().captures(line) else {
+            match turns.last_mut() {
+                Some(turn) => {
+                    turn.text.push(' ');
+                    turn.text.push_str(line);
+                }
+                None => turns.push(Turn {
+                    speaker: String::new(),
+                    time: None,
+                    text: line.to_string(),
+                }),
+            }
+            continue;
+        };
+        let speaker = start["speaker"].trim();
+        let text = start["text"].trim();
+        match turns.last_mut() {
+            Some(turn) if turn.speaker == speaker => {
+                turn.text.push(' ');
+                turn.text.push_str(text);
+            }
+            _ => turns.push(Turn {
+                speaker: speaker.to_string(),
+                time: start.name("time").map(|time| time.as_str().to_string()),
+                text: text.to_string(),
+            }),
+        }
+    }
+    turns
+}
+
+// -- items joined by `separator` into groups of at most `max`, an item over `max` on its own
+fn packed<T>(
+    items: Vec<(T, String)>,
+    separator: &str,
+    size: &impl Fn(&str) -> usize,
+    max: usize,
+) -> Vec<Vec<(T, String)>> {
+    let mut groups: Vec<Vec<(T, String)>> = vec![];
+    let mut text = String::new();
+    for (item, piece) in items {
+        let joined = format!("{}{}{}", text, separator, piece);
+        match groups.last_mut() {
+            Some(group) if !text.is_empty() && size(&joined) <= max => {
+                group.push((item, piece));
+                text = joined;
+            }
+            _ => {
+                text = piece.clone();
+                groups.push(vec![(item, piece)]);
+            }
+        }
+    }
+    groups
+}
+
+pub fn transcript_chunks(
+    turns: &[Turn],
+    size: impl Fn(&str) -> usize,
+    chunk_size: usize,
+) -> Vec<Document> {
+    let mut lines: Vec<(&Turn, String)> = vec![];
+    for turn in turns {
+        let line = turn.line(&turn.text);
+        if size(&line) <= chunk_size {
+            lines.push((turn, line));
+            continue;
+        }
+        let sentences = chunking::split_sentences(&turn.text)
+            .into_iter()
+            .map(|sentence| ((), sentence))
+            .collect();
+        for part in packed(sentences, " ", &size, chunk_size) {
+            let text: Vec<String> = part.into_iter().map(|(_, sentence)| sentence).collect();
+            lines.push((turn, turn.line(&text.join(" "))));
+        }
+    }
+    packed(lines, "\n", &size, chunk_size)
+        .into_iter()
+        .map(|chunk| {
+            let mut speakers: Vec<&str> = vec![];
+            for (turn, _) in &chunk {
+                if !turn.speaker.is_empty() && !speakers.contains(&turn.speaker.as_str()) {
+                    speakers.push(&turn.speaker);
+                }
+            }
+            let times: Vec<&str> = chunk
+                .iter()
+                .filter_map(|(t, _)| t.time.as_deref())
+                .collect();
+            let mut metadata: HashMap<String, Value> =
+                HashMap::from([("speakers".to_string(), json!(speakers))]);
+            if let (Some(start), Some(end)) = (times.first(), times.last()) {
+                metadata.insert("time_start".to_string(), json!(start));
+                metadata.insert("time_end".to_string(), json!(end));
+            }
+            let text: Vec<String> = chunk.into_iter().map(|(_, line)| line).collect();
+            Document::new(text.join("\n")).with_metadata(metadata)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSCRIPT: &str = "Porada 3. 3. 2025
+
+[00:00:05] Jana: Dobrý den, začneme rozpočtem.
+Máme ho schválený.
+[00:01:10] Petr: Kolik zbývá?
+[00:01:20] Petr: A do kdy?
+[00:02:00] Jana: Do konce roku.";
+
+    #[test]
+    fn lines_are_grouped_into_turns() {
+        let turns = parse_turns(TRANSCRIPT);
+        assert_eq!(turns.len(), 4);
+        assert_eq!(turns[0].speaker, "");
+        assert_eq!(
+            turns[1],
+            Turn {
+                speaker: "Jana".to_string(),
+                time: Some("00:00:05".to_string()),
+                text: "Dobrý den, začneme rozpočtem. Máme ho schválený.".to_string(),
+            }
+        );
+        assert_eq!(turns[2].text, "Kolik zbývá? A do kdy?");
+        assert_eq!(turns[2].time.as_deref(), Some("00:01:10"));
+    }
+
+    #[test]
+    fn chunks_end_on_turn_boundaries() {
+        let turns = parse_turns(TRANSCRIPT);
+        let chars = |text: &str| text.chars().count();
+        let chunks = transcript_chunks(&turns[1..], chars, 80);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.page_content.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "Jana: Dobrý den, začneme rozpočtem. Máme ho schválený.",
+                "Petr: Kolik zbývá? A do kdy?\nJana: Do konce roku.",
+            ]
+        );
+        assert_eq!(chunks[1].metadata["speakers"], json!(["Petr", "Jana"]));
+        assert_eq!(chunks[1].metadata["time_start"], json!("00:01:10"));
+        assert_eq!(chunks[1].metadata["time_end"], json!("00:02:00"));
+
+        // -- a turn over the chunk size is split by sentences, keeping its speaker
+        let chunks = transcript_chunks(&turns[1..2], chars, 40);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.page_content.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "Jana: Dobrý den, začneme rozpočtem.",
+                "Jana: Máme ho schválený."
+            ]
+        );
+    }
+}
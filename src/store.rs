@@ -0,0 +1,1596 @@
+// -------------------------------------
+// -- chunk stores: qdrant, `--db sqlite:<file>` for a single machine, or
+// -- `--db memory` for trying the tool and tests
+//
+// The memory store is a brute-force cosine search over a Vec shared by the
+// whole process, so documents ingested in web mode can be chatted with, but
+// nothing survives a restart.
+
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use langchain_rust::{
+    embedding::{Embedder, EmbedderError},
+    schemas::Document,
+    vectorstore::qdrant::{Qdrant, Store, StoreBuilder},
+};
+use qdrant_client::{
+    qdrant::{
+        vector_output, vectors_config, vectors_output::VectorsOptions, Condition,
+        CountPointsBuilder, CreateCollectionBuilder, CreateFieldIndexCollectionBuilder,
+        DeletePointsBuilder, Distance, FieldType, Filter, GetPointsBuilder, HnswConfigDiffBuilder,
+        PointStruct, ScrollPointsBuilder, SearchPointsBuilder, UpsertPointsBuilder,
+        VectorParamsBuilder, VectorsOutput,
+    },
+    Payload,
+};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// `--db` value selecting the memory store
+pub const MEMORY_DB: &str = "memory";
+
+// points fetched by one qdrant scroll request
+const SCROLL_PAGE: usize = 256;
+
+// -- short stable id of a document path, stored as the `source_id` metadata
+pub fn source_id(path: &str) -> String {
+    hex::encode(Sha256::digest(path.as_bytes()))[..8].to_string()
+}
+
+// -- metadata conditions, all of `must` have to match and none of `must_not`
+#[derive(Clone, Debug, Default)]
+pub struct MetadataFilter {
+    pub must: Vec<(String, Value)>,
+    pub must_not: Vec<(String, Value)>,
+}
+
+impl MetadataFilter {
+    pub fn path(path: &str) -> Self {
+        MetadataFilter {
+            must: vec![("path".to_string(), json!(path))],
+            must_not: vec![],
+        }
+    }
+
+    // -- chunks of the collection, the `collection.name` of their _collection.toml
+    pub fn collection(collection: &str) -> Self {
+        MetadataFilter {
+            must: vec![("collection.name".to_string(), json!(collection))],
+            must_not: vec![],
+        }
+    }
+
+    // -- qdrant filter json, e.g. `{"must": [{"key": "department", "match": {"value": "HR"}}]}`,
+    // -- only `must` and `must_not` exact matches of metadata keys
+    pub fn from_json(filter: &Value) -> Result<Self, String> {
+        let Some(clauses) = filter.as_object() else {
+            return Err(format!("filter must be an object, got {}", filter));
+        };
+        let mut parsed = MetadataFilter::default();
+        for (clause, conditions) in clauses {
+            let target = match clause.as_str() {
+                "must" => &mut parsed.must,
+                "must_not" => &mut parsed.must_not,
+                _ => return Err(format!("unsupported filter clause {}", clause)),
+            };
+            let conditions = match conditions {
+                Value::Array(conditions) => conditions.as_slice(),
+                condition => std::slice::from_ref(condition),
+            };
+            for condition in conditions {
+                let key = condition["key"].as_str();
+                let value = &condition["match"]["value"];
+                match key {
+                    Some(key) if !value.is_null() => {
+                        let key = key.strip_prefix("metadata.").unwrap_or(key);
+                        target.push((key.to_string(), value.clone()));
+                    }
+                    _ => return Err(format!("unsupported filter condition {}", condition)),
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    // -- chunks matching both filters
+    pub fn and(mut self, other: &MetadataFilter) -> Self {
+        self.must.extend(other.must.iter().cloned());
+        self.must_not.extend(other.must_not.iter().cloned());
+        self
+    }
+
+    fn matches(&self, metadata: &HashMap<String, Value>) -> bool {
+        self.must
+            .iter()
+            .all(|(key, value)| value_matches(metadata_value(metadata, key), value))
+            && !self
+                .must_not
+                .iter()
+                .any(|(key, value)| value_matches(metadata_value(metadata, key), value))
+    }
+}
+
+// -- a list matches when one of its items does, as in qdrant
+fn value_matches(stored: Option<&Value>, value: &Value) -> bool {
+    match stored {
+        Some(Value::Array(items)) => items.contains(value),
+        stored => stored == Some(value),
+    }
+}
+
+// -- `collection.name` is the `name` of the `collection` object, as in qdrant
+fn metadata_value<'a>(metadata: &'a HashMap<String, Value>, key: &str) -> Option<&'a Value> {
+    let mut path = key.split('.');
+    let first = metadata.get(path.next()?)?;
+    path.try_fold(first, |value, key| value.get(key))
+}
+
+#[async_trait]
+pub trait ChunkStore: Send + Sync {
+    async fn add_documents(&self, docs: &[Document]) -> Result<(), String>;
+
+    // -- most similar chunks first, `score` is the similarity to the query
+    async fn similarity_search(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<Document>, String> {
+        let found = self
+            .similarity_search_with_vectors(query, limit, score_threshold, filter)
+            .await?;
+        Ok(found.into_iter().map(|(doc, _)| doc).collect())
+    }
+
+    // -- `similarity_search` with the stored embedding of every chunk
+    async fn similarity_search_with_vectors(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String>;
+
+    async fn delete(&self, filter: &MetadataFilter) -> Result<(), String>;
+
+    // -- up to `limit` stored chunks matching the filter, in no particular order
+    async fn scroll(&self, filter: &MetadataFilter, limit: usize) -> Result<Vec<Document>, String>;
+
+    // -- number of stored chunks
+    async fn count(&self) -> Result<u64, String>;
+
+    // -- settings json `config-set` and generate stored for the collection, None when there are none
+    async fn collection_settings(&self, _collection: &str) -> Result<Option<Value>, String> {
+        Ok(None)
+    }
+
+    async fn save_collection_settings(&self, _collection: &str, _: &Value) -> Result<(), String> {
+        Err("this store keeps no collection settings".to_string())
+    }
+}
+
+// -- qdrant payload text comes back JSON encoded (with quotes and escapes)
+pub fn chunk_text(page_content: &str) -> String {
+    serde_json::from_str::<String>(page_content).unwrap_or_else(|_| page_content.to_string())
+}
+
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+pub fn l2_norm(vector: &[f64]) -> f64 {
+    vector.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+// -------------------------------------
+// -- `--embed-dimensions N`: the first N dimensions of every embedding, normalized
+//
+// Matryoshka-trained embedding models keep most of their quality in the
+// leading dimensions. The store sizes a new qdrant collection by the
+// embeddings it gets, so the collection is created with N dimensions too.
+// `--embed-normalize` normalizes the embeddings of models that don't do it
+// themselves without truncating them.
+pub struct TruncatedEmbedder<E> {
+    inner: E,
+    dimensions: Option<usize>,
+    normalize: bool,
+}
+
+impl<E> TruncatedEmbedder<E> {
+    // -- no truncation for `None`
+    pub fn new(inner: E, dimensions: Option<usize>) -> Self {
+        TruncatedEmbedder {
+            inner,
+            dimensions,
+            normalize: false,
+        }
+    }
+
+    // -- L2-normalized even when not truncated
+    pub fn normalized(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    fn truncate(&self, mut vector: Vec<f64>) -> Vec<f64> {
+        match self.dimensions {
+            Some(dimensions) => vector.truncate(dimensions),
+            None if !self.normalize => return vector,
+            None => {}
+        }
+        let norm = l2_norm(&vector);
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl<E: Embedder> Embedder for TruncatedEmbedder<E> {
+    async fn embed_documents(&self, documents: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        let vectors = self.inner.embed_documents(documents).await?;
+        Ok(vectors.into_iter().map(|v| self.truncate(v)).collect())
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+        Ok(self.truncate(self.inner.embed_query(text).await?))
+    }
+}
+
+// -------------------------------------
+// -- qdrant
+
+// qdrant collection of the chunks
+pub const COLLECTION: &str = "documents";
+// qdrant collection of the collection settings, a point with a dummy vector per collection
+const SETTINGS_COLLECTION: &str = "collection_settings";
+
+// -- point id of the collection's settings
+fn settings_point_id(collection: &str) -> u64 {
+    let digest = Sha256::digest(collection.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+// -- how qdrant compares embeddings, fixed when the collection is created
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SimilarityMetric {
+    #[default]
+    Cosine,
+    Dot,
+    Euclidean,
+}
+
+impl SimilarityMetric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SimilarityMetric::Cosine => "cosine",
+            SimilarityMetric::Dot => "dot",
+            SimilarityMetric::Euclidean => "euclidean",
+        }
+    }
+
+    fn distance(&self) -> Distance {
+        match self {
+            SimilarityMetric::Cosine => Distance::Cosine,
+            SimilarityMetric::Dot => Distance::Dot,
+            SimilarityMetric::Euclidean => Distance::Euclid,
+        }
+    }
+
+    // -- euclidean distances are turned into a similarity, 1 for the same vector falling
+    // -- towards 0, so scores are higher-is-better with every metric
+    fn similarity(&self, score: f32) -> f64 {
+        match self {
+            SimilarityMetric::Euclidean => 1.0 / (1.0 + score as f64),
+            _ => score as f64,
+        }
+    }
+
+    // -- qdrant's threshold for a minimal similarity, the largest distance for euclidean
+    fn score_threshold(&self, similarity: f32) -> Option<f32> {
+        match self {
+            SimilarityMetric::Euclidean if similarity <= 0.0 => None,
+            SimilarityMetric::Euclidean => Some(1.0 / similarity - 1.0),
+            _ => Some(similarity),
+        }
+    }
+}
+
+// -- metric the collection was created with, None when there is no collection yet
+pub async fn collection_metric(client: &Qdrant) -> Result<Option<SimilarityMetric>, String> {
+    let exists = client
+        .collection_exists(COLLECTION)
+        .await
+        .map_err(|e| format!("checking the collection failed: {}", e))?;
+    if !exists {
+        return Ok(None);
+    }
+    let info = client
+        .collection_info(COLLECTION)
+        .await
+        .map_err(|e| format!("reading the collection failed: {}", e))?;
+    let vectors = info
+        .result
+        .and_then(|info| info.config)
+        .and_then(|config| config.params)
+        .and_then(|params| params.vectors_config)
+        .and_then(|vectors| vectors.config);
+    let distance = match vectors {
+        Some(vectors_config::Config::Params(params)) => params.distance(),
+        _ => return Err(format!("collection {} has no single vector", COLLECTION)),
+    };
+    [
+        SimilarityMetric::Cosine,
+        SimilarityMetric::Dot,
+        SimilarityMetric::Euclidean,
+    ]
+    .into_iter()
+    .find(|metric| metric.distance() == distance)
+    .map(Some)
+    .ok_or_else(|| format!("collection {} uses unsupported {:?}", COLLECTION, distance))
+}
+
+// payload key of the chunk metadata in langchain's qdrant store
+const METADATA_FIELD: &str = "metadata";
+
+// -- `--payload-indexes` entry: a metadata field filtered by retrieval, ingestion or sources
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadIndex {
+    pub field: String,
+    // integer metadata (`version`) needs an integer index, keyword indexes skip numbers
+    integer: bool,
+}
+
+// -- `field`, `field:keyword` or `field:integer`
+pub fn parse_payload_index(value: &str) -> Result<PayloadIndex, String> {
+    let (field, integer) = match value.trim().split_once(':') {
+        None => (value.trim(), false),
+        Some((field, "keyword")) => (field, false),
+        Some((field, "integer")) => (field, true),
+        Some((_, kind)) => {
+            return Err(format!(
+                "unknown index type {}, expected keyword or integer",
+                kind
+            ))
+        }
+    };
+    match field.is_empty() {
+        true => Err("empty payload index field".to_string()),
+        false => Ok(PayloadIndex {
+            field: field.to_string(),
+            integer,
+        }),
+    }
+}
+
+// -- creates the payload indexes the collection lacks, the fields they were created on
+pub async fn ensure_payload_indexes(
+    client: &Qdrant,
+    indexes: &[PayloadIndex],
+) -> Result<Vec<String>, String> {
+    let exists = client
+        .collection_exists(COLLECTION)
+        .await
+        .map_err(|e| format!("checking the collection failed: {}", e))?;
+    if !exists {
+        return Err(format!("there is no {} collection", COLLECTION));
+    }
+    let schema = client
+        .collection_info(COLLECTION)
+        .await
+        .map_err(|e| format!("reading the collection failed: {}", e))?
+        .result
+        .map(|info| info.payload_schema)
+        .unwrap_or_default();
+    let mut created = vec![];
+    for index in indexes {
+        let key = format!("{}.{}", METADATA_FIELD, index.field);
+        if schema.contains_key(&key) {
+            continue;
+        }
+        let field_type = match index.integer {
+            true => FieldType::Integer,
+            false => FieldType::Keyword,
+        };
+        client
+            .create_field_index(
+                CreateFieldIndexCollectionBuilder::new(COLLECTION, &key, field_type).wait(true),
+            )
+            .await
+            .map_err(|e| format!("creating the payload index on {} failed: {}", key, e))?;
+        created.push(key);
+    }
+    Ok(created)
+}
+
+// -- storage and index parameters of a new qdrant collection, qdrant's defaults when unset
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CollectionParams {
+    pub on_disk_payload: bool,
+    pub hnsw_ef_construct: Option<u64>,
+    pub hnsw_m: Option<u64>,
+}
+
+fn create_collection(
+    dimension: usize,
+    metric: SimilarityMetric,
+    params: &CollectionParams,
+) -> CreateCollectionBuilder {
+    let mut create = CreateCollectionBuilder::new(COLLECTION).vectors_config(
+        VectorParamsBuilder::new(dimension as u64, metric.distance()),
+    );
+    if params.on_disk_payload {
+        create = create.on_disk_payload(true);
+    }
+    if params.hnsw_ef_construct.is_some() || params.hnsw_m.is_some() {
+        let mut hnsw = HnswConfigDiffBuilder::default();
+        if let Some(ef_construct) = params.hnsw_ef_construct {
+            hnsw = hnsw.ef_construct(ef_construct);
+        }
+        if let Some(m) = params.hnsw_m {
+            hnsw = hnsw.m(m);
+        }
+        create = create.hnsw_config(hnsw);
+    }
+    create
+}
+
+// -- langchain's qdrant store, whose builder always creates cosine collections
+pub struct QdrantStore {
+    store: Store,
+    metric: SimilarityMetric,
+}
+
+impl QdrantStore {
+    // -- the collection is created with `metric` and `params` when there is none
+    pub async fn open<E: Embedder + 'static>(
+        client: Qdrant,
+        embedder: E,
+        metric: SimilarityMetric,
+        params: &CollectionParams,
+    ) -> Result<Self, String> {
+        if collection_metric(&client).await?.is_none() {
+            let dimension = embedder
+                .embed_query("Text to retrieve embeddings dimension")
+                .await
+                .map_err(|e| format!("embedding failed: {}", e))?
+                .len();
+            client
+                .create_collection(create_collection(dimension, metric, params))
+                .await
+                .map_err(|e| format!("creating the collection failed: {}", e))?;
+        } else if *params != CollectionParams::default() {
+            log::warn!(
+                "the {} collection exists, --qdrant-on-disk-payload and --qdrant-hnsw-* apply to a new one only",
+                COLLECTION
+            );
+        }
+        let store = StoreBuilder::new()
+            .recreate_collection(false)
+            .embedder(embedder)
+            .client(client)
+            .collection_name(COLLECTION)
+            .build()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(QdrantStore { store, metric })
+    }
+}
+
+impl Deref for QdrantStore {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
+
+fn qdrant_condition(store: &Store, key: &str, value: &Value) -> Result<Condition, String> {
+    let key = format!("{}.{}", store.metadata_field, key);
+    match value {
+        Value::String(value) => Ok(Condition::matches(key, value.clone())),
+        Value::Bool(value) => Ok(Condition::matches(key, *value)),
+        Value::Number(number) if number.is_i64() => {
+            Ok(Condition::matches(key, number.as_i64().unwrap()))
+        }
+        _ => Err(format!("can't filter {} by {}", key, value)),
+    }
+}
+
+fn qdrant_filter(store: &Store, filter: &MetadataFilter) -> Result<Filter, String> {
+    let conditions = |pairs: &[(String, Value)]| -> Result<Vec<Condition>, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| qdrant_condition(store, key, value))
+            .collect()
+    };
+    Ok(Filter {
+        must: conditions(&filter.must)?,
+        must_not: conditions(&filter.must_not)?,
+        ..Default::default()
+    })
+}
+
+// -- same document shape as langchain's qdrant store, the content comes back json encoded
+fn qdrant_document(
+    store: &Store,
+    payload: &HashMap<String, qdrant_client::qdrant::Value>,
+) -> Document {
+    let metadata = payload
+        .get(&store.metadata_field)
+        .and_then(|metadata| serde_json::from_value(metadata.clone().into_json()).ok())
+        .unwrap_or_default();
+    Document {
+        page_content: payload
+            .get(&store.content_field)
+            .map(ToString::to_string)
+            .unwrap_or_default(),
+        metadata,
+        score: 0.0,
+    }
+}
+
+fn qdrant_vector(vectors: Option<VectorsOutput>) -> Vec<f64> {
+    let Some(VectorsOptions::Vector(vector)) = vectors.and_then(|v| v.vectors_options) else {
+        return vec![];
+    };
+    let data = match vector.vector {
+        Some(vector_output::Vector::Dense(dense)) => dense.data,
+        _ => vector.data,
+    };
+    data.into_iter().map(f64::from).collect()
+}
+
+#[async_trait]
+impl ChunkStore for QdrantStore {
+    async fn add_documents(&self, docs: &[Document]) -> Result<(), String> {
+        let texts: Vec<String> = docs.iter().map(|d| d.page_content.clone()).collect();
+        let vectors = self
+            .embedder
+            .embed_documents(&texts)
+            .await
+            .map_err(|e| format!("embedding chunks failed: {}", e))?;
+
+        let mut points = Vec::with_capacity(docs.len());
+        for (doc, vector) in docs.iter().zip(vectors) {
+            let payload = Payload::try_from(json!({
+                &self.content_field: doc.page_content,
+                &self.metadata_field: doc.metadata,
+            }))
+            .map_err(|e| format!("invalid chunk payload: {}", e))?;
+            let vector: Vec<f32> = vector.into_iter().map(|f| f as f32).collect();
+            points.push(PointStruct::new(
+                uuid::Uuid::new_v4().to_string(),
+                vector,
+                payload,
+            ));
+        }
+        self.client
+            .upsert_points(UpsertPointsBuilder::new(&self.collection_name, points).wait(true))
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("storing chunks failed: {}", e))
+    }
+
+    async fn similarity_search_with_vectors(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+        let query_vector: Vec<f32> = self
+            .embedder
+            .embed_query(query)
+            .await
+            .map_err(|e| format!("embedding query failed: {}", e))?
+            .into_iter()
+            .map(|f| f as f32)
+            .collect();
+
+        let mut search =
+            SearchPointsBuilder::new(&self.collection_name, query_vector, limit as u64)
+                .with_payload(true)
+                .with_vectors(true)
+                .filter(qdrant_filter(self, filter)?);
+        if let Some(threshold) = self.metric.score_threshold(score_threshold) {
+            search = search.score_threshold(threshold);
+        }
+        let response = self
+            .client
+            .search_points(search)
+            .await
+            .map_err(|e| format!("searching chunks failed: {}", e))?;
+
+        Ok(response
+            .result
+            .into_iter()
+            .map(|point| {
+                let doc = Document {
+                    score: self.metric.similarity(point.score),
+                    ..qdrant_document(self, &point.payload)
+                };
+                (doc, qdrant_vector(point.vectors))
+            })
+            .collect())
+    }
+
+    async fn delete(&self, filter: &MetadataFilter) -> Result<(), String> {
+        self.client
+            .delete_points(
+                DeletePointsBuilder::new(&self.collection_name)
+                    .points(qdrant_filter(self, filter)?)
+                    .wait(true),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("deleting chunks failed: {}", e))
+    }
+
+    // -- page by page, following qdrant's next page offset
+    async fn scroll(&self, filter: &MetadataFilter, limit: usize) -> Result<Vec<Document>, String> {
+        let mut docs = vec![];
+        let mut offset = None;
+        while docs.len() < limit {
+            let mut request = ScrollPointsBuilder::new(&self.collection_name)
+                .filter(qdrant_filter(self, filter)?)
+                .limit((limit - docs.len()).min(SCROLL_PAGE) as u32)
+                .with_payload(true);
+            if let Some(offset) = offset.take() {
+                request = request.offset(offset);
+            }
+            let response = self
+                .client
+                .scroll(request)
+                .await
+                .map_err(|e| format!("listing chunks failed: {}", e))?;
+            docs.extend(
+                response
+                    .result
+                    .into_iter()
+                    .map(|point| qdrant_document(self, &point.payload)),
+            );
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+        }
+        Ok(docs)
+    }
+
+    async fn count(&self) -> Result<u64, String> {
+        self.client
+            .count(CountPointsBuilder::new(&self.collection_name).exact(true))
+            .await
+            .map(|response| response.result.map_or(0, |result| result.count))
+            .map_err(|e| format!("counting chunks failed: {}", e))
+    }
+
+    async fn collection_settings(&self, collection: &str) -> Result<Option<Value>, String> {
+        let exists = self
+            .client
+            .collection_exists(SETTINGS_COLLECTION)
+            .await
+            .map_err(|e| format!("checking the settings collection failed: {}", e))?;
+        if !exists {
+            return Ok(None);
+        }
+        let response = self
+            .client
+            .get_points(
+                GetPointsBuilder::new(
+                    SETTINGS_COLLECTION,
+                    vec![settings_point_id(collection).into()],
+                )
+                .with_payload(true),
+            )
+            .await
+            .map_err(|e| format!("reading the collection settings failed: {}", e))?;
+        Ok(response
+            .result
+            .into_iter()
+            .map(|point| {
+                Value::Object(
+                    point
+                        .payload
+                        .into_iter()
+                        .map(|(k, v)| (k, v.into_json()))
+                        .collect(),
+                )
+            })
+            .find(|payload| payload["collection"] == collection)
+            .map(|mut payload| payload["settings"].take()))
+    }
+
+    async fn save_collection_settings(
+        &self,
+        collection: &str,
+        settings: &Value,
+    ) -> Result<(), String> {
+        let exists = self
+            .client
+            .collection_exists(SETTINGS_COLLECTION)
+            .await
+            .map_err(|e| format!("checking the settings collection failed: {}", e))?;
+        if !exists {
+            self.client
+                .create_collection(
+                    CreateCollectionBuilder::new(SETTINGS_COLLECTION)
+                        .vectors_config(VectorParamsBuilder::new(1, Distance::Dot)),
+                )
+                .await
+                .map_err(|e| format!("creating the settings collection failed: {}", e))?;
+        }
+        let payload = Payload::try_from(json!({ "collection": collection, "settings": settings }))
+            .map_err(|e| format!("invalid collection settings: {}", e))?;
+        let point = PointStruct::new(settings_point_id(collection), vec![0.0_f32], payload);
+        self.client
+            .upsert_points(UpsertPointsBuilder::new(SETTINGS_COLLECTION, vec![point]).wait(true))
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("storing the collection settings failed: {}", e))
+    }
+}
+
+// -------------------------------------
+// -- memory
+
+pub struct MemoryStore {
+    embedder: Arc<dyn Embedder>,
+    chunks: RwLock<Vec<(Vec<f64>, Document)>>,
+    settings: RwLock<HashMap<String, Value>>,
+}
+
+impl MemoryStore {
+    pub fn new(embedder: Arc<dyn Embedder>) -> Self {
+        MemoryStore {
+            embedder,
+            chunks: RwLock::new(vec![]),
+            settings: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // -- the store of this process, created with the embedder of the first call
+    pub fn shared(embedder: Arc<dyn Embedder>) -> Arc<MemoryStore> {
+        static SHARED: OnceLock<Arc<MemoryStore>> = OnceLock::new();
+        SHARED
+            .get_or_init(|| Arc::new(MemoryStore::new(embedder)))
+            .clone()
+    }
+}
+
+#[async_trait]
+impl ChunkStore for MemoryStore {
+    async fn add_documents(&self, docs: &[Document]) -> Result<(), String> {
+        let texts: Vec<String> = docs.iter().map(|d| d.page_content.clone()).collect();
+        let vectors = self
+            .embedder
+            .embed_documents(&texts)
+            .await
+            .map_err(|e| format!("embedding chunks failed: {}", e))?;
+        self.chunks
+            .write()
+            .unwrap()
+            .extend(vectors.into_iter().zip(docs.iter().cloned()));
+        Ok(())
+    }
+
+    async fn similarity_search_with_vectors(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+        let query_vector = self
+            .embedder
+            .embed_query(query)
+            .await
+            .map_err(|e| format!("embedding query failed: {}", e))?;
+
+        let chunks = self.chunks.read().unwrap();
+        Ok(ranked(
+            &query_vector,
+            chunks.iter().map(|(vector, doc)| (vector.as_slice(), doc)),
+            limit,
+            score_threshold,
+            filter,
+        ))
+    }
+
+    async fn delete(&self, filter: &MetadataFilter) -> Result<(), String> {
+        self.chunks
+            .write()
+            .unwrap()
+            .retain(|(_, doc)| !filter.matches(&doc.metadata));
+        Ok(())
+    }
+
+    async fn scroll(&self, filter: &MetadataFilter, limit: usize) -> Result<Vec<Document>, String> {
+        Ok(self
+            .chunks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, doc)| filter.matches(&doc.metadata))
+            .take(limit)
+            .map(|(_, doc)| Document {
+                page_content: format!("{:?}", doc.page_content),
+                ..doc.clone()
+            })
+            .collect())
+    }
+
+    async fn count(&self) -> Result<u64, String> {
+        Ok(self.chunks.read().unwrap().len() as u64)
+    }
+
+    async fn collection_settings(&self, collection: &str) -> Result<Option<Value>, String> {
+        Ok(self.settings.read().unwrap().get(collection).cloned())
+    }
+
+    async fn save_collection_settings(
+        &self,
+        collection: &str,
+        settings: &Value,
+    ) -> Result<(), String> {
+        self.settings
+            .write()
+            .unwrap()
+            .insert(collection.to_string(), settings.clone());
+        Ok(())
+    }
+}
+
+// -- brute-force search of the memory and sqlite stores, page content is json encoded
+// -- the same way the qdrant store returns it
+fn ranked<'a>(
+    query_vector: &[f64],
+    chunks: impl Iterator<Item = (&'a [f64], &'a Document)>,
+    limit: usize,
+    score_threshold: f32,
+    filter: &MetadataFilter,
+) -> Vec<(Document, Vec<f64>)> {
+    let mut found: Vec<(Document, &[f64])> = chunks
+        .filter(|(_, doc)| filter.matches(&doc.metadata))
+        .map(|(vector, doc)| {
+            let doc = Document {
+                page_content: format!("{:?}", doc.page_content),
+                metadata: doc.metadata.clone(),
+                score: cosine_similarity(query_vector, vector),
+            };
+            (doc, vector)
+        })
+        .filter(|(doc, _)| doc.score >= score_threshold as f64)
+        .collect();
+    found.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+    found.truncate(limit);
+    found
+        .into_iter()
+        .map(|(doc, vector)| (doc, vector.to_vec()))
+        .collect()
+}
+
+// -------------------------------------
+// -- sqlite, `--db sqlite:path/to/store.db`
+//
+// Chunks are rows with the metadata as json and the embedding as a blob of
+// little endian f32s, searched by brute force. The database is opened in
+// exclusive locking mode, so a second process gets a clear error instead of
+// writing into the same file.
+
+pub const SQLITE_DB_PREFIX: &str = "sqlite:";
+// bumped on every incompatible change of the tables below
+const SQLITE_SCHEMA_VERSION: i64 = 1;
+// -- the lock is held by the other process until it exits, waiting longer doesn't help
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub struct SqliteStore {
+    embedder: Arc<dyn Embedder>,
+    path: String,
+    connection: Mutex<Connection>,
+}
+
+fn sqlite_error(path: &str, e: rusqlite::Error) -> String {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+            format!("{} is locked, is another chunk_contextor using it?", path)
+        }
+        _ => format!("sqlite store {} failed: {}", path, e),
+    }
+}
+
+fn vector_to_blob(vector: &[f64]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| (*value as f32).to_le_bytes())
+        .collect()
+}
+
+fn blob_to_vector(blob: &[u8]) -> Vec<f64> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+        .collect()
+}
+
+impl SqliteStore {
+    pub fn open(path: &str, embedder: Arc<dyn Embedder>) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(|e| sqlite_error(path, e))?;
+        connection
+            .busy_timeout(SQLITE_BUSY_TIMEOUT)
+            .map_err(|e| sqlite_error(path, e))?;
+        connection
+            .execute_batch(
+                "PRAGMA locking_mode = EXCLUSIVE;
+                BEGIN IMMEDIATE;
+                CREATE TABLE IF NOT EXISTS store_info (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+                CREATE TABLE IF NOT EXISTS chunks (
+                    id INTEGER PRIMARY KEY,
+                    content TEXT NOT NULL,
+                    metadata TEXT NOT NULL,
+                    vector BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS collection_settings (
+                    collection TEXT PRIMARY KEY,
+                    settings TEXT NOT NULL
+                );
+                COMMIT;",
+            )
+            .map_err(|e| sqlite_error(path, e))?;
+
+        let version: Option<i64> = connection
+            .query_row(
+                "SELECT value FROM store_info WHERE key = 'schema_version'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| sqlite_error(path, e))?;
+        match version {
+            Some(SQLITE_SCHEMA_VERSION) => {}
+            Some(version) => {
+                return Err(format!(
+                    "{} has store schema version {}, this build reads version {}",
+                    path, version, SQLITE_SCHEMA_VERSION
+                ))
+            }
+            None => {
+                connection
+                    .execute(
+                        "INSERT INTO store_info (key, value) VALUES ('schema_version', ?1)",
+                        [SQLITE_SCHEMA_VERSION],
+                    )
+                    .map_err(|e| sqlite_error(path, e))?;
+            }
+        }
+
+        Ok(SqliteStore {
+            embedder,
+            path: path.to_string(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // -- one store per file in this process, the exclusive lock allows only one connection
+    pub fn shared(path: &str, embedder: Arc<dyn Embedder>) -> Result<Arc<SqliteStore>, String> {
+        static SHARED: OnceLock<Mutex<HashMap<String, Arc<SqliteStore>>>> = OnceLock::new();
+        let mut stores = SHARED.get_or_init(Default::default).lock().unwrap();
+        if let Some(store) = stores.get(path) {
+            return Ok(store.clone());
+        }
+        let store = Arc::new(SqliteStore::open(path, embedder)?);
+        stores.insert(path.to_string(), store.clone());
+        Ok(store)
+    }
+
+    // -- id, vector and document of every chunk matching the filter
+    fn chunks(&self, filter: &MetadataFilter) -> Result<Vec<(i64, Vec<f64>, Document)>, String> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT id, content, metadata, vector FROM chunks ORDER BY id")
+            .map_err(|e| sqlite_error(&self.path, e))?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                ))
+            })
+            .map_err(|e| sqlite_error(&self.path, e))?;
+
+        let mut chunks = vec![];
+        for row in rows {
+            let (id, content, metadata, vector) = row.map_err(|e| sqlite_error(&self.path, e))?;
+            let metadata: HashMap<String, Value> =
+                serde_json::from_str(&metadata).map_err(|e| {
+                    format!("{} has invalid metadata in chunk {}: {}", self.path, id, e)
+                })?;
+            if filter.matches(&metadata) {
+                let doc = Document::new(content).with_metadata(metadata);
+                chunks.push((id, blob_to_vector(&vector), doc));
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+#[async_trait]
+impl ChunkStore for SqliteStore {
+    async fn add_documents(&self, docs: &[Document]) -> Result<(), String> {
+        let texts: Vec<String> = docs.iter().map(|d| d.page_content.clone()).collect();
+        let vectors = self
+            .embedder
+            .embed_documents(&texts)
+            .await
+            .map_err(|e| format!("embedding chunks failed: {}", e))?;
+
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .map_err(|e| sqlite_error(&self.path, e))?;
+        for (doc, vector) in docs.iter().zip(vectors) {
+            transaction
+                .execute(
+                    "INSERT INTO chunks (content, metadata, vector) VALUES (?1, ?2, ?3)",
+                    params![
+                        doc.page_content,
+                        json!(doc.metadata).to_string(),
+                        vector_to_blob(&vector)
+                    ],
+                )
+                .map_err(|e| sqlite_error(&self.path, e))?;
+        }
+        transaction
+            .commit()
+            .map_err(|e| sqlite_error(&self.path, e))
+    }
+
+    async fn similarity_search_with_vectors(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+        let query_vector = self
+            .embedder
+            .embed_query(query)
+            .await
+            .map_err(|e| format!("embedding query failed: {}", e))?;
+        let chunks = self.chunks(filter)?;
+        Ok(ranked(
+            &query_vector,
+            chunks
+                .iter()
+                .map(|(_, vector, doc)| (vector.as_slice(), doc)),
+            limit,
+            score_threshold,
+            &MetadataFilter::default(),
+        ))
+    }
+
+    async fn delete(&self, filter: &MetadataFilter) -> Result<(), String> {
+        let ids: Vec<i64> = self
+            .chunks(filter)?
+            .into_iter()
+            .map(|(id, _, _)| id)
+            .collect();
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .map_err(|e| sqlite_error(&self.path, e))?;
+        for id in ids {
+            transaction
+                .execute("DELETE FROM chunks WHERE id = ?1", [id])
+                .map_err(|e| sqlite_error(&self.path, e))?;
+        }
+        transaction
+            .commit()
+            .map_err(|e| sqlite_error(&self.path, e))
+    }
+
+    async fn scroll(&self, filter: &MetadataFilter, limit: usize) -> Result<Vec<Document>, String> {
+        Ok(self
+            .chunks(filter)?
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, doc)| Document {
+                page_content: format!("{:?}", doc.page_content),
+                ..doc
+            })
+            .collect())
+    }
+
+    async fn count(&self) -> Result<u64, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM chunks", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|count| count as u64)
+            .map_err(|e| sqlite_error(&self.path, e))
+    }
+
+    async fn collection_settings(&self, collection: &str) -> Result<Option<Value>, String> {
+        let settings: Option<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT settings FROM collection_settings WHERE collection = ?1",
+                [collection],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| sqlite_error(&self.path, e))?;
+        settings
+            .map(|settings| {
+                serde_json::from_str(&settings).map_err(|e| {
+                    format!(
+                        "{} has invalid settings of {}: {}",
+                        self.path, collection, e
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    async fn save_collection_settings(
+        &self,
+        collection: &str,
+        settings: &Value,
+    ) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO collection_settings (collection, settings) VALUES (?1, ?2)",
+                params![collection, settings.to_string()],
+            )
+            .map(|_| ())
+            .map_err(|e| sqlite_error(&self.path, e))
+    }
+}
+
+// -------------------------------------
+// -- web: a store that may go away for a while
+//
+// The store is built on first use. When building it or any operation fails
+// (qdrant restarting, say) the store is reported unavailable and built again
+// on the next use, so the server recovers without a restart once it is back.
+
+// error of every operation while the store is unavailable
+pub const UNAVAILABLE: &str = "knowledge base temporarily unavailable";
+
+fn unavailable(e: String) -> String {
+    format!("{}: {}", UNAVAILABLE, e)
+}
+
+pub type StoreBuild =
+    Box<dyn Fn() -> BoxFuture<'static, Result<Arc<dyn ChunkStore>, String>> + Send + Sync>;
+
+pub struct ResilientStore {
+    build: StoreBuild,
+    store: tokio::sync::Mutex<Option<Arc<dyn ChunkStore>>>,
+}
+
+impl ResilientStore {
+    pub fn new(build: StoreBuild) -> Self {
+        ResilientStore {
+            build,
+            store: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    // -- the store, built when there is none yet or the last use failed
+    pub async fn connect(&self) -> Result<Arc<dyn ChunkStore>, String> {
+        let mut store = self.store.lock().await;
+        if let Some(store) = store.as_ref() {
+            return Ok(store.clone());
+        }
+        let built = (self.build)().await.map_err(unavailable)?;
+        *store = Some(built.clone());
+        Ok(built)
+    }
+
+    // -- why the store is unavailable, None when a cheap listing works. For /health
+    pub async fn probe(&self) -> Option<String> {
+        self.scroll(&MetadataFilter::default(), 1).await.err()
+    }
+
+    // -- a failed operation drops the store, the next one builds it again
+    async fn checked<T>(&self, result: Result<T, String>) -> Result<T, String> {
+        if result.is_err() {
+            *self.store.lock().await = None;
+        }
+        result.map_err(unavailable)
+    }
+}
+
+#[async_trait]
+impl ChunkStore for ResilientStore {
+    async fn add_documents(&self, docs: &[Document]) -> Result<(), String> {
+        let result = self.connect().await?.add_documents(docs).await;
+        self.checked(result).await
+    }
+
+    async fn similarity_search_with_vectors(
+        &self,
+        query: &str,
+        limit: usize,
+        score_threshold: f32,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+        let result = self
+            .connect()
+            .await?
+            .similarity_search_with_vectors(query, limit, score_threshold, filter)
+            .await;
+        self.checked(result).await
+    }
+
+    async fn delete(&self, filter: &MetadataFilter) -> Result<(), String> {
+        let result = self.connect().await?.delete(filter).await;
+        self.checked(result).await
+    }
+
+    async fn scroll(&self, filter: &MetadataFilter, limit: usize) -> Result<Vec<Document>, String> {
+        let result = self.connect().await?.scroll(filter, limit).await;
+        self.checked(result).await
+    }
+
+    async fn count(&self) -> Result<u64, String> {
+        let result = self.connect().await?.count().await;
+        self.checked(result).await
+    }
+
+    async fn collection_settings(&self, collection: &str) -> Result<Option<Value>, String> {
+        let result = self.connect().await?.collection_settings(collection).await;
+        self.checked(result).await
+    }
+
+    async fn save_collection_settings(
+        &self,
+        collection: &str,
+        settings: &Value,
+    ) -> Result<(), String> {
+        let result = self
+            .connect()
+            .await?
+            .save_collection_settings(collection, settings)
+            .await;
+        self.checked(result).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collection_params_are_set_only_when_given() {
+        let default =
+            create_collection(768, SimilarityMetric::Dot, &CollectionParams::default()).build();
+        assert_eq!(default.on_disk_payload, None);
+        assert_eq!(default.hnsw_config, None);
+
+        let create = create_collection(
+            768,
+            SimilarityMetric::Cosine,
+            &CollectionParams {
+                on_disk_payload: true,
+                hnsw_ef_construct: None,
+                hnsw_m: Some(32),
+            },
+        )
+        .build();
+        assert_eq!(create.on_disk_payload, Some(true));
+        let hnsw = create.hnsw_config.unwrap();
+        assert_eq!(hnsw.m, Some(32));
+        assert_eq!(hnsw.ef_construct, None);
+    }
+
+    #[test]
+    fn payload_indexes_are_keyword_unless_integer() {
+        assert_eq!(
+            parse_payload_index("path"),
+            Ok(PayloadIndex {
+                field: "path".to_string(),
+                integer: false
+            })
+        );
+        assert_eq!(
+            parse_payload_index("version:integer"),
+            Ok(PayloadIndex {
+                field: "version".to_string(),
+                integer: true
+            })
+        );
+        assert_eq!(parse_payload_index("kind:keyword").unwrap().field, "kind");
+        assert!(parse_payload_index("score:float").is_err());
+        assert!(parse_payload_index(":integer").is_err());
+    }
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // -- counts of a few letters, texts sharing letters are similar
+    struct LetterEmbedder;
+
+    #[async_trait]
+    impl Embedder for LetterEmbedder {
+        async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+            Ok(texts.iter().map(|text| letters(text)).collect())
+        }
+
+        async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+            Ok(letters(text))
+        }
+    }
+
+    fn letters(text: &str) -> Vec<f64> {
+        ['a', 'b', 'c']
+            .iter()
+            .map(|letter| text.chars().filter(|c| c == letter).count() as f64)
+            .collect()
+    }
+
+    fn doc(text: &str, path: &str, version: i64) -> Document {
+        Document::new(text).with_metadata(HashMap::from([
+            ("path".to_string(), json!(path)),
+            ("version".to_string(), json!(version)),
+        ]))
+    }
+
+    async fn store() -> MemoryStore {
+        let store = MemoryStore::new(Arc::new(LetterEmbedder));
+        store
+            .add_documents(&[
+                doc("aaa", "a.pdf", 1),
+                doc("bbb", "b.pdf", 1),
+                doc("aab", "a.pdf", 2),
+            ])
+            .await
+            .unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn memory_search_ranks_by_similarity_above_threshold() {
+        let found = store()
+            .await
+            .similarity_search("a", 5, 0.5, &MetadataFilter::default())
+            .await
+            .unwrap();
+        let contents: Vec<&str> = found.iter().map(|d| d.page_content.as_str()).collect();
+        assert_eq!(contents, vec!["\"aaa\"", "\"aab\""]);
+        assert!(found[0].score > found[1].score);
+    }
+
+    #[tokio::test]
+    async fn memory_search_applies_filter_and_limit() {
+        let store = store().await;
+        let filter = MetadataFilter::path("b.pdf");
+        let found = store.similarity_search("a", 5, 0.0, &filter).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].page_content, "\"bbb\"");
+
+        let found = store
+            .similarity_search("a", 1, 0.0, &MetadataFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_persists_chunks_and_checks_schema_version() {
+        let path = std::env::temp_dir().join(format!("chunks-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        {
+            let store = SqliteStore::open(path, Arc::new(LetterEmbedder)).unwrap();
+            store
+                .add_documents(&[doc("aaa", "a.pdf", 1), doc("bbb", "b.pdf", 1)])
+                .await
+                .unwrap();
+            store.delete(&MetadataFilter::path("b.pdf")).await.unwrap();
+        }
+
+        let store = SqliteStore::open(path, Arc::new(LetterEmbedder)).unwrap();
+        let found = store
+            .similarity_search("a", 5, 0.0, &MetadataFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].page_content, "\"aaa\"");
+        assert_eq!(found[0].metadata["path"], json!("a.pdf"));
+        let error = SqliteStore::open(path, Arc::new(LetterEmbedder))
+            .err()
+            .unwrap();
+        assert!(error.contains("is locked"), "{}", error);
+        drop(store);
+
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute("UPDATE store_info SET value = 99", [])
+            .unwrap();
+        drop(connection);
+        let error = SqliteStore::open(path, Arc::new(LetterEmbedder))
+            .err()
+            .unwrap();
+        assert!(error.contains("schema version 99"), "{}", error);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn memory_delete_keeps_other_versions_and_paths() {
+        let store = store().await;
+        let filter = MetadataFilter {
+            must_not: vec![("version".to_string(), json!(2))],
+            ..MetadataFilter::path("a.pdf")
+        };
+        store.delete(&filter).await.unwrap();
+
+        let left = store.scroll(&MetadataFilter::default(), 10).await.unwrap();
+        let contents: Vec<&str> = left.iter().map(|d| d.page_content.as_str()).collect();
+        assert_eq!(contents, vec!["\"bbb\"", "\"aab\""]);
+    }
+
+    #[tokio::test]
+    async fn truncated_embedder_keeps_leading_dimensions_normalized() {
+        let embedder = TruncatedEmbedder::new(LetterEmbedder, Some(2));
+        assert_eq!(
+            embedder.embed_query("aaabbbbc").await.unwrap(),
+            vec![0.6, 0.8]
+        );
+        assert_eq!(embedder.embed_query("c").await.unwrap(), vec![0.0, 0.0]);
+
+        let untouched = TruncatedEmbedder::new(LetterEmbedder, None);
+        assert_eq!(
+            untouched.embed_query("abc").await.unwrap(),
+            vec![1.0, 1.0, 1.0]
+        );
+
+        let normalized = TruncatedEmbedder::new(LetterEmbedder, None).normalized(true);
+        assert_eq!(
+            normalized.embed_query("aaabbbb").await.unwrap(),
+            vec![0.6, 0.8, 0.0]
+        );
+        assert_eq!(
+            normalized.embed_query("b").await.unwrap(),
+            vec![0.0, 1.0, 0.0]
+        );
+    }
+
+    // -- a store whose server went away
+    struct DownStore;
+
+    #[async_trait]
+    impl ChunkStore for DownStore {
+        async fn add_documents(&self, _: &[Document]) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+
+        async fn similarity_search_with_vectors(
+            &self,
+            _: &str,
+            _: usize,
+            _: f32,
+            _: &MetadataFilter,
+        ) -> Result<Vec<(Document, Vec<f64>)>, String> {
+            Err("connection refused".to_string())
+        }
+
+        async fn delete(&self, _: &MetadataFilter) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+
+        async fn scroll(&self, _: &MetadataFilter, _: usize) -> Result<Vec<Document>, String> {
+            Err("connection refused".to_string())
+        }
+
+        async fn count(&self) -> Result<u64, String> {
+            Err("connection refused".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn resilient_store_reports_outages_and_recovers() {
+        // -- builds fail, then the built store fails, then it works
+        let builds = Arc::new(AtomicUsize::new(0));
+        let counter = builds.clone();
+        let store = ResilientStore::new(Box::new(move || {
+            let build = counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                match build {
+                    0 => Err("dns error".to_string()),
+                    1 => Ok(Arc::new(DownStore) as Arc<dyn ChunkStore>),
+                    _ => Ok(Arc::new(store().await) as Arc<dyn ChunkStore>),
+                }
+            })
+        }));
+
+        let e = store.connect().await.err().unwrap();
+        assert_eq!(e, format!("{}: dns error", UNAVAILABLE));
+
+        let e = store
+            .similarity_search("a", 5, 0.0, &MetadataFilter::default())
+            .await;
+        assert_eq!(
+            e.unwrap_err(),
+            format!("{}: connection refused", UNAVAILABLE)
+        );
+        assert_eq!(store.probe().await, None);
+        let found = store
+            .scroll(&MetadataFilter::path("b.pdf"), 5)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(builds.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn euclidean_distances_become_similarities() {
+        let euclidean = SimilarityMetric::Euclidean;
+        assert_eq!(euclidean.similarity(0.0), 1.0);
+        assert_eq!(euclidean.similarity(1.0), 0.5);
+        assert_eq!(euclidean.score_threshold(0.5), Some(1.0));
+        assert_eq!(euclidean.score_threshold(0.0), None);
+        assert_eq!(SimilarityMetric::Dot.similarity(0.75), 0.75);
+        assert_eq!(SimilarityMetric::Cosine.score_threshold(0.55), Some(0.55));
+    }
+
+    #[test]
+    fn qdrant_filter_json_is_parsed() {
+        let filter = MetadataFilter::from_json(&json!({
+            "must": [{ "key": "department", "match": { "value": "HR" } }],
+            "must_not": { "key": "metadata.version", "match": { "value": 1 } },
+        }))
+        .unwrap();
+        assert_eq!(filter.must, vec![("department".to_string(), json!("HR"))]);
+        assert_eq!(filter.must_not, vec![("version".to_string(), json!(1))]);
+
+        assert!(MetadataFilter::from_json(&json!({ "should": [] })).is_err());
+        assert!(MetadataFilter::from_json(&json!({ "must": [{ "key": "a" }] })).is_err());
+        assert!(MetadataFilter::from_json(&json!(["must"])).is_err());
+
+        let filter = MetadataFilter::from_json(&json!({
+            "must": { "key": "collection.owner", "match": { "value": "HR Dept" } },
+        }))
+        .unwrap();
+        let metadata =
+            |owner| HashMap::from([("collection".to_string(), json!({ "owner": owner }))]);
+        assert!(filter.matches(&metadata("HR Dept")));
+        assert!(!filter.matches(&metadata("IT")));
+        assert!(!filter.matches(&HashMap::new()));
+
+        let keywords = HashMap::from([("keywords".to_string(), json!(["dovolená", "mzda"]))]);
+        let filter = MetadataFilter {
+            must: vec![("keywords".to_string(), json!("mzda"))],
+            must_not: vec![],
+        };
+        assert!(filter.matches(&keywords));
+        assert!(!MetadataFilter {
+            must: vec![("keywords".to_string(), json!("plat"))],
+            must_not: vec![],
+        }
+        .matches(&keywords));
+    }
+}
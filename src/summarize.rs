@@ -0,0 +1,182 @@
+// -------------------------------------
+// -- --summarize-answers: one sentence of every answer for the transcript, logs and GET /session
+//
+// Long answers make the transcript and the logs hard to scan, so every
+// answer gets a one-sentence summary from --model with a budget of a few
+// tokens. It's asked only after the answer has been delivered, and a failed,
+// empty or slow summary falls back to the first sentence of the answer, so
+// the summary never holds up or breaks the answer itself. Answers of a single
+// short sentence are their own summary, without a call.
+
+use std::time::Duration;
+
+use langchain_rust::{language_models::llm::LLM, schemas::Message};
+
+use crate::config;
+
+// tokens the model may summarize in
+pub const SUMMARY_TOKENS: i32 = 80;
+// the summary is the first sentence when the model takes longer
+const SUMMARY_TIMEOUT: Duration = Duration::from_secs(30);
+// longer summaries and first sentences are cut
+const MAX_SUMMARY_CHARS: usize = 200;
+// answer summaries listed by GET /session
+pub const SESSION_SUMMARIES: usize = 50;
+
+fn shortened(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_SUMMARY_CHARS) {
+        Some((end, _)) => format!("{}…", text[..end].trim_end()),
+        None => text,
+    }
+}
+
+// -- the answer without the thinking of reasoning models, headings and markdown markers
+fn answer_body(answer: &str) -> String {
+    answer
+        .rsplit("</think>")
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .map(|line| line.trim().trim_start_matches(['-', '*', '>']).trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// -- the first sentence of the answer, the summary without a model. A sentence ends before
+// -- a capital letter, so `čl. 3.2` or `např. smlouva` don't end it
+pub fn first_sentence(answer: &str) -> String {
+    let body = answer_body(answer);
+    for (index, c) in body.char_indices() {
+        let end = index + c.len_utf8();
+        let mut rest = body[end..].chars();
+        let ends = matches!(c, '.' | '!' | '?')
+            && match rest.next() {
+                None => true,
+                Some(next) => {
+                    next.is_whitespace()
+                        && rest
+                            .find(|c| !c.is_whitespace())
+                            .is_none_or(char::is_uppercase)
+                }
+            };
+        if ends {
+            return shortened(&body[..end]);
+        }
+    }
+    shortened(&body)
+}
+
+pub struct AnswerSummarizer {
+    llm: Box<dyn LLM>,
+}
+
+impl AnswerSummarizer {
+    pub fn new(llm: Box<dyn LLM>) -> Self {
+        AnswerSummarizer { llm }
+    }
+
+    async fn generated(&self, body: &str) -> Result<String, String> {
+        let messages = [
+            Message::new_system_message(config::ANSWER_SUMMARY_PROMPT_STR),
+            Message::new_human_message(body),
+        ];
+        let generated = tokio::time::timeout(SUMMARY_TIMEOUT, self.llm.generate(&messages))
+            .await
+            .map_err(|_| format!("timed out after {:?}", SUMMARY_TIMEOUT))?
+            .map_err(|e| e.to_string())?;
+        let summary = answer_body(&generated.generation);
+        match summary.is_empty() {
+            true => Err("the summary is empty".to_string()),
+            false => Ok(shortened(&summary)),
+        }
+    }
+
+    // -- one sentence of the answer, never fails
+    pub async fn summarize(&self, answer: &str) -> String {
+        let body = answer_body(answer);
+        let first = first_sentence(&body);
+        if first == shortened(&body) && body.chars().count() <= MAX_SUMMARY_CHARS {
+            return first;
+        }
+        match self.generated(&body).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                log::warn!(
+                    "summarizing the answer failed, using its first sentence: {}",
+                    e
+                );
+                first
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::Stream;
+    use langchain_rust::{
+        language_models::{GenerateResult, LLMError},
+        schemas::StreamData,
+    };
+    use std::pin::Pin;
+
+    #[derive(Clone)]
+    struct Fixed(Option<&'static str>);
+
+    #[async_trait]
+    impl LLM for Fixed {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            match self.0 {
+                Some(generation) => Ok(GenerateResult {
+                    tokens: None,
+                    generation: generation.to_string(),
+                }),
+                None => Err(LLMError::OtherError("model not found".to_string())),
+            }
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_summaries_fall_back_to_the_first_sentence() {
+        let answer = "<think>Hledám v dokumentech.</think>\n## Dovolená\nZaměstnanci mají nárok na 25 dní dovolené (viz čl. 3.2). Čerpání schvaluje vedoucí.\n- O dovolené se žádá v systému.";
+        assert_eq!(
+            first_sentence(answer),
+            "Zaměstnanci mají nárok na 25 dní dovolené (viz čl. 3.2)."
+        );
+        assert_eq!(first_sentence("Bez tečky"), "Bez tečky");
+        assert!(first_sentence(&"slovo ".repeat(100)).ends_with('…'));
+
+        let summarizer = AnswerSummarizer::new(Box::new(Fixed(Some(
+            "<think>...</think>\nZaměstnanci mají 25 dní dovolené.",
+        ))));
+        assert_eq!(
+            summarizer.summarize(answer).await,
+            "Zaměstnanci mají 25 dní dovolené."
+        );
+        let failing = AnswerSummarizer::new(Box::new(Fixed(None)));
+        assert_eq!(
+            failing.summarize(answer).await,
+            "Zaměstnanci mají nárok na 25 dní dovolené (viz čl. 3.2)."
+        );
+        assert_eq!(failing.summarize("Ano.").await, "Ano.");
+        assert_eq!(
+            AnswerSummarizer::new(Box::new(Fixed(Some(""))))
+                .summarize(answer)
+                .await,
+            failing.summarize(answer).await
+        );
+    }
+}
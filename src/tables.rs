@@ -0,0 +1,150 @@
+// -------------------------------------
+// -- table detection in extracted pdf text
+//
+// pdf-extract renders table rows as lines with the cells separated by runs of
+// spaces. Consecutive lines like that are taken out of the page text and kept
+// as markdown tables, so the splitter can't mix their cells with the text
+// around them.
+
+// at least this many spaces (or a tab) separate two cells
+const MIN_CELL_GAP: usize = 2;
+const MIN_COLUMNS: usize = 2;
+const MIN_ROWS: usize = 3;
+// longer lines are not considered headings
+const MAX_HEADING_CHARS: usize = 80;
+
+pub struct Table {
+    // nearest heading above the table
+    pub section: Option<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn columns(&self) -> usize {
+        self.rows.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    fn markdown_row(&self, row: &[String]) -> String {
+        let cells: Vec<String> = (0..self.columns())
+            .map(|i| {
+                row.get(i)
+                    .map(|c| c.replace('|', "\\|"))
+                    .unwrap_or_default()
+            })
+            .collect();
+        format!("| {} |", cells.join(" | "))
+    }
+
+    fn markdown_header(&self) -> String {
+        format!(
+            "{}\n|{}",
+            self.markdown_row(&self.rows[0]),
+            "---|".repeat(self.columns())
+        )
+    }
+
+    // -- markdown parts of at most `max_size`, the first row is repeated as the header
+    // -- of every part and rows are never cut
+    pub fn split(&self, size: impl Fn(&str) -> usize, max_size: usize) -> Vec<String> {
+        let header = self.markdown_header();
+        let mut parts = vec![];
+        let mut current = header.clone();
+        let mut has_rows = false;
+        for row in &self.rows[1..] {
+            let candidate = format!("{}\n{}", current, self.markdown_row(row));
+            if has_rows && size(&candidate) > max_size {
+                parts.push(std::mem::replace(
+                    &mut current,
+                    format!("{}\n{}", header, self.markdown_row(row)),
+                ));
+            } else {
+                current = candidate;
+            }
+            has_rows = true;
+        }
+        parts.push(current);
+        parts
+    }
+}
+
+fn cells(line: &str) -> Vec<String> {
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut spaces = 0;
+    for c in line.trim().chars() {
+        match c {
+            '\t' => spaces = MIN_CELL_GAP,
+            ' ' => spaces += 1,
+            _ => {
+                if spaces >= MIN_CELL_GAP {
+                    cells.push(std::mem::take(&mut cell));
+                } else if spaces > 0 {
+                    cell.push(' ');
+                }
+                spaces = 0;
+                cell.push(c);
+            }
+        }
+    }
+    if !cell.is_empty() {
+        cells.push(cell);
+    }
+    cells
+}
+
+fn is_heading(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty()
+        && line.chars().count() <= MAX_HEADING_CHARS
+        && !line.ends_with(['.', ',', ';', ':'])
+        && line
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_uppercase() || c.is_ascii_digit())
+        && cells(line).len() == 1
+}
+
+// -- page text without the tables, and the tables
+pub fn extract_tables(text: &str) -> (String, Vec<Table>) {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut remaining: Vec<&str> = vec![];
+    let mut tables = vec![];
+    let mut section: Option<String> = None;
+
+    let mut i = 0;
+    while i < lines.len() {
+        // -- rows of a table candidate, blank lines between rows are allowed
+        let mut rows = vec![];
+        let mut end = i;
+        let mut j = i;
+        while j < lines.len() {
+            if lines[j].trim().is_empty() {
+                j += 1;
+                continue;
+            }
+            let row = cells(lines[j]);
+            if row.len() < MIN_COLUMNS {
+                break;
+            }
+            rows.push(row);
+            j += 1;
+            end = j;
+        }
+
+        if rows.len() >= MIN_ROWS {
+            tables.push(Table {
+                section: section.clone(),
+                rows,
+            });
+            i = end;
+            continue;
+        }
+
+        if is_heading(lines[i]) {
+            section = Some(lines[i].trim().to_string());
+        }
+        remaining.push(lines[i]);
+        i += 1;
+    }
+    (remaining.join("\n"), tables)
+}
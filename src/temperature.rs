@@ -0,0 +1,285 @@
+// -------------------------------------
+// -- `--temperature-schedule 0.7,0.4,0.1`: unusable answers retried cooler
+//
+// An answer shorter than `--min-answer-chars`, or one sharing too little with
+// the retrieved chunks, is generated again at the next temperature of the
+// schedule, at most once per temperature. The last answer is kept when none
+// is usable. Streams are buffered, a retried answer can't be taken back once
+// sent.
+//
+// The retries are sent by `OllamaWithOptions` with their temperature.
+
+use std::{collections::HashSet, pin::Pin};
+
+use async_trait::async_trait;
+use futures::{stream, Stream, StreamExt};
+use langchain_rust::{
+    language_models::{llm::LLM, options::CallOptions, GenerateResult, LLMError},
+    schemas::{Message, StreamData},
+};
+
+use crate::injection;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// answer words also found in the chunks for a grounded answer
+const GROUNDED_RATIO: f64 = 0.3;
+// words are compared by their start, Czech words change their endings
+const STEM_CHARS: usize = 5;
+
+pub fn parse_temperature(value: &str) -> Result<f32, String> {
+    let temperature = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("expected a temperature like 0.4, got {}", value))?;
+    match (0.0..=2.0).contains(&temperature) {
+        true => Ok(temperature),
+        false => Err(format!(
+            "temperature {} is not between 0 and 2",
+            temperature
+        )),
+    }
+}
+
+// -- why the answer isn't usable, None when it is. Only answers to prompts with retrieved
+// -- chunks are checked, the rephrased questions of the same model are short by design
+fn rejection(answer: &str, messages: &[Message], min_answer_chars: usize) -> Option<&'static str> {
+    let chunks = injection::retrieved_chunks(messages);
+    if chunks.is_empty() {
+        return None;
+    }
+    // -- reasoning models think before they answer
+    let answer = answer.rsplit("</think>").next().unwrap_or(answer).trim();
+    if answer.chars().count() < min_answer_chars {
+        return Some("too short");
+    }
+    let context: HashSet<String> = chunks.iter().flat_map(|c| stems(c)).collect();
+    let words = stems(answer);
+    let grounded = words.iter().filter(|w| context.contains(*w)).count();
+    match !words.is_empty() && (grounded as f64) < words.len() as f64 * GROUNDED_RATIO {
+        true => Some("not grounded in the retrieved chunks"),
+        false => None,
+    }
+}
+
+// -- starts of the words long enough to carry meaning
+fn stems(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= STEM_CHARS)
+        .map(|word| word.to_lowercase().chars().take(STEM_CHARS).collect())
+        .collect()
+}
+
+pub struct ScheduledLlm {
+    inner: Box<dyn LLM>,
+    // the model at every temperature of the schedule
+    retries: Vec<(f32, Box<dyn LLM>)>,
+    min_answer_chars: usize,
+}
+
+impl ScheduledLlm {
+    pub fn new(
+        inner: Box<dyn LLM>,
+        retries: Vec<(f32, Box<dyn LLM>)>,
+        min_answer_chars: usize,
+    ) -> Self {
+        ScheduledLlm {
+            inner,
+            retries,
+            min_answer_chars,
+        }
+    }
+
+    fn attempts(&self) -> impl Iterator<Item = (Option<f32>, &dyn LLM)> {
+        std::iter::once((None, self.inner.as_ref())).chain(
+            self.retries
+                .iter()
+                .map(|(temperature, llm)| (Some(*temperature), llm.as_ref())),
+        )
+    }
+
+    // -- logs the rejection, true when there is no retry left
+    fn rejected(&self, attempt: usize, reason: &str) -> bool {
+        match self.retries.get(attempt) {
+            Some((temperature, _)) => {
+                log::warn!(
+                    "answer rejected ({}), retrying at temperature {}",
+                    reason,
+                    temperature
+                );
+                false
+            }
+            None => {
+                log::warn!("answer rejected ({}), no retry left, keeping it", reason);
+                true
+            }
+        }
+    }
+
+    fn accepted(attempt: usize, temperature: Option<f32>) {
+        if let Some(temperature) = temperature {
+            log::info!(
+                "retry {} at temperature {} gave a usable answer",
+                attempt,
+                temperature
+            );
+        }
+    }
+}
+
+impl Clone for ScheduledLlm {
+    fn clone(&self) -> Self {
+        ScheduledLlm {
+            inner: self.inner.clone_box(),
+            retries: self
+                .retries
+                .iter()
+                .map(|(temperature, llm)| (*temperature, llm.clone_box()))
+                .collect(),
+            min_answer_chars: self.min_answer_chars,
+        }
+    }
+}
+
+#[async_trait]
+impl LLM for ScheduledLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        for (attempt, (temperature, llm)) in self.attempts().enumerate() {
+            let result = llm.generate(messages).await?;
+            match rejection(&result.generation, messages, self.min_answer_chars) {
+                Some(reason) if !self.rejected(attempt, reason) => continue,
+                Some(_) => return Ok(result),
+                None => {
+                    Self::accepted(attempt, temperature);
+                    return Ok(result);
+                }
+            }
+        }
+        unreachable!("the last attempt is always returned")
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        if self.retries.is_empty() {
+            return self.inner.stream(messages).await;
+        }
+        for (attempt, (temperature, llm)) in self.attempts().enumerate() {
+            let items: Vec<_> = llm.stream(messages).await?.collect().await;
+            let answer: String = items
+                .iter()
+                .filter_map(|item| item.as_ref().ok())
+                .map(|data| data.content.as_str())
+                .collect();
+            match rejection(&answer, messages, self.min_answer_chars) {
+                Some(reason) if !self.rejected(attempt, reason) => continue,
+                Some(_) => return Ok(Box::pin(stream::iter(items))),
+                None => {
+                    Self::accepted(attempt, temperature);
+                    return Ok(Box::pin(stream::iter(items)));
+                }
+            }
+        }
+        unreachable!("the last attempt is always returned")
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.inner.add_options(options);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use langchain_rust::schemas::Document;
+
+    use crate::injection::ChunkGuard;
+    use crate::test_llms::ScriptedLlm;
+
+    const CHUNK: &str = "Zaměstnanci mají nárok na dovolenou v rozsahu pětadvaceti pracovních dnů.";
+    const GROUNDED: &str = "Zaměstnanci mají nárok na pětadvacet pracovních dnů dovolené.";
+
+    fn prompt() -> Vec<Message> {
+        let mut doc = Document::new(CHUNK);
+        ChunkGuard::default().quote(1, &mut doc);
+        vec![
+            Message::new_system_message("Odpovídej česky a podrobně."),
+            Message::new_human_message(format!("{}\nOtázka: kolik je dovolené?", doc.page_content)),
+        ]
+    }
+
+    #[test]
+    fn short_and_ungrounded_answers_are_rejected() {
+        let messages = prompt();
+        assert_eq!(rejection(GROUNDED, &messages, 20), None);
+        assert_eq!(rejection("Nevím.", &messages, 20), Some("too short"));
+        assert_eq!(
+            rejection(
+                "<think>dlouhé přemýšlení o ničem</think>Krátce.",
+                &messages,
+                20
+            ),
+            Some("too short")
+        );
+        assert_eq!(
+            rejection(
+                "Ředitelství rozhodlo o rekonstrukci parkoviště.",
+                &messages,
+                20
+            ),
+            Some("not grounded in the retrieved chunks")
+        );
+        let rephrasing = [Message::new_human_message("Otázka: kolik je dovolené?")];
+        assert_eq!(rejection("Kolik dní?", &rephrasing, 20), None);
+        assert_eq!(
+            rejection(
+                "Ředitelství rozhodlo o rekonstrukci parkoviště.",
+                &rephrasing,
+                20
+            ),
+            None
+        );
+    }
+
+    fn scheduled(first: ScriptedLlm, retries: &[(f32, ScriptedLlm)]) -> ScheduledLlm {
+        let retries = retries
+            .iter()
+            .map(|(temperature, llm)| (*temperature, Box::new(llm.clone()) as Box<dyn LLM>))
+            .collect();
+        ScheduledLlm::new(Box::new(first), retries, 20)
+    }
+
+    #[tokio::test]
+    async fn unusable_answers_are_retried_down_the_schedule() {
+        let llm = scheduled(
+            ScriptedLlm::new(&["Nevím."]),
+            &[
+                (
+                    0.4,
+                    ScriptedLlm::new(&["Ředitelství rozhodlo o rekonstrukci parkoviště."]),
+                ),
+                (0.1, ScriptedLlm::new(&[GROUNDED])),
+            ],
+        );
+        let result = llm.generate(&prompt()).await.unwrap();
+        assert_eq!(result.generation, GROUNDED);
+
+        let llm = scheduled(
+            ScriptedLlm::new(&["Nevím."]),
+            &[(0.1, ScriptedLlm::new(&["Taky nevím."]))],
+        );
+        assert_eq!(
+            llm.generate(&prompt()).await.unwrap().generation,
+            "Taky nevím."
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_replay_the_usable_answer() {
+        let llm = scheduled(
+            ScriptedLlm::new(&["Nevím."]),
+            &[(0.4, ScriptedLlm::new(&[GROUNDED]))],
+        );
+        let items: Vec<_> = llm.stream(&prompt()).await.unwrap().collect().await;
+        let answer: String = items.into_iter().map(|i| i.unwrap().content).collect();
+        assert_eq!(answer, GROUNDED);
+    }
+}
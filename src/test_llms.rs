@@ -0,0 +1,81 @@
+// -------------------------------------
+// -- LLM test doubles shared by the tests of the LLM wrappers
+//
+// The wrappers (collection settings, answer language, output schema,
+// temperature schedule) are tested against these instead of ollama.
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use futures::{stream, Stream};
+use langchain_rust::{
+    language_models::{llm::LLM, GenerateResult, LLMError},
+    schemas::{Message, StreamData},
+};
+use serde_json::json;
+
+type LLMStream = Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>;
+
+// -- answers with nothing, records the system prompt (the first message)
+#[derive(Clone)]
+pub struct SystemEcho(pub Arc<Mutex<Vec<String>>>);
+
+#[async_trait]
+impl LLM for SystemEcho {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        self.0.lock().unwrap().push(messages[0].content.clone());
+        Ok(GenerateResult {
+            tokens: None,
+            generation: String::new(),
+        })
+    }
+
+    async fn stream(&self, _messages: &[Message]) -> Result<LLMStream, LLMError> {
+        unimplemented!()
+    }
+}
+
+// -- answers with the next of its answers, records the prompts. Streams the answer word by word
+#[derive(Clone, Default)]
+pub struct ScriptedLlm {
+    pub answers: Arc<Mutex<Vec<&'static str>>>,
+    pub prompts: Arc<Mutex<Vec<Vec<Message>>>>,
+}
+
+impl ScriptedLlm {
+    pub fn new(answers: &[&'static str]) -> Self {
+        ScriptedLlm {
+            answers: Arc::new(Mutex::new(answers.to_vec())),
+            prompts: Arc::default(),
+        }
+    }
+
+    fn next(&self, messages: &[Message]) -> &'static str {
+        self.prompts.lock().unwrap().push(messages.to_vec());
+        let mut answers = self.answers.lock().unwrap();
+        assert!(!answers.is_empty(), "no answer left");
+        answers.remove(0)
+    }
+}
+
+#[async_trait]
+impl LLM for ScriptedLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        Ok(GenerateResult {
+            tokens: None,
+            generation: self.next(messages).to_string(),
+        })
+    }
+
+    async fn stream(&self, messages: &[Message]) -> Result<LLMStream, LLMError> {
+        let words: Vec<_> = self
+            .next(messages)
+            .split_inclusive(' ')
+            .map(|word| Ok(StreamData::new(json!({}), None, word)))
+            .collect();
+        Ok(Box::pin(stream::iter(words)))
+    }
+}
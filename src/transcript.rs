@@ -0,0 +1,143 @@
+// -------------------------------------
+// -- `--transcript out.jsonl`: every chat exchange as one json line
+//
+// {"schema_version": 1, "timestamp": 1700000000, "model": "gemma3:12b",
+//  "question": "...", "rephrased_question": "...", "answer": "...",
+//  "sources": [{"path": "...", "score": 0.71}], "timings": {"total_ms": 5321},
+//  "summary": "..."}
+//
+// Fields are only added within a schema version, anything else bumps it.
+// Failed exchanges have `"answer": null` and an `error`, `summary` is
+// null without --summarize-answers. The `rephrase` mode
+// reads a transcript back as the conversation history.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::Duration,
+};
+
+use langchain_rust::schemas::Message;
+use serde_json::{json, Value};
+
+use crate::{answer::answer_text, jobs::unix_now};
+
+const SCHEMA_VERSION: u32 = 1;
+
+pub struct Transcript {
+    file: File,
+}
+
+impl Transcript {
+    pub fn open(path: &str) -> Result<Self, String> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(|file| Transcript { file })
+            .map_err(|e| format!("opening transcript {} failed: {}", path, e))
+    }
+
+    // -- `result` is the output of the chat chain, or its error
+    pub fn record(
+        &mut self,
+        model: &str,
+        question: &str,
+        result: Result<&HashMap<String, Value>, String>,
+        elapsed: Duration,
+        summary: Option<&str>,
+    ) -> Result<(), String> {
+        let mut line = json!({
+            "schema_version": SCHEMA_VERSION,
+            "timestamp": unix_now(),
+            "model": model,
+            "question": question,
+            "rephrased_question": Value::Null,
+            "answer": Value::Null,
+            "sources": [],
+            "timings": { "total_ms": elapsed.as_millis() as u64 },
+            "summary": summary,
+        });
+        match result {
+            Ok(data) => {
+                line["rephrased_question"] = data
+                    .get("generated_question")
+                    .cloned()
+                    .unwrap_or(Value::Null);
+                if let Some(output) = data.get("output") {
+                    line["answer"] = json!(answer_text(output));
+                }
+                line["sources"] = sources(data.get("source_documents"));
+            }
+            Err(e) => line["error"] = json!(e),
+        }
+
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.flush())
+            .map_err(|e| format!("writing transcript failed: {}", e))
+    }
+}
+
+fn sources(source_documents: Option<&Value>) -> Value {
+    let docs = source_documents
+        .and_then(Value::as_array)
+        .map(|docs| docs.as_slice())
+        .unwrap_or_default();
+    docs.iter()
+        .map(|d| {
+            json!({
+                "path": d["metadata"]["path"],
+                "score": d["score"],
+                "collection": d["metadata"]["collection"],
+            })
+        })
+        .collect()
+}
+
+// -- the answered exchanges of a transcript as the conversation history
+pub fn history(path: &str) -> Result<Vec<Message>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("reading transcript {} failed: {}", path, e))?;
+    history_messages(&content).map_err(|e| format!("transcript {}: {}", path, e))
+}
+
+fn history_messages(content: &str) -> Result<Vec<Message>, String> {
+    let mut messages = vec![];
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exchange: Value = serde_json::from_str(line)
+            .map_err(|e| format!("line {} is not json: {}", index + 1, e))?;
+        if let (Some(question), Some(answer)) =
+            (exchange["question"].as_str(), exchange["answer"].as_str())
+        {
+            messages.push(Message::new_human_message(question));
+            messages.push(Message::new_ai_message(answer));
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answered_exchanges_are_the_history() {
+        let content = [
+            json!({"schema_version": 1, "question": "Kolik je dovolené?", "answer": "25 dní."}),
+            json!({"schema_version": 1, "question": "A pro brigádníky?", "answer": null, "error": "timeout"}),
+            json!({"schema_version": 1, "question": "Kdo ji schvaluje?", "answer": "Vedoucí."}),
+        ]
+        .map(|line| line.to_string())
+        .join("\n");
+        let history = history_messages(&content).unwrap();
+        assert_eq!(
+            Message::messages_to_string(&history),
+            "HumanMessage: Kolik je dovolené?\nAIMessage: 25 dní.\nHumanMessage: Kdo ji schvaluje?\nAIMessage: Vedoucí."
+        );
+        assert!(history_messages("{}\nnot json").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+// -------------------------------------
+// -- `watch` mode: documents of a directory ingested again when they change
+//
+// The directory is scanned every --watch-interval-secs and files are compared
+// by their content hash, so it works the same on NFS or CIFS mounts where file
+// system events never arrive. The hashes are kept in --watch-state-file, an
+// unchanged file is not ingested again after a restart. A changed file's old
+// chunks are deleted before it is ingested, a removed file's chunks go too.
+
+use std::{collections::BTreeMap, fs, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    jobs::panic_message,
+    output,
+    store::{ChunkStore, MetadataFilter},
+    Ingest, IngestOutcome,
+};
+
+// -- content hash of every ingested file, by path
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct WatchState {
+    files: BTreeMap<String, String>,
+}
+
+impl WatchState {
+    // -- empty when the file doesn't exist yet
+    pub fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("parsing {} failed: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(WatchState::default()),
+            Err(e) => Err(format!("reading {} failed: {}", path, e)),
+        }
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+            .map_err(|e| format!("writing {} failed: {}", path, e))
+    }
+}
+
+pub fn content_hash(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+// -- files whose hash differs from the state (new ones included) and files gone since
+pub fn changes(
+    state: &WatchState,
+    scanned: &BTreeMap<String, String>,
+) -> (Vec<String>, Vec<String>) {
+    let changed = scanned
+        .iter()
+        .filter(|(path, hash)| state.files.get(*path) != Some(*hash))
+        .map(|(path, _)| path.clone())
+        .collect();
+    let removed = state
+        .files
+        .keys()
+        .filter(|path| !scanned.contains_key(*path))
+        .cloned()
+        .collect();
+    (changed, removed)
+}
+
+pub struct Watcher {
+    pub files: Box<dyn Fn() -> Vec<String> + Send + Sync>,
+    pub ingest: Arc<Ingest>,
+    pub store: Arc<dyn ChunkStore>,
+    pub state_file: String,
+    pub interval: Duration,
+}
+
+impl Watcher {
+    pub async fn run(&self, mut state: WatchState) {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            self.scan(&mut state).await;
+        }
+    }
+
+    // -- a file that failed is left out of the state and tried again on the next scan
+    async fn scan(&self, state: &mut WatchState) {
+        let scanned: BTreeMap<String, String> = (self.files)()
+            .into_iter()
+            .filter_map(|path| match fs::read(&path) {
+                Ok(content) => Some((path, content_hash(&content))),
+                Err(e) => {
+                    log::warn!("reading {} failed: {}", path, e);
+                    None
+                }
+            })
+            .collect();
+        let (changed, removed) = changes(state, &scanned);
+        for path in removed {
+            match self.store.delete(&MetadataFilter::path(&path)).await {
+                Ok(()) => {
+                    output::note(&format!("{} - removed, its chunks deleted", path));
+                    state.files.remove(&path);
+                }
+                Err(e) => log::error!("deleting chunks of {} failed: {}", path, e),
+            }
+        }
+        for path in changed {
+            match self.ingest(&path, &scanned[&path]).await {
+                Ok(chunks) => {
+                    output::note(&format!("{} - ingested, {} chunks", path, chunks));
+                    state.files.insert(path.clone(), scanned[&path].clone());
+                }
+                Err(e) => log::error!("ingesting {} failed: {}", path, e),
+            }
+        }
+        if let Err(e) = state.save(&self.state_file) {
+            log::error!("{}", e);
+        }
+    }
+
+    async fn ingest(&self, path: &str, hash: &str) -> Result<usize, String> {
+        self.store
+            .delete(&MetadataFilter::path(path))
+            .await
+            .map_err(|e| format!("deleting the previous chunks failed: {}", e))?;
+        let ingest = self.ingest.clone();
+        let doc_path = path.to_string();
+        let extra_metadata = [("content_hash".to_string(), json!(hash))].into();
+        let task = tokio::spawn(async move {
+            ingest
+                .ingest_document(&doc_path, &extra_metadata, &|_, _| {})
+                .await
+        });
+        match task.await {
+            Ok(IngestOutcome::Stored(stats)) => Ok(stats.chunks),
+            Ok(IngestOutcome::Skipped(reason)) => Err(format!("document skipped: {}", reason)),
+            Ok(IngestOutcome::Failed(e)) => Err(e),
+            Err(e) => Err(panic_message(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_new_and_removed_files_are_told_apart() {
+        let state = WatchState {
+            files: BTreeMap::from([
+                ("a.pdf".to_string(), "1".to_string()),
+                ("b.pdf".to_string(), "2".to_string()),
+                ("c.pdf".to_string(), "3".to_string()),
+            ]),
+        };
+        let scanned = BTreeMap::from([
+            ("a.pdf".to_string(), "1".to_string()),
+            ("b.pdf".to_string(), "20".to_string()),
+            ("d.pdf".to_string(), "4".to_string()),
+        ]);
+        assert_eq!(
+            changes(&state, &scanned),
+            (
+                vec!["b.pdf".to_string(), "d.pdf".to_string()],
+                vec!["c.pdf".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn missing_state_file_is_empty() {
+        assert_eq!(
+            WatchState::load("/nonexistent/watch_state.json"),
+            Ok(WatchState::default())
+        );
+    }
+}
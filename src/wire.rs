@@ -0,0 +1,107 @@
+// -------------------------------------
+// -- the SSE events of `POST /chat`
+//
+// Every event is named by its type and its JSON data carries the same
+// `type`: `generation`, `sources`, `token`, `thinking`, `attribution`,
+// `aborted`, `error` and `done`. The chain streams chunks of different
+// shapes: ollama chat responses with `message.content`, `output` of other
+// chains, the final result with `source_documents` and bare content of the
+// wrappers answering at once. Only the text of the answer is sent, as
+// `{"type": "token", "content": ...}`; the retrieved documents have their
+// own `sources` event, and chunks of other shapes are logged and skipped.
+
+use axum::response::sse::Event;
+use serde_json::{json, Value};
+
+pub fn event(kind: &str, data: Value) -> Result<Event, axum::Error> {
+    let mut data = data;
+    if let Value::Object(fields) = &mut data {
+        fields.insert("type".to_string(), json!(kind));
+    }
+    Event::default().event(kind).json_data(data)
+}
+
+pub fn token(content: &str) -> Result<Event, axum::Error> {
+    event("token", json!({ "content": content }))
+}
+
+// -- answer text of a streamed chunk, None for chunks without any
+pub fn stream_text(value: &Value, content: &str) -> Option<String> {
+    let text = match value {
+        // -- the whole answer once more, already streamed
+        Value::Object(fields) if fields.contains_key("source_documents") => return None,
+        Value::Object(fields) if fields.is_empty() => Some(content),
+        Value::Object(fields) => match (
+            fields
+                .get("message")
+                .and_then(|message| message.get("content")),
+            fields.get("output"),
+        ) {
+            (Some(Value::String(text)), _) => Some(text.as_str()),
+            (None, Some(Value::String(output))) => Some(output.as_str()),
+            _ => None,
+        },
+        Value::String(_) | Value::Null => Some(content),
+        _ => None,
+    };
+    match text {
+        Some(text) => Some(text.to_string()).filter(|text| !text.is_empty()),
+        None => {
+            log::debug!("skipping stream chunk of unknown shape: {}", value);
+            None
+        }
+    }
+}
+
+// -- tokens generated for the answer, reported by ollama's last chunk
+pub fn eval_count(value: &Value) -> Option<u64> {
+    value
+        .get("done")
+        .and_then(Value::as_bool)
+        .filter(|done| *done)
+        .and_then(|_| value.get("eval_count"))
+        .and_then(Value::as_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // recorded chunks of the chain's stream
+    const OLLAMA_CHUNK: &str = r#"{"model":"gemma3:12b","created_at":"2025-03-20T10:12:01.5Z","message":{"role":"assistant","content":"Směrnice"},"done":false}"#;
+    const OLLAMA_LAST: &str = r#"{"model":"gemma3:12b","created_at":"2025-03-20T10:12:04.1Z","message":{"role":"assistant","content":""},"done":true,"total_duration":2614230000,"eval_count":42}"#;
+    const OUTPUT_CHUNK: &str = r#"{"output":" platí"}"#;
+    const FINAL_RESULT: &str = r#"{"output":"Směrnice platí.","source_documents":[{"page_content":"...","metadata":{"path":"a.pdf"}}]}"#;
+    const SCHEMA_ANSWER: &str =
+        r#"{"message":{"role":"assistant","content":"{\"answer\":\"ano\"}"},"done":true}"#;
+    const UNKNOWN: &str = r#"{"choices":[{"delta":{"content":"x"}}]}"#;
+
+    fn text(chunk: &str, content: &str) -> Option<String> {
+        stream_text(&serde_json::from_str(chunk).unwrap(), content)
+    }
+
+    #[test]
+    fn stream_chunks_are_mapped_to_answer_text() {
+        assert_eq!(text(OLLAMA_CHUNK, "Směrnice").as_deref(), Some("Směrnice"));
+        assert_eq!(text(OLLAMA_LAST, ""), None);
+        assert_eq!(text(OUTPUT_CHUNK, "").as_deref(), Some(" platí"));
+        assert_eq!(text(FINAL_RESULT, "Směrnice platí."), None);
+        assert_eq!(
+            text(SCHEMA_ANSWER, "{\"answer\":\"ano\"}").as_deref(),
+            Some("{\"answer\":\"ano\"}")
+        );
+        assert_eq!(text("{}", "slovo ").as_deref(), Some("slovo "));
+        assert_eq!(text(r#""ok""#, "ok").as_deref(), Some("ok"));
+        assert_eq!(text(UNKNOWN, "x"), None);
+        assert_eq!(text("[1, 2]", "x"), None);
+    }
+
+    #[test]
+    fn eval_count_is_read_from_the_last_chunk() {
+        let value = |chunk: &str| serde_json::from_str::<Value>(chunk).unwrap();
+        assert_eq!(eval_count(&value(OLLAMA_LAST)), Some(42));
+        assert_eq!(eval_count(&value(OLLAMA_CHUNK)), None);
+        assert_eq!(eval_count(&value(SCHEMA_ANSWER)), None);
+        assert_eq!(eval_count(&value(OUTPUT_CHUNK)), None);
+    }
+}